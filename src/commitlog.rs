@@ -0,0 +1,625 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Cursor, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use uuid::Uuid;
+
+use crate::db::{TableMetaData, TableRow};
+use crate::io::{CassRead, CassWrite, DecodeError};
+use crate::memtable::Memtable;
+use crate::sstable::row_data::{RowDataReader, RowWriter};
+use crate::util::other_error;
+
+const SEGMENT_PREFIX: &str = "CommitLog-";
+const SEGMENT_SUFFIX: &str = ".log";
+const FLUSHED_MARKER: &str = "CommitLog.flushed";
+
+/// record length (u32) and checksum (u32)
+const RECORD_HEADER_SIZE: usize = 8;
+
+
+/// Controls when appended records are fsync'ed to disk, i.e. how many acknowledged writes may be
+///  lost if the machine crashes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncMode {
+    /// every record is synced before `append` returns
+    PerWrite,
+    /// every call to `append` or `append_batch` is synced once before it returns
+    Batch,
+    /// records are synced by a background thread at fixed intervals; a failed sync is reported by
+    ///  all later calls to `append`, `append_batch` and `sync`
+    Periodic(Duration),
+}
+
+#[derive(Debug, Clone)]
+pub struct CommitLogConfig {
+    pub folder: PathBuf,
+    /// a new segment is started when a record would make the current segment exceed this size
+    pub segment_size: u64,
+    pub sync_mode: SyncMode,
+}
+
+/// a position in the commit log, ordered by time of writing
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CommitLogPosition {
+    pub segment_id: u64,
+    pub offset: u64,
+}
+
+impl CommitLogPosition {
+    const START: CommitLogPosition = CommitLogPosition { segment_id: 0, offset: 0 };
+}
+
+
+/// An append-only log of all mutations that are not yet flushed to sstables. The log consists of
+///  segment files, each of which is a sequence of records: the payload's length (u32) and its xxhash
+///  checksum (u32), followed by the payload, which is the table's ID and the row in data file format.
+pub struct CommitLog {
+    config: CommitLogConfig,
+    segment_id: u64,
+    offset: u64,
+    out: CassWrite<BufWriter<File>>,
+    syncer: Option<PeriodicSyncer>,
+}
+
+impl CommitLog {
+    /// Starts a new segment after all existing ones. Existing segments should be replayed before
+    ///  the commit log is opened.
+    pub fn open(config: CommitLogConfig) -> std::io::Result<CommitLog> {
+        std::fs::create_dir_all(&config.folder)?;
+
+        let last_segment_id = list_segments(&config.folder)?.last().map(|(id, _)| *id);
+        let flushed_segment_id = read_flushed_marker(&config.folder)?.segment_id;
+        let segment_id = last_segment_id.unwrap_or(0).max(flushed_segment_id) + 1;
+
+        let file = create_segment(&config.folder, segment_id)?;
+        let syncer = match config.sync_mode {
+            SyncMode::Periodic(interval) => Some(PeriodicSyncer::new(interval, file.try_clone()?)),
+            _ => None,
+        };
+
+        Ok(CommitLog {
+            config,
+            segment_id,
+            offset: 0,
+            out: CassWrite::new(BufWriter::new(file)),
+            syncer,
+        })
+    }
+
+    /// the position after the last appended record
+    pub fn position(&self) -> CommitLogPosition {
+        CommitLogPosition { segment_id: self.segment_id, offset: self.offset }
+    }
+
+    /// returns the position after the record
+    pub fn append(&mut self, row: &TableRow) -> std::io::Result<CommitLogPosition> {
+        self.write_record(row)?;
+        self.commit()?;
+        Ok(self.position())
+    }
+
+    /// appends several rows, syncing them together in `Batch` mode; returns the position after the
+    ///  last record
+    pub fn append_batch(&mut self, rows: &[TableRow]) -> std::io::Result<CommitLogPosition> {
+        for row in rows {
+            self.write_record(row)?;
+            if self.config.sync_mode == SyncMode::PerWrite {
+                self.sync()?;
+            }
+        }
+        self.commit()?;
+        Ok(self.position())
+    }
+
+    fn write_record(&mut self, row: &TableRow) -> std::io::Result<()> {
+        let mut payload = CassWrite::new(Cursor::new(Vec::new()));
+        payload.write_uuid(&row.meta_data().id)?;
+        let mut payload = RowWriter::new(payload.into_inner());
+        payload.write_row(row)?;
+        let payload = payload.into_inner().into_inner();
+
+        if payload.len() > u32::MAX as usize {
+            return other_error("row too big for the commit log");
+        }
+
+        let record_size = (RECORD_HEADER_SIZE + payload.len()) as u64;
+        if self.offset > 0 && self.offset + record_size > self.config.segment_size {
+            self.start_new_segment()?;
+        }
+
+        self.out.write_u32(payload.len() as u32)?;
+        self.out.write_u32(fasthash::xx::hash32(&payload))?;
+        self.out.write_raw(&payload)?;
+        self.offset += record_size;
+        Ok(())
+    }
+
+    /// makes appended records visible to the OS and syncs them unless that is left to the
+    ///  periodic syncer
+    fn commit(&mut self) -> std::io::Result<()> {
+        match self.config.sync_mode {
+            SyncMode::Periodic(_) => {
+                self.check_syncer()?;
+                self.out.flush()
+            },
+            SyncMode::PerWrite | SyncMode::Batch => self.sync(),
+        }
+    }
+
+    pub fn sync(&mut self) -> std::io::Result<()> {
+        self.check_syncer()?;
+        self.out.flush()?;
+        self.out.get_ref().get_ref().sync_data()
+    }
+
+    fn check_syncer(&self) -> std::io::Result<()> {
+        match &self.syncer {
+            Some(syncer) => syncer.check(),
+            None => Ok(()),
+        }
+    }
+
+    /// old segments are synced completely before a new segment is created, so only the last
+    ///  segment can have a torn record at its end
+    fn start_new_segment(&mut self) -> std::io::Result<()> {
+        self.sync()?;
+
+        self.segment_id += 1;
+        let file = create_segment(&self.config.folder, self.segment_id)?;
+        if let Some(syncer) = &self.syncer {
+            syncer.switch_segment(file.try_clone()?);
+        }
+        self.out = CassWrite::new(BufWriter::new(file));
+        self.offset = 0;
+        Ok(())
+    }
+
+    /// Marks all records before `position` as obsolete and deletes the segments that contain
+    ///  only such records. Callers must ensure that all tables' data up to `position` was flushed.
+    pub fn discard_until(&mut self, position: CommitLogPosition) -> std::io::Result<()> {
+        if position > self.position() {
+            return other_error("position is after the end of the commit log");
+        }
+
+        write_flushed_marker(&self.config.folder, position)?;
+
+        for (segment_id, path) in list_segments(&self.config.folder)? {
+            if segment_id < position.segment_id {
+                std::fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+
+/// Fsyncs the current segment at fixed intervals, and once more before it is replaced or the
+///  commit log is closed.
+///
+/// The first failed sync is recorded and reported from then on: after a failed fsync it is unknown
+///  which writes reached the disk, so no later write can be acknowledged as durable.
+struct PeriodicSyncer {
+    segments: Option<Sender<File>>,
+    thread: Option<JoinHandle<()>>,
+    error: Arc<Mutex<Option<std::io::Error>>>,
+}
+
+impl PeriodicSyncer {
+    fn new(interval: Duration, segment: File) -> PeriodicSyncer {
+        let (segments, rx) = channel::<File>();
+        let error = Arc::new(Mutex::new(None));
+
+        let thread_error = error.clone();
+        let thread = std::thread::spawn(move || {
+            let sync = |segment: &File| {
+                if let Err(e) = segment.sync_data() {
+                    thread_error.lock().unwrap().get_or_insert(e);
+                }
+            };
+
+            let mut segment = segment;
+            loop {
+                match rx.recv_timeout(interval) {
+                    Ok(new_segment) => {
+                        sync(&segment);
+                        segment = new_segment;
+                    },
+                    Err(RecvTimeoutError::Timeout) => {
+                        sync(&segment);
+                    },
+                    Err(RecvTimeoutError::Disconnected) => {
+                        sync(&segment);
+                        break;
+                    },
+                }
+            }
+        });
+
+        PeriodicSyncer {
+            segments: Some(segments),
+            thread: Some(thread),
+            error,
+        }
+    }
+
+    /// fails if any sync failed so far
+    fn check(&self) -> std::io::Result<()> {
+        match self.error.lock().unwrap().as_ref() {
+            None => Ok(()),
+            Some(e) => Err(std::io::Error::new(e.kind(), format!("periodic commit log sync failed: {}", e))),
+        }
+    }
+
+    fn switch_segment(&self, segment: File) {
+        if let Some(segments) = &self.segments {
+            let _ = segments.send(segment);
+        }
+    }
+}
+
+impl Drop for PeriodicSyncer {
+    fn drop(&mut self) {
+        self.segments.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+
+/// Reads all segments' records after the position up to which data was flushed into memtables, one
+///  per table. Records for tables that are not in `tables` (i.e. were dropped) are skipped.
+///  Segments that contain only flushed data are deleted, and a torn record at the end of the last
+///  segment is truncated.
+pub fn replay(folder: &Path, tables: &HashMap<Uuid, Arc<TableMetaData>>) -> std::io::Result<HashMap<Uuid, Memtable>> {
+    let mut memtables = HashMap::new();
+    if !folder.exists() {
+        return Ok(memtables);
+    }
+
+    let flushed = read_flushed_marker(folder)?;
+    let segments = list_segments(folder)?;
+    let num_segments = segments.len();
+
+    for (idx, (segment_id, path)) in segments.into_iter().enumerate() {
+        if segment_id < flushed.segment_id {
+            std::fs::remove_file(path)?;
+            continue;
+        }
+
+        let buf = std::fs::read(&path)?;
+        let start = if segment_id == flushed.segment_id { flushed.offset as usize } else { 0 };
+        let is_last_segment = idx == num_segments - 1;
+
+        let valid_len = replay_segment(&path, &buf, start, is_last_segment, tables, &mut memtables)?;
+        if valid_len < buf.len() {
+            OpenOptions::new().write(true).open(&path)?.set_len(valid_len as u64)?;
+        }
+    }
+
+    Ok(memtables)
+}
+
+/// returns the length of the segment's valid part
+fn replay_segment(path: &Path, buf: &[u8], start: usize, is_last_segment: bool, tables: &HashMap<Uuid, Arc<TableMetaData>>, memtables: &mut HashMap<Uuid, Memtable>) -> std::io::Result<usize> {
+    let mut r = CassRead::wrap(buf);
+    r.pos = start;
+
+    while r.pos < buf.len() {
+        let record_start = r.pos;
+
+        let is_complete = buf.len() - record_start >= RECORD_HEADER_SIZE
+            && buf.len() - record_start - RECORD_HEADER_SIZE >= r.peek_u32()? as usize;
+        if !is_complete {
+            return torn_record(record_start, is_last_segment);
+        }
+
+        let len = r.read_u32()? as usize;
+        let checksum = r.read_u32()?;
+        let payload = r.read_slice(len)?;
+        if fasthash::xx::hash32(payload) != checksum {
+            if r.pos == buf.len() {
+                return torn_record(record_start, is_last_segment);
+            }
+            return Err(std::io::Error::new(ErrorKind::InvalidData, format!("commit log checksum mismatch at offset {}", record_start)));
+        }
+
+        // decode errors in a payload with a valid checksum are reported with their offset in the segment
+        let in_segment = |e: DecodeError| DecodeError { offset: record_start + RECORD_HEADER_SIZE + e.offset, ..e }.in_file(path);
+
+        let mut payload = CassRead::wrap(payload);
+        let table_id = payload.read_uuid().map_err(in_segment)?;
+        if let Some(table_metadata) = tables.get(&table_id) {
+            let row = RowDataReader::new(table_metadata.clone(), payload).read_row().map_err(in_segment)?;
+            memtables.entry(table_id)
+                .or_insert_with(|| Memtable::new(table_metadata.clone()))
+                .apply(&row)
+                .map_err(|e| e.in_file(path))?;
+        }
+    }
+
+    Ok(buf.len())
+}
+
+fn torn_record(offset: usize, is_last_segment: bool) -> std::io::Result<usize> {
+    if is_last_segment {
+        Ok(offset)
+    }
+    else {
+        Err(std::io::Error::new(ErrorKind::InvalidData, format!("incomplete commit log record at offset {}", offset)))
+    }
+}
+
+
+fn segment_path(folder: &Path, segment_id: u64) -> PathBuf {
+    folder.join(format!("{}{}{}", SEGMENT_PREFIX, segment_id, SEGMENT_SUFFIX))
+}
+
+fn create_segment(folder: &Path, segment_id: u64) -> std::io::Result<File> {
+    OpenOptions::new().write(true).create_new(true).open(segment_path(folder, segment_id))
+}
+
+/// all segment files, ordered by ID
+fn list_segments(folder: &Path) -> std::io::Result<Vec<(u64, PathBuf)>> {
+    let mut result = Vec::new();
+    for entry in std::fs::read_dir(folder)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let segment_id = file_name.to_str()
+            .and_then(|name| name.strip_prefix(SEGMENT_PREFIX))
+            .and_then(|name| name.strip_suffix(SEGMENT_SUFFIX))
+            .and_then(|id| id.parse::<u64>().ok());
+
+        if let Some(segment_id) = segment_id {
+            result.push((segment_id, entry.path()));
+        }
+    }
+    result.sort();
+    Ok(result)
+}
+
+fn read_flushed_marker(folder: &Path) -> std::io::Result<CommitLogPosition> {
+    let buf = match std::fs::read(folder.join(FLUSHED_MARKER)) {
+        Ok(buf) => buf,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(CommitLogPosition::START),
+        Err(e) => return Err(e),
+    };
+    if buf.len() != 16 {
+        return other_error("corrupt commit log marker file");
+    }
+
+    let mut r = CassRead::wrap(&buf);
+    Ok(CommitLogPosition {
+        segment_id: r.read_u64()?,
+        offset: r.read_u64()?,
+    })
+}
+
+/// written to a temp file and renamed so that a crash can not leave a partially written marker
+fn write_flushed_marker(folder: &Path, position: CommitLogPosition) -> std::io::Result<()> {
+    let tmp_path = folder.join(format!("{}.tmp", FLUSHED_MARKER));
+
+    let mut out = CassWrite::new(File::create(&tmp_path)?);
+    out.write_u64(position.segment_id)?;
+    out.write_u64(position.offset)?;
+    out.into_inner().sync_all()?;
+
+    std::fs::rename(tmp_path, folder.join(FLUSHED_MARKER))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::fs::OpenOptions;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use uuid::Uuid;
+
+    use crate::commitlog::{list_segments, replay, segment_path, CommitLog, CommitLogConfig, PeriodicSyncer, SyncMode};
+    use crate::db::{ColumnType, RegularRowData, RowDetails, TableCell, TableCellData, TableMetaData, TableOptions, TableRow};
+    use crate::memtable::Memtable;
+    use crate::sstable::testing::{clustered_table_metadata, TempFolder};
+    use crate::util::NO_EXPIRY;
+
+    fn tables(table_metadata: &Arc<TableMetaData>) -> HashMap<Uuid, Arc<TableMetaData>> {
+        let mut result = HashMap::new();
+        result.insert(table_metadata.id, table_metadata.clone());
+        result
+    }
+
+    fn table_metadata() -> Arc<TableMetaData> {
+        clustered_table_metadata(&[("v", ColumnType::Long)], TableOptions::default())
+    }
+
+    fn config(folder: &TempFolder, sync_mode: SyncMode, segment_size: u64) -> CommitLogConfig {
+        CommitLogConfig {
+            folder: folder.path().to_path_buf(),
+            segment_size,
+            sync_mode,
+        }
+    }
+
+    /// appends row `n` with column v set to `n`
+    fn append(log: &mut CommitLog, table_metadata: &Arc<TableMetaData>, n: u64) {
+        let key = n.to_be_bytes();
+        let row = TableRow::new(table_metadata.clone(), &key, RowDetails::Regular(RegularRowData {
+            pk_expiry: NO_EXPIRY,
+            cluster_key: vec!(&key),
+            regular_cols: vec!(TableCell {
+                meta_data: table_metadata.columns[2].clone(),
+                path: None,
+                timestamp: n,
+                expiry: NO_EXPIRY,
+                data: TableCellData::Regular(&key),
+            }),
+        }));
+        log.append(&row).unwrap();
+    }
+
+    fn replayed_rows(folder: &Path, table_metadata: &Arc<TableMetaData>) -> Vec<u64> {
+        let memtables = replay(folder, &tables(table_metadata)).unwrap();
+        let mut result = Vec::new();
+        if let Some(memtable) = memtables.get(&table_metadata.id) {
+            collect_rows(memtable, &mut result);
+        }
+        result.sort();
+        result
+    }
+
+    fn collect_rows(memtable: &Memtable, result: &mut Vec<u64>) {
+        for (_, partition_key, partition) in memtable.partitions() {
+            for (_, row) in partition.rows() {
+                for cell in row.cells.values() {
+                    assert_eq!(Some(partition_key.to_vec()), cell.data);
+                }
+                let mut key = [0u8; 8];
+                key.copy_from_slice(partition_key);
+                result.push(u64::from_be_bytes(key));
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_replay() {
+        let table_metadata = table_metadata();
+
+        for sync_mode in [SyncMode::PerWrite, SyncMode::Batch, SyncMode::Periodic(Duration::from_millis(1))].iter() {
+            let folder = TempFolder::new();
+            let config = config(&folder, *sync_mode, 1024*1024);
+            {
+                let mut log = CommitLog::open(config.clone()).unwrap();
+                for n in 0..100 {
+                    append(&mut log, &table_metadata, n);
+                }
+            }
+
+            assert_eq!((0..100).collect::<Vec<_>>(), replayed_rows(&config.folder, &table_metadata));
+
+            // rows of unknown tables are skipped
+            assert!(replay(&config.folder, &HashMap::new()).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    pub fn test_segments_and_discard() {
+        let table_metadata = table_metadata();
+        let folder = TempFolder::new();
+        let config = config(&folder, SyncMode::Batch, 500);
+
+        let mut log = CommitLog::open(config.clone()).unwrap();
+        for n in 0..50 {
+            append(&mut log, &table_metadata, n);
+        }
+        let flushed = log.position();
+        for n in 50..100 {
+            append(&mut log, &table_metadata, n);
+        }
+
+        let num_segments = list_segments(&config.folder).unwrap().len();
+        assert!(num_segments > 5, "{} segments", num_segments);
+        for (segment_id, path) in list_segments(&config.folder).unwrap() {
+            let len = std::fs::metadata(path).unwrap().len();
+            assert!(len <= 500, "segment {} has size {}", segment_id, len);
+        }
+
+        log.discard_until(flushed).unwrap();
+        assert!(list_segments(&config.folder).unwrap().len() < num_segments);
+        assert_eq!((50..100).collect::<Vec<_>>(), replayed_rows(&config.folder, &table_metadata));
+
+        // a restarted commit log continues after the existing segments
+        drop(log);
+        let mut log = CommitLog::open(config.clone()).unwrap();
+        append(&mut log, &table_metadata, 100);
+        assert_eq!((50..101).collect::<Vec<_>>(), replayed_rows(&config.folder, &table_metadata));
+
+        // replay deletes segments that contain only flushed data
+        let flushed = log.position();
+        log.discard_until(flushed).unwrap();
+        drop(log);
+        assert_eq!(Vec::<u64>::new(), replayed_rows(&config.folder, &table_metadata));
+        assert_eq!(1, list_segments(&config.folder).unwrap().len());
+    }
+
+    #[test]
+    pub fn test_torn_last_record() {
+        let table_metadata = table_metadata();
+        let folder = TempFolder::new();
+        let config = config(&folder, SyncMode::PerWrite, 1024*1024);
+
+        let mut log = CommitLog::open(config.clone()).unwrap();
+        for n in 0..10 {
+            append(&mut log, &table_metadata, n);
+        }
+        let segment_id = log.position().segment_id;
+        let len = log.position().offset;
+        drop(log);
+
+        let path = segment_path(&config.folder, segment_id);
+        OpenOptions::new().write(true).open(&path).unwrap().set_len(len - 3).unwrap();
+
+        assert_eq!((0..9).collect::<Vec<_>>(), replayed_rows(&config.folder, &table_metadata));
+
+        // the torn record was truncated, so that the segment is valid once it is no longer the last one
+        let mut log = CommitLog::open(config.clone()).unwrap();
+        append(&mut log, &table_metadata, 10);
+        drop(log);
+        assert_eq!((0..9).chain(10..11).collect::<Vec<_>>(), replayed_rows(&config.folder, &table_metadata));
+    }
+
+    #[test]
+    pub fn test_corrupt_record() {
+        let table_metadata = table_metadata();
+        let folder = TempFolder::new();
+        let config = config(&folder, SyncMode::PerWrite, 1024*1024);
+
+        let mut log = CommitLog::open(config.clone()).unwrap();
+        for n in 0..10 {
+            append(&mut log, &table_metadata, n);
+        }
+        let segment_id = log.position().segment_id;
+        drop(log);
+
+        let path = segment_path(&config.folder, segment_id);
+        let mut buf = std::fs::read(&path).unwrap();
+        buf[20] ^= 1;
+        std::fs::write(&path, buf).unwrap();
+
+        assert!(replay(&config.folder, &tables(&table_metadata)).is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    pub fn test_periodic_sync_failure() {
+        let table_metadata = table_metadata();
+        let folder = TempFolder::new();
+        let config = config(&folder, SyncMode::Periodic(Duration::from_millis(1)), 1024*1024);
+        let mut log = CommitLog::open(config.clone()).unwrap();
+        append(&mut log, &table_metadata, 0);
+
+        // pipes can not be fsync'ed
+        let (_reader, writer) = std::io::pipe().unwrap();
+        let pipe = std::fs::File::from(std::os::fd::OwnedFd::from(writer));
+        log.syncer = Some(PeriodicSyncer::new(Duration::from_millis(1), pipe));
+        std::thread::sleep(Duration::from_millis(100));
+
+        // the failure is reported by all later writes, not just the next one
+        let key = 1u64.to_be_bytes();
+        let row = TableRow::new(table_metadata.clone(), &key, RowDetails::Regular(RegularRowData {
+            pk_expiry: NO_EXPIRY,
+            cluster_key: vec!(&key),
+            regular_cols: Vec::new(),
+        }));
+        assert!(log.append(&row).is_err());
+        assert!(log.append_batch(&[row]).is_err());
+        assert!(log.sync().is_err());
+    }
+}
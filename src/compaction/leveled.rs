@@ -0,0 +1,402 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use uuid::Uuid;
+
+use crate::compaction::{CompactionStrategy, CompactionTask};
+use crate::db::{LeveledOptions, TableMetaData};
+use crate::io::{CassRead, CassWrite, DecodeResult};
+use crate::memtable::Memtable;
+use crate::sstable::SstableMetaData;
+use crate::util::{other_error, Token};
+
+
+/// Keeps sstables in levels. Flushed sstables go into level 0, where their token ranges may
+///  overlap. In all other levels, sstables have non-overlapping token ranges, so a read needs to
+///  look at no more than one sstable per level, and each level holds `fanout` times as much data
+///  as the previous one.
+///
+/// Once there are enough sstables in level 0, they are compacted together with the overlapping
+///  sstables of level 1. A level that exceeds its size has one of its sstables compacted with the
+///  overlapping sstables of the next level, going round-robin through the level's token range.
+pub struct LeveledStrategy {
+    options: LeveledOptions,
+    manifest: LeveledManifest,
+    token_ranges: HashMap<Uuid, Option<(Token, Token)>>,
+    /// per level, the last token of the most recently compacted sstable
+    compaction_cursors: HashMap<usize, Token>,
+}
+
+impl LeveledStrategy {
+    pub fn open(table_metadata: Arc<TableMetaData>, folder: &Path, options: LeveledOptions) -> std::io::Result<LeveledStrategy> {
+        Ok(LeveledStrategy {
+            options,
+            manifest: LeveledManifest::load(&table_metadata, folder)?,
+            token_ranges: HashMap::new(),
+            compaction_cursors: HashMap::new(),
+        })
+    }
+
+    pub fn manifest(&self) -> &LeveledManifest {
+        &self.manifest
+    }
+
+    fn token_range(&mut self, sstable: &SstableMetaData) -> std::io::Result<Option<(Token, Token)>> {
+        if let Some(token_range) = self.token_ranges.get(&sstable.uuid()) {
+            return Ok(*token_range);
+        }
+        let token_range = sstable.token_range()?;
+        self.token_ranges.insert(sstable.uuid(), token_range);
+        Ok(token_range)
+    }
+}
+
+impl CompactionStrategy for LeveledStrategy {
+    fn next_task(&mut self, sstables: &[SstableMetaData], _memtables: &[&Memtable]) -> std::io::Result<Option<CompactionTask>> {
+        let mut candidates = Vec::new();
+        for sstable in sstables {
+            candidates.push(Candidate {
+                item: sstable.clone(),
+                size: sstable.data_size()?,
+                level: self.manifest.level(&sstable.uuid()),
+                token_range: self.token_range(sstable)?,
+            });
+        }
+
+        Ok(plan(&candidates, &self.options, &self.compaction_cursors)
+            .map(|(inputs, level)| CompactionTask {
+                inputs,
+                max_output_size: Some(self.options.sstable_size),
+                output_level: Some(level),
+                expired_only: false,
+            }))
+    }
+
+    fn task_finished(&mut self, task: &CompactionTask, outputs: &[SstableMetaData]) -> std::io::Result<()> {
+        let output_level = match task.output_level {
+            Some(level) => level,
+            None => return other_error("leveled compaction task without an output level"),
+        };
+
+        if output_level > 1 {
+            let cursor = task.inputs.iter()
+                .filter(|input| self.manifest.level(&input.uuid()) == output_level - 1)
+                .filter_map(|input| self.token_ranges.get(&input.uuid()).copied().flatten())
+                .map(|(_, last)| last)
+                .max();
+            if let Some(cursor) = cursor {
+                self.compaction_cursors.insert(output_level - 1, cursor);
+            }
+        }
+
+        for input in task.inputs.iter() {
+            self.manifest.remove(&input.uuid());
+            self.token_ranges.remove(&input.uuid());
+        }
+        for output in outputs {
+            self.manifest.set_level(output.uuid(), output_level);
+        }
+        self.manifest.save()
+    }
+}
+
+
+struct Candidate<T> {
+    item: T,
+    size: u64,
+    level: usize,
+    token_range: Option<(Token, Token)>,
+}
+
+fn overlaps(a: Option<(Token, Token)>, b: Option<(Token, Token)>) -> bool {
+    match (a, b) {
+        (Some((first_a, last_a)), Some((first_b, last_b))) => first_a <= last_b && first_b <= last_a,
+        _ => false,
+    }
+}
+
+fn max_level_size(level: usize, options: &LeveledOptions) -> u64 {
+    options.sstable_size.saturating_mul(options.fanout.saturating_pow(level as u32))
+}
+
+/// picks the inputs and output level of the next compaction
+fn plan<T: Clone>(candidates: &[Candidate<T>], options: &LeveledOptions, compaction_cursors: &HashMap<usize, Token>) -> Option<(Vec<T>, usize)> {
+    let in_level = |level: usize| {
+        let mut result: Vec<&Candidate<T>> = candidates.iter().filter(|c| c.level == level).collect();
+        result.sort_by_key(|c| c.token_range);
+        result
+    };
+    let with_overlapping = |picked: Vec<&Candidate<T>>, token_range: Option<(Token, Token)>, next_level: usize| {
+        picked.into_iter()
+            .chain(in_level(next_level).into_iter().filter(|c| overlaps(c.token_range, token_range)))
+            .map(|c| c.item.clone())
+            .collect::<Vec<T>>()
+    };
+
+    let level0 = in_level(0);
+    if !level0.is_empty() && level0.len() >= options.l0_threshold {
+        let first = level0.iter().filter_map(|c| c.token_range).map(|(first, _)| first).min();
+        let last = level0.iter().filter_map(|c| c.token_range).map(|(_, last)| last).max();
+        let token_range = first.zip(last);
+        return Some((with_overlapping(level0, token_range, 1), 1));
+    }
+
+    let max_level = candidates.iter().map(|c| c.level).max().unwrap_or(0);
+    for level in 1..=max_level {
+        let sstables = in_level(level);
+        if sstables.iter().map(|c| c.size).sum::<u64>() <= max_level_size(level, options) {
+            continue;
+        }
+
+        let picked = match compaction_cursors.get(&level) {
+            None => sstables[0],
+            Some(cursor) => *sstables.iter()
+                .find(|c| c.token_range.is_some_and(|(first, _)| first > *cursor))
+                .unwrap_or(&sstables[0]),
+        };
+        return Some((with_overlapping(vec!(picked), picked.token_range, level + 1), level + 1));
+    }
+    None
+}
+
+
+/// Records which sstable belongs to which level. It is stored in the table's folder as the number
+///  of entries (u32), followed by each entry's sstable UUID and level (u8). sstables that are not
+///  in the manifest belong to level 0.
+pub struct LeveledManifest {
+    path: PathBuf,
+    levels: HashMap<Uuid, usize>,
+}
+
+impl LeveledManifest {
+    pub fn load(table_metadata: &TableMetaData, folder: &Path) -> std::io::Result<LeveledManifest> {
+        let path = folder.join(format!("{}_{}.manifest", table_metadata.name, table_metadata.id.to_hyphenated()));
+
+        let levels = if path.exists() {
+            let buf = std::fs::read(&path)?;
+            LeveledManifest::decode(&buf).map_err(|e| e.in_file(&path))?
+        }
+        else {
+            HashMap::new()
+        };
+
+        Ok(LeveledManifest { path, levels })
+    }
+
+    fn decode(buf: &[u8]) -> DecodeResult<HashMap<Uuid, usize>> {
+        let mut levels = HashMap::new();
+        let mut r = CassRead::wrap(buf);
+        let num_entries = r.read_u32()?;
+        for _ in 0..num_entries {
+            let uuid = r.read_uuid()?;
+            levels.insert(uuid, r.read_u8()? as usize);
+        }
+        Ok(levels)
+    }
+
+    pub fn level(&self, sstable_uuid: &Uuid) -> usize {
+        self.levels.get(sstable_uuid).copied().unwrap_or(0)
+    }
+
+    pub fn set_level(&mut self, sstable_uuid: Uuid, level: usize) {
+        self.levels.insert(sstable_uuid, level);
+    }
+
+    pub fn remove(&mut self, sstable_uuid: &Uuid) {
+        self.levels.remove(sstable_uuid);
+    }
+
+    /// written to a temp file and renamed so that a crash can not leave a partially written manifest
+    pub fn save(&self) -> std::io::Result<()> {
+        let tmp_path = self.path.with_extension("manifest.tmp");
+
+        let mut out = CassWrite::new(File::create(&tmp_path)?);
+        out.write_u32(self.levels.len() as u32)?;
+        for (uuid, level) in self.levels.iter() {
+            if *level > u8::MAX as usize {
+                return other_error("level out of range");
+            }
+            out.write_uuid(uuid)?;
+            out.write_u8(*level as u8)?;
+        }
+        out.into_inner().sync_all()?;
+
+        std::fs::rename(tmp_path, &self.path)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use crate::compaction::leveled::{plan, Candidate, LeveledManifest, LeveledStrategy};
+    use crate::compaction::run_compaction;
+    use crate::db::{ColumnType, CompactionOptions, LeveledOptions, RegularRowData, RowDetails, TableCell, TableCellData, TableMetaData, TableOptions, TableRow};
+    use crate::memtable::Memtable;
+    use crate::sstable::flush::flush_memtable;
+    use crate::sstable::row_data::RowDataReader;
+    use crate::sstable::set::SstableSet;
+    use crate::sstable::testing::{clustered_table_metadata, TempFolder};
+    use crate::sstable::SstableMetaData;
+    use crate::util::NO_EXPIRY;
+
+    fn options() -> LeveledOptions {
+        LeveledOptions {
+            sstable_size: 1000,
+            fanout: 2,
+            l0_threshold: 2,
+        }
+    }
+
+    fn candidate(item: u32, size: u64, level: usize, first: u128, last: u128) -> Candidate<u32> {
+        Candidate { item, size, level, token_range: Some((first, last)) }
+    }
+
+    #[test]
+    pub fn test_plan() {
+        let options = options();
+        let no_cursors = HashMap::new();
+
+        assert_eq!(None, plan::<u32>(&[], &options, &no_cursors));
+        assert_eq!(None, plan(&[candidate(1, 100, 0, 10, 20)], &options, &no_cursors));
+
+        // level 0 is compacted with the overlapping sstables of level 1
+        let candidates = vec!(
+            candidate(1, 100, 0, 10, 20),
+            candidate(2, 100, 0, 30, 40),
+            candidate(3, 1000, 1, 0, 5),
+            candidate(4, 1000, 1, 6, 15),
+            candidate(5, 1000, 1, 35, 50),
+            candidate(6, 1000, 1, 51, 60),
+        );
+        assert_eq!(Some((vec!(1, 2, 4, 5), 1)), plan(&candidates, &options, &no_cursors));
+
+        // level 1 exceeds its size of 2000
+        let candidates = vec!(
+            candidate(3, 1000, 1, 0, 5),
+            candidate(4, 1000, 1, 6, 15),
+            candidate(5, 1000, 1, 35, 50),
+            candidate(7, 1000, 2, 0, 7),
+            candidate(8, 1000, 2, 8, 40),
+            candidate(9, 1000, 2, 41, 60),
+        );
+        assert_eq!(Some((vec!(3, 7), 2)), plan(&candidates, &options, &no_cursors));
+
+        let mut cursors = HashMap::new();
+        cursors.insert(1, 5);
+        assert_eq!(Some((vec!(4, 7, 8), 2)), plan(&candidates, &options, &cursors));
+        cursors.insert(1, 15);
+        assert_eq!(Some((vec!(5, 8, 9), 2)), plan(&candidates, &options, &cursors));
+        // wrap around
+        cursors.insert(1, 50);
+        assert_eq!(Some((vec!(3, 7), 2)), plan(&candidates, &options, &cursors));
+    }
+
+    fn table_metadata() -> Arc<TableMetaData> {
+        let options = TableOptions {
+            compaction: CompactionOptions::Leveled(options()),
+            ..TableOptions::default()
+        };
+        clustered_table_metadata(&[("v", ColumnType::Long)], options)
+    }
+
+    fn apply_row(memtable: &mut Memtable, id: u64, value: u64) {
+        let table_metadata = memtable.table_metadata().clone();
+        let id = id.to_be_bytes();
+        let timestamp = value;
+        let value = value.to_be_bytes();
+        memtable.apply(&TableRow::new(table_metadata.clone(), &id, RowDetails::Regular(RegularRowData {
+            pk_expiry: NO_EXPIRY,
+            cluster_key: vec!(&id),
+            regular_cols: vec!(TableCell {
+                meta_data: table_metadata.columns[2].clone(),
+                path: None,
+                timestamp,
+                expiry: NO_EXPIRY,
+                data: TableCellData::Regular(&value),
+            }),
+        }))).unwrap();
+    }
+
+    /// (partition key, value) of all rows
+    fn read_rows(sstable: &SstableMetaData) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+        let contents = sstable.read_data().unwrap();
+
+        let mut memtable = Memtable::new(sstable.table_metadata.clone());
+        for row in RowDataReader::for_contents(sstable.table_metadata.clone(), &contents, 0).unwrap() {
+            memtable.apply(&row.unwrap()).unwrap();
+        }
+
+        let mut result = Vec::new();
+        for (_, partition_key, partition) in memtable.partitions() {
+            for (_, row) in partition.rows() {
+                for cell in row.cells.values() {
+                    result.push((partition_key.to_vec(), cell.data.clone()));
+                }
+            }
+        }
+        result
+    }
+
+    #[test]
+    pub fn test_leveled_compaction() {
+        let table_metadata = table_metadata();
+        let folder = TempFolder::new();
+
+        let sstables = SstableSet::new(Vec::new());
+        let mut strategy = LeveledStrategy::open(table_metadata.clone(), folder.path(), options()).unwrap();
+
+        let mut expected = HashMap::new();
+        for generation in 0..8 {
+            let mut memtable = Memtable::new(table_metadata.clone());
+            for id in (generation * 17)..(generation * 17 + 40) {
+                apply_row(&mut memtable, id, generation);
+                expected.insert(id.to_be_bytes().to_vec(), Some(generation.to_be_bytes().to_vec()));
+            }
+            sstables.add(flush_memtable(&memtable, SstableMetaData::new(table_metadata.clone(), folder.path())).unwrap());
+
+            let mut num_compactions = 0;
+            while run_compaction(&sstables, &[], &mut strategy).unwrap() {
+                num_compactions += 1;
+                assert!(num_compactions < 100);
+            }
+        }
+
+        let snapshot = sstables.snapshot();
+        let manifest = strategy.manifest();
+
+        // non-overlapping token ranges in all levels but level 0
+        for a in snapshot.iter() {
+            for b in snapshot.iter() {
+                let level = manifest.level(&a.uuid());
+                if a.uuid() != b.uuid() && level > 0 && level == manifest.level(&b.uuid()) {
+                    let (first_a, last_a) = a.stats().token_range.unwrap();
+                    let (first_b, last_b) = b.stats().token_range.unwrap();
+                    assert!(last_a < first_b || last_b < first_a);
+                }
+            }
+        }
+        assert!(snapshot.iter().any(|s| manifest.level(&s.uuid()) >= 2));
+
+        // no data is lost - a partition can be in several levels, the lowest level has its latest value
+        let mut by_level: Vec<&SstableMetaData> = snapshot.iter().map(|s| s.meta_data()).collect();
+        by_level.sort_by_key(|s| std::cmp::Reverse(manifest.level(&s.uuid())));
+        let mut actual = HashMap::new();
+        for sstable in by_level {
+            for (partition_key, value) in read_rows(sstable) {
+                actual.insert(partition_key, value);
+            }
+        }
+        assert_eq!(expected, actual);
+
+        // the manifest is persisted
+        let reloaded = LeveledManifest::load(&table_metadata, folder.path()).unwrap();
+        for sstable in snapshot.iter() {
+            assert_eq!(manifest.level(&sstable.uuid()), reloaded.level(&sstable.uuid()));
+        }
+    }
+}
@@ -0,0 +1,430 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::db::{CompactionOptions, TableMetaData};
+use crate::memtable::{MemPartition, Memtable};
+use crate::sstable::flush::write_partition;
+use crate::sstable::row_data::{RowDataFileCreator, RowDataStream};
+use crate::sstable::set::SstableSet;
+use crate::sstable::{Sstable, SstableMetaData};
+use crate::util::{expiry_now, DbExpiryTimestamp, DbTimestamp, Token};
+
+pub mod size_tiered;
+pub mod leveled;
+pub mod time_window;
+
+
+/// sstables that are merged into new sstables
+pub struct CompactionTask {
+    pub inputs: Vec<SstableMetaData>,
+    /// the output is split into sstables of roughly this size, None for a single output sstable
+    pub max_output_size: Option<u64>,
+    /// the level of the output sstables for strategies that organize sstables in levels
+    pub output_level: Option<usize>,
+    /// the inputs contain only expired data, so they are dropped without writing any output
+    pub expired_only: bool,
+}
+
+pub trait CompactionStrategy {
+    /// picks the next sstables to compact from a table's live sstables, None if there is nothing
+    ///  to do. `memtables` hold the table's data that is not flushed yet, see `run_compaction`.
+    fn next_task(&mut self, sstables: &[SstableMetaData], memtables: &[&Memtable]) -> std::io::Result<Option<CompactionTask>>;
+
+    /// called after a task's outputs replaced its inputs in the live set
+    fn task_finished(&mut self, _task: &CompactionTask, _outputs: &[SstableMetaData]) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// `folder` is where the table's sstables and any state the strategy keeps on disk are stored
+pub fn create_strategy(table_metadata: &Arc<TableMetaData>, folder: &Path) -> std::io::Result<Box<dyn CompactionStrategy>> {
+    Ok(match &table_metadata.options.compaction {
+        CompactionOptions::SizeTiered(options) => Box::new(size_tiered::SizeTieredStrategy::new(options.clone())),
+        CompactionOptions::Leveled(options) => Box::new(leveled::LeveledStrategy::open(table_metadata.clone(), folder, options.clone())?),
+        CompactionOptions::TimeWindow(options) => Box::new(time_window::TimeWindowStrategy::new(options.clone(), table_metadata.options.gc_grace)),
+    })
+}
+
+
+/// Runs the strategy's next compaction if there is one, replacing its inputs in the live set.
+///  Returns false if there was nothing to compact.
+///
+/// `memtables` are all of the table's memtables, including those that are being flushed: data
+///  that is flushed while the compaction runs is not in the snapshot of live sstables, so it is
+///  taken into account through the memtables when purging tombstones or dropping expired
+///  sstables.
+///
+/// The inputs are marked obsolete only after the new sstables are complete and live, and their
+///  files are deleted once no read holds them anymore. So a crash leaves the old sstables in
+///  place - possibly next to the new ones, which is harmless because their data is reconciled when
+///  it is read.
+pub fn run_compaction(sstables: &SstableSet, memtables: &[&Memtable], strategy: &mut dyn CompactionStrategy) -> std::io::Result<bool> {
+    let snapshot = sstables.snapshot();
+    let candidates: Vec<SstableMetaData> = snapshot.iter().map(|s| s.meta_data().clone()).collect();
+    let task = match strategy.next_task(&candidates, memtables)? {
+        None => return Ok(false),
+        Some(task) => task,
+    };
+    if task.inputs.is_empty() {
+        return Ok(false);
+    }
+
+    let outputs = if task.expired_only {
+        Vec::new()
+    }
+    else {
+        let (inputs, others): (Vec<Arc<Sstable>>, Vec<Arc<Sstable>>) = snapshot.iter()
+            .cloned()
+            .partition(|s| task.inputs.iter().any(|input| input.uuid() == s.uuid()));
+        compact(&inputs, &others, memtables, task.max_output_size)?
+    };
+    let output_meta_data: Vec<SstableMetaData> = outputs.iter().map(|s| s.meta_data().clone()).collect();
+    sstables.replace(&task.inputs, outputs)?;
+    strategy.task_finished(&task, &output_meta_data)?;
+    Ok(true)
+}
+
+/// Merges the inputs' partitions into new sstables in the first input's folder. Every input is
+///  sorted by partition, so the merge reads all of them sequentially, collecting one partition at
+///  a time from all inputs and reconciling their rows in a `MemPartition`. The inputs' data files
+///  are decompressed a chunk at a time while they are read.
+///
+/// Expired cells are turned into tombstones, and tombstones are purged once they are older than the
+///  table's `gc_grace` - but only if neither the `others` (i.e. the table's live sstables that are
+///  not compacted) nor the `memtables` have data for the partition that might be shadowed by them.
+///
+/// If there is a `max_output_size`, a new output sstable is started once the current one reaches
+///  that size. Partitions with the same token always go into the same output sstable, so the
+///  outputs' token ranges do not overlap.
+pub fn compact(inputs: &[Arc<Sstable>], others: &[Arc<Sstable>], memtables: &[&Memtable], max_output_size: Option<u64>) -> std::io::Result<Vec<Arc<Sstable>>> {
+    if inputs.is_empty() {
+        return Ok(Vec::new());
+    }
+    let table_metadata = inputs[0].meta_data().table_metadata.clone();
+    let folder = inputs[0].meta_data().folder();
+
+    let now = expiry_now();
+    let gc_before = now.saturating_sub(table_metadata.options.gc_grace.as_secs() as DbExpiryTimestamp);
+
+    let mut streams: Vec<RowDataStream> = inputs.iter()
+        .map(|input| RowDataStream::new(input.clone()))
+        .collect::<std::io::Result<_>>()?;
+
+    let mut outputs = Vec::new();
+    let mut creator: Option<(RowDataFileCreator, Token)> = None;
+    loop {
+        let mut next_partition: Option<(Token, Vec<u8>)> = None;
+        for stream in streams.iter_mut() {
+            if let Some((token, partition_key)) = stream.peek_partition()? {
+                if next_partition.as_ref().is_none_or(|(t, k)| (token, partition_key) < (*t, k.as_slice())) {
+                    next_partition = Some((token, partition_key.to_vec()));
+                }
+            }
+        }
+        let (token, partition_key) = match &next_partition {
+            None => break,
+            Some((token, partition_key)) => (*token, partition_key.as_slice()),
+        };
+
+        let mut partition = MemPartition::new(table_metadata.cluster_key_comparator().clone());
+        for stream in streams.iter_mut() {
+            while stream.peek_partition()? == Some((token, partition_key)) {
+                stream.next_row(|row| partition.apply(&row.details))?;
+            }
+        }
+        let max_purgeable_timestamp = min_timestamp(others, token, partition_key)?.into_iter()
+            .chain(memtables.iter().filter_map(|m| m.partition(token, partition_key)?.min_timestamp()))
+            .min()
+            .unwrap_or(DbTimestamp::MAX);
+        partition.purge(now, gc_before, max_purgeable_timestamp);
+        if partition.is_empty() {
+            continue;
+        }
+
+        let is_full = match (creator.as_mut(), max_output_size) {
+            (Some((cur, last_token)), Some(max_output_size)) => *last_token != token && cur.data_size()? >= max_output_size,
+            _ => false,
+        };
+        if is_full {
+            if let Some((full, _)) = creator.take() {
+                outputs.push(full.finalize()?);
+            }
+        }
+
+        let (cur, last_token) = match creator.as_mut() {
+            Some(c) => c,
+            None => {
+                let output = SstableMetaData::new(table_metadata.clone(), folder);
+                creator.get_or_insert((RowDataFileCreator::new(output)?, token))
+            }
+        };
+        write_partition(cur, &table_metadata, token, partition_key, &partition)?;
+        *last_token = token;
+    }
+
+    if let Some((cur, _)) = creator {
+        outputs.push(cur.finalize()?);
+    }
+    Ok(outputs)
+}
+
+/// the oldest timestamp of the partition's data in any of the sstables
+fn min_timestamp(sstables: &[Arc<Sstable>], token: Token, partition_key: &[u8]) -> std::io::Result<Option<DbTimestamp>> {
+    let mut result = None;
+    for sstable in sstables {
+        if !sstable.filter()?.might_contain(token) {
+            continue;
+        }
+        let entry = sstable.index()?.find(token, partition_key)
+            .map_err(|e| e.in_file(&sstable.meta_data().index_filename()))?;
+        if let Some(entry) = entry {
+            result = Some(result.map_or(entry.min_timestamp, |min: DbTimestamp| min.min(entry.min_timestamp)));
+        }
+    }
+    Ok(result)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::compaction::{compact, run_compaction, CompactionStrategy, CompactionTask};
+    use crate::db::{ColumnType, KeyBound, RegularRowData, RowDetails, RowTombstoneData, TableCell, TableCellData, TableMetaData, TableOptions, TableRow};
+    use crate::memtable::Memtable;
+    use crate::sstable::flush::flush_memtable;
+    use crate::sstable::row_data::RowDataReader;
+    use crate::sstable::set::SstableSet;
+    use crate::sstable::testing::{clustered_table_metadata, TempFolder};
+    use crate::sstable::{Sstable, SstableMetaData};
+    use crate::util::{DbExpiryTimestamp, DbTimestamp, NO_EXPIRY};
+
+    fn table_metadata() -> Arc<TableMetaData> {
+        clustered_table_metadata(&[("v", ColumnType::Long)], TableOptions::default())
+    }
+
+    fn key(n: u64) -> Vec<u8> {
+        n.to_be_bytes().to_vec()
+    }
+
+    fn apply_row(memtable: &mut Memtable, id: u64, ck: u64, value: u64, timestamp: DbTimestamp) {
+        apply_cell(memtable, id, ck, Some(value), timestamp, NO_EXPIRY);
+    }
+
+    /// a tombstone for `value` None - deleting a cell does not make its row live, so the row's
+    ///  primary key is expired in that case
+    fn apply_cell(memtable: &mut Memtable, id: u64, ck: u64, value: Option<u64>, timestamp: DbTimestamp, expiry: DbExpiryTimestamp) {
+        let table_metadata = memtable.table_metadata().clone();
+        let id = key(id);
+        let ck = key(ck);
+        let pk_expiry = if value.is_some() { expiry } else { 0 };
+        let value = value.map(key);
+        memtable.apply(&TableRow::new(table_metadata.clone(), &id, RowDetails::Regular(RegularRowData {
+            pk_expiry,
+            cluster_key: vec!(&ck),
+            regular_cols: vec!(TableCell {
+                meta_data: table_metadata.columns[2].clone(),
+                path: None,
+                timestamp,
+                expiry,
+                data: match &value {
+                    None => TableCellData::Tombstone,
+                    Some(value) => TableCellData::Regular(value),
+                },
+            }),
+        }))).unwrap();
+    }
+
+    fn apply_tombstone(memtable: &mut Memtable, id: u64, lower: u64, upper: u64, timestamp: DbTimestamp) {
+        let table_metadata = memtable.table_metadata().clone();
+        let id = key(id);
+        let lower = key(lower);
+        let upper = key(upper);
+        memtable.apply(&TableRow::new(table_metadata, &id, RowDetails::RowTombstone(RowTombstoneData {
+            timestamp,
+            lower_bound: Some(KeyBound { cluster_key_prefix: vec!(&lower), is_inclusive: true }),
+            upper_bound: Some(KeyBound { cluster_key_prefix: vec!(&upper), is_inclusive: false }),
+        }))).unwrap();
+    }
+
+    fn flush(folder: &TempFolder, memtable: &Memtable) -> Arc<Sstable> {
+        flush_memtable(memtable, SstableMetaData::new(memtable.table_metadata().clone(), folder.path())).unwrap()
+    }
+
+    /// (partition key, cluster key, value, timestamp)
+    type CellSummary = (Vec<u8>, Vec<u8>, Option<Vec<u8>>, DbTimestamp);
+
+    fn read_memtable(sstable: &Arc<Sstable>) -> Memtable {
+        let contents = sstable.read_data().unwrap();
+
+        let table_metadata = sstable.meta_data().table_metadata.clone();
+        let mut memtable = Memtable::new(table_metadata.clone());
+        for row in RowDataReader::for_contents(table_metadata, &contents, 0).unwrap() {
+            memtable.apply(&row.unwrap()).unwrap();
+        }
+        memtable
+    }
+
+    fn read_cells(sstable: &Arc<Sstable>) -> Vec<CellSummary> {
+        cells(&read_memtable(sstable))
+    }
+
+    fn cells(memtable: &Memtable) -> Vec<CellSummary> {
+        let mut result = Vec::new();
+        for (_, partition_key, partition) in memtable.partitions() {
+            for (cluster_key, row) in partition.rows() {
+                for cell in row.cells.values() {
+                    result.push((partition_key.to_vec(), cluster_key[0].clone(), cell.data.clone(), cell.timestamp));
+                }
+            }
+        }
+        result
+    }
+
+    #[test]
+    pub fn test_compact() {
+        let folder = TempFolder::new();
+        let table_metadata = table_metadata();
+
+        // all mutations in a single memtable as a reference
+        let mut all = Memtable::new(table_metadata.clone());
+        let mut inputs = Vec::new();
+
+        for i in 0..4u64 {
+            let mut memtable = Memtable::new(table_metadata.clone());
+            for id in (i*5)..(i*5 + 20) {
+                for ck in 0..5 {
+                    apply_row(&mut memtable, id, ck, id*100 + i, 10 + i);
+                    apply_row(&mut all, id, ck, id*100 + i, 10 + i);
+                }
+            }
+            if i == 2 {
+                apply_tombstone(&mut memtable, 12, 1, 3, 12);
+                apply_tombstone(&mut all, 12, 1, 3, 12);
+            }
+            inputs.push(flush(&folder, &memtable));
+        }
+
+        let mut expected = cells(&all);
+        expected.sort();
+
+        let outputs = compact(&inputs, &[], &[], None).unwrap();
+        assert_eq!(1, outputs.len());
+        let mut actual = read_cells(&outputs[0]);
+        actual.sort();
+        assert_eq!(expected, actual);
+
+        // split output
+        let outputs = compact(&inputs, &[], &[], Some(500)).unwrap();
+        assert!(outputs.len() > 3, "{} outputs", outputs.len());
+        let mut actual: Vec<_> = outputs.iter().flat_map(read_cells).collect();
+        actual.sort();
+        assert_eq!(expected, actual);
+    }
+
+    struct CompactAll {}
+    impl CompactionStrategy for CompactAll {
+        fn next_task(&mut self, sstables: &[SstableMetaData], _memtables: &[&Memtable]) -> std::io::Result<Option<CompactionTask>> {
+            if sstables.len() < 2 {
+                return Ok(None);
+            }
+            Ok(Some(CompactionTask { inputs: sstables.to_vec(), max_output_size: None, output_level: None, expired_only: false }))
+        }
+    }
+
+    #[test]
+    pub fn test_run_compaction() {
+        let folder = TempFolder::new();
+        let table_metadata = table_metadata();
+
+        let mut inputs = Vec::new();
+        for i in 0..3 {
+            let mut memtable = Memtable::new(table_metadata.clone());
+            apply_row(&mut memtable, i, 0, i, 10);
+            inputs.push(flush(&folder, &memtable));
+        }
+
+        let input_meta_data: Vec<SstableMetaData> = inputs.iter().map(|s| s.meta_data().clone()).collect();
+        let sstables = SstableSet::new(inputs);
+
+        // a read in progress holds the inputs, so their files are kept until it is done
+        let in_flight = sstables.snapshot();
+        assert!(run_compaction(&sstables, &[], &mut CompactAll {}).unwrap());
+        assert!(!run_compaction(&sstables, &[], &mut CompactAll {}).unwrap());
+
+        assert!(in_flight.iter().all(|s| s.is_obsolete()));
+        assert_eq!(3, in_flight.iter().map(|s| read_cells(s).len()).sum::<usize>());
+        drop(in_flight);
+
+        let snapshot = sstables.snapshot();
+        assert_eq!(1, snapshot.len());
+        assert!(!snapshot[0].is_obsolete());
+        assert_eq!(3, read_cells(&snapshot[0]).len());
+
+        for input in input_meta_data {
+            assert!(!input.data_filename().exists());
+            assert!(!input.index_filename().exists());
+            assert!(!input.filter_filename().exists());
+        }
+    }
+
+    #[test]
+    pub fn test_tombstone_purging() {
+        let folder = TempFolder::new();
+        let table_metadata = table_metadata();
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_nanos() as DbTimestamp;
+
+        // written at the beginning of the epoch, i.e. long before gc_grace
+        let mut old_data = Memtable::new(table_metadata.clone());
+        apply_row(&mut old_data, 1, 1, 1, 10);
+        apply_row(&mut old_data, 2, 1, 1, 10);
+        let old_data = flush(&folder, &old_data);
+
+        let mut deletions = Memtable::new(table_metadata.clone());
+        apply_tombstone(&mut deletions, 1, 0, 100, 20);
+        apply_cell(&mut deletions, 2, 2, None, 20, NO_EXPIRY);
+        apply_cell(&mut deletions, 3, 1, Some(1), 20, 1);
+        apply_cell(&mut deletions, 4, 1, Some(1), 20, NO_EXPIRY);
+        apply_cell(&mut deletions, 5, 1, None, now, NO_EXPIRY);
+        let deletions = flush(&folder, &deletions);
+
+        let partition = |memtable: &Memtable, id: u64| {
+            let id = key(id);
+            memtable.partition(fasthash::murmur3::hash128(&id), &id).map(|p| (p.rows().count(), p.range_tombstones().len()))
+        };
+
+        // tombstones are kept while other sstables have older data for the partition
+        let outputs = compact(std::slice::from_ref(&deletions), std::slice::from_ref(&old_data), &[], None).unwrap();
+        let compacted = read_memtable(&outputs[0]);
+        assert_eq!(Some((0, 1)), partition(&compacted, 1));
+        assert_eq!(Some((1, 0)), partition(&compacted, 2));
+        assert_eq!(None, partition(&compacted, 3));
+        assert_eq!(Some((1, 0)), partition(&compacted, 4));
+        assert_eq!(Some((1, 0)), partition(&compacted, 5));
+
+        // ... or while a memtable has older data for the partition, which would be resurrected once
+        //  it is flushed
+        let mut unflushed = Memtable::new(table_metadata.clone());
+        apply_row(&mut unflushed, 1, 50, 1, 5);
+        let outputs = compact(&[old_data.clone(), deletions.clone()], &[], &[&unflushed], None).unwrap();
+        let compacted = read_memtable(&outputs[0]);
+        assert_eq!(Some((0, 1)), partition(&compacted, 1));
+        assert_eq!(Some((1, 0)), partition(&compacted, 2));
+
+        // ... and purged together with the data they shadow when it is compacted as well
+        let outputs = compact(&[old_data, deletions], &[], &[], None).unwrap();
+        let compacted = read_memtable(&outputs[0]);
+        assert_eq!(None, partition(&compacted, 1));
+        assert_eq!(Some((1, 0)), partition(&compacted, 2));
+        assert_eq!(None, partition(&compacted, 3));
+        assert_eq!(Some((1, 0)), partition(&compacted, 4));
+        assert_eq!(Some((1, 0)), partition(&compacted, 5));
+
+        let partition_key = key(2);
+        let row_cells: Vec<_> = compacted.partition(fasthash::murmur3::hash128(&partition_key), &partition_key).unwrap()
+            .rows()
+            .flat_map(|(_, row)| row.cells.values().map(|c| c.data.clone()))
+            .collect();
+        assert_eq!(vec!(Some(key(1))), row_cells);
+    }
+}
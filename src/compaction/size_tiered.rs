@@ -0,0 +1,120 @@
+use crate::compaction::{CompactionStrategy, CompactionTask};
+use crate::db::SizeTieredOptions;
+use crate::memtable::Memtable;
+use crate::sstable::SstableMetaData;
+
+
+/// Groups sstables into buckets of similar size and compacts the sstables of a bucket once there
+///  are enough of them. Merged sstables move up to a bucket of bigger sstables, so every piece of
+///  data is rewritten roughly once per size tier.
+pub struct SizeTieredStrategy {
+    options: SizeTieredOptions,
+}
+
+impl SizeTieredStrategy {
+    pub fn new(options: SizeTieredOptions) -> SizeTieredStrategy {
+        SizeTieredStrategy { options }
+    }
+}
+
+impl CompactionStrategy for SizeTieredStrategy {
+    fn next_task(&mut self, sstables: &[SstableMetaData], _memtables: &[&Memtable]) -> std::io::Result<Option<CompactionTask>> {
+        let mut sized = Vec::new();
+        for sstable in sstables {
+            sized.push((sstable.clone(), sstable.data_size()?));
+        }
+
+        Ok(most_interesting_bucket(buckets(sized, &self.options), &self.options)
+            .map(|inputs| CompactionTask { inputs, max_output_size: None, output_level: None, expired_only: false }))
+    }
+}
+
+/// Sorts the items by size and puts each into the first bucket whose average size is close
+///  enough, or into a new bucket.
+fn buckets<T>(mut items: Vec<(T, u64)>, options: &SizeTieredOptions) -> Vec<Vec<(T, u64)>> {
+    items.sort_by_key(|(_, size)| *size);
+
+    let mut result: Vec<Vec<(T, u64)>> = Vec::new();
+    for (item, size) in items {
+        let bucket = result.iter_mut().find(|bucket| {
+            let avg = average_size(bucket);
+            (size as f64 >= avg * options.bucket_low && size as f64 <= avg * options.bucket_high)
+                || (size < options.min_sstable_size && (avg as u64) < options.min_sstable_size)
+        });
+
+        match bucket {
+            Some(bucket) => bucket.push((item, size)),
+            None => result.push(vec!((item, size))),
+        }
+    }
+    result
+}
+
+fn average_size<T>(bucket: &[(T, u64)]) -> f64 {
+    bucket.iter().map(|(_, size)| *size as f64).sum::<f64>() / bucket.len() as f64
+}
+
+/// Among the buckets with at least `min_threshold` sstables, compacting the one with the smallest
+///  sstables is cheapest and reduces the number of sstables just as much. Buckets are capped at
+///  `max_threshold`, keeping the smallest sstables.
+fn most_interesting_bucket<T>(buckets: Vec<Vec<(T, u64)>>, options: &SizeTieredOptions) -> Option<Vec<T>> {
+    buckets.into_iter()
+        .filter(|bucket| bucket.len() >= options.min_threshold)
+        .map(|mut bucket| {
+            bucket.truncate(options.max_threshold);
+            bucket
+        })
+        .min_by(|a, b| average_size(a).total_cmp(&average_size(b)))
+        .map(|bucket| bucket.into_iter().map(|(item, _)| item).collect())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::compaction::size_tiered::{buckets, most_interesting_bucket};
+    use crate::db::SizeTieredOptions;
+
+    fn options() -> SizeTieredOptions {
+        SizeTieredOptions {
+            min_threshold: 3,
+            max_threshold: 4,
+            bucket_low: 0.5,
+            bucket_high: 1.5,
+            min_sstable_size: 100,
+        }
+    }
+
+    fn sized(sizes: &[u64]) -> Vec<(u64, u64)> {
+        sizes.iter().map(|s| (*s, *s)).collect()
+    }
+
+    fn bucket_contents(sizes: &[u64]) -> Vec<Vec<u64>> {
+        buckets(sized(sizes), &options()).into_iter()
+            .map(|b| b.into_iter().map(|(s, _)| s).collect())
+            .collect()
+    }
+
+    #[test]
+    pub fn test_buckets() {
+        assert_eq!(Vec::<Vec<u64>>::new(), bucket_contents(&[]));
+
+        // small sstables go into a single bucket regardless of their relative size
+        assert_eq!(vec!(vec!(1, 10, 50, 99)), bucket_contents(&[50, 1, 99, 10]));
+
+        assert_eq!(
+            vec!(vec!(5), vec!(1000, 1100, 1400), vec!(10_000, 12_000)),
+            bucket_contents(&[10_000, 1000, 5, 1400, 12_000, 1100]));
+    }
+
+    #[test]
+    pub fn test_most_interesting_bucket() {
+        let options = options();
+
+        let candidates = |sizes: &[u64]| most_interesting_bucket(buckets(sized(sizes), &options), &options);
+
+        assert_eq!(None, candidates(&[1000, 1000, 10_000, 10_000]));
+        assert_eq!(Some(vec!(10_000, 10_000, 10_000)), candidates(&[1000, 1000, 10_000, 10_000, 10_000]));
+        // the bucket with the smaller sstables wins, and is capped at max_threshold
+        assert_eq!(Some(vec!(1000, 1000, 1001, 1002)), candidates(&[1003, 1002, 1001, 1000, 1000, 10_000, 10_000, 10_000]));
+    }
+}
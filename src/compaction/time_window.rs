@@ -0,0 +1,289 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use uuid::Uuid;
+
+use crate::compaction::{CompactionStrategy, CompactionTask};
+use crate::db::TimeWindowOptions;
+use crate::memtable::Memtable;
+use crate::sstable::SstableMetaData;
+use crate::util::{expiry_now, DbExpiryTimestamp, DbTimestamp};
+
+
+/// For time series data: sstables are grouped into windows by the newest write timestamp of their
+///  data, and only sstables in the same window are compacted together. The current window is
+///  compacted once it has `min_threshold` sstables, older windows are compacted into a single
+///  sstable each.
+///
+/// sstables whose data has all expired more than `gc_grace` ago are dropped as a whole, as long
+///  as no other sstable or memtable can hold older data that they shadow.
+pub struct TimeWindowStrategy {
+    options: TimeWindowOptions,
+    gc_grace: Duration,
+    times: HashMap<Uuid, SstableTimes>,
+}
+
+impl TimeWindowStrategy {
+    pub fn new(options: TimeWindowOptions, gc_grace: Duration) -> TimeWindowStrategy {
+        TimeWindowStrategy {
+            options,
+            gc_grace,
+            times: HashMap::new(),
+        }
+    }
+
+    fn times(&mut self, sstable: &SstableMetaData) -> std::io::Result<SstableTimes> {
+        if let Some(times) = self.times.get(&sstable.uuid()) {
+            return Ok(*times);
+        }
+        let times = SstableTimes::read(sstable)?;
+        self.times.insert(sstable.uuid(), times);
+        Ok(times)
+    }
+}
+
+impl CompactionStrategy for TimeWindowStrategy {
+    fn next_task(&mut self, sstables: &[SstableMetaData], memtables: &[&Memtable]) -> std::io::Result<Option<CompactionTask>> {
+        let mut candidates = Vec::new();
+        for sstable in sstables {
+            candidates.push((sstable.clone(), self.times(sstable)?));
+        }
+
+        let gc_before = expiry_now().saturating_sub(self.gc_grace.as_secs() as DbExpiryTimestamp);
+        let memtables_min_timestamp = memtables.iter().filter_map(|m| m.min_timestamp()).min();
+        Ok(plan(&candidates, &self.options, gc_before, memtables_min_timestamp)
+            .map(|(inputs, expired_only)| CompactionTask {
+                inputs,
+                max_output_size: None,
+                output_level: None,
+                expired_only,
+            }))
+    }
+
+    fn task_finished(&mut self, task: &CompactionTask, _outputs: &[SstableMetaData]) -> std::io::Result<()> {
+        for input in task.inputs.iter() {
+            self.times.remove(&input.uuid());
+        }
+        Ok(())
+    }
+}
+
+
+#[derive(Debug, Clone, Copy)]
+struct SstableTimes {
+    min_timestamp: DbTimestamp,
+    max_timestamp: DbTimestamp,
+    /// when the sstable's last data expires, NO_EXPIRY if it has data without a TTL or tombstones
+    max_expiry: DbExpiryTimestamp,
+}
+
+impl SstableTimes {
+    fn read(sstable: &SstableMetaData) -> std::io::Result<SstableTimes> {
+        let stats = sstable.read_stats()?;
+        Ok(SstableTimes {
+            min_timestamp: stats.min_timestamp,
+            max_timestamp: stats.max_timestamp,
+            max_expiry: stats.max_expiry,
+        })
+    }
+}
+
+
+/// Picks the next sstables to compact, and whether they can be dropped because they are expired.
+///  An sstable's data turns into tombstones when it expires, so it is dropped only once those are
+///  purgeable, i.e. expired before `gc_before`.
+fn plan<T: Clone>(candidates: &[(T, SstableTimes)], options: &TimeWindowOptions, gc_before: DbExpiryTimestamp, memtables_min_timestamp: Option<DbTimestamp>) -> Option<(Vec<T>, bool)> {
+    let is_expired = |times: &SstableTimes| times.max_expiry <= gc_before;
+
+    // expired data still shadows older data in other sstables and in memtables
+    let min_live_timestamp = candidates.iter()
+        .filter(|(_, times)| !is_expired(times))
+        .map(|(_, times)| times.min_timestamp)
+        .chain(memtables_min_timestamp)
+        .min();
+    let droppable: Vec<T> = candidates.iter()
+        .filter(|(_, times)| is_expired(times) && min_live_timestamp.is_none_or(|min| times.max_timestamp < min))
+        .map(|(item, _)| item.clone())
+        .collect();
+    if !droppable.is_empty() {
+        return Some((droppable, true));
+    }
+
+    let window_size = options.window_size.as_nanos().max(1);
+    let mut windows: BTreeMap<u128, Vec<&(T, SstableTimes)>> = BTreeMap::new();
+    for candidate in candidates {
+        windows.entry(candidate.1.max_timestamp as u128 / window_size).or_default().push(candidate);
+    }
+
+    for (idx, (_, mut sstables)) in windows.into_iter().rev().enumerate() {
+        let min_threshold = if idx == 0 { options.min_threshold } else { 2 };
+        if sstables.len() >= min_threshold.max(2) {
+            sstables.sort_by_key(|(_, times)| times.max_timestamp);
+            sstables.truncate(options.max_threshold);
+            return Some((sstables.into_iter().map(|(item, _)| item.clone()).collect(), false));
+        }
+    }
+    None
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::compaction::run_compaction;
+    use crate::compaction::time_window::{plan, SstableTimes, TimeWindowStrategy};
+    use crate::db::{ColumnType, RegularRowData, RowDetails, TableCell, TableCellData, TableMetaData, TableOptions, TableRow, TimeWindowOptions};
+    use crate::memtable::Memtable;
+    use crate::sstable::flush::flush_memtable;
+    use crate::sstable::set::SstableSet;
+    use crate::sstable::testing::{clustered_table_metadata, TempFolder};
+    use crate::sstable::SstableMetaData;
+    use crate::util::{DbExpiryTimestamp, DbTimestamp, NO_EXPIRY};
+
+    const SECOND: DbTimestamp = 1_000_000_000;
+
+    fn options() -> TimeWindowOptions {
+        TimeWindowOptions {
+            window_size: Duration::from_secs(10),
+            min_threshold: 3,
+            max_threshold: 4,
+        }
+    }
+
+    fn times(min_timestamp: DbTimestamp, max_timestamp: DbTimestamp, max_expiry: DbExpiryTimestamp) -> SstableTimes {
+        SstableTimes { min_timestamp, max_timestamp, max_expiry }
+    }
+
+    #[test]
+    pub fn test_plan() {
+        let options = options();
+        let now = 1000;
+
+        assert_eq!(None, plan::<u32>(&[], &options, now, None));
+
+        // windows [0, 10), [10, 20) and [20, 30) seconds; the newest window needs min_threshold sstables
+        let candidates = vec!(
+            (1, times(SECOND, 2 * SECOND, NO_EXPIRY)),
+            (2, times(12 * SECOND, 13 * SECOND, NO_EXPIRY)),
+            (3, times(21 * SECOND, 22 * SECOND, NO_EXPIRY)),
+            (4, times(23 * SECOND, 24 * SECOND, NO_EXPIRY)),
+        );
+        assert_eq!(None, plan(&candidates, &options, now, None));
+
+        let mut with_current = candidates.clone();
+        with_current.push((5, times(25 * SECOND, 26 * SECOND, NO_EXPIRY)));
+        assert_eq!(Some((vec!(3, 4, 5), false)), plan(&with_current, &options, now, None));
+
+        let mut with_old = candidates.clone();
+        with_old.push((6, times(5 * SECOND, 6 * SECOND, NO_EXPIRY)));
+        assert_eq!(Some((vec!(1, 6), false)), plan(&with_old, &options, now, None));
+
+        // expired sstables are dropped, unless there is older live data they might shadow
+        let mut with_expired = candidates.clone();
+        with_expired.push((7, times(0, SECOND / 2, now)));
+        with_expired.push((8, times(30 * SECOND, 31 * SECOND, now - 1)));
+        with_expired.push((9, times(31 * SECOND, 31 * SECOND, now + 1)));
+        assert_eq!(Some((vec!(7), true)), plan(&with_expired, &options, now, None));
+        // ... or in a memtable
+        assert_eq!(Some((vec!(3, 4), false)), plan(&with_expired, &options, now, Some(0)));
+        // expired data is a tombstone until gc_grace has passed
+        assert_eq!(Some((vec!(3, 4), false)), plan(&with_expired, &options, now - 1, None));
+    }
+
+    fn table_metadata() -> Arc<TableMetaData> {
+        clustered_table_metadata(&[("v", ColumnType::Long)], TableOptions::default())
+    }
+
+    fn flush_rows(folder: &TempFolder, sstables: &SstableSet, table_metadata: &Arc<TableMetaData>, ids: std::ops::Range<u64>, timestamp: DbTimestamp, expiry: DbExpiryTimestamp) -> SstableMetaData {
+        let mut memtable = Memtable::new(table_metadata.clone());
+        for id in ids {
+            let id = id.to_be_bytes();
+            memtable.apply(&TableRow::new(table_metadata.clone(), &id, RowDetails::Regular(RegularRowData {
+                pk_expiry: expiry,
+                cluster_key: vec!(&id),
+                regular_cols: vec!(TableCell {
+                    meta_data: table_metadata.columns[2].clone(),
+                    path: None,
+                    timestamp,
+                    expiry,
+                    data: TableCellData::Regular(&id),
+                }),
+            }))).unwrap();
+        }
+
+        let sstable = flush_memtable(&memtable, SstableMetaData::new(table_metadata.clone(), folder.path())).unwrap();
+        let meta_data = sstable.meta_data().clone();
+        sstables.add(sstable);
+        meta_data
+    }
+
+    #[test]
+    pub fn test_time_window_compaction() {
+        let folder = TempFolder::new();
+        let table_metadata = table_metadata();
+        let sstables = SstableSet::new(Vec::new());
+
+        let expired = flush_rows(&folder, &sstables, &table_metadata, 0..10, SECOND, 1);
+        let old_window = vec!(
+            flush_rows(&folder, &sstables, &table_metadata, 10..20, 2 * SECOND, NO_EXPIRY),
+            flush_rows(&folder, &sstables, &table_metadata, 20..30, 3 * SECOND, NO_EXPIRY),
+        );
+        let current_window = vec!(
+            flush_rows(&folder, &sstables, &table_metadata, 30..40, 100 * SECOND, NO_EXPIRY),
+            flush_rows(&folder, &sstables, &table_metadata, 40..50, 101 * SECOND, NO_EXPIRY),
+        );
+
+        let mut strategy = TimeWindowStrategy::new(options(), table_metadata.options.gc_grace);
+        let expired_size = std::fs::metadata(expired.data_filename()).unwrap().len();
+        assert!(expired_size > 0);
+
+        // the expired sstable is dropped first, then the old window is compacted
+        assert!(run_compaction(&sstables, &[], &mut strategy).unwrap());
+        assert!(!expired.data_filename().exists());
+        assert_eq!(4, sstables.snapshot().len());
+
+        assert!(run_compaction(&sstables, &[], &mut strategy).unwrap());
+        assert!(!run_compaction(&sstables, &[], &mut strategy).unwrap());
+
+        let snapshot = sstables.snapshot();
+        assert_eq!(3, snapshot.len());
+        for sstable in old_window {
+            assert!(!snapshot.iter().any(|s| s.uuid() == sstable.uuid()));
+        }
+        for sstable in current_window {
+            assert!(snapshot.iter().any(|s| s.uuid() == sstable.uuid()));
+        }
+    }
+
+    #[test]
+    pub fn test_expired_sstable_shadows_memtable() {
+        let folder = TempFolder::new();
+        let table_metadata = table_metadata();
+        let sstables = SstableSet::new(Vec::new());
+        let expired = flush_rows(&folder, &sstables, &table_metadata, 0..10, 2 * SECOND, 1);
+
+        // older data for the same rows that is not flushed yet
+        let mut memtable = Memtable::new(table_metadata.clone());
+        let id = 1u64.to_be_bytes();
+        memtable.apply(&TableRow::new(table_metadata.clone(), &id, RowDetails::Regular(RegularRowData {
+            pk_expiry: NO_EXPIRY,
+            cluster_key: vec!(&id),
+            regular_cols: vec!(TableCell {
+                meta_data: table_metadata.columns[2].clone(),
+                path: None,
+                timestamp: SECOND,
+                expiry: NO_EXPIRY,
+                data: TableCellData::Regular(&id),
+            }),
+        }))).unwrap();
+
+        let mut strategy = TimeWindowStrategy::new(options(), table_metadata.options.gc_grace);
+        assert!(!run_compaction(&sstables, &[&memtable], &mut strategy).unwrap());
+        assert!(expired.data_filename().exists());
+
+        assert!(run_compaction(&sstables, &[], &mut strategy).unwrap());
+        assert!(sstables.snapshot().is_empty());
+    }
+}
@@ -1,11 +1,17 @@
 use crate::io;
 
+use std::cmp::Ordering;
 use std::sync::Arc;
+use std::time::Duration;
 use std::io::{Write, Read, ErrorKind};
 
 use uuid::*;
 use crate::util::*;
 use std::collections::HashMap;
+use std::convert::TryInto;
+use std::mem::size_of;
+use crate::io::CassRead;
+use crate::value::serialized_len;
 
 
 /// a (sparse) in-memory representation of a row's data, i.e. primary keys (partition and
@@ -37,6 +43,10 @@ impl TableRow<'_> {
             details
         }
     }
+
+    pub fn meta_data(&self) -> &Arc<TableMetaData> {
+        &self.meta_data
+    }
 }
 
 pub struct RegularRowData<'a> {
@@ -52,19 +62,126 @@ pub struct KeyBound<'a> {
     pub is_inclusive: bool,
 }
 
+/// deletes all rows between the bounds that were written no later than the tombstone's timestamp.
+///  'None' bounds are unlimited, so a tombstone without any bounds deletes the entire partition.
 pub struct RowTombstoneData<'a> {
+    pub timestamp: DbTimestamp,
     pub lower_bound: Option<KeyBound<'a>>,
     pub upper_bound: Option<KeyBound<'a>>,
 }
 
+/// A key bound is a position *between* cluster keys: either before or after all cluster keys
+///  starting with its prefix. An empty prefix with `Before` / `After` is before / after all
+///  cluster keys, which is how unlimited bounds are represented.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BoundSide {
+    Before,
+    After,
+}
+impl BoundSide {
+    pub fn of_lower(is_inclusive: bool) -> BoundSide {
+        if is_inclusive { BoundSide::Before } else { BoundSide::After }
+    }
+    pub fn of_upper(is_inclusive: bool) -> BoundSide {
+        if is_inclusive { BoundSide::After } else { BoundSide::Before }
+    }
+}
+
+/// the order of a cluster key column's values in a partition
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClusteringOrder {
+    Ascending,
+    Descending,
+}
+
+/// Orders a table's rows in a partition by cluster key, comparing the cluster key columns with
+///  their types' `ColumnType::compare` in their clustering order. Key bounds are positions in
+///  this order, so a range tombstone's lower bound is the greater value for descending columns.
+#[derive(Debug)]
+pub struct ClusterKeyComparator {
+    columns: Vec<(ColumnType, ClusteringOrder)>,
+}
+
+impl ClusterKeyComparator {
+    pub fn new(columns: Vec<(ColumnType, ClusteringOrder)>) -> ClusterKeyComparator {
+        ClusterKeyComparator { columns }
+    }
+
+    fn compare_column(column: &(ColumnType, ClusteringOrder), a: &[u8], b: &[u8]) -> Ordering {
+        let (col_type, order) = column;
+        match order {
+            ClusteringOrder::Ascending => col_type.compare(a, b),
+            ClusteringOrder::Descending => col_type.compare(a, b).reverse(),
+        }
+    }
+
+    /// compares the columns both (partial) keys have, i.e. a prefix is equal to the keys starting
+    ///  with it. Values beyond the table's cluster key columns, which only malformed keys have,
+    ///  are ignored.
+    fn compare_common_prefix<P,Q>(&self, a: &[P], b: &[Q]) -> Ordering where P: AsRef<[u8]>, Q: AsRef<[u8]> {
+        for (column, (a, b)) in self.columns.iter().zip(a.iter().zip(b.iter())) {
+            match ClusterKeyComparator::compare_column(column, a.as_ref(), b.as_ref()) {
+                Ordering::Equal => {},
+                o => return o,
+            }
+        }
+        Ordering::Equal
+    }
+
+    /// compares two complete cluster keys
+    pub fn compare_keys<P,Q>(&self, a: &[P], b: &[Q]) -> Ordering where P: AsRef<[u8]>, Q: AsRef<[u8]> {
+        self.compare_common_prefix(a, b)
+    }
+
+    /// compares a key bound position with a (complete) cluster key - they are never equal
+    pub fn compare_bound_with_key<P,K>(&self, prefix: &[P], side: BoundSide, key: &[K]) -> Ordering where P: AsRef<[u8]>, K: AsRef<[u8]> {
+        match self.compare_common_prefix(prefix, key) {
+            Ordering::Equal => match side {
+                BoundSide::Before => Ordering::Less,
+                BoundSide::After => Ordering::Greater,
+            },
+            o => o,
+        }
+    }
+
+    pub fn compare_bounds<P,Q>(&self, prefix_a: &[P], side_a: BoundSide, prefix_b: &[Q], side_b: BoundSide) -> Ordering where P: AsRef<[u8]>, Q: AsRef<[u8]> {
+        match self.compare_common_prefix(prefix_a, prefix_b) {
+            Ordering::Equal => {},
+            o => return o,
+        }
+
+        // one prefix starts with the other: a shorter prefix' position encloses the longer one's
+        match prefix_a.len().cmp(&prefix_b.len()) {
+            Ordering::Equal => side_a.cmp(&side_b),
+            Ordering::Less => match side_a {
+                BoundSide::Before => Ordering::Less,
+                BoundSide::After => Ordering::Greater,
+            },
+            Ordering::Greater => match side_b {
+                BoundSide::Before => Ordering::Greater,
+                BoundSide::After => Ordering::Less,
+            },
+        }
+    }
+}
+
 pub enum RowDetails<'a> {
     Regular (RegularRowData<'a>),
     RowTombstone (RowTombstoneData<'a>),
 }
 
+/// Collection columns are stored with one cell per element, identified by the element's `path`
+///  (see `ColumnType::cell_path_type`), and user type columns with one cell per field. A
+///  tombstone without a path deletes the whole collection (or user type value), i.e. all of its
+///  elements that are not newer than the tombstone. Overwriting a collection is done by writing
+///  such a tombstone together with the new elements in the same row, see `Memtable::apply`.
 pub struct TableCell<'a> {
     pub meta_data: Arc<ColumnMetaData>,
+    /// the element or field a cell holds for multi-cell columns, None for all other columns
+    pub path: Option<&'a [u8]>,
     pub timestamp: DbTimestamp,
+    /// when the cell's data expires, NO_EXPIRY for data without a TTL. Tombstones that replaced
+    ///  expired data keep its expiry as their deletion time for garbage collection.
     pub expiry: DbExpiryTimestamp,
     pub data: TableCellData<'a>,
 }
@@ -82,7 +199,7 @@ pub struct ColumnMetaData {
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum ColumnType {
     Text,      // UTF-8, with u32 as maximum length
     Uuid,
@@ -90,18 +207,485 @@ pub enum ColumnType {
     Long,
     Timestamp, // millis since epoch stored as i64
     Boolean,
+    Float,     // IEEE 754 single precision
+    Double,    // IEEE 754 double precision
+    Decimal,   // i32 scale followed by the unscaled value as a varint
+    Varint,    // arbitrary precision integer: two's complement big endian bytes with a varint length
+    Blob,      // raw bytes with a varint length
+    Inet,      // IPv4 or IPv6 address: length byte (4 or 16) followed by the address
+    Date,      // days since epoch stored as i32
+    Time,      // nanos since midnight stored as i64
+    Duration,  // months, days and nanos as zigzag encoded varints
     Tuple(Vec<ColumnType>),
+    List(Box<ColumnType>),
+    Set(Box<ColumnType>),
+    Map(Box<ColumnType>, Box<ColumnType>),
+    /// Stored with one cell per field as a column's type. Nested in other types, a value is the
+    ///  number of fields (u32) followed by the field values, and fields added to the type after
+    ///  the value was written are null.
+    UserType(Arc<UserType>),
+}
+
+impl ColumnType {
+    /// collections and user types are stored with one cell per element / field
+    pub fn is_multi_cell(&self) -> bool {
+        matches!(self, ColumnType::List(_) | ColumnType::Set(_) | ColumnType::Map(_, _) | ColumnType::UserType(_))
+    }
+
+    /// the type of a multi-cell column's cell path: a time based UUID for list elements so that
+    ///  they are kept in insertion order (see `util::new_list_element_path`), the element for sets,
+    ///  the key for maps and the field's index (as Int) for user types
+    pub fn cell_path_type(&self) -> Option<&ColumnType> {
+        match self {
+            ColumnType::List(_) => Some(&ColumnType::Uuid),
+            ColumnType::Set(element) => Some(element),
+            ColumnType::Map(key, _) => Some(key),
+            ColumnType::UserType(_) => Some(&ColumnType::Int),
+            _ => None,
+        }
+    }
+
+    /// the type of a cell's value - set elements are stored in their path, so their value is
+    ///  the empty tuple. None for a path that refers to a field the user type does not have.
+    pub fn cell_value_type(&self, path: Option<&[u8]>) -> Option<&ColumnType> {
+        match (self, path) {
+            (ColumnType::List(element), _) => Some(element),
+            (ColumnType::Set(_), _) => Some(&EMPTY_TUPLE),
+            (ColumnType::Map(_, value), _) => Some(value),
+            (ColumnType::UserType(user_type), Some(path)) => user_type.field_of_path(path).map(|f| &f.field_type),
+            _ => Some(self),
+        }
+    }
+
+    /// Compares two values of this type that were serialized in the data file format. Types that
+    ///  have no ordering of their own are compared by their serialized bytes.
+    ///
+    /// Values are validated where they enter a memtable, so comparisons do not check them up
+    ///  front. If a comparison runs into a malformed value anyway (e.g. corrupt data), the values
+    ///  are compared by their bytes rather than panicking.
+    pub fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        self.compare_well_formed(a, b).unwrap_or_else(|| a.cmp(b))
+    }
+
+    /// None if one of the values is malformed
+    fn compare_well_formed(&self, a: &[u8], b: &[u8]) -> Option<Ordering> {
+        Some(match self {
+            ColumnType::Int => i32::from_be_bytes(a.try_into().ok()?).cmp(&i32::from_be_bytes(b.try_into().ok()?)),
+            ColumnType::Long | ColumnType::Timestamp => i64::from_be_bytes(a.try_into().ok()?).cmp(&i64::from_be_bytes(b.try_into().ok()?)),
+            // by the UTF-8 bytes, without the length
+            ColumnType::Text => a.get(size_of::<u32>()..)?.cmp(b.get(size_of::<u32>()..)?),
+            ColumnType::Uuid => compare_uuids(a.try_into().ok()?, b.try_into().ok()?),
+            // IEEE 754 total order, i.e. -NaN < -inf < ... < -0 < 0 < ... < inf < NaN
+            ColumnType::Float => f32::from_be_bytes(a.try_into().ok()?).total_cmp(&f32::from_be_bytes(b.try_into().ok()?)),
+            ColumnType::Double => f64::from_be_bytes(a.try_into().ok()?).total_cmp(&f64::from_be_bytes(b.try_into().ok()?)),
+            ColumnType::Decimal => compare_decimals(a, b)?,
+            ColumnType::Varint => compare_varints(without_length(a)?, without_length(b)?),
+            ColumnType::Blob => without_length(a)?.cmp(without_length(b)?),
+            ColumnType::Date => i32::from_be_bytes(a.try_into().ok()?).cmp(&i32::from_be_bytes(b.try_into().ok()?)),
+            ColumnType::Time => i64::from_be_bytes(a.try_into().ok()?).cmp(&i64::from_be_bytes(b.try_into().ok()?)),
+            ColumnType::Duration => decode_duration(a)?.cmp(&decode_duration(b)?),
+            // composites are compared element by element
+            ColumnType::Tuple(types) => compare_sequences(types.iter(), (a, types.len()), (b, types.len()))?,
+            ColumnType::List(element) | ColumnType::Set(element) => {
+                compare_sequences(std::iter::repeat(element.as_ref()), with_count(a, 1)?, with_count(b, 1)?)?
+            },
+            ColumnType::Map(key, value) => {
+                compare_sequences([key.as_ref(), value.as_ref()].iter().copied().cycle(), with_count(a, 2)?, with_count(b, 2)?)?
+            },
+            ColumnType::UserType(user_type) => {
+                compare_sequences(user_type.fields.iter().map(|f| &f.field_type), with_count(a, 1)?, with_count(b, 1)?)?
+            },
+            _ => a.cmp(b),
+        })
+    }
+}
+
+/// Time based (version 1) UUIDs are ordered by their timestamp, other UUIDs by their bytes. UUIDs
+///  of different versions are ordered by version.
+fn compare_uuids(a: &[u8; 16], b: &[u8; 16]) -> Ordering {
+    let version = |u: &[u8; 16]| u[6] >> 4;
+    let timestamp = |u: &[u8; 16]| {
+        let time_low = u32::from_be_bytes([u[0], u[1], u[2], u[3]]) as u64;
+        let time_mid = u16::from_be_bytes([u[4], u[5]]) as u64;
+        let time_high = (u16::from_be_bytes([u[6], u[7]]) & 0x0fff) as u64;
+        (time_high << 48) | (time_mid << 32) | time_low
+    };
+
+    version(a).cmp(&version(b))
+        .then_with(|| if version(a) == 1 { timestamp(a).cmp(&timestamp(b)) } else { Ordering::Equal })
+        .then_with(|| a.cmp(b))
+}
+
+/// a whole collection's or user type value's elements, following their number (u32), and the
+///  number of serialized values they consist of
+fn with_count(value: &[u8], values_per_element: usize) -> Option<(&[u8], usize)> {
+    let (num_elements, elements) = value.split_at_checked(size_of::<u32>())?;
+    let num_elements = u32::from_be_bytes(num_elements.try_into().ok()?) as usize;
+    Some((elements, num_elements * values_per_element))
+}
+
+/// compares two sequences of serialized values lexicographically, where a sequence that is a prefix
+///  of the other one is less
+fn compare_sequences<'t, I>(types: I, a: (&[u8], usize), b: (&[u8], usize)) -> Option<Ordering> where I: Iterator<Item=&'t ColumnType> {
+    let ((mut a, num_a), (mut b, num_b)) = (a, b);
+    for col_type in types.take(num_a.min(num_b)) {
+        // the elements' lengths, without decoding them
+        let (element_a, rest_a) = a.split_at_checked(serialized_len(col_type, a).ok()?)?;
+        let (element_b, rest_b) = b.split_at_checked(serialized_len(col_type, b).ok()?)?;
+        match col_type.compare_well_formed(element_a, element_b)? {
+            Ordering::Equal => {},
+            o => return Some(o),
+        }
+        a = rest_a;
+        b = rest_b;
+    }
+    Some(num_a.cmp(&num_b))
+}
+
+/// strips the varint length prefix of a serialized value
+fn without_length(value: &[u8]) -> Option<&[u8]> {
+    let (_, prefix_len) = CassRead::wrap(value).peek_varint_offs(0).ok()?;
+    value.get(prefix_len..)
+}
+
+/// compares two's complement big endian integers of arbitrary length
+fn compare_varints(a: &[u8], b: &[u8]) -> Ordering {
+    match (is_negative(a), is_negative(b)) {
+        (true, false) => return Ordering::Less,
+        (false, true) => return Ordering::Greater,
+        _ => {},
+    }
+
+    // sign extend to the same length, after which bytes compare like unsigned numbers
+    let len = a.len().max(b.len());
+    let extended = |v: &[u8]| -> Vec<u8> {
+        let fill = if is_negative(v) { 0xff } else { 0 };
+        std::iter::repeat_n(fill, len - v.len()).chain(v.iter().copied()).collect()
+    };
+    extended(a).cmp(&extended(b))
+}
+
+/// Compares `unscaled * 10^-scale` numerically: by sign first, then by their decimal exponents,
+///  which are estimated from the unscaled values' bit lengths. Only values whose exponents are too
+///  close to tell apart are brought to the same scale, so the number of multiplications is bounded
+///  by the values' lengths rather than by the difference of their scales.
+fn compare_decimals(a: &[u8], b: &[u8]) -> Option<Ordering> {
+    let (scale_a, unscaled_a) = a.split_at_checked(size_of::<i32>())?;
+    let (scale_b, unscaled_b) = b.split_at_checked(size_of::<i32>())?;
+    let scale_a = i32::from_be_bytes(scale_a.try_into().ok()?) as i64;
+    let scale_b = i32::from_be_bytes(scale_b.try_into().ok()?) as i64;
+    let unscaled_a = without_length(unscaled_a)?;
+    let unscaled_b = without_length(unscaled_b)?;
+
+    let signum = |v: &[u8]| match (is_negative(v), v.iter().all(|b| *b == 0)) {
+        (true, _) => -1,
+        (false, true) => 0,
+        (false, false) => 1,
+    };
+    match (signum(unscaled_a), signum(unscaled_b)) {
+        (0, 0) => return Some(Ordering::Equal),
+        (sign_a, sign_b) if sign_a != sign_b => return Some(sign_a.cmp(&sign_b)),
+        _ => {},
+    }
+
+    // |unscaled| * 10^-scale lies between 10^(exponent - 1.31) and 10^exponent
+    let exponent = |unscaled: &[u8], scale: i64| (bit_length(&magnitude(unscaled)) as f64 * std::f64::consts::LOG10_2 - scale as f64).ceil();
+    let exponent_a = exponent(unscaled_a, scale_a);
+    let exponent_b = exponent(unscaled_b, scale_b);
+    // leaving room for rounding errors
+    if (exponent_a - exponent_b).abs() > 3.0 {
+        let by_magnitude = exponent_a.partial_cmp(&exponent_b)?;
+        return Some(if is_negative(unscaled_a) { by_magnitude.reverse() } else { by_magnitude });
+    }
+
+    let mut unscaled_a = unscaled_a.to_vec();
+    let mut unscaled_b = unscaled_b.to_vec();
+    for _ in scale_a..scale_b {
+        unscaled_a = times_ten(&unscaled_a);
+    }
+    for _ in scale_b..scale_a {
+        unscaled_b = times_ten(&unscaled_b);
+    }
+    Some(compare_varints(&unscaled_a, &unscaled_b))
+}
+
+fn is_negative(value: &[u8]) -> bool {
+    value.first().is_some_and(|b| b & 0x80 != 0)
+}
+
+/// the absolute value of a two's complement big endian integer, as an unsigned big endian integer
+fn magnitude(value: &[u8]) -> Vec<u8> {
+    if !is_negative(value) {
+        return value.to_vec();
+    }
+    let mut result: Vec<u8> = value.iter().map(|b| !b).collect();
+    for b in result.iter_mut().rev() {
+        let (sum, overflow) = b.overflowing_add(1);
+        *b = sum;
+        if !overflow {
+            break;
+        }
+    }
+    result
+}
+
+/// the number of significant bits of an unsigned big endian integer
+fn bit_length(value: &[u8]) -> u64 {
+    match value.iter().position(|b| *b != 0) {
+        None => 0,
+        Some(idx) => (value.len() - idx) as u64 * 8 - value[idx].leading_zeros() as u64,
+    }
+}
+
+/// multiplies a two's complement big endian integer by ten
+fn times_ten(value: &[u8]) -> Vec<u8> {
+    // one extra byte of sign extension is enough room for the result
+    let fill = if value.first().is_some_and(|b| b & 0x80 != 0) { 0xff } else { 0 };
+    let mut result: Vec<u8> = std::iter::once(fill).chain(value.iter().copied()).collect();
+
+    let mut carry = 0u16;
+    for b in result.iter_mut().rev() {
+        let product = *b as u16 * 10 + carry;
+        *b = product as u8;
+        carry = product >> 8;
+    }
+    result
+}
+
+/// (months, days, nanos)
+fn decode_duration(value: &[u8]) -> Option<(i64, i64, i64)> {
+    let mut r = CassRead::wrap(value);
+    Some((r.read_signed_varint().ok()?, r.read_signed_varint().ok()?, r.read_signed_varint().ok()?))
+}
+
+/// A named list of fields that columns can use as their type, registered in a keyspace's
+///  schema (see `KeyspaceMetaData`). Fields are identified by their index, so the only supported
+///  change is adding fields at the end, which does not require rewriting existing data.
+#[derive(Debug)]
+pub struct UserType {
+    pub name: String,
+    pub fields: Vec<UserTypeField>,
+}
+
+#[derive(Debug)]
+pub struct UserTypeField {
+    pub name: String,
+    pub field_type: ColumnType,
+}
+
+impl UserType {
+    pub fn field_index(&self, name: &str) -> Option<usize> {
+        self.fields.iter().position(|f| f.name == name)
+    }
+
+    /// the path of the cell holding a field's value
+    pub fn field_path(&self, name: &str) -> Option<[u8; 4]> {
+        self.field_index(name).map(|idx| (idx as i32).to_be_bytes())
+    }
+
+    pub fn field_of_path(&self, path: &[u8]) -> Option<&UserTypeField> {
+        let idx: usize = i32::from_be_bytes(path.try_into().ok()?).try_into().ok()?;
+        self.fields.get(idx)
+    }
 }
 
+/// A keyspace's user types by name. Types are immutable, so adding a field registers a new
+///  version of the type, and tables pick it up when their metadata is re-created with it.
+pub struct KeyspaceMetaData {
+    pub name: String,
+    user_types: HashMap<String, Arc<UserType>>,
+}
+
+impl KeyspaceMetaData {
+    pub fn new(name: String) -> KeyspaceMetaData {
+        KeyspaceMetaData {
+            name,
+            user_types: HashMap::new(),
+        }
+    }
+
+    pub fn user_type(&self, name: &str) -> Option<Arc<UserType>> {
+        self.user_types.get(name).cloned()
+    }
+
+    pub fn create_user_type(&mut self, name: &str, fields: Vec<UserTypeField>) -> std::io::Result<Arc<UserType>> {
+        if self.user_types.contains_key(name) {
+            return other_error("user type exists");
+        }
+        if has_duplicate_names(&fields) {
+            return other_error("duplicate field name");
+        }
+
+        let user_type = Arc::new(UserType { name: name.to_string(), fields });
+        self.user_types.insert(name.to_string(), user_type.clone());
+        Ok(user_type)
+    }
+
+    /// adds a field at the end of a user type, returning the type's new version
+    pub fn add_user_type_field(&mut self, type_name: &str, field: UserTypeField) -> std::io::Result<Arc<UserType>> {
+        let old = match self.user_types.get(type_name) {
+            None => return other_error("unknown user type"),
+            Some(old) => old,
+        };
+        if old.field_index(&field.name).is_some() {
+            return other_error("duplicate field name");
+        }
+
+        let mut fields: Vec<UserTypeField> = old.fields.iter()
+            .map(|f| UserTypeField { name: f.name.clone(), field_type: f.field_type.clone() })
+            .collect();
+        fields.push(field);
+        let user_type = Arc::new(UserType { name: type_name.to_string(), fields });
+        self.user_types.insert(type_name.to_string(), user_type.clone());
+        Ok(user_type)
+    }
+}
+
+fn has_duplicate_names(fields: &[UserTypeField]) -> bool {
+    fields.iter().enumerate().any(|(idx, f)| fields[..idx].iter().any(|other| other.name == f.name))
+}
+
+static EMPTY_TUPLE: ColumnType = ColumnType::Tuple(Vec::new());
+
 pub type ClusterKeys = Vec<usize>;
 
+/// per-table settings that do not affect the table's data model
+#[derive(Debug, Clone)]
+pub struct TableOptions {
+    /// target false positive rate of sstable bloom filters
+    pub bloom_filter_fp_chance: f64,
+    pub compaction: CompactionOptions,
+    pub compression: CompressionOptions,
+    /// Tombstones are kept at least this long after they were written, so that replicas that
+    ///  missed a deletion can be repaired before it is forgotten.
+    pub gc_grace: Duration,
+}
+
+impl Default for TableOptions {
+    fn default() -> TableOptions {
+        TableOptions {
+            bloom_filter_fp_chance: 0.01,
+            compaction: CompactionOptions::SizeTiered(SizeTieredOptions::default()),
+            compression: CompressionOptions::default(),
+            gc_grace: Duration::from_secs(10 * 24 * 60 * 60),
+        }
+    }
+}
+
+/// Data files are compressed in chunks of `chunk_size` uncompressed bytes, so that reading a
+///  partition only decompresses the chunks containing it, see `sstable::compression`
+#[derive(Debug, Clone)]
+pub struct CompressionOptions {
+    pub codec: CompressionCodec,
+    /// between 1 and `u32::MAX`, which is checked when an sstable is created
+    pub chunk_size: usize,
+}
+
+impl Default for CompressionOptions {
+    fn default() -> CompressionOptions {
+        CompressionOptions {
+            codec: CompressionCodec::Lz4,
+            chunk_size: 64 * 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionCodec {
+    None,
+    Lz4,
+    Deflate,
+}
+
+/// selects a table's compaction strategy and holds its settings
+#[derive(Debug, Clone)]
+pub enum CompactionOptions {
+    SizeTiered(SizeTieredOptions),
+    Leveled(LeveledOptions),
+    TimeWindow(TimeWindowOptions),
+}
+
+/// Size-tiered compaction merges sstables of similar size, see `compaction::size_tiered`
+#[derive(Debug, Clone)]
+pub struct SizeTieredOptions {
+    /// minimum number of similar sized sstables for a compaction
+    pub min_threshold: usize,
+    /// maximum number of sstables that are compacted at once
+    pub max_threshold: usize,
+    /// sstables belong to the same bucket if their size is between `bucket_low` and `bucket_high`
+    ///  times the bucket's average size
+    pub bucket_low: f64,
+    pub bucket_high: f64,
+    /// sstables smaller than this (in bytes) are all put in the same bucket
+    pub min_sstable_size: u64,
+}
+
+impl Default for SizeTieredOptions {
+    fn default() -> SizeTieredOptions {
+        SizeTieredOptions {
+            min_threshold: 4,
+            max_threshold: 32,
+            bucket_low: 0.5,
+            bucket_high: 1.5,
+            min_sstable_size: 50 * 1024 * 1024,
+        }
+    }
+}
+
+/// Leveled compaction keeps sstables in levels of non-overlapping token ranges, see
+///  `compaction::leveled`
+#[derive(Debug, Clone)]
+pub struct LeveledOptions {
+    /// target size in bytes of the sstables that compaction writes
+    pub sstable_size: u64,
+    /// every level holds `fanout` times as much data as the previous one
+    pub fanout: u64,
+    /// number of sstables in level 0 that triggers their compaction into level 1
+    pub l0_threshold: usize,
+}
+
+impl Default for LeveledOptions {
+    fn default() -> LeveledOptions {
+        LeveledOptions {
+            sstable_size: 160 * 1024 * 1024,
+            fanout: 10,
+            l0_threshold: 4,
+        }
+    }
+}
+
+/// Time-window compaction only merges sstables whose data was written in the same time window,
+///  see `compaction::time_window`
+#[derive(Debug, Clone)]
+pub struct TimeWindowOptions {
+    pub window_size: Duration,
+    /// minimum number of sstables in the current window for a compaction
+    pub min_threshold: usize,
+    /// maximum number of sstables that are compacted at once
+    pub max_threshold: usize,
+}
+
+impl Default for TimeWindowOptions {
+    fn default() -> TimeWindowOptions {
+        TimeWindowOptions {
+            window_size: Duration::from_secs(24 * 60 * 60),
+            min_threshold: 4,
+            max_threshold: 32,
+        }
+    }
+}
+
 pub struct TableMetaData {
     pub name: String,
     pub id: Uuid,
     pub columns: Vec<Arc<ColumnMetaData>>, // sorted by name
     pub idx_partition_key: usize,
     pub idx_cluster_keys: ClusterKeys,
+    /// per cluster key column, in key definition order
+    pub clustering_orders: Vec<ClusteringOrder>,
+    pub options: TableOptions,
     columns_by_id: HashMap<Uuid, Arc<ColumnMetaData>>,
+    cluster_key_comparator: Arc<ClusterKeyComparator>,
 }
 impl TableMetaData {
     pub fn new(name: String, id: Uuid, columns: Vec<Arc<ColumnMetaData>>, idx_partition_key: usize, idx_cluster_keys: ClusterKeys) -> TableMetaData {
@@ -110,25 +694,212 @@ impl TableMetaData {
             columns_by_id.insert(col.id, col.clone());
         }
 
+        let clustering_orders = vec!(ClusteringOrder::Ascending; idx_cluster_keys.len());
+        let cluster_key_comparator = Arc::new(new_cluster_key_comparator(&columns, &idx_cluster_keys, &clustering_orders));
+
         TableMetaData {
             name,
             id,
             columns,
             idx_partition_key,
             idx_cluster_keys,
-            columns_by_id
+            clustering_orders,
+            options: TableOptions::default(),
+            columns_by_id,
+            cluster_key_comparator,
         }
     }
 
+    /// all cluster key columns are ascending unless specified otherwise
+    pub fn with_clustering_orders(mut self, clustering_orders: Vec<ClusteringOrder>) -> TableMetaData {
+        assert_eq!(self.idx_cluster_keys.len(), clustering_orders.len());
+        self.cluster_key_comparator = Arc::new(new_cluster_key_comparator(&self.columns, &self.idx_cluster_keys, &clustering_orders));
+        self.clustering_orders = clustering_orders;
+        self
+    }
+
+    pub fn with_options(mut self, options: TableOptions) -> TableMetaData {
+        self.options = options;
+        self
+    }
+
     pub fn partition_key(&self) -> Arc<ColumnMetaData> {
         self.columns.get(self.idx_partition_key).unwrap().clone()
     }
 
     pub fn cluster_key(&self, idx: usize) -> Arc<ColumnMetaData> {
-        self.columns.get(idx+1).unwrap().clone()
+        self.columns.get(self.idx_cluster_keys[idx]).unwrap().clone()
+    }
+
+    /// all columns that are neither partition key nor cluster key
+    pub fn regular_columns(&self) -> impl Iterator<Item=&Arc<ColumnMetaData>> {
+        self.columns.iter()
+            .enumerate()
+            .filter(move |(idx, _)| *idx != self.idx_partition_key && !self.idx_cluster_keys.contains(idx))
+            .map(|(_, col)| col)
+    }
+
+    pub fn column_by_id(&self, col_id: &Uuid) -> Option<Arc<ColumnMetaData>> {
+        self.columns_by_id.get(col_id).cloned()
+    }
+
+    pub fn cluster_key_comparator(&self) -> &Arc<ClusterKeyComparator> {
+        &self.cluster_key_comparator
+    }
+}
+
+fn new_cluster_key_comparator(columns: &[Arc<ColumnMetaData>], idx_cluster_keys: &[usize], clustering_orders: &[ClusteringOrder]) -> ClusterKeyComparator {
+    ClusterKeyComparator::new(idx_cluster_keys.iter()
+        .zip(clustering_orders.iter())
+        .map(|(idx, order)| (columns[*idx].col_type.clone(), *order))
+        .collect())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+    use std::io::Cursor;
+
+    use crate::db::{BoundSide, ClusterKeyComparator, ClusteringOrder, ColumnType};
+    use crate::io::CassWrite;
+
+    fn ser<F>(f: F) -> Vec<u8> where F: FnOnce(&mut CassWrite<Cursor<Vec<u8>>>) {
+        let mut w = CassWrite::new(Cursor::new(Vec::new()));
+        f(&mut w);
+        w.into_inner().into_inner()
     }
 
-    pub fn column_by_id(&self, col_id: &Uuid) -> Arc<ColumnMetaData> {
-        self.columns_by_id.get(col_id).unwrap().clone() //TODO error reporting
+    fn varint(bytes: &[u8]) -> Vec<u8> {
+        ser(|w| {
+            w.write_varint(bytes.len() as u64).unwrap();
+            w.write_raw(bytes).unwrap();
+        })
+    }
+
+    fn decimal(scale: i32, unscaled: &[u8]) -> Vec<u8> {
+        [scale.to_be_bytes().to_vec(), varint(unscaled)].concat()
+    }
+
+    fn duration(months: i64, days: i64, nanos: i64) -> Vec<u8> {
+        ser(|w| {
+            w.write_signed_varint(months).unwrap();
+            w.write_signed_varint(days).unwrap();
+            w.write_signed_varint(nanos).unwrap();
+        })
+    }
+
+    /// checks that the values are sorted in strictly ascending order
+    fn assert_sorted(col_type: &ColumnType, values: &[Vec<u8>]) {
+        for (idx, a) in values.iter().enumerate() {
+            for (other_idx, b) in values.iter().enumerate() {
+                assert_eq!(idx.cmp(&other_idx), col_type.compare(a, b), "{:?}: {:?} / {:?}", col_type, a, b);
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_compare() {
+        let floats = [f32::NEG_INFINITY, -2.5, -0.0, 0.0, 1e-10, 3.0, f32::INFINITY, f32::NAN];
+        assert_sorted(&ColumnType::Float, &floats.iter().map(|f| f.to_be_bytes().to_vec()).collect::<Vec<_>>());
+        let doubles = [f64::MIN, -1.0, 0.0, 0.5, 1e300];
+        assert_sorted(&ColumnType::Double, &doubles.iter().map(|f| f.to_be_bytes().to_vec()).collect::<Vec<_>>());
+
+        // -129, -128, -1, 0, 1, 127, 128, 65536
+        assert_sorted(&ColumnType::Varint, &[
+            varint(&[0xff, 0x7f]), varint(&[0x80]), varint(&[0xff]), varint(&[]), varint(&[1]), varint(&[0x7f]), varint(&[0, 0x80]), varint(&[1, 0, 0]),
+        ]);
+        // -1.5, -0.15, 0, 0.12, 1.2, 1.21, 13
+        assert_sorted(&ColumnType::Decimal, &[
+            decimal(1, &[0xf1]), decimal(2, &[0xf1]), decimal(5, &[0]), decimal(2, &[12]), decimal(1, &[12]), decimal(2, &[121]), decimal(-1, &[1, 0x03]),
+        ]);
+        assert_eq!(Ordering::Equal, ColumnType::Decimal.compare(&decimal(1, &[12]), &decimal(3, &[0x04, 0xb0])));
+        // values with extreme scales are compared without rescaling them
+        assert_sorted(&ColumnType::Decimal, &[
+            decimal(i32::MIN, &[0xff]), decimal(0, &[0x80]), decimal(i32::MAX - 1, &[0xff]), decimal(i32::MAX, &[0xff]), decimal(0, &[]),
+            decimal(i32::MAX, &[1]), decimal(i32::MAX - 1, &[1]), decimal(i32::MAX, &[0x7f, 0xff]), decimal(0, &[1]), decimal(i32::MIN, &[1]),
+        ]);
+        assert_eq!(Ordering::Equal, ColumnType::Decimal.compare(&decimal(i32::MAX, &[100]), &decimal(i32::MAX - 2, &[1])));
+
+        assert_sorted(&ColumnType::Blob, &[varint(&[]), varint(&[0]), varint(&[0, 0]), varint(&[1])]);
+        assert_sorted(&ColumnType::Date, &[(-5i32).to_be_bytes().to_vec(), 0i32.to_be_bytes().to_vec(), 18000i32.to_be_bytes().to_vec()]);
+        assert_sorted(&ColumnType::Time, &[0i64.to_be_bytes().to_vec(), 1i64.to_be_bytes().to_vec(), 86_399_999_999_999i64.to_be_bytes().to_vec()]);
+        assert_sorted(&ColumnType::Duration, &[duration(-1, 0, 0), duration(0, -3, 100), duration(0, 0, -1), duration(0, 0, 0), duration(1, -30, 0)]);
+        assert_sorted(&ColumnType::Inet, &[vec!(4, 10, 0, 0, 1), vec!(4, 192, 168, 0, 1), [vec!(16), vec!(0; 15), vec!(1)].concat()]);
+
+        assert_sorted(&ColumnType::Int, &[(-5i32).to_be_bytes().to_vec(), 0i32.to_be_bytes().to_vec(), 7i32.to_be_bytes().to_vec()]);
+        assert_sorted(&ColumnType::Long, &[i64::MIN.to_be_bytes().to_vec(), (-1i64).to_be_bytes().to_vec(), 1i64.to_be_bytes().to_vec()]);
+        assert_sorted(&ColumnType::Timestamp, &[(-1000i64).to_be_bytes().to_vec(), 1000i64.to_be_bytes().to_vec()]);
+        assert_sorted(&ColumnType::Text, &[text(""), text("a"), text("ab"), text("b"), text("ä")]);
+
+        // time based UUIDs by timestamp, even if the time fields' bytes are ordered differently
+        assert_sorted(&ColumnType::Uuid, &[
+            time_uuid(0x0000_0000_ffff_ffff, 9), time_uuid(0x0000_0001_0000_0000, 1), time_uuid(0x0000_0001_0000_0000, 2), time_uuid(0x0100_0000_0000_0000, 0),
+            [vec!(0; 6), vec!(0x40), vec!(0; 9)].concat(), [vec!(0xff; 6), vec!(0x40), vec!(0; 9)].concat(),
+        ]);
+
+        let tuple = ColumnType::Tuple(vec!(ColumnType::Int, ColumnType::Text));
+        assert_sorted(&tuple, &[
+            [(-1i32).to_be_bytes().to_vec(), text("z")].concat(),
+            [0i32.to_be_bytes().to_vec(), text("")].concat(),
+            [0i32.to_be_bytes().to_vec(), text("a")].concat(),
+        ]);
+        let list = ColumnType::List(Box::new(ColumnType::Int));
+        let ints = |values: &[i32]| [(values.len() as u32).to_be_bytes().to_vec(), values.iter().flat_map(|v| v.to_be_bytes().to_vec()).collect()].concat();
+        assert_sorted(&list, &[ints(&[]), ints(&[-1, 5]), ints(&[-1, 5, 0]), ints(&[0])]);
+        let map = ColumnType::Map(Box::new(ColumnType::Int), Box::new(ColumnType::Int));
+        let entries = |entries: &[(i32, i32)]| [(entries.len() as u32).to_be_bytes().to_vec(), entries.iter().flat_map(|(k, v)| [k.to_be_bytes(), v.to_be_bytes()].concat()).collect()].concat();
+        assert_sorted(&map, &[entries(&[(-1, 5)]), entries(&[(0, -3), (7, 7)]), entries(&[(0, 2)])]);
+
+        // malformed values are compared by their bytes
+        let malformed = [
+            (ColumnType::Int, vec!(0, 0, 0, 0, 0), 7i32.to_be_bytes().to_vec()),
+            (ColumnType::Text, vec!(0, 0), text("z")),
+            (ColumnType::Uuid, vec!(0; 6), time_uuid(1, 0)),
+            (ColumnType::Decimal, vec!(0, 0), decimal(0, &[1])),
+            (ColumnType::Decimal, vec!(0, 0, 0, 0, 0x80), decimal(0, &[1])),
+            (ColumnType::Varint, vec!(0xff; 11), varint(&[1])),
+            (ColumnType::Duration, vec!(0x80), duration(0, 0, 0)),
+            (list.clone(), ints(&[-1, 5])[..8].to_vec(), ints(&[-1, 5, 0])),
+        ];
+        for (col_type, malformed, well_formed) in malformed.iter() {
+            assert_eq!(malformed.cmp(well_formed), col_type.compare(malformed, well_formed), "{:?}: {:?}", col_type, malformed);
+            assert_eq!(well_formed.cmp(malformed), col_type.compare(well_formed, malformed), "{:?}: {:?}", col_type, malformed);
+        }
+    }
+
+    fn text(s: &str) -> Vec<u8> {
+        [(s.len() as u32).to_be_bytes().to_vec(), s.as_bytes().to_vec()].concat()
+    }
+
+    /// a version 1 UUID with a 60 bit timestamp
+    fn time_uuid(timestamp: u64, node: u8) -> Vec<u8> {
+        let mut result = Vec::new();
+        result.extend_from_slice(&(timestamp as u32).to_be_bytes());
+        result.extend_from_slice(&((timestamp >> 32) as u16).to_be_bytes());
+        result.extend_from_slice(&(((timestamp >> 48) as u16 & 0x0fff) | 0x1000).to_be_bytes());
+        result.extend_from_slice(&[0x80, 0, 0, 0, 0, 0, 0, node]);
+        result
+    }
+
+    #[test]
+    pub fn test_cluster_key_comparator() {
+        let comparator = ClusterKeyComparator::new(vec!((ColumnType::Int, ClusteringOrder::Ascending), (ColumnType::Int, ClusteringOrder::Descending)));
+        let key = |a: i32, b: i32| vec!(a.to_be_bytes().to_vec(), b.to_be_bytes().to_vec());
+        let prefix = |a: i32| vec!(a.to_be_bytes().to_vec());
+
+        assert_eq!(Ordering::Less, comparator.compare_keys(&key(-1, 0), &key(0, 5)));
+        assert_eq!(Ordering::Less, comparator.compare_keys(&key(0, 5), &key(0, -5)));
+        assert_eq!(Ordering::Equal, comparator.compare_keys(&key(3, 3), &key(3, 3)));
+
+        assert_eq!(Ordering::Less, comparator.compare_bound_with_key(&prefix(0), BoundSide::Before, &key(0, 100)));
+        assert_eq!(Ordering::Greater, comparator.compare_bound_with_key(&prefix(-1), BoundSide::After, &key(-1, -100)));
+        assert_eq!(Ordering::Less, comparator.compare_bound_with_key(&key(0, 1), BoundSide::After, &key(0, 0)));
+        assert_eq!(Ordering::Greater, comparator.compare_bound_with_key(&key(0, 1), BoundSide::Before, &key(0, 2)));
+
+        assert_eq!(Ordering::Less, comparator.compare_bounds(&prefix(-1), BoundSide::After, &prefix(0), BoundSide::Before));
+        assert_eq!(Ordering::Less, comparator.compare_bounds(&prefix(0), BoundSide::Before, &key(0, 7), BoundSide::Before));
+        assert_eq!(Ordering::Less, comparator.compare_bounds(&key(0, 7), BoundSide::After, &key(0, 6), BoundSide::Before));
+        assert_eq!(Ordering::Greater, comparator.compare_bounds(&prefix(0), BoundSide::After, &key(0, -7), BoundSide::After));
     }
 }
@@ -1,9 +1,15 @@
-use std::io::{Write, Seek, SeekFrom};
+use std::fmt::{Display, Formatter};
+use std::io::{ErrorKind, Write, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
 use std::mem::size_of;
 use crate::util::*;
 use uuid::Uuid;
 use std::intrinsics::transmute;
 use std::convert::TryInto;
+use integer_encoding::VarInt;
+
+/// the maximum encoded length of a 64 bit variable length integer
+const MAX_VARINT_LEN: usize = 10;
 
 
 pub trait CassSerializer<T> {
@@ -13,8 +19,8 @@ pub trait CassSerializer<T> {
     /// that number of bytes
     fn fixed_size() -> Option<usize>;
 }
-pub trait CassDeserializer<T> {
-    fn deser(r: &mut CassRead) -> T;
+pub trait CassDeserializer<'a, T> {
+    fn deser(r: &mut CassRead<'a>) -> DecodeResult<T>;
 
     /// indicates whether a value is serialized to a fixed number of bytes, in which case it returns
     /// that number of bytes
@@ -22,6 +28,52 @@ pub trait CassDeserializer<T> {
 }
 
 
+/// (de)serializes u64 values as fixed size big endian numbers
+pub struct U64Serializer {}
+
+impl CassSerializer<u64> for U64Serializer {
+    fn ser<W>(out: &mut CassWrite<W>, o: &u64) -> std::io::Result<()> where W: Write+Seek {
+        out.write_u64(*o)
+    }
+
+    fn fixed_size() -> Option<usize> {
+        Some(size_of::<u64>())
+    }
+}
+impl <'a> CassDeserializer<'a, u64> for U64Serializer {
+    fn deser(r: &mut CassRead<'a>) -> DecodeResult<u64> {
+        r.read_u64()
+    }
+
+    fn fixed_size() -> Option<usize> {
+        Some(size_of::<u64>())
+    }
+}
+
+/// (de)serializes byte strings of arbitrary length with a u32 length prefix. Deserialized values
+///  point into the underlying buffer rather than being copied.
+pub struct BytesSerializer {}
+
+impl CassSerializer<Vec<u8>> for BytesSerializer {
+    fn ser<W>(out: &mut CassWrite<W>, o: &Vec<u8>) -> std::io::Result<()> where W: Write+Seek {
+        out.write_bytes(o)
+    }
+
+    fn fixed_size() -> Option<usize> {
+        None
+    }
+}
+impl <'a> CassDeserializer<'a, &'a [u8]> for BytesSerializer {
+    fn deser(r: &mut CassRead<'a>) -> DecodeResult<&'a [u8]> {
+        r.read_bytes()
+    }
+
+    fn fixed_size() -> Option<usize> {
+        None
+    }
+}
+
+
 pub struct CassWrite<W> where W: Write+Seek {
     out: W
 }
@@ -57,6 +109,10 @@ impl<W> CassWrite<W> where W: Write+Seek {
         let ptr = &value_be as *const u64 as *const u8;
         self.write_raw(unsafe { std::slice::from_raw_parts(ptr, size_of::<u64>()) })
     }
+    #[inline]
+    pub fn write_u128(&mut self, value: u128) -> std::io::Result<()> {
+        self.write_raw(&value.to_be_bytes())
+    }
 
     #[inline]
     pub fn write_uuid(&mut self, value: &Uuid) -> std::io::Result<()> {
@@ -92,15 +148,126 @@ impl<W> CassWrite<W> where W: Write+Seek {
         Ok(())
     }
 
+    /// writes a byte string of arbitrary length with a u32 length prefix
+    pub fn write_bytes(&mut self, value: &[u8]) -> std::io::Result<()>  {
+        let len = value.len();
+        if len > std::u32::MAX as usize {
+            return other_error("byte string too long");
+        }
+        self.write_u32(len as u32)?;
+        self.write_raw(value)
+    }
+
+    /// writes an unsigned LEB128 variable length integer
+    pub fn write_varint(&mut self, value: u64) -> std::io::Result<()> {
+        self.write_raw(&value.encode_var_vec())
+    }
+    /// writes a zigzag encoded variable length integer, so that small negative numbers are short
+    pub fn write_signed_varint(&mut self, value: i64) -> std::io::Result<()> {
+        self.write_raw(&value.encode_var_vec())
+    }
+
     pub fn write_raw(&mut self, value: &[u8]) -> std::io::Result<()> {
         self.out.write_all(value)
     }
 
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.out.flush()
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.out
+    }
+
     pub fn into_inner(mut self) -> W {
         self.out
     }
 }
 
+/// the reason why persistent data could not be decoded
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeErrorKind {
+    /// the data ends before the value that starts at the error's offset
+    UnexpectedEnd { needed: usize, available: usize },
+    /// a tag identifying the kind of the following data has none of the expected values
+    InvalidTag { tag: &'static str, expected: &'static [u8], actual: u8 },
+    /// a cell refers to a column that is not part of the table's schema
+    UnknownColumn(Uuid),
+    InvalidUtf8,
+    /// a value that is outside of its valid range
+    InvalidValue(&'static str),
+    /// the data file block's contents do not match its checksum
+    ChecksumMismatch { block: usize },
+    /// a compressed chunk of the data file can not be decompressed
+    CorruptChunk { chunk: usize },
+    /// the file does not start with the magic number of the expected sstable component
+    InvalidMagic { component: &'static str },
+    /// the file was written in a format version this build can not read
+    UnsupportedVersion { version: u16, min: u16, max: u16 },
+    /// the file was written for a different table, or with different key columns
+    SchemaMismatch(&'static str),
+}
+
+/// Corrupt or truncated data, with the offset at which decoding failed. The file is added by code
+///  that knows which file the data came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError {
+    pub file: Option<PathBuf>,
+    pub offset: usize,
+    pub kind: DecodeErrorKind,
+}
+
+pub type DecodeResult<T> = Result<T, DecodeError>;
+
+impl DecodeError {
+    pub fn new(offset: usize, kind: DecodeErrorKind) -> DecodeError {
+        DecodeError { file: None, offset, kind }
+    }
+
+    pub fn invalid_tag(offset: usize, tag: &'static str, expected: &'static [u8], actual: u8) -> DecodeError {
+        DecodeError::new(offset, DecodeErrorKind::InvalidTag { tag, expected, actual })
+    }
+
+    /// sets the file unless it is set already
+    pub fn in_file(mut self, file: &Path) -> DecodeError {
+        if self.file.is_none() {
+            self.file = Some(file.to_path_buf());
+        }
+        self
+    }
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            DecodeErrorKind::UnexpectedEnd { needed, available } => write!(f, "unexpected end of data: {} bytes needed, {} available", needed, available)?,
+            DecodeErrorKind::InvalidTag { tag, expected, actual } => write!(f, "invalid {}: expected one of {:?}, was {}", tag, expected, actual)?,
+            DecodeErrorKind::UnknownColumn(id) => write!(f, "unknown column id {}", id)?,
+            DecodeErrorKind::InvalidUtf8 => write!(f, "invalid UTF-8 string")?,
+            DecodeErrorKind::InvalidValue(what) => write!(f, "invalid {}", what)?,
+            DecodeErrorKind::ChecksumMismatch { block } => write!(f, "checksum mismatch for block {}", block)?,
+            DecodeErrorKind::CorruptChunk { chunk } => write!(f, "corrupt compressed chunk {}", chunk)?,
+            DecodeErrorKind::InvalidMagic { component } => write!(f, "not an sstable {} file", component)?,
+            DecodeErrorKind::UnsupportedVersion { version, min, max } => write!(f, "unsupported format version {}, supported are {} to {}", version, min, max)?,
+            DecodeErrorKind::SchemaMismatch(what) => write!(f, "written with a different {}", what)?,
+        }
+        write!(f, " at offset {}", self.offset)?;
+        if let Some(file) = &self.file {
+            write!(f, " in {:?}", file)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl From<DecodeError> for std::io::Error {
+    fn from(e: DecodeError) -> std::io::Error {
+        std::io::Error::new(ErrorKind::InvalidData, e)
+    }
+}
+
+
 pub struct CassRead <'a> {
     buf: &'a[u8],
     pub pos: usize,
@@ -115,77 +282,149 @@ impl<'a> CassRead<'a> {
     }
 
     #[inline]
-    pub fn assert_remaining(&self, size: usize) {
-        assert!(self.buf.len() >= self.pos + size);
+    pub fn is_at_end(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    /// the whole underlying buffer, regardless of the current position
+    pub fn data(&self) -> &'a [u8] {
+        self.buf
+    }
+
+    /// checks that there are `size` bytes starting `offs` bytes after the current position
+    #[inline]
+    pub fn check_remaining(&self, offs: usize, size: usize) -> DecodeResult<()> {
+        let available = self.buf.len().saturating_sub(self.pos.saturating_add(offs));
+        if size > available {
+            return Err(DecodeError::new(self.pos + offs, DecodeErrorKind::UnexpectedEnd { needed: size, available }));
+        }
+        Ok(())
     }
 
 
     #[inline]
-    pub fn read_slice(&mut self, size: usize) -> &'a[u8] {
+    pub fn read_slice(&mut self, size: usize) -> DecodeResult<&'a[u8]> {
+        self.check_remaining(0, size)?;
         let result = &self.buf[self.pos..self.pos+size];
         self.pos += size;
-        result
+        Ok(result)
     }
 
     #[inline]
-    pub fn peek_u8(&self) -> u8 {
-        self.buf[self.pos]
+    pub fn peek_u8(&self) -> DecodeResult<u8> {
+        self.peek_u8_offs(0)
+    }
+    #[inline]
+    pub fn peek_u8_offs(&self, offs: usize) -> DecodeResult<u8> {
+        self.check_remaining(offs, 1)?;
+        Ok(self.buf[self.pos + offs])
     }
     #[inline]
-    pub fn read_u8(&mut self) -> u8 {
-        let result = self.peek_u8();
+    pub fn read_u8(&mut self) -> DecodeResult<u8> {
+        let result = self.peek_u8()?;
         self.pos += 1;
-        result
+        Ok(result)
+    }
+
+    #[inline]
+    pub fn peek_u16(&self) -> DecodeResult<u16> {
+        self.check_remaining(0, size_of::<u16>())?;
+        let (int_bytes, _) = self.buf[self.pos..].split_at(size_of::<u16>());
+        Ok(u16::from_be_bytes(int_bytes.try_into().unwrap()))
+    }
+    #[inline]
+    pub fn read_u16(&mut self) -> DecodeResult<u16> {
+        let result = self.peek_u16()?;
+        self.pos += size_of::<u16>();
+        Ok(result)
     }
 
     #[inline]
-    pub fn peek_u32(&self) -> u32 {
+    pub fn peek_u32(&self) -> DecodeResult<u32> {
         self.peek_u32_offs(0)
     }
     #[inline]
-    pub fn peek_u32_offs(&self, offs: usize) -> u32 {
-        let (int_bytes, _) = self.buf[self.pos+offs..].split_at(std::mem::size_of::<u32>());
-        u32::from_be_bytes(int_bytes.try_into().unwrap())
+    pub fn peek_u32_offs(&self, offs: usize) -> DecodeResult<u32> {
+        self.check_remaining(offs, size_of::<u32>())?;
+        let (int_bytes, _) = self.buf[self.pos+offs..].split_at(size_of::<u32>());
+        Ok(u32::from_be_bytes(int_bytes.try_into().unwrap()))
     }
     #[inline]
-    pub fn read_u32(&mut self) -> u32 {
-        let result = self.peek_u32();
+    pub fn read_u32(&mut self) -> DecodeResult<u32> {
+        let result = self.peek_u32()?;
         self.pos += size_of::<u32>();
-        result
+        Ok(result)
     }
 
     #[inline]
-    pub fn peek_u64(&self) -> u64 {
-        let (int_bytes, _) = self.buf[self.pos..].split_at(std::mem::size_of::<u64>());
-        u64::from_be_bytes(int_bytes.try_into().unwrap())
+    pub fn peek_u64(&self) -> DecodeResult<u64> {
+        self.check_remaining(0, size_of::<u64>())?;
+        let (int_bytes, _) = self.buf[self.pos..].split_at(size_of::<u64>());
+        Ok(u64::from_be_bytes(int_bytes.try_into().unwrap()))
     }
     #[inline]
-    pub fn read_u64(&mut self) -> u64 {
-        let result = self.peek_u64();
+    pub fn read_u64(&mut self) -> DecodeResult<u64> {
+        let result = self.peek_u64()?;
         self.pos += size_of::<u64>();
-        result
+        Ok(result)
+    }
+
+    /// an unsigned variable length integer `offs` bytes after the current position, together with
+    ///  its encoded length
+    pub fn peek_varint_offs(&self, offs: usize) -> DecodeResult<(u64, usize)> {
+        self.check_remaining(offs, 1)?;
+        let start = self.pos + offs;
+        let src = &self.buf[start..(start + MAX_VARINT_LEN).min(self.buf.len())];
+        match src.iter().position(|b| b & 0x80 == 0) {
+            Some(last) => Ok(u64::decode_var(&src[..=last])),
+            None if src.len() == MAX_VARINT_LEN => Err(DecodeError::new(start, DecodeErrorKind::InvalidValue("varint"))),
+            None => Err(DecodeError::new(start, DecodeErrorKind::UnexpectedEnd { needed: src.len() + 1, available: src.len() })),
+        }
+    }
+    pub fn read_varint(&mut self) -> DecodeResult<u64> {
+        let (result, len) = self.peek_varint_offs(0)?;
+        self.pos += len;
+        Ok(result)
+    }
+    pub fn read_signed_varint(&mut self) -> DecodeResult<i64> {
+        let (_, len) = self.peek_varint_offs(0)?;
+        let (result, _) = i64::decode_var(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(result)
     }
 
     #[inline]
-    pub fn read_uuid(&mut self) -> Uuid {
-        let slice = self.read_slice(16);
+    pub fn read_u128(&mut self) -> DecodeResult<u128> {
+        let slice = self.read_slice(16)?;
+        Ok(u128::from_be_bytes(slice.try_into().unwrap()))
+    }
+
+    #[inline]
+    pub fn read_uuid(&mut self) -> DecodeResult<Uuid> {
+        let slice = self.read_slice(16)?;
         let bytes: &[u8;16] = slice.try_into().unwrap();
-        Uuid::from_bytes(*bytes)
+        Ok(Uuid::from_bytes(*bytes))
     }
 
     #[inline]
-    pub fn read_db_timestamp(&mut self) -> DbTimestamp {
+    pub fn read_db_timestamp(&mut self) -> DecodeResult<DbTimestamp> {
         self.read_u64()
     }
     #[inline]
-    pub fn read_db_expiry_timestamp(&mut self) -> DbExpiryTimestamp {
+    pub fn read_db_expiry_timestamp(&mut self) -> DecodeResult<DbExpiryTimestamp> {
         self.read_u32()
     }
 
 
-    pub fn read_utf8(&mut self) -> &str {
-        let len = self.read_u32() as usize;
-        //TODO unchecked or checked?
-        unsafe { std::str::from_utf8_unchecked(&self.buf[0..len]) }
+    pub fn read_bytes(&mut self) -> DecodeResult<&'a [u8]> {
+        let len = self.read_u32()? as usize;
+        self.read_slice(len)
+    }
+
+    pub fn read_utf8(&mut self) -> DecodeResult<&'a str> {
+        let offset = self.pos;
+        let bytes = self.read_bytes()?;
+        std::str::from_utf8(bytes)
+            .map_err(|_| DecodeError::new(offset, DecodeErrorKind::InvalidUtf8))
     }
 }
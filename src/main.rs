@@ -6,6 +6,11 @@ use memmap::MmapOptions;
 mod db;
 mod io;
 mod util;
+mod memtable;
+mod commitlog;
+mod compaction;
+mod read;
+mod value;
 
 mod sstable;
 
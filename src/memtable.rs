@@ -0,0 +1,920 @@
+use std::cmp::Ordering;
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use crate::db::{BoundSide, ClusterKeyComparator, ColumnMetaData, ColumnType, KeyBound, RegularRowData, RowDetails, RowTombstoneData, TableCell, TableCellData, TableMetaData, TableRow};
+use crate::io::DecodeResult;
+use crate::util::{expiry_of_timestamp, DbExpiryTimestamp, DbTimestamp, Token, NO_EXPIRY};
+use crate::value::validate_row;
+
+// rough per-entry memory overhead (tree nodes, vectors, Arcs) for memory accounting
+const PARTITION_OVERHEAD: usize = 96;
+const ROW_OVERHEAD: usize = 96;
+const CELL_OVERHEAD: usize = 80;
+const RANGE_TOMBSTONE_OVERHEAD: usize = 96;
+
+/// a complete cluster key, in key definition order
+pub type ClusterKey = Vec<Vec<u8>>;
+
+/// a tombstone for a whole collection (or user type value) in a row that also has new elements for it
+fn is_overwrite_tombstone(data: &RegularRowData, cell: &TableCell) -> bool {
+    cell.meta_data.col_type.is_multi_cell() && cell.path.is_none() && matches!(cell.data, TableCellData::Tombstone)
+        && data.regular_cols.iter().any(|c| c.meta_data.id == cell.meta_data.id && c.path.is_some())
+}
+
+fn with_overwrite_tombstones_lowered<'a>(data: &RegularRowData<'a>) -> RegularRowData<'a> {
+    RegularRowData {
+        pk_expiry: data.pk_expiry,
+        cluster_key: data.cluster_key.clone(),
+        regular_cols: data.regular_cols.iter()
+            .map(|c| TableCell {
+                meta_data: c.meta_data.clone(),
+                path: c.path,
+                timestamp: if is_overwrite_tombstone(data, c) { c.timestamp.saturating_sub(1) } else { c.timestamp },
+                expiry: c.expiry,
+                data: match c.data {
+                    TableCellData::Tombstone => TableCellData::Tombstone,
+                    TableCellData::Regular(v) => TableCellData::Regular(v),
+                },
+            })
+            .collect(),
+    }
+}
+
+/// a cluster key that is ordered by its table's `ClusterKeyComparator`, for sorting rows
+struct SortedClusterKey {
+    key: ClusterKey,
+    comparator: Arc<ClusterKeyComparator>,
+}
+
+impl Ord for SortedClusterKey {
+    fn cmp(&self, other: &SortedClusterKey) -> Ordering {
+        self.comparator.compare_keys(&self.key, &other.key)
+    }
+}
+impl PartialOrd for SortedClusterKey {
+    fn partial_cmp(&self, other: &SortedClusterKey) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl PartialEq for SortedClusterKey {
+    fn eq(&self, other: &SortedClusterKey) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for SortedClusterKey {}
+
+/// Identifies a cell in a row: its column, and the element's or field's path for multi-cell
+///  columns (see `TableCell`). Paths are ordered by the column's `cell_path_type`, so that set
+///  elements and map keys are sorted by their values rather than their serialized bytes.
+pub struct CellKey {
+    pub meta_data: Arc<ColumnMetaData>,
+    pub path: Option<Vec<u8>>,
+}
+
+impl CellKey {
+    pub fn new(meta_data: Arc<ColumnMetaData>, path: Option<Vec<u8>>) -> CellKey {
+        CellKey { meta_data, path }
+    }
+}
+
+impl Ord for CellKey {
+    fn cmp(&self, other: &CellKey) -> Ordering {
+        self.meta_data.id.cmp(&other.meta_data.id)
+            .then_with(|| match (&self.path, &other.path, self.meta_data.col_type.cell_path_type()) {
+                (Some(a), Some(b), Some(path_type)) => path_type.compare(a, b),
+                (a, b, _) => a.cmp(b),
+            })
+    }
+}
+impl PartialOrd for CellKey {
+    fn partial_cmp(&self, other: &CellKey) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl PartialEq for CellKey {
+    fn eq(&self, other: &CellKey) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for CellKey {}
+
+
+/// Holds a table's recent mutations in memory until they are flushed to an sstable. Partitions
+///  are sorted by token (and partition key for colliding tokens), rows inside a partition by
+///  cluster key.
+///
+/// Mutations are reconciled as they are applied: the newest cell wins, and tombstones shadow
+///  older data, which is dropped.
+pub struct Memtable {
+    table_metadata: Arc<TableMetaData>,
+    partitions: BTreeMap<(Token, Vec<u8>), MemPartition>,
+    size_in_bytes: usize,
+    min_timestamp: Option<DbTimestamp>,
+}
+
+impl Memtable {
+    pub fn new(table_metadata: Arc<TableMetaData>) -> Memtable {
+        Memtable {
+            table_metadata,
+            partitions: BTreeMap::new(),
+            size_in_bytes: 0,
+            min_timestamp: None,
+        }
+    }
+
+    pub fn table_metadata(&self) -> &Arc<TableMetaData> {
+        &self.table_metadata
+    }
+
+    /// Applies a row after checking that its keys and values match their columns' types, which
+    ///  comparisons rely on. Error offsets are relative to the offending value.
+    ///
+    /// A row that overwrites a collection (or user type value), i.e. that has a tombstone for the
+    ///  whole value and new elements of the same column, has the tombstone applied one below its
+    ///  timestamp. So it does not shadow the new elements, which usually share its timestamp.
+    pub fn apply(&mut self, row: &TableRow) -> DecodeResult<()> {
+        validate_row(row)?;
+        let partition = match self.partitions.entry((row.token, row.partition_key.to_vec())) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                self.size_in_bytes += PARTITION_OVERHEAD + row.partition_key.len();
+                e.insert(MemPartition::new(self.table_metadata.cluster_key_comparator().clone()))
+            }
+        };
+
+        let old_size = partition.size_in_bytes;
+        match &row.details {
+            RowDetails::Regular(data) if data.regular_cols.iter().any(|c| is_overwrite_tombstone(data, c)) => {
+                partition.apply(&RowDetails::Regular(with_overwrite_tombstones_lowered(data)));
+            },
+            details => partition.apply(details),
+        }
+        self.size_in_bytes = self.size_in_bytes + partition.size_in_bytes - old_size;
+
+        let row_min_timestamp = match &row.details {
+            RowDetails::Regular(data) => data.regular_cols.iter().map(|c| c.timestamp).min(),
+            RowDetails::RowTombstone(data) => Some(data.timestamp),
+        };
+        self.min_timestamp = self.min_timestamp.into_iter().chain(row_min_timestamp).min();
+        Ok(())
+    }
+
+    /// the oldest timestamp of any cell or range tombstone applied to the memtable, including
+    ///  data that was shadowed since
+    pub fn min_timestamp(&self) -> Option<DbTimestamp> {
+        self.min_timestamp
+    }
+
+    /// approximate number of bytes of heap memory held by this memtable
+    pub fn size_in_bytes(&self) -> usize {
+        self.size_in_bytes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.partitions.is_empty()
+    }
+
+    pub fn partition(&self, token: Token, partition_key: &[u8]) -> Option<&MemPartition> {
+        self.partitions.get(&(token, partition_key.to_vec()))
+    }
+
+    /// all partitions in token order
+    pub fn partitions(&self) -> impl Iterator<Item=(Token, &[u8], &MemPartition)> {
+        self.partitions.iter().map(|((token, partition_key), p)| (*token, partition_key.as_slice(), p))
+    }
+}
+
+
+/// a partition's rows and range tombstones, ordered by the table's cluster key comparator
+pub struct MemPartition {
+    comparator: Arc<ClusterKeyComparator>,
+    rows: BTreeMap<SortedClusterKey, MemRow>,
+    range_tombstones: Vec<MemRangeTombstone>, // sorted and non-overlapping
+    size_in_bytes: usize,
+}
+
+impl MemPartition {
+    pub fn new(comparator: Arc<ClusterKeyComparator>) -> MemPartition {
+        MemPartition {
+            comparator,
+            rows: BTreeMap::new(),
+            range_tombstones: Vec::new(),
+            size_in_bytes: 0,
+        }
+    }
+
+    pub fn apply(&mut self, details: &RowDetails) {
+        match details {
+            RowDetails::Regular(data) => self.apply_regular(data),
+            RowDetails::RowTombstone(data) => self.apply_range_tombstone(MemRangeTombstone::from(data)),
+        }
+    }
+
+    /// reconciles another partition's data into this one
+    pub fn merge(&mut self, other: &MemPartition) {
+        for tombstone in other.range_tombstones.iter() {
+            self.apply_range_tombstone(tombstone.clone());
+        }
+        for (cluster_key, row) in other.rows.iter() {
+            self.apply_row(&cluster_key.key, row);
+        }
+    }
+
+    /// a copy of the rows between two bounds, and of the range tombstones cut at the bounds
+    pub fn slice(&self, start: &MemClusterBound, end: &MemClusterBound) -> MemPartition {
+        let comparator = &self.comparator;
+        let mut result = MemPartition::new(comparator.clone());
+        for tombstone in self.range_tombstones.iter() {
+            result.apply_range_tombstone(MemRangeTombstone {
+                start: std::cmp::max_by(tombstone.start.clone(), start.clone(), |a, b| a.compare(comparator, b)),
+                end: std::cmp::min_by(tombstone.end.clone(), end.clone(), |a, b| a.compare(comparator, b)),
+                timestamp: tombstone.timestamp,
+            });
+        }
+        for (cluster_key, row) in self.rows.iter() {
+            if start.compare_with_key(comparator, &cluster_key.key) == Ordering::Less && end.compare_with_key(comparator, &cluster_key.key) == Ordering::Greater {
+                result.apply_row(&cluster_key.key, row);
+            }
+        }
+        result
+    }
+
+    fn apply_row(&mut self, cluster_key: &[Vec<u8>], row: &MemRow) {
+        self.apply_regular(&RegularRowData {
+            pk_expiry: row.pk_expiry,
+            cluster_key: cluster_key.iter().map(|c| c.as_slice()).collect(),
+            regular_cols: row.cells.values().map(|c| c.as_table_cell()).collect(),
+        });
+    }
+
+    /// A row inside a range tombstone survives only if at least one of its cells is newer than
+    ///  the tombstone - there is no timestamp for the row itself.
+    fn apply_regular(&mut self, data: &RegularRowData) {
+        let cluster_key: ClusterKey = data.cluster_key.iter().map(|c| c.to_vec()).collect();
+        let shadowed_until = self.tombstone_timestamp(&cluster_key);
+
+        let cells: Vec<MemCell> = data.regular_cols.iter()
+            .filter(|c| shadowed_until.is_none_or(|ts| c.timestamp > ts))
+            .map(MemCell::from)
+            .collect();
+
+        if cells.is_empty() && shadowed_until.is_some() {
+            return;
+        }
+
+        let row = match self.rows.entry(SortedClusterKey { key: cluster_key, comparator: self.comparator.clone() }) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                self.size_in_bytes += ROW_OVERHEAD + e.key().key.iter().map(|c| c.len()).sum::<usize>();
+                e.insert(MemRow { pk_expiry: data.pk_expiry, cells: BTreeMap::new() })
+            }
+        };
+        row.pk_expiry = row.pk_expiry.max(data.pk_expiry);
+
+        for cell in cells {
+            if cell.is_multi_cell_tombstone() {
+                let mut removed_size = 0;
+                row.cells.retain(|key, element| {
+                    let is_shadowed = key.meta_data.id == cell.meta_data.id && key.path.is_some() && element.timestamp <= cell.timestamp;
+                    if is_shadowed {
+                        removed_size += element.size_in_bytes();
+                    }
+                    !is_shadowed
+                });
+                self.size_in_bytes -= removed_size;
+            }
+            else if cell.path.is_some() && row.cells.get(&CellKey::new(cell.meta_data.clone(), None)).is_some_and(|t| t.timestamp >= cell.timestamp) {
+                continue;
+            }
+
+            match row.cells.entry(CellKey::new(cell.meta_data.clone(), cell.path.clone())) {
+                Entry::Vacant(e) => {
+                    self.size_in_bytes += cell.size_in_bytes();
+                    e.insert(cell);
+                },
+                Entry::Occupied(mut e) => {
+                    if cell.supersedes(e.get()) {
+                        self.size_in_bytes = self.size_in_bytes + cell.size_in_bytes() - e.get().size_in_bytes();
+                        e.insert(cell);
+                    }
+                },
+            }
+        }
+    }
+
+    fn apply_range_tombstone(&mut self, tombstone: MemRangeTombstone) {
+        let comparator = self.comparator.clone();
+        if tombstone.start.compare(&comparator, &tombstone.end) != Ordering::Less {
+            return;
+        }
+
+        // drop shadowed data
+        let mut removed_size = 0;
+        self.rows.retain(|cluster_key, row| {
+            if !tombstone.contains(&comparator, &cluster_key.key) {
+                return true;
+            }
+
+            row.cells.retain(|_, cell| {
+                let is_shadowed = cell.timestamp <= tombstone.timestamp;
+                if is_shadowed {
+                    removed_size += cell.size_in_bytes();
+                }
+                !is_shadowed
+            });
+
+            if row.cells.is_empty() {
+                removed_size += ROW_OVERHEAD + cluster_key.key.iter().map(|c| c.len()).sum::<usize>();
+                return false;
+            }
+            true
+        });
+        self.size_in_bytes -= removed_size;
+
+        // The existing tombstones are sorted and non-overlapping, so only those overlapping the new
+        //  one (and neighbours touching it, which may merge with it) need to be split and merged
+        //  back, keeping the newest timestamp for every piece.
+        let from = self.range_tombstones
+            .partition_point(|t| t.end.compare(&comparator, &tombstone.start) == Ordering::Less);
+        let to = self.range_tombstones
+            .partition_point(|t| t.start.compare(&comparator, &tombstone.end) != Ordering::Greater);
+
+        let mut merged: Vec<MemRangeTombstone> = Vec::new();
+        let mut push = |start: &MemClusterBound, end: &MemClusterBound, timestamp: DbTimestamp| {
+            if start.compare(&comparator, end) != Ordering::Less {
+                return;
+            }
+            match merged.last_mut() {
+                Some(last) if last.timestamp == timestamp && last.end.compare(&comparator, start) == Ordering::Equal => {
+                    last.end = end.clone();
+                },
+                _ => merged.push(MemRangeTombstone {
+                    start: start.clone(),
+                    end: end.clone(),
+                    timestamp,
+                }),
+            }
+        };
+
+        let max = |a: &MemClusterBound, b: &MemClusterBound| if a.compare(&comparator, b) == Ordering::Less { b.clone() } else { a.clone() };
+        let min = |a: &MemClusterBound, b: &MemClusterBound| if a.compare(&comparator, b) == Ordering::Less { a.clone() } else { b.clone() };
+
+        let mut covered_until = tombstone.start.clone();
+        for t in self.range_tombstones[from..to].iter() {
+            push(&t.start, &min(&t.end, &tombstone.start), t.timestamp);
+            push(&covered_until, &min(&t.start, &tombstone.end), tombstone.timestamp);
+            push(&max(&t.start, &tombstone.start), &min(&t.end, &tombstone.end), t.timestamp.max(tombstone.timestamp));
+            push(&max(&t.start, &tombstone.end), &t.end, t.timestamp);
+            covered_until = max(&covered_until, &t.end);
+        }
+        push(&covered_until, &tombstone.end, tombstone.timestamp);
+
+        self.size_in_bytes -= self.range_tombstones[from..to].iter().map(|t| t.size_in_bytes()).sum::<usize>();
+        self.size_in_bytes += merged.iter().map(|t| t.size_in_bytes()).sum::<usize>();
+        self.range_tombstones.splice(from..to, merged);
+    }
+
+    /// the timestamp of the range tombstone containing a cluster key, if any
+    pub fn tombstone_timestamp(&self, cluster_key: &[Vec<u8>]) -> Option<DbTimestamp> {
+        let idx = self.range_tombstones
+            .partition_point(|t| t.end.compare_with_key(&self.comparator, cluster_key) != Ordering::Greater);
+        self.range_tombstones.get(idx)
+            .filter(|t| t.contains(&self.comparator, cluster_key))
+            .map(|t| t.timestamp)
+    }
+
+    /// Turns expired cells into tombstones, and drops tombstones with a deletion time up to
+    ///  `gc_before` unless they might still shadow data elsewhere, i.e. are not older than
+    ///  `max_purgeable_timestamp`. Rows without cells are dropped once their primary key expired.
+    pub fn purge(&mut self, now: DbExpiryTimestamp, gc_before: DbExpiryTimestamp, max_purgeable_timestamp: DbTimestamp) {
+        let is_purgeable = |deletion_time: DbExpiryTimestamp, timestamp: DbTimestamp| {
+            deletion_time <= gc_before && timestamp < max_purgeable_timestamp
+        };
+
+        let mut removed_size = 0;
+        self.rows.retain(|cluster_key, row| {
+            row.cells.retain(|_, cell| {
+                let old_size = cell.size_in_bytes();
+                if cell.data.is_some() && cell.expiry <= now {
+                    cell.data = None;
+                }
+
+                let is_purged = cell.deletion_time().is_some_and(|t| is_purgeable(t, cell.timestamp));
+                removed_size += old_size - if is_purged { 0 } else { cell.size_in_bytes() };
+                !is_purged
+            });
+
+            if row.cells.is_empty() && row.pk_expiry <= now {
+                removed_size += ROW_OVERHEAD + cluster_key.key.iter().map(|c| c.len()).sum::<usize>();
+                return false;
+            }
+            true
+        });
+
+        self.range_tombstones.retain(|t| {
+            let is_purged = is_purgeable(expiry_of_timestamp(t.timestamp), t.timestamp);
+            if is_purged {
+                removed_size += t.size_in_bytes();
+            }
+            !is_purged
+        });
+
+        self.size_in_bytes -= removed_size;
+    }
+
+    /// the oldest timestamp of any cell or range tombstone in the partition
+    pub fn min_timestamp(&self) -> Option<DbTimestamp> {
+        let cells = self.rows.values().flat_map(|row| row.cells.values().map(|c| c.timestamp));
+        let tombstones = self.range_tombstones.iter().map(|t| t.timestamp);
+        cells.chain(tombstones).min()
+    }
+
+    /// rows in cluster key order
+    pub fn rows(&self) -> impl Iterator<Item=(&ClusterKey, &MemRow)> {
+        self.rows.iter().map(|(cluster_key, row)| (&cluster_key.key, row))
+    }
+
+    /// sorted and non-overlapping
+    pub fn range_tombstones(&self) -> &[MemRangeTombstone] {
+        &self.range_tombstones
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty() && self.range_tombstones.is_empty()
+    }
+}
+
+
+pub struct MemRow {
+    pub pk_expiry: DbExpiryTimestamp,
+    /// a collection's tombstone is ordered before its elements
+    pub cells: BTreeMap<CellKey, MemCell>,
+}
+
+impl MemRow {
+    /// the cell holding a field of a user type column, if the field was written
+    pub fn user_type_field(&self, column: &Arc<ColumnMetaData>, field_name: &str) -> Option<&MemCell> {
+        let path = match &column.col_type {
+            ColumnType::UserType(user_type) => user_type.field_path(field_name)?,
+            _ => return None,
+        };
+        self.cells.get(&CellKey::new(column.clone(), Some(path.to_vec())))
+    }
+}
+
+#[derive(Clone)]
+pub struct MemCell {
+    pub meta_data: Arc<ColumnMetaData>,
+    pub path: Option<Vec<u8>>,
+    pub timestamp: DbTimestamp,
+    pub expiry: DbExpiryTimestamp,
+    /// None for a tombstone
+    pub data: Option<Vec<u8>>,
+}
+
+impl MemCell {
+    /// The newer timestamp wins. For equal timestamps, tombstones win over regular data, and the
+    ///  greater value wins between regular values so that reconciliation is deterministic.
+    pub fn supersedes(&self, other: &MemCell) -> bool {
+        match self.timestamp.cmp(&other.timestamp) {
+            Ordering::Greater => true,
+            Ordering::Less => false,
+            Ordering::Equal => match (&self.data, &other.data) {
+                (None, _) => true,
+                (Some(_), None) => false,
+                (Some(a), Some(b)) => a > b,
+            }
+        }
+    }
+
+    /// a tombstone for all elements of a collection (or fields of a user type) that are not newer
+    ///  than it
+    pub fn is_multi_cell_tombstone(&self) -> bool {
+        self.meta_data.col_type.is_multi_cell() && self.path.is_none() && self.data.is_none()
+    }
+
+    pub fn as_table_cell(&self) -> TableCell<'_> {
+        TableCell {
+            meta_data: self.meta_data.clone(),
+            path: self.path.as_deref(),
+            timestamp: self.timestamp,
+            expiry: self.expiry,
+            data: match &self.data {
+                None => TableCellData::Tombstone,
+                Some(data) => TableCellData::Regular(data),
+            }
+        }
+    }
+
+    /// for tombstones, the time from which on they can be garbage collected: the expiry of the data
+    ///  they replaced, or the time they were written. None for regular cells.
+    pub fn deletion_time(&self) -> Option<DbExpiryTimestamp> {
+        match (&self.data, self.expiry) {
+            (Some(_), _) => None,
+            (None, NO_EXPIRY) => Some(expiry_of_timestamp(self.timestamp)),
+            (None, expiry) => Some(expiry),
+        }
+    }
+
+    fn size_in_bytes(&self) -> usize {
+        CELL_OVERHEAD + self.path.as_ref().map_or(0, |p| p.len()) + self.data.as_ref().map_or(0, |d| d.len())
+    }
+}
+
+impl From<&TableCell<'_>> for MemCell {
+    fn from(cell: &TableCell) -> MemCell {
+        MemCell {
+            meta_data: cell.meta_data.clone(),
+            path: cell.path.map(|p| p.to_vec()),
+            timestamp: cell.timestamp,
+            expiry: cell.expiry,
+            data: match cell.data {
+                TableCellData::Tombstone => None,
+                TableCellData::Regular(data) => Some(data.to_vec()),
+            }
+        }
+    }
+}
+
+
+/// an owned key bound position, see `BoundSide`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemClusterBound {
+    pub prefix: ClusterKey,
+    pub side: BoundSide,
+}
+
+impl MemClusterBound {
+    pub fn lower(bound: &Option<KeyBound>) -> MemClusterBound {
+        match bound {
+            None => MemClusterBound { prefix: Vec::new(), side: BoundSide::Before },
+            Some(b) => MemClusterBound {
+                prefix: b.cluster_key_prefix.iter().map(|c| c.to_vec()).collect(),
+                side: BoundSide::of_lower(b.is_inclusive),
+            },
+        }
+    }
+
+    pub fn upper(bound: &Option<KeyBound>) -> MemClusterBound {
+        match bound {
+            None => MemClusterBound { prefix: Vec::new(), side: BoundSide::After },
+            Some(b) => MemClusterBound {
+                prefix: b.cluster_key_prefix.iter().map(|c| c.to_vec()).collect(),
+                side: BoundSide::of_upper(b.is_inclusive),
+            },
+        }
+    }
+
+    pub fn compare_with_key(&self, comparator: &ClusterKeyComparator, cluster_key: &[Vec<u8>]) -> Ordering {
+        comparator.compare_bound_with_key(&self.prefix, self.side, cluster_key)
+    }
+
+    pub fn compare(&self, comparator: &ClusterKeyComparator, other: &MemClusterBound) -> Ordering {
+        comparator.compare_bounds(&self.prefix, self.side, &other.prefix, other.side)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemRangeTombstone {
+    pub start: MemClusterBound,
+    pub end: MemClusterBound,
+    pub timestamp: DbTimestamp,
+}
+
+impl MemRangeTombstone {
+    pub fn contains(&self, comparator: &ClusterKeyComparator, cluster_key: &[Vec<u8>]) -> bool {
+        self.start.compare_with_key(comparator, cluster_key) == Ordering::Less && self.end.compare_with_key(comparator, cluster_key) == Ordering::Greater
+    }
+
+    fn size_in_bytes(&self) -> usize {
+        RANGE_TOMBSTONE_OVERHEAD
+            + self.start.prefix.iter().map(|c| c.len()).sum::<usize>()
+            + self.end.prefix.iter().map(|c| c.len()).sum::<usize>()
+    }
+}
+
+impl From<&RowTombstoneData<'_>> for MemRangeTombstone {
+    fn from(data: &RowTombstoneData) -> MemRangeTombstone {
+        MemRangeTombstone {
+            start: MemClusterBound::lower(&data.lower_bound),
+            end: MemClusterBound::upper(&data.upper_bound),
+            timestamp: data.timestamp,
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+    use std::convert::TryInto;
+    use std::sync::Arc;
+
+    use crate::db::{BoundSide, ColumnType, KeyBound, RegularRowData, RowDetails, RowTombstoneData, TableCell, TableCellData, TableMetaData, TableOptions, TableRow};
+    use crate::memtable::{MemClusterBound, MemRangeTombstone, Memtable};
+    use crate::sstable::testing::clustered_table_metadata;
+    use crate::util::{DbTimestamp, NO_EXPIRY};
+
+    fn table_metadata() -> Arc<TableMetaData> {
+        clustered_table_metadata(&[("value", ColumnType::Text)], TableOptions::default())
+    }
+
+    fn key(n: u64) -> Vec<u8> {
+        n.to_be_bytes().to_vec()
+    }
+
+    fn apply_cell(memtable: &mut Memtable, id: u64, ck: u64, timestamp: DbTimestamp, value: Option<&str>) {
+        let table_metadata = memtable.table_metadata().clone();
+        let id = key(id);
+        let ck = key(ck);
+        let value = value.map(|v| [(v.len() as u32).to_be_bytes().to_vec(), v.as_bytes().to_vec()].concat());
+        let cell = TableCell {
+            meta_data: table_metadata.columns[2].clone(),
+            path: None,
+            timestamp,
+            expiry: NO_EXPIRY,
+            data: match &value {
+                None => TableCellData::Tombstone,
+                Some(v) => TableCellData::Regular(v),
+            },
+        };
+        memtable.apply(&TableRow::new(table_metadata, &id, RowDetails::Regular(RegularRowData {
+            pk_expiry: NO_EXPIRY,
+            cluster_key: vec!(&ck),
+            regular_cols: vec!(cell),
+        }))).unwrap();
+    }
+
+    fn apply_tombstone(memtable: &mut Memtable, id: u64, lower: Option<(u64, bool)>, upper: Option<(u64, bool)>, timestamp: DbTimestamp) {
+        let table_metadata = memtable.table_metadata().clone();
+        let id = key(id);
+        let lower_key = lower.map(|(k,_)| key(k));
+        let upper_key = upper.map(|(k,_)| key(k));
+
+        memtable.apply(&TableRow::new(table_metadata, &id, RowDetails::RowTombstone(RowTombstoneData {
+            timestamp,
+            lower_bound: lower.map(|(_, is_inclusive)| KeyBound { cluster_key_prefix: vec!(lower_key.as_ref().unwrap()), is_inclusive }),
+            upper_bound: upper.map(|(_, is_inclusive)| KeyBound { cluster_key_prefix: vec!(upper_key.as_ref().unwrap()), is_inclusive }),
+        }))).unwrap();
+    }
+
+    /// (cluster key, timestamp, value) of a partition's rows' single cell
+    fn cells(memtable: &Memtable, id: u64) -> Vec<(u64, DbTimestamp, Option<String>)> {
+        let id = key(id);
+        let partition = memtable.partition(fasthash::murmur3::hash128(&id), &id).unwrap();
+        partition.rows()
+            .map(|(ck, row)| {
+                let cell = row.cells.values().next().unwrap();
+                (
+                    u64::from_be_bytes(ck[0].as_slice().try_into().unwrap()),
+                    cell.timestamp,
+                    cell.data.as_ref().map(|d| String::from_utf8(d[4..].to_vec()).unwrap()),
+                )
+            })
+            .collect()
+    }
+
+    fn bound(ck: u64, side: BoundSide) -> MemClusterBound {
+        MemClusterBound { prefix: vec!(key(ck)), side }
+    }
+
+    #[test]
+    pub fn test_sort_order() {
+        let mut memtable = Memtable::new(table_metadata());
+        for id in (0..20).rev() {
+            for ck in [5, 1, 3, 2, 4].iter() {
+                apply_cell(&mut memtable, id, *ck, 10, Some("x"));
+            }
+        }
+
+        let tokens: Vec<u128> = memtable.partitions().map(|(token, _, _)| token).collect();
+        let mut sorted_tokens = tokens.clone();
+        sorted_tokens.sort();
+        assert_eq!(20, tokens.len());
+        assert_eq!(sorted_tokens, tokens);
+
+        for (_, _, partition) in memtable.partitions() {
+            let cluster_keys: Vec<Vec<u8>> = partition.rows().map(|(ck, _)| ck[0].clone()).collect();
+            assert_eq!((1..=5).map(key).collect::<Vec<_>>(), cluster_keys);
+        }
+    }
+
+    #[test]
+    pub fn test_newest_cell_wins() {
+        let mut memtable = Memtable::new(table_metadata());
+
+        apply_cell(&mut memtable, 1, 1, 10, Some("a"));
+        apply_cell(&mut memtable, 1, 1, 5, Some("b"));
+        assert_eq!(vec!((1, 10, Some("a".to_string()))), cells(&memtable, 1));
+
+        apply_cell(&mut memtable, 1, 1, 20, Some("c"));
+        assert_eq!(vec!((1, 20, Some("c".to_string()))), cells(&memtable, 1));
+
+        // for equal timestamps, tombstones win
+        apply_cell(&mut memtable, 1, 1, 20, None);
+        assert_eq!(vec!((1, 20, None)), cells(&memtable, 1));
+        apply_cell(&mut memtable, 1, 1, 20, Some("d"));
+        assert_eq!(vec!((1, 20, None)), cells(&memtable, 1));
+
+        apply_cell(&mut memtable, 1, 1, 21, Some("e"));
+        assert_eq!(vec!((1, 21, Some("e".to_string()))), cells(&memtable, 1));
+    }
+
+    #[test]
+    pub fn test_range_tombstone_shadows_rows() {
+        let mut memtable = Memtable::new(table_metadata());
+        for ck in 1..=10 {
+            apply_cell(&mut memtable, 1, ck, 10, Some("x"));
+        }
+
+        // [3, 7)
+        apply_tombstone(&mut memtable, 1, Some((3, true)), Some((7, false)), 15);
+        assert_eq!(vec!(1, 2, 7, 8, 9, 10), cells(&memtable, 1).iter().map(|c| c.0).collect::<Vec<_>>());
+
+        // older writes inside the tombstone are shadowed, newer ones are not
+        apply_cell(&mut memtable, 1, 4, 12, Some("y"));
+        apply_cell(&mut memtable, 1, 5, 20, Some("z"));
+        assert_eq!(vec!(1, 2, 5, 7, 8, 9, 10), cells(&memtable, 1).iter().map(|c| c.0).collect::<Vec<_>>());
+
+        // partition tombstone
+        apply_tombstone(&mut memtable, 1, None, None, 16);
+        assert_eq!(vec!((5, 20, Some("z".to_string()))), cells(&memtable, 1));
+    }
+
+    #[test]
+    pub fn test_overlapping_range_tombstones() {
+        let mut memtable = Memtable::new(table_metadata());
+        apply_tombstone(&mut memtable, 1, Some((1, true)), Some((5, true)), 10);
+        apply_tombstone(&mut memtable, 1, Some((3, true)), Some((8, true)), 20);
+        apply_tombstone(&mut memtable, 1, Some((6, false)), Some((7, true)), 5);
+        apply_tombstone(&mut memtable, 1, Some((9, true)), None, 20);
+
+        let id = key(1);
+        let partition = memtable.partition(fasthash::murmur3::hash128(&id), &id).unwrap();
+        assert_eq!(&[
+            MemRangeTombstone { start: bound(1, BoundSide::Before), end: bound(3, BoundSide::Before), timestamp: 10 },
+            MemRangeTombstone { start: bound(3, BoundSide::Before), end: bound(8, BoundSide::After), timestamp: 20 },
+            MemRangeTombstone { start: bound(9, BoundSide::Before), end: MemClusterBound { prefix: Vec::new(), side: BoundSide::After }, timestamp: 20 },
+        ], partition.range_tombstones());
+
+        assert_eq!(None, partition.tombstone_timestamp(&[key(0)]));
+        assert_eq!(Some(10), partition.tombstone_timestamp(&[key(2)]));
+        assert_eq!(Some(20), partition.tombstone_timestamp(&[key(3)]));
+        assert_eq!(Some(20), partition.tombstone_timestamp(&[key(8)]));
+        assert_eq!(Some(20), partition.tombstone_timestamp(&[key(1000)]));
+    }
+
+    #[test]
+    pub fn test_many_range_tombstones() {
+        let mut memtable = Memtable::new(table_metadata());
+        let mut expected: Vec<Option<DbTimestamp>> = vec!(None; 110);
+        for n in 0..200u64 {
+            let start = (n * 37) % 97;
+            let end = start + (n * 13) % 11;
+            let timestamp = (n * 7919) % 50;
+            apply_tombstone(&mut memtable, 1, Some((start, true)), Some((end, n % 2 == 0)), timestamp);
+
+            let covered_until = if n % 2 == 0 { end + 1 } else { end };
+            for ck in start..covered_until {
+                expected[ck as usize] = expected[ck as usize].max(Some(timestamp));
+            }
+        }
+
+        let id = key(1);
+        let partition = memtable.partition(fasthash::murmur3::hash128(&id), &id).unwrap();
+        for (ck, timestamp) in expected.iter().enumerate() {
+            assert_eq!(*timestamp, partition.tombstone_timestamp(&[key(ck as u64)]), "cluster key {}", ck);
+        }
+
+        // sorted, non-overlapping, and adjacent pieces with the same timestamp are merged
+        let comparator = memtable.table_metadata().cluster_key_comparator().clone();
+        for w in partition.range_tombstones().windows(2) {
+            match w[0].end.compare(&comparator, &w[1].start) {
+                Ordering::Less => {},
+                Ordering::Equal => assert_ne!(w[0].timestamp, w[1].timestamp),
+                Ordering::Greater => panic!("overlapping range tombstones {:?}", w),
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_size_in_bytes() {
+        let mut memtable = Memtable::new(table_metadata());
+        assert_eq!(0, memtable.size_in_bytes());
+        assert!(memtable.is_empty());
+
+        apply_cell(&mut memtable, 1, 1, 10, Some("abc"));
+        let size_one_row = memtable.size_in_bytes();
+        assert!(size_one_row > 0);
+
+        // overwriting with a value of the same size does not change the footprint
+        apply_cell(&mut memtable, 1, 1, 11, Some("def"));
+        assert_eq!(size_one_row, memtable.size_in_bytes());
+
+        apply_cell(&mut memtable, 1, 1, 12, Some("a much longer value"));
+        assert_eq!(size_one_row + 16, memtable.size_in_bytes());
+
+        apply_cell(&mut memtable, 1, 2, 10, Some("abc"));
+        apply_cell(&mut memtable, 2, 1, 10, Some("abc"));
+        let size_three_rows = memtable.size_in_bytes();
+        assert!(size_three_rows > size_one_row + 16);
+
+        // dropping the data in partition 1 frees it, the tombstone itself takes some room
+        apply_tombstone(&mut memtable, 1, None, None, 20);
+        assert!(memtable.size_in_bytes() < size_three_rows);
+    }
+
+    #[test]
+    pub fn test_apply_validates_row() {
+        let mut memtable = Memtable::new(table_metadata());
+        let table_metadata = memtable.table_metadata().clone();
+        let id = key(1);
+        let ck = vec!(0u8; 3);
+        let row = TableRow::new(table_metadata, &id, RowDetails::Regular(RegularRowData {
+            pk_expiry: NO_EXPIRY,
+            cluster_key: vec!(&ck),
+            regular_cols: Vec::new(),
+        }));
+        assert!(memtable.apply(&row).is_err());
+        assert!(memtable.is_empty());
+    }
+
+    #[test]
+    pub fn test_collections() {
+        let table_metadata = clustered_table_metadata(&[
+            ("tags", ColumnType::Map(Box::new(ColumnType::Text), Box::new(ColumnType::Text))),
+            ("numbers", ColumnType::Set(Box::new(ColumnType::Int))),
+        ], TableOptions::default());
+        let mut memtable = Memtable::new(table_metadata.clone());
+
+        let text = |s: &str| [(s.len() as u32).to_be_bytes().as_ref(), s.as_bytes()].concat();
+        let apply_cell = |memtable: &mut Memtable, column: usize, path: Option<Vec<u8>>, timestamp: DbTimestamp, value: Option<Vec<u8>>| {
+            let id = key(1);
+            let ck = key(1);
+            memtable.apply(&TableRow::new(table_metadata.clone(), &id, RowDetails::Regular(RegularRowData {
+                pk_expiry: NO_EXPIRY,
+                cluster_key: vec!(&ck),
+                regular_cols: vec!(TableCell {
+                    meta_data: table_metadata.columns[column].clone(),
+                    path: path.as_deref(),
+                    timestamp,
+                    expiry: NO_EXPIRY,
+                    data: match &value {
+                        None => TableCellData::Tombstone,
+                        Some(v) => TableCellData::Regular(v),
+                    },
+                }),
+            }))).unwrap();
+        };
+        let apply_element = |memtable: &mut Memtable, path: Option<&str>, timestamp: DbTimestamp, value: Option<&str>| {
+            apply_cell(memtable, 2, path.map(text), timestamp, value.map(text));
+        };
+        let elements = |memtable: &Memtable| -> Vec<(Option<String>, DbTimestamp, Option<String>)> {
+            let id = key(1);
+            let partition = memtable.partition(fasthash::murmur3::hash128(&id), &id).unwrap();
+            let as_string = |v: &Vec<u8>| String::from_utf8(v[4..].to_vec()).unwrap();
+            partition.rows()
+                .flat_map(|(_, row)| row.cells.values())
+                .filter(|c| c.meta_data.id == table_metadata.columns[2].id)
+                .map(|c| (c.path.as_ref().map(as_string), c.timestamp, c.data.as_ref().map(as_string)))
+                .collect()
+        };
+
+        // single elements are updated and deleted independently
+        apply_element(&mut memtable, Some("a"), 10, Some("1"));
+        apply_element(&mut memtable, Some("b"), 10, Some("2"));
+        apply_element(&mut memtable, Some("b"), 11, Some("3"));
+        apply_element(&mut memtable, Some("a"), 12, None);
+        assert_eq!(vec!((Some("a".to_string()), 12, None), (Some("b".to_string()), 11, Some("3".to_string()))), elements(&memtable));
+
+        // overwriting the whole collection drops all older elements
+        apply_element(&mut memtable, None, 19, None);
+        apply_element(&mut memtable, Some("c"), 20, Some("4"));
+        apply_element(&mut memtable, Some("b"), 15, Some("5"));
+        assert_eq!(vec!((None, 19, None), (Some("c".to_string()), 20, Some("4".to_string()))), elements(&memtable));
+
+        // an overwrite's tombstone does not shadow the new elements that share its timestamp
+        let (id, ck, path, value) = (key(1), key(1), text("d"), text("6"));
+        let cell = |path: Option<&'static [u8]>, data| TableCell { meta_data: table_metadata.columns[2].clone(), path, timestamp: 30, expiry: NO_EXPIRY, data };
+        memtable.apply(&TableRow::new(table_metadata.clone(), &id, RowDetails::Regular(RegularRowData {
+            pk_expiry: NO_EXPIRY,
+            cluster_key: vec!(&ck),
+            regular_cols: vec!(cell(None, TableCellData::Tombstone), TableCell { path: Some(&path), ..cell(None, TableCellData::Regular(&value)) }),
+        }))).unwrap();
+        assert_eq!(vec!((None, 29, None), (Some("d".to_string()), 30, Some("6".to_string()))), elements(&memtable));
+
+        // elements are ordered by their values rather than their serialized bytes
+        for n in [1000i32, -1, 2, -300].iter() {
+            apply_cell(&mut memtable, 3, Some(n.to_be_bytes().to_vec()), 10, Some(Vec::new()));
+        }
+        let id = key(1);
+        let numbers: Vec<i32> = memtable.partition(fasthash::murmur3::hash128(&id), &id).unwrap().rows()
+            .flat_map(|(_, row)| row.cells.values())
+            .filter(|c| c.meta_data.id == table_metadata.columns[3].id)
+            .map(|c| i32::from_be_bytes(c.path.as_deref().unwrap().try_into().unwrap()))
+            .collect();
+        assert_eq!(vec!(-300, -1, 2, 1000), numbers);
+    }
+}
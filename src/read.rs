@@ -0,0 +1,452 @@
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+use crate::db::{KeyBound, RowDetails, TableMetaData};
+use crate::io::DecodeError;
+use crate::memtable::{MemClusterBound, MemPartition, MemRangeTombstone, Memtable};
+use crate::sstable::header::CLUSTER_ORDER_VERSION;
+use crate::sstable::row_data::RowDataReader;
+use crate::sstable::Sstable;
+use crate::util::{expiry_now, Token};
+
+
+/// the rows of a partition between two key bounds, `None` bounds are unlimited
+pub struct ClusterSlice<'a> {
+    pub lower_bound: Option<KeyBound<'a>>,
+    pub upper_bound: Option<KeyBound<'a>>,
+}
+
+/// Reads a partition's data from memtables and sstables, reconciling it into a single result. The
+///  result contains the tombstones that shadow data, and expired cells are returned as tombstones.
+///  Returns `None` if neither memtables nor sstables have data for the partition.
+///
+/// Only the rows in `slice` are returned (all rows for `None`), and range tombstones are cut at
+///  the slice's bounds.
+pub fn read_partition(table_metadata: &Arc<TableMetaData>, memtables: &[&Memtable], sstables: &[Arc<Sstable>], partition_key: &[u8], slice: Option<&ClusterSlice>) -> std::io::Result<Option<MemPartition>> {
+    let token = fasthash::murmur3::hash128(partition_key);
+    let (start, end) = match slice {
+        None => (MemClusterBound::lower(&None), MemClusterBound::upper(&None)),
+        Some(slice) => (MemClusterBound::lower(&slice.lower_bound), MemClusterBound::upper(&slice.upper_bound)),
+    };
+
+    let new_partition = || MemPartition::new(table_metadata.cluster_key_comparator().clone());
+    let mut result: Option<MemPartition> = None;
+    for memtable in memtables {
+        if let Some(partition) = memtable.partition(token, partition_key) {
+            result.get_or_insert_with(new_partition).merge(&partition.slice(&start, &end));
+        }
+    }
+    for sstable in sstables {
+        if let Some(partition) = read_sstable_partition(table_metadata, sstable, token, partition_key, &start, &end)? {
+            result.get_or_insert_with(new_partition).merge(&partition);
+        }
+    }
+
+    Ok(result.map(|mut partition| {
+        partition.purge(expiry_now(), 0, 0);
+        partition.slice(&start, &end)
+    }))
+}
+
+/// Uses the sstable's bloom filter and partition index to skip it if it has no data for the
+///  partition. Rows are stored in cluster key order, so reading stops at the end of the slice -
+///  except for sstables written before `CLUSTER_ORDER_VERSION`, whose partitions are read whole.
+fn read_sstable_partition(table_metadata: &Arc<TableMetaData>, sstable: &Sstable, token: Token, partition_key: &[u8], start: &MemClusterBound, end: &MemClusterBound) -> std::io::Result<Option<MemPartition>> {
+    if !sstable.filter()?.might_contain(token) {
+        return Ok(None);
+    }
+
+    let index = sstable.index()?;
+    let in_index_file = |e: DecodeError| e.in_file(&sstable.meta_data().index_filename());
+    let offset = match index.find(token, partition_key).map_err(in_index_file)? {
+        None => return Ok(None),
+        Some(entry) => entry.offset as usize,
+    };
+    // only the chunks holding the partition are decompressed
+    let end_offset = index.next_partition_offset(token, partition_key).map_err(in_index_file)?;
+
+    let end_offset = end_offset.map(|o| o as usize);
+
+    let contents = sstable.read_data_range(offset, end_offset)?;
+    let is_cluster_ordered = contents.version() >= CLUSTER_ORDER_VERSION;
+    let comparator = table_metadata.cluster_key_comparator();
+    let mut partition = MemPartition::new(comparator.clone());
+    let data_filename = sstable.meta_data().data_filename();
+    let mut reader = RowDataReader::for_contents(table_metadata.clone(), &contents, offset)
+        .map_err(|e| e.in_file(&data_filename))?
+        .in_file(&data_filename)
+        .with_checksums(sstable.checksums());
+    while !reader.is_at_end() && end_offset.is_none_or(|end| reader.position() < end) {
+        let row = reader.read_row()?;
+        if row.token != token || row.partition_key != partition_key {
+            break;
+        }
+
+        match &row.details {
+            RowDetails::Regular(data) => {
+                let cluster_key: Vec<Vec<u8>> = data.cluster_key.iter().map(|c| c.to_vec()).collect();
+                if end.compare_with_key(comparator, &cluster_key) != Ordering::Greater {
+                    if is_cluster_ordered {
+                        break;
+                    }
+                    continue;
+                }
+                if start.compare_with_key(comparator, &cluster_key) != Ordering::Less {
+                    continue;
+                }
+            },
+            RowDetails::RowTombstone(data) => {
+                let tombstone = MemRangeTombstone::from(data);
+                if tombstone.start.compare(comparator, end) != Ordering::Less {
+                    if is_cluster_ordered {
+                        break;
+                    }
+                    continue;
+                }
+                if tombstone.end.compare(comparator, start) != Ordering::Greater {
+                    continue;
+                }
+            },
+        }
+        partition.apply(&row.details);
+    }
+    Ok(Some(partition))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    use uuid::Uuid;
+
+    use crate::db::{ClusteringOrder, ColumnMetaData, ColumnType, KeyBound, KeyspaceMetaData, RegularRowData, RowDetails, RowTombstoneData, TableCell, TableCellData, TableMetaData, TableOptions, TableRow, UserType, UserTypeField};
+    use crate::io::CassWrite;
+    use crate::memtable::{MemPartition, Memtable};
+    use crate::read::{read_partition, ClusterSlice};
+    use crate::sstable::flush::flush_memtable;
+    use crate::sstable::header::{Component, ComponentHeader};
+    use crate::sstable::testing::{clustered_table_metadata, column, id_column, TempFolder};
+    use crate::sstable::{Sstable, SstableMetaData};
+    use crate::util::{new_list_element_path, DbExpiryTimestamp, DbTimestamp, NO_EXPIRY};
+
+    fn table_metadata() -> Arc<TableMetaData> {
+        clustered_table_metadata(&[("v", ColumnType::Long)], TableOptions::default())
+    }
+
+    fn key(n: u64) -> Vec<u8> {
+        n.to_be_bytes().to_vec()
+    }
+
+    fn apply_cell(memtable: &mut Memtable, id: u64, ck: u64, value: u64, timestamp: DbTimestamp, expiry: DbExpiryTimestamp) {
+        let table_metadata = memtable.table_metadata().clone();
+        let id = key(id);
+        let ck = key(ck);
+        let value = key(value);
+        memtable.apply(&TableRow::new(table_metadata.clone(), &id, RowDetails::Regular(RegularRowData {
+            pk_expiry: expiry,
+            cluster_key: vec!(&ck),
+            regular_cols: vec!(TableCell {
+                meta_data: table_metadata.columns[2].clone(),
+                path: None,
+                timestamp,
+                expiry,
+                data: TableCellData::Regular(&value),
+            }),
+        }))).unwrap();
+    }
+
+    fn apply_tombstone(memtable: &mut Memtable, id: u64, lower: u64, upper: u64, timestamp: DbTimestamp) {
+        let table_metadata = memtable.table_metadata().clone();
+        let id = key(id);
+        let lower = key(lower);
+        let upper = key(upper);
+        memtable.apply(&TableRow::new(table_metadata, &id, RowDetails::RowTombstone(RowTombstoneData {
+            timestamp,
+            lower_bound: Some(KeyBound { cluster_key_prefix: vec!(&lower), is_inclusive: true }),
+            upper_bound: Some(KeyBound { cluster_key_prefix: vec!(&upper), is_inclusive: false }),
+        }))).unwrap();
+    }
+
+    fn flush(folder: &TempFolder, memtable: &Memtable) -> Arc<Sstable> {
+        flush_memtable(memtable, SstableMetaData::new(memtable.table_metadata().clone(), folder.path())).unwrap()
+    }
+
+    /// (cluster key, value) of all live cells
+    fn live_cells(partition: &MemPartition) -> Vec<(u64, u64)> {
+        let as_u64 = |v: &[u8]| {
+            let mut buf = [0u8; 8];
+            buf.copy_from_slice(v);
+            u64::from_be_bytes(buf)
+        };
+
+        partition.rows()
+            .flat_map(|(ck, row)| row.cells.values().filter_map(move |c| c.data.as_ref().map(|d| (as_u64(&ck[0]), as_u64(d)))))
+            .collect()
+    }
+
+    #[test]
+    pub fn test_read_partition() {
+        let folder = TempFolder::new();
+        let table_metadata = table_metadata();
+
+        // rows 0..10 of partition 1, overwritten in the next generation and partially deleted
+        let mut old = Memtable::new(table_metadata.clone());
+        for ck in 0..10 {
+            apply_cell(&mut old, 1, ck, 100 + ck, 10, NO_EXPIRY);
+            apply_cell(&mut old, 2, ck, 200 + ck, 10, NO_EXPIRY);
+        }
+        let old = flush(&folder, &old);
+
+        let mut newer = Memtable::new(table_metadata.clone());
+        for ck in 0..5 {
+            apply_cell(&mut newer, 1, ck, 1000 + ck, 20, NO_EXPIRY);
+        }
+        apply_tombstone(&mut newer, 1, 3, 7, 30);
+        let newer = flush(&folder, &newer);
+
+        let mut memtable = Memtable::new(table_metadata.clone());
+        apply_cell(&mut memtable, 1, 4, 2004, 40, NO_EXPIRY);
+        apply_cell(&mut memtable, 1, 8, 2008, 40, 1);
+
+        let sstables = vec!(old, newer);
+        let read = |partition_key: u64, slice: Option<&ClusterSlice>| {
+            read_partition(&table_metadata, &[&memtable], &sstables, &key(partition_key), slice).unwrap()
+        };
+
+        let partition = read(1, None).unwrap();
+        assert_eq!(vec!((0, 1000), (1, 1001), (2, 1002), (4, 2004), (7, 107), (9, 109)), live_cells(&partition));
+        assert_eq!(1, partition.range_tombstones().len());
+        // the expired cell is returned as a tombstone
+        assert_eq!(Some(None), partition.rows().find(|(ck, _)| ck[0] == key(8)).map(|(_, row)| row.cells.values().next().unwrap().data.clone()));
+
+        let lower = key(2);
+        let upper = key(5);
+        let slice = ClusterSlice {
+            lower_bound: Some(KeyBound { cluster_key_prefix: vec!(&lower), is_inclusive: false }),
+            upper_bound: Some(KeyBound { cluster_key_prefix: vec!(&upper), is_inclusive: true }),
+        };
+        let partition = read(1, Some(&slice)).unwrap();
+        assert_eq!(vec!((4, 2004)), live_cells(&partition));
+        let tombstone = &partition.range_tombstones()[0];
+        assert_eq!((vec!(key(3)), vec!(key(5))), (tombstone.start.prefix.clone(), tombstone.end.prefix.clone()));
+
+        assert_eq!((0..10).map(|ck| (ck, 200 + ck)).collect::<Vec<_>>(), live_cells(&read(2, None).unwrap()));
+        assert!(read(3, None).is_none());
+    }
+
+    #[test]
+    pub fn test_user_type_schema_evolution() {
+        let folder = TempFolder::new();
+        let mut keyspace = KeyspaceMetaData::new("ks".to_string());
+        let address_v1 = keyspace.create_user_type("address", vec!(
+            UserTypeField { name: "street".to_string(), field_type: ColumnType::Text },
+            UserTypeField { name: "zip".to_string(), field_type: ColumnType::Int },
+        )).unwrap();
+
+        let table_id = Uuid::new_v4();
+        let column_ids: Vec<Uuid> = (0..3).map(|_| Uuid::new_v4()).collect();
+        let table_with = |address: Arc<UserType>| {
+            let col = |idx: usize, name: &str, col_type: ColumnType| Arc::new(ColumnMetaData {
+                name: name.to_string(),
+                id: column_ids[idx],
+                col_type,
+            });
+            let columns = vec!(
+                col(0, "addr", ColumnType::UserType(address)),
+                col(1, "ck", ColumnType::Long),
+                col(2, "id", ColumnType::Long),
+            );
+            Arc::new(TableMetaData::new("t".to_string(), table_id, columns, 2, vec!(1)))
+        };
+        let text = |s: &str| [(s.len() as u32).to_be_bytes().to_vec(), s.as_bytes().to_vec()].concat();
+        let apply_field = |memtable: &mut Memtable, field: &str, value: &[u8], timestamp: DbTimestamp| {
+            let table_metadata = memtable.table_metadata().clone();
+            let path = match &table_metadata.columns[0].col_type {
+                ColumnType::UserType(user_type) => user_type.field_path(field).unwrap(),
+                _ => panic!("user type expected"),
+            };
+            let id = key(1);
+            let ck = key(1);
+            memtable.apply(&TableRow::new(table_metadata.clone(), &id, RowDetails::Regular(RegularRowData {
+                pk_expiry: NO_EXPIRY,
+                cluster_key: vec!(&ck),
+                regular_cols: vec!(TableCell {
+                    meta_data: table_metadata.columns[0].clone(),
+                    path: Some(&path),
+                    timestamp,
+                    expiry: NO_EXPIRY,
+                    data: TableCellData::Regular(value),
+                }),
+            }))).unwrap();
+        };
+
+        let table_v1 = table_with(address_v1);
+        let mut memtable = Memtable::new(table_v1.clone());
+        apply_field(&mut memtable, "street", &text("Main St"), 10);
+        apply_field(&mut memtable, "zip", &12345i32.to_be_bytes(), 10);
+        let old = flush(&folder, &memtable);
+
+        // the sstable written before the field was added is read with the new version of the type
+        let address_v2 = keyspace.add_user_type_field("address", UserTypeField { name: "city".to_string(), field_type: ColumnType::Text }).unwrap();
+        assert!(keyspace.add_user_type_field("address", UserTypeField { name: "city".to_string(), field_type: ColumnType::Text }).is_err());
+        let table_v2 = table_with(address_v2);
+        let mut memtable = Memtable::new(table_v2.clone());
+        apply_field(&mut memtable, "city", &text("Springfield"), 20);
+        apply_field(&mut memtable, "zip", &54321i32.to_be_bytes(), 20);
+
+        let partition = read_partition(&table_v2, &[&memtable], &[old], &key(1), None).unwrap().unwrap();
+        let (_, row) = partition.rows().next().unwrap();
+        let field = |name: &str| row.user_type_field(&table_v2.columns[0], name).and_then(|c| c.data.clone());
+        assert_eq!(Some(text("Main St")), field("street"));
+        assert_eq!(Some(54321i32.to_be_bytes().to_vec()), field("zip"));
+        assert_eq!(Some(text("Springfield")), field("city"));
+        assert_eq!(None, field("country"));
+
+        // data with the new field can not be read with the old version of the type
+        let newer = flush(&folder, &memtable);
+        assert!(read_partition(&table_v1, &[], &[newer], &key(1), None).is_err());
+    }
+
+    #[test]
+    pub fn test_descending_cluster_key() {
+        let folder = TempFolder::new();
+        let columns = vec!(
+            column("ck", ColumnType::Int),
+            id_column(),
+            column("v", ColumnType::Long),
+        );
+        let table_metadata = Arc::new(TableMetaData::new("t".to_string(), Uuid::new_v4(), columns, 1, vec!(0))
+            .with_clustering_orders(vec!(ClusteringOrder::Descending)));
+
+        let id = key(1);
+        let int = |n: i32| n.to_be_bytes().to_vec();
+        let mut memtable = Memtable::new(table_metadata.clone());
+        for ck in -3..=3 {
+            let ck = int(ck);
+            let value = key(10);
+            memtable.apply(&TableRow::new(table_metadata.clone(), &id, RowDetails::Regular(RegularRowData {
+                pk_expiry: NO_EXPIRY,
+                cluster_key: vec!(&ck),
+                regular_cols: vec!(TableCell {
+                    meta_data: table_metadata.columns[2].clone(),
+                    path: None,
+                    timestamp: 10,
+                    expiry: NO_EXPIRY,
+                    data: TableCellData::Regular(&value),
+                }),
+            }))).unwrap();
+        }
+        // bounds are in clustering order, i.e. from 1 down to -1
+        let (lower, upper) = (int(1), int(-1));
+        memtable.apply(&TableRow::new(table_metadata.clone(), &id, RowDetails::RowTombstone(RowTombstoneData {
+            timestamp: 20,
+            lower_bound: Some(KeyBound { cluster_key_prefix: vec!(&lower), is_inclusive: true }),
+            upper_bound: Some(KeyBound { cluster_key_prefix: vec!(&upper), is_inclusive: false }),
+        }))).unwrap();
+        let sstable = flush(&folder, &memtable);
+
+        let cluster_keys = |partition: &MemPartition| -> Vec<i32> {
+            partition.rows().map(|(ck, _)| i32::from_be_bytes(ck[0].as_slice().try_into().unwrap())).collect()
+        };
+        let partition = read_partition(&table_metadata, &[], std::slice::from_ref(&sstable), &id, None).unwrap().unwrap();
+        assert_eq!(vec!(3, 2, -1, -2, -3), cluster_keys(&partition));
+
+        let (from, to) = (int(2), int(-2));
+        let slice = ClusterSlice {
+            lower_bound: Some(KeyBound { cluster_key_prefix: vec!(&from), is_inclusive: false }),
+            upper_bound: Some(KeyBound { cluster_key_prefix: vec!(&to), is_inclusive: true }),
+        };
+        let partition = read_partition(&table_metadata, &[&memtable], &[sstable], &id, Some(&slice)).unwrap().unwrap();
+        assert_eq!(vec!(-1, -2), cluster_keys(&partition));
+        assert_eq!(1, partition.range_tombstones().len());
+    }
+
+    #[test]
+    pub fn test_read_format_version_1() {
+        let folder = TempFolder::new();
+        let table_metadata = table_metadata();
+
+        // version 1 did not store rows in cluster key order: an sstable written with Long cluster
+        //  keys holds the bits of Double keys in a different order than the Double comparator's
+        let doubles = [-2.0f64, -1.0, -0.5, 0.5, 1.0];
+        let mut memtable = Memtable::new(table_metadata.clone());
+        for (idx, d) in doubles.iter().enumerate() {
+            apply_cell(&mut memtable, 1, d.to_bits(), idx as u64, 10, NO_EXPIRY);
+        }
+        let sstable = flush(&folder, &memtable);
+
+        let mut columns = table_metadata.columns.clone();
+        columns[0] = Arc::new(ColumnMetaData { name: "ck".to_string(), id: columns[0].id, col_type: ColumnType::Double });
+        let table_metadata = Arc::new(TableMetaData::new("t".to_string(), table_metadata.id, columns, 1, vec!(0)));
+        let data_filename = sstable.meta_data().data_filename();
+        let data = std::fs::read(&data_filename).unwrap();
+        let body_offset = ComponentHeader::read(&data, Component::Data).unwrap().body_offset();
+        let mut out = CassWrite::new(Cursor::new(Vec::new()));
+        ComponentHeader::write_version(&mut out, Component::Data, &table_metadata, 1).unwrap();
+        out.write_raw(&data[body_offset..]).unwrap();
+        std::fs::write(&data_filename, out.into_inner().into_inner()).unwrap();
+        let sstable = Sstable::open(SstableMetaData::existing(table_metadata.clone(), folder.path(), sstable.uuid())).unwrap();
+
+        let id = key(1);
+        let read = |slice: Option<&ClusterSlice>| {
+            let partition = read_partition(&table_metadata, &[], std::slice::from_ref(&sstable), &id, slice).unwrap().unwrap();
+            live_cells(&partition).into_iter().map(|(ck, _)| f64::from_bits(ck)).collect::<Vec<_>>()
+        };
+        assert_eq!(doubles.to_vec(), read(None));
+
+        // the first row in the sstable, -0.5, is past the end of the slice
+        let lower = (-2.0f64).to_bits().to_be_bytes();
+        let upper = (-1.0f64).to_bits().to_be_bytes();
+        let slice = ClusterSlice {
+            lower_bound: Some(KeyBound { cluster_key_prefix: vec!(&lower), is_inclusive: true }),
+            upper_bound: Some(KeyBound { cluster_key_prefix: vec!(&upper), is_inclusive: true }),
+        };
+        assert_eq!(vec!(-2.0, -1.0), read(Some(&slice)));
+    }
+
+    #[test]
+    pub fn test_list_elements_keep_insertion_order() {
+        let folder = TempFolder::new();
+        let table_metadata = clustered_table_metadata(&[("numbers", ColumnType::List(Box::new(ColumnType::Int)))], TableOptions::default());
+
+        let id = key(1);
+        let append = |memtable: &mut Memtable, n: i32| {
+            let ck = key(0);
+            let path = new_list_element_path();
+            let value = n.to_be_bytes();
+            memtable.apply(&TableRow::new(table_metadata.clone(), &id, RowDetails::Regular(RegularRowData {
+                pk_expiry: NO_EXPIRY,
+                cluster_key: vec!(&ck),
+                regular_cols: vec!(TableCell {
+                    meta_data: table_metadata.columns[2].clone(),
+                    path: Some(path.as_bytes()),
+                    timestamp: 10,
+                    expiry: NO_EXPIRY,
+                    data: TableCellData::Regular(&value),
+                }),
+            }))).unwrap();
+        };
+        let elements = |partition: &MemPartition| -> Vec<i32> {
+            partition.rows()
+                .flat_map(|(_, row)| row.cells.values())
+                .map(|c| i32::from_be_bytes(c.data.as_deref().unwrap().try_into().unwrap()))
+                .collect()
+        };
+
+        let mut flushed = Memtable::new(table_metadata.clone());
+        for n in [5, -1, 300, 2].iter() {
+            append(&mut flushed, *n);
+        }
+        let sstable = flush(&folder, &flushed);
+        let mut memtable = Memtable::new(table_metadata.clone());
+        for n in [0, 7].iter() {
+            append(&mut memtable, *n);
+        }
+
+        assert_eq!(vec!(5, -1, 300, 2), elements(&read_partition(&table_metadata, &[], std::slice::from_ref(&sstable), &id, None).unwrap().unwrap()));
+        assert_eq!(vec!(5, -1, 300, 2, 0, 7), elements(&read_partition(&table_metadata, &[&memtable], &[sstable], &id, None).unwrap().unwrap()));
+    }
+}
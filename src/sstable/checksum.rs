@@ -0,0 +1,223 @@
+use std::hash::Hasher;
+use std::io::{ErrorKind, Seek, SeekFrom, Write};
+
+use fasthash::xx::Hasher64;
+
+use crate::db::TableMetaData;
+use crate::io::{CassRead, CassWrite, DecodeError, DecodeErrorKind, DecodeResult};
+use crate::sstable::header::{Component, ComponentHeader};
+
+
+/// Passes the data file's bytes through to the actual writer, splitting them into fixed size
+///  blocks with an xxhash32 checksum each. Only the last block may be shorter. It also calculates an xxhash64 digest of the whole file.
+///
+/// Seeking is only supported for querying the current position, which avoids flushing a
+///  `BufWriter` for every row.
+pub struct BlockChecksumWriter<W> where W: Write {
+    inner: W,
+    block_size: usize,
+    block: Vec<u8>,
+    checksums: Vec<u32>,
+    digest: Hasher64,
+    position: u64,
+}
+
+impl <W> BlockChecksumWriter<W> where W: Write {
+    pub fn new(inner: W, block_size: usize) -> BlockChecksumWriter<W> {
+        BlockChecksumWriter {
+            inner,
+            block_size,
+            block: Vec::with_capacity(block_size),
+            checksums: Vec::new(),
+            digest: Hasher64::default(),
+            position: 0,
+        }
+    }
+
+    /// returns the inner writer together with the block checksums and the file's digest
+    pub fn finish(mut self) -> (W, BlockChecksums, u64) {
+        if !self.block.is_empty() {
+            self.checksums.push(fasthash::xx::hash32(&self.block));
+        }
+        let checksums = BlockChecksums {
+            block_size: self.block_size,
+            checksums: self.checksums,
+        };
+        (self.inner, checksums, self.digest.finish())
+    }
+}
+
+impl <W> Write for BlockChecksumWriter<W> where W: Write {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.digest.write(&buf[..n]);
+        self.position += n as u64;
+
+        let mut remaining = &buf[..n];
+        while !remaining.is_empty() {
+            let len = remaining.len().min(self.block_size - self.block.len());
+            self.block.extend_from_slice(&remaining[..len]);
+            remaining = &remaining[len..];
+
+            if self.block.len() == self.block_size {
+                self.checksums.push(fasthash::xx::hash32(&self.block));
+                self.block.clear();
+            }
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl <W> Seek for BlockChecksumWriter<W> where W: Write {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        match pos {
+            SeekFrom::Current(0) => Ok(self.position),
+            _ => Err(std::io::Error::new(ErrorKind::Unsupported, "checksummed data files are written sequentially")),
+        }
+    }
+}
+
+
+/// The checksums of a data file's blocks. The checksum file consists of the header, the block size
+///  (u32) and the number of blocks (u32), followed by each block's checksum (u32).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockChecksums {
+    block_size: usize,
+    checksums: Vec<u32>,
+}
+
+impl BlockChecksums {
+    pub fn read(buf: &[u8]) -> DecodeResult<BlockChecksums> {
+        let mut r = CassRead::wrap(buf);
+        r.pos = ComponentHeader::read(buf, Component::Checksums)?.body_offset();
+        let block_size_offset = r.pos;
+        let block_size = r.read_u32()? as usize;
+        if block_size == 0 {
+            return Err(DecodeError::new(block_size_offset, DecodeErrorKind::InvalidValue("block size")));
+        }
+        let num_blocks = r.read_u32()?;
+        let mut checksums = Vec::new();
+        for _ in 0..num_blocks {
+            checksums.push(r.read_u32()?);
+        }
+        Ok(BlockChecksums { block_size, checksums })
+    }
+
+    pub fn write<W>(&self, out: &mut CassWrite<W>, table_metadata: &TableMetaData) -> std::io::Result<()> where W: Write+Seek {
+        ComponentHeader::write(out, Component::Checksums, table_metadata)?;
+        out.write_u32(self.block_size as u32)?;
+        out.write_u32(self.checksums.len() as u32)?;
+        for checksum in self.checksums.iter() {
+            out.write_u32(*checksum)?;
+        }
+        Ok(())
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.checksums.len()
+    }
+
+    /// the index of the block containing a given offset in the data file
+    pub fn block_of(&self, offset: usize) -> usize {
+        offset / self.block_size
+    }
+
+    /// false if the block's checksum does not match, or if `data` does not have the block at its
+    ///  full size. `data` is the part of the data file starting at offset `base`.
+    pub fn verify_block(&self, data: &[u8], base: usize, block: usize) -> bool {
+        if block * self.block_size < base {
+            return false;
+        }
+        let start = block * self.block_size - base;
+        let end = (start + self.block_size).min(data.len());
+        let is_last = block + 1 == self.checksums.len();
+
+        match self.checksums.get(block) {
+            None => false,
+            Some(_) if start >= data.len() => false,
+            Some(_) if !is_last && end - start < self.block_size => false,
+            Some(checksum) => fasthash::xx::hash32(&data[start..end]) == *checksum,
+        }
+    }
+}
+
+/// the digest file contains the header followed by the whole (uncompressed) data file's xxhash64
+pub fn read_digest(buf: &[u8]) -> DecodeResult<u64> {
+    let mut r = CassRead::wrap(buf);
+    r.pos = ComponentHeader::read(buf, Component::Digest)?.body_offset();
+    r.read_u64()
+}
+
+pub fn write_digest<W>(out: &mut CassWrite<W>, digest: u64, table_metadata: &TableMetaData) -> std::io::Result<()> where W: Write+Seek {
+    ComponentHeader::write(out, Component::Digest, table_metadata)?;
+    out.write_u64(digest)
+}
+
+pub fn digest(data: &[u8]) -> u64 {
+    let mut hasher = Hasher64::default();
+    hasher.write(data);
+    hasher.finish()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Seek, SeekFrom, Write};
+
+    use uuid::Uuid;
+
+    use crate::db::TableMetaData;
+    use crate::io::CassWrite;
+    use crate::sstable::checksum::{digest, read_digest, write_digest, BlockChecksumWriter, BlockChecksums};
+    use crate::sstable::testing::id_column;
+
+    #[test]
+    pub fn test_block_checksums() {
+        let data: Vec<u8> = (0..250u32).map(|n| (n * 7) as u8).collect();
+
+        let mut writer = BlockChecksumWriter::new(Vec::new(), 100);
+        let mut position = 0;
+        for chunk in data.chunks(33) {
+            writer.write_all(chunk).unwrap();
+            position += chunk.len() as u64;
+            assert_eq!(position, writer.stream_position().unwrap());
+        }
+        assert!(writer.seek(SeekFrom::Start(0)).is_err());
+
+        let (written, checksums, file_digest) = writer.finish();
+        assert_eq!(data, written);
+        assert_eq!(digest(&data), file_digest);
+        assert_eq!(3, checksums.num_blocks());
+        assert_eq!(2, checksums.block_of(200));
+        for block in 0..3 {
+            assert!(checksums.verify_block(&data, 0, block));
+        }
+        assert!(!checksums.verify_block(&data, 0, 3));
+
+        let mut corrupt = data.clone();
+        corrupt[150] ^= 1;
+        assert!(checksums.verify_block(&corrupt, 0, 0));
+        assert!(!checksums.verify_block(&corrupt, 0, 1));
+        assert!(checksums.verify_block(&corrupt, 0, 2));
+        assert!(!checksums.verify_block(&data[..150], 0, 1));
+        assert!(checksums.verify_block(&data[100..200], 100, 1));
+        assert!(!checksums.verify_block(&data[150..], 150, 1));
+
+        let table_metadata = TableMetaData::new("t".to_string(), Uuid::new_v4(), vec!(id_column()), 0, Vec::new());
+        let mut out = CassWrite::new(Cursor::new(Vec::new()));
+        checksums.write(&mut out, &table_metadata).unwrap();
+        assert_eq!(checksums, BlockChecksums::read(&out.into_inner().into_inner()).unwrap());
+
+        let mut out = CassWrite::new(Cursor::new(Vec::new()));
+        write_digest(&mut out, file_digest, &table_metadata).unwrap();
+        assert_eq!(file_digest, read_digest(&out.into_inner().into_inner()).unwrap());
+    }
+}
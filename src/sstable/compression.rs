@@ -0,0 +1,393 @@
+use std::borrow::Cow;
+use std::io::{Seek, Write};
+use std::sync::Arc;
+
+use memmap::Mmap;
+
+use crate::db::{CompressionCodec, TableMetaData};
+use crate::io::{CassRead, CassWrite, DecodeError, DecodeErrorKind, DecodeResult};
+use crate::sstable::header::{Component, ComponentHeader};
+use crate::sstable::SstableMetaData;
+
+const ID_CODEC_NONE: u8 = 0;
+const ID_CODEC_LZ4: u8 = 1;
+const ID_CODEC_DEFLATE: u8 = 2;
+
+const DEFLATE_LEVEL: u8 = 6;
+
+
+fn compress(codec: CompressionCodec, data: &[u8]) -> Cow<'_, [u8]> {
+    match codec {
+        CompressionCodec::None => Cow::Borrowed(data),
+        CompressionCodec::Lz4 => Cow::Owned(lz4_flex::block::compress(data)),
+        CompressionCodec::Deflate => Cow::Owned(miniz_oxide::deflate::compress_to_vec(data, DEFLATE_LEVEL)),
+    }
+}
+
+/// None if the data is corrupt, i.e. it can not be decompressed or has a different length
+fn decompress(codec: CompressionCodec, data: &[u8], uncompressed_length: usize) -> Option<Vec<u8>> {
+    let result = match codec {
+        CompressionCodec::None => Some(data.to_vec()),
+        CompressionCodec::Lz4 => lz4_flex::block::decompress(data, uncompressed_length).ok(),
+        CompressionCodec::Deflate => miniz_oxide::inflate::decompress_to_vec_with_limit(data, uncompressed_length).ok(),
+    };
+    result.filter(|r| r.len() == uncompressed_length)
+}
+
+
+/// Splits the data written to it into chunks of `chunk_size` bytes and writes each chunk
+///  compressed with the codec, recording the chunks' offsets.
+pub struct ChunkWriter<W> where W: Write {
+    inner: W,
+    codec: CompressionCodec,
+    chunk_size: usize,
+    chunk: Vec<u8>,
+    offsets: Vec<u64>,
+    position: u64,
+    uncompressed_length: u64,
+}
+
+impl <W> ChunkWriter<W> where W: Write {
+    pub fn new(inner: W, codec: CompressionCodec, chunk_size: usize) -> ChunkWriter<W> {
+        ChunkWriter {
+            inner,
+            codec,
+            chunk_size,
+            chunk: Vec::with_capacity(chunk_size),
+            offsets: Vec::new(),
+            position: 0,
+            uncompressed_length: 0,
+        }
+    }
+
+    fn write_chunk(&mut self) -> std::io::Result<()> {
+        let compressed = compress(self.codec, &self.chunk);
+        self.inner.write_all(&compressed)?;
+
+        self.offsets.push(self.position);
+        self.position += compressed.len() as u64;
+        self.uncompressed_length += self.chunk.len() as u64;
+        self.chunk.clear();
+        Ok(())
+    }
+
+    /// writes the last (partial) chunk and returns the inner writer together with the chunk table
+    pub fn finish(mut self) -> std::io::Result<(W, ChunkInfo)> {
+        if !self.chunk.is_empty() {
+            self.write_chunk()?;
+        }
+        let info = ChunkInfo {
+            codec: self.codec,
+            chunk_size: self.chunk_size,
+            uncompressed_length: self.uncompressed_length as usize,
+            offsets: self.offsets,
+        };
+        Ok((self.inner, info))
+    }
+}
+
+/// `flush()` does not write a partial chunk, that is done by `finish()`
+impl <W> Write for ChunkWriter<W> where W: Write {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut remaining = buf;
+        while !remaining.is_empty() {
+            let len = remaining.len().min(self.chunk_size - self.chunk.len());
+            self.chunk.extend_from_slice(&remaining[..len]);
+            remaining = &remaining[len..];
+
+            if self.chunk.len() == self.chunk_size {
+                self.write_chunk()?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+
+/// The chunk table allows random access to a compressed data file: offsets in the uncompressed
+///  data are mapped to a chunk, which is the only part of the file that needs decompressing.
+///
+/// The chunk file consists of the header, the codec (u8), the chunk size (u32), the uncompressed
+///  data's length (u64) and the number of chunks (u32), followed by each chunk's offset (u64)
+///  relative to the end of the data file's header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkInfo {
+    codec: CompressionCodec,
+    chunk_size: usize,
+    uncompressed_length: usize,
+    offsets: Vec<u64>,
+}
+
+impl ChunkInfo {
+    pub fn read(buf: &[u8]) -> DecodeResult<ChunkInfo> {
+        let mut r = CassRead::wrap(buf);
+        r.pos = ComponentHeader::read(buf, Component::Chunks)?.body_offset();
+        let codec_offset = r.pos;
+        let codec = match r.read_u8()? {
+            ID_CODEC_NONE => CompressionCodec::None,
+            ID_CODEC_LZ4 => CompressionCodec::Lz4,
+            ID_CODEC_DEFLATE => CompressionCodec::Deflate,
+            n => return Err(DecodeError::invalid_tag(codec_offset, "compression codec", &[ID_CODEC_NONE, ID_CODEC_LZ4, ID_CODEC_DEFLATE], n)),
+        };
+        let chunk_size = r.read_u32()? as usize;
+        if chunk_size == 0 {
+            return Err(DecodeError::new(codec_offset + 1, DecodeErrorKind::InvalidValue("chunk size")));
+        }
+        let uncompressed_length = r.read_u64()? as usize;
+
+        let num_chunks_pos = r.pos;
+        let num_chunks = r.read_u32()? as usize;
+        if num_chunks != uncompressed_length.div_ceil(chunk_size) {
+            return Err(DecodeError::new(num_chunks_pos, DecodeErrorKind::InvalidValue("number of chunks")));
+        }
+        let mut offsets = Vec::new();
+        for _ in 0..num_chunks {
+            offsets.push(r.read_u64()?);
+        }
+
+        Ok(ChunkInfo { codec, chunk_size, uncompressed_length, offsets })
+    }
+
+    pub fn write<W>(&self, out: &mut CassWrite<W>, table_metadata: &TableMetaData) -> std::io::Result<()> where W: Write+Seek {
+        ComponentHeader::write(out, Component::Chunks, table_metadata)?;
+        out.write_u8(match self.codec {
+            CompressionCodec::None => ID_CODEC_NONE,
+            CompressionCodec::Lz4 => ID_CODEC_LZ4,
+            CompressionCodec::Deflate => ID_CODEC_DEFLATE,
+        })?;
+        out.write_u32(self.chunk_size as u32)?;
+        out.write_u64(self.uncompressed_length as u64)?;
+        out.write_u32(self.offsets.len() as u32)?;
+        for offset in self.offsets.iter() {
+            out.write_u64(*offset)?;
+        }
+        Ok(())
+    }
+
+    pub fn codec(&self) -> CompressionCodec {
+        self.codec
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    pub fn uncompressed_length(&self) -> usize {
+        self.uncompressed_length
+    }
+
+    pub fn num_chunks(&self) -> usize {
+        self.offsets.len()
+    }
+
+    /// the index of the chunk containing a given offset in the uncompressed data
+    pub fn chunk_of(&self, offset: usize) -> usize {
+        offset / self.chunk_size
+    }
+
+    /// uncompressed length of a chunk, only the last chunk may be shorter than the chunk size
+    pub fn chunk_length(&self, chunk: usize) -> usize {
+        self.chunk_size.min(self.uncompressed_length - chunk * self.chunk_size)
+    }
+
+    /// decompresses one of the chunks of the (memory mapped) data file
+    pub fn decompress_chunk(&self, file: &[u8], chunk: usize) -> DecodeResult<Vec<u8>> {
+        let start = self.offsets[chunk] as usize;
+        let end = self.offsets.get(chunk + 1).map_or(file.len(), |offset| *offset as usize);
+        if start > end || end > file.len() {
+            return Err(DecodeError::new(start, DecodeErrorKind::UnexpectedEnd { needed: end.saturating_sub(start), available: file.len().saturating_sub(start) }));
+        }
+
+        decompress(self.codec, &file[start..end], self.chunk_length(chunk))
+            .ok_or_else(|| DecodeError::new(start, DecodeErrorKind::CorruptChunk { chunk }))
+    }
+}
+
+
+/// A data file's uncompressed contents, or the part of them starting at offset `base`. Offsets
+///  are relative to the end of the data file's header. Uncompressed data files are memory mapped
+///  rather than copied.
+pub struct DataFileContents {
+    base: usize,
+    /// the data file's format version, see `header`
+    version: u16,
+    contents: Contents,
+}
+
+enum Contents {
+    /// the mapped file and the offset of the data following its header
+    Mapped(Arc<Mmap>, usize),
+    Decompressed(Vec<u8>),
+}
+
+impl DataFileContents {
+    /// offset of `data()` in the uncompressed data file
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    pub fn data(&self) -> &[u8] {
+        match &self.contents {
+            Contents::Mapped(m, body_offset) => &m[*body_offset..],
+            Contents::Decompressed(data) => data,
+        }
+    }
+}
+
+/// Reads the uncompressed data between `start` and `end` (or the end of the data file if `None`).
+///  Compressed files are decompressed in whole chunks, so the result may start earlier and end
+///  later.
+pub fn read_contents(sstable: &SstableMetaData, start: usize, end: Option<usize>) -> std::io::Result<DataFileContents> {
+    let info = sstable.read_chunk_info()?;
+    let (header, file) = sstable.map_component(&sstable.data_filename(), Component::Data)?;
+    contents_of(sstable, &info, &header, &Arc::new(file), start, end)
+}
+
+/// like `read_contents`, but for a data file that is already mapped
+pub fn contents_of(sstable: &SstableMetaData, info: &ChunkInfo, header: &ComponentHeader, file: &Arc<Mmap>, start: usize, end: Option<usize>) -> std::io::Result<DataFileContents> {
+    let body_offset = header.body_offset();
+    if info.codec == CompressionCodec::None {
+        return Ok(DataFileContents { base: 0, version: header.version, contents: Contents::Mapped(file.clone(), body_offset) });
+    }
+
+    let end = end.unwrap_or(info.uncompressed_length).min(info.uncompressed_length);
+    if start >= end {
+        return Ok(DataFileContents { base: start, version: header.version, contents: Contents::Decompressed(Vec::new()) });
+    }
+
+    let first = info.chunk_of(start);
+    let mut data = Vec::new();
+    for chunk in first..=info.chunk_of(end - 1) {
+        let decompressed = info.decompress_chunk(&file[body_offset..], chunk)
+            .map_err(|e| DecodeError { offset: body_offset + e.offset, ..e }.in_file(&sstable.data_filename()))?;
+        data.extend(decompressed);
+    }
+    Ok(DataFileContents { base: first * info.chunk_size, version: header.version, contents: Contents::Decompressed(data) })
+}
+
+/// For reading a data file sequentially: drops the chunks before the one containing `start` from
+///  `contents` and appends the chunk following them, so only the chunks still being read are kept.
+///  Returns false at the end of the data file. Uncompressed data files are mapped in their
+///  entirety, so there is never a next chunk for them.
+pub fn next_chunk_of(sstable: &SstableMetaData, info: &ChunkInfo, file: &Arc<Mmap>, body_offset: usize, contents: &mut DataFileContents, start: usize) -> std::io::Result<bool> {
+    let data = match &mut contents.contents {
+        Contents::Mapped(..) => return Ok(false),
+        Contents::Decompressed(data) => data,
+    };
+    let end = contents.base + data.len();
+    if end >= info.uncompressed_length {
+        return Ok(false);
+    }
+
+    let next = info.chunk_of(end);
+    let first = info.chunk_of(start).clamp(info.chunk_of(contents.base), next);
+    let decompressed = info.decompress_chunk(&file[body_offset..], next)
+        .map_err(|e| DecodeError { offset: body_offset + e.offset, ..e }.in_file(&sstable.data_filename()))?;
+    match (first * info.chunk_size).checked_sub(contents.base) {
+        Some(consumed) if !data.is_empty() => {
+            data.drain(..consumed);
+            contents.base = first * info.chunk_size;
+        },
+        // the contents are empty or start in the middle of a chunk, so there is nothing to keep
+        _ => {
+            data.clear();
+            contents.base = next * info.chunk_size;
+        },
+    }
+    data.extend(decompressed);
+    Ok(true)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use uuid::Uuid;
+
+    use crate::db::{CompressionCodec, CompressionOptions, TableMetaData};
+    use crate::io::{CassWrite, DecodeErrorKind};
+    use crate::sstable::compression::{ChunkInfo, ChunkWriter};
+    use crate::sstable::map_file;
+    use crate::sstable::partition_index::PartitionIndex;
+    use crate::sstable::row_data::RowDataReader;
+    use crate::sstable::testing::{id_column, ids, rows, table_metadata, text_value, write_sstable, TempFolder};
+
+    const CODECS: [CompressionCodec; 3] = [CompressionCodec::None, CompressionCodec::Lz4, CompressionCodec::Deflate];
+
+    #[test]
+    pub fn test_chunks() {
+        let data: Vec<u8> = (0..1000).flat_map(|n: u32| format!("row {} ", n % 17).into_bytes()).collect();
+
+        for codec in CODECS.iter() {
+            let mut writer = ChunkWriter::new(Vec::new(), *codec, 1000);
+            for part in data.chunks(333) {
+                writer.write_all(part).unwrap();
+            }
+            let (file, info) = writer.finish().unwrap();
+
+            assert_eq!(data.len(), info.uncompressed_length());
+            assert_eq!(data.len().div_ceil(1000), info.num_chunks());
+            if *codec != CompressionCodec::None {
+                assert!(file.len() < data.len() / 2, "{:?}", codec);
+            }
+
+            let decompressed: Vec<u8> = (0..info.num_chunks())
+                .flat_map(|chunk| info.decompress_chunk(&file, chunk).unwrap())
+                .collect();
+            assert_eq!(data, decompressed);
+
+            let table_metadata = TableMetaData::new("t".to_string(), Uuid::new_v4(), vec!(id_column()), 0, Vec::new());
+            let mut out = CassWrite::new(Cursor::new(Vec::new()));
+            info.write(&mut out, &table_metadata).unwrap();
+            assert_eq!(info, ChunkInfo::read(&out.into_inner().into_inner()).unwrap());
+
+            if *codec != CompressionCodec::None {
+                let e = info.decompress_chunk(&file[..file.len() - 1], info.num_chunks() - 1).unwrap_err();
+                assert_eq!(DecodeErrorKind::CorruptChunk { chunk: info.num_chunks() - 1 }, e.kind);
+            }
+        }
+    }
+
+    #[test]
+    pub fn test_random_access() {
+        for codec in CODECS.iter() {
+            let table_metadata = table_metadata(CompressionOptions { codec: *codec, chunk_size: 256 });
+            let ids = ids(200);
+            let value = text_value(30);
+            let rows = rows(&table_metadata, &ids, &value);
+            let folder = TempFolder::new();
+            let sstable = write_sstable(&table_metadata, folder.path(), &rows).meta_data().clone();
+            assert_eq!(*codec, sstable.read_chunk_info().unwrap().codec());
+
+            let index_buf = map_file(&sstable.index_filename()).unwrap().unwrap();
+            let index = PartitionIndex::new(&index_buf).unwrap();
+            let checksums = sstable.read_checksums().unwrap();
+            for id in ids.iter() {
+                let token = fasthash::murmur3::hash128(id);
+                let offset = index.find(token, id).unwrap().unwrap().offset as usize;
+                let end = index.next_partition_offset(token, id).unwrap().map(|o| o as usize);
+                let contents = sstable.read_data_range(offset, end).unwrap();
+                if *codec != CompressionCodec::None {
+                    assert!(contents.data().len() <= 2 * 256);
+                }
+
+                let row = RowDataReader::for_contents(table_metadata.clone(), &contents, offset).unwrap()
+                    .with_checksums(&checksums)
+                    .next().unwrap().unwrap();
+                assert_eq!(id.as_slice(), row.partition_key);
+            }
+
+            let contents = sstable.read_data().unwrap();
+            assert_eq!(rows.len(), RowDataReader::for_contents(table_metadata.clone(), &contents, 0).unwrap().count());
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use std::io::{Seek, Write};
+
+use crate::db::TableMetaData;
+use crate::io::{CassRead, CassWrite, DecodeError, DecodeErrorKind, DecodeResult};
+use crate::sstable::header::{Component, ComponentHeader};
+use crate::util::{other_error, Token};
+
+
+/// Collects the tokens of an sstable's partitions and writes a bloom filter for them. The filter
+///  file consists of the header, the number of hash functions (u32) and the number of bits (u64),
+///  followed by the bits themselves.
+pub struct BloomFilterBuilder {
+    fp_chance: f64,
+    tokens: Vec<Token>,
+}
+
+impl BloomFilterBuilder {
+    /// fails for a false positive chance outside the open interval (0, 1)
+    pub fn new(fp_chance: f64) -> std::io::Result<BloomFilterBuilder> {
+        if !(fp_chance > 0.0 && fp_chance < 1.0) {
+            return other_error(&format!("bloom filter false positive chance must be between 0 and 1, was {}", fp_chance));
+        }
+        Ok(BloomFilterBuilder {
+            fp_chance,
+            tokens: Vec::new(),
+        })
+    }
+
+    pub fn add(&mut self, token: Token) {
+        self.tokens.push(token);
+    }
+
+    pub fn write<W>(self, out: &mut CassWrite<W>, table_metadata: &TableMetaData) -> std::io::Result<()> where W: Write+Seek {
+        ComponentHeader::write(out, Component::Filter, table_metadata)?;
+
+        let (num_hashes, num_bits) = filter_dimensions(self.tokens.len(), self.fp_chance);
+
+        let mut bits = vec!(0u8; num_bits.div_ceil(8) as usize);
+        for token in self.tokens {
+            for bit in bit_positions(token, num_hashes, num_bits) {
+                bits[(bit / 8) as usize] |= 1 << (bit % 8);
+            }
+        }
+
+        out.write_u32(num_hashes)?;
+        out.write_u64(num_bits)?;
+        out.write_raw(&bits)
+    }
+}
+
+/// the textbook optimum for n entries: m = -n*ln(p) / ln(2)^2 bits and k = m/n * ln(2) hash functions
+fn filter_dimensions(num_entries: usize, fp_chance: f64) -> (u32, u64) {
+    let n = num_entries.max(1) as f64;
+    let ln2 = std::f64::consts::LN_2;
+
+    let num_bits = (-n * fp_chance.ln() / (ln2 * ln2)).ceil().max(64.0);
+    let num_hashes = (num_bits / n * ln2).round().max(1.0);
+    (num_hashes as u32, num_bits as u64)
+}
+
+/// tokens are murmur3 hashes already, so their two halves serve as the two independent hashes for
+///  double hashing (Kirsch / Mitzenmacher)
+fn bit_positions(token: Token, num_hashes: u32, num_bits: u64) -> impl Iterator<Item=u64> {
+    let h1 = token as u64;
+    let h2 = (token >> 64) as u64;
+    (0..num_hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+}
+
+
+/// a bloom filter that operates directly on a (typically memory mapped) filter file's contents
+pub struct BloomFilter<'a> {
+    num_hashes: u32,
+    num_bits: u64,
+    bits: &'a [u8],
+}
+
+impl <'a> BloomFilter<'a> {
+    pub fn new(buf: &'a [u8]) -> DecodeResult<BloomFilter<'a>> {
+        let mut r = CassRead::wrap(buf);
+        r.pos = ComponentHeader::read(buf, Component::Filter)?.body_offset();
+        let num_hashes = r.read_u32()?;
+        let num_bits_offset = r.pos;
+        let num_bits = r.read_u64()?;
+        if num_bits == 0 {
+            return Err(DecodeError::new(num_bits_offset, DecodeErrorKind::InvalidValue("bloom filter size")));
+        }
+        let bits = r.read_slice(num_bits.div_ceil(8) as usize)?;
+
+        Ok(BloomFilter {
+            num_hashes,
+            num_bits,
+            bits,
+        })
+    }
+
+    /// false means that the sstable definitely does not contain the token, true means that it may
+    pub fn might_contain(&self, token: Token) -> bool {
+        bit_positions(token, self.num_hashes, self.num_bits)
+            .all(|bit| self.bits[(bit / 8) as usize] & (1 << (bit % 8)) != 0)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use uuid::Uuid;
+
+    use crate::db::TableMetaData;
+    use crate::io::CassWrite;
+    use crate::sstable::filter::{BloomFilter, BloomFilterBuilder};
+    use crate::sstable::testing::id_column;
+    use crate::util::Token;
+
+    fn token(n: u64) -> Token {
+        fasthash::murmur3::hash128(&n.to_be_bytes())
+    }
+
+    fn create_filter(fp_chance: f64, num_entries: u64) -> Vec<u8> {
+        let mut builder = BloomFilterBuilder::new(fp_chance).unwrap();
+        for n in 0..num_entries {
+            builder.add(token(n));
+        }
+
+        let table_metadata = TableMetaData::new("t".to_string(), Uuid::new_v4(), vec!(id_column()), 0, Vec::new());
+        let mut out = CassWrite::new(Cursor::new(Vec::new()));
+        builder.write(&mut out, &table_metadata).unwrap();
+        out.into_inner().into_inner()
+    }
+
+    #[test]
+    pub fn test_empty() {
+        let buf = create_filter(0.01, 0);
+        let filter = BloomFilter::new(&buf).unwrap();
+        assert!(!filter.might_contain(token(0)));
+        assert!(!filter.might_contain(token(1)));
+    }
+
+    #[test]
+    pub fn test_invalid_fp_chance() {
+        for fp_chance in [0.0, 1.0, -0.5, f64::NAN].iter() {
+            assert!(BloomFilterBuilder::new(*fp_chance).is_err(), "fp chance {}", fp_chance);
+        }
+    }
+
+    #[test]
+    pub fn test_zero_bits() {
+        let mut buf = create_filter(0.01, 10);
+        let num_bits_offset = buf.len() - BloomFilter::new(&buf).unwrap().bits.len() - 8;
+        buf.truncate(num_bits_offset);
+        buf.extend_from_slice(&0u64.to_be_bytes());
+
+        assert!(BloomFilter::new(&buf).is_err());
+    }
+
+    #[test]
+    pub fn test_false_positive_rate() {
+        let num_entries = 20_000;
+        let num_probes = 200_000;
+
+        for fp_chance in [0.1, 0.01, 0.001].iter() {
+            let buf = create_filter(*fp_chance, num_entries);
+            let filter = BloomFilter::new(&buf).unwrap();
+
+            for n in 0..num_entries {
+                assert!(filter.might_contain(token(n)), "false negative for {}", n);
+            }
+
+            let num_false_positives = (num_entries..num_entries+num_probes)
+                .filter(|n| filter.might_contain(token(*n)))
+                .count();
+            let fp_rate = num_false_positives as f64 / num_probes as f64;
+
+            assert!(fp_rate < fp_chance * 1.3, "configured false positive chance {}, measured {}", fp_chance, fp_rate);
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+use crate::db::{BoundSide, KeyBound, RegularRowData, RowDetails, RowTombstoneData, TableCell, TableCellData, TableMetaData, TableRow};
+use crate::memtable::{CellKey, ClusterKey, MemClusterBound, MemPartition, MemRangeTombstone, MemRow, Memtable};
+use crate::sstable::row_data::RowDataFileCreator;
+use crate::sstable::{Sstable, SstableMetaData};
+use crate::util::{Token, NO_EXPIRY};
+
+
+/// Writes a memtable's contents to a new sstable. The memtable must not be modified while it is
+///  flushed, i.e. callers should switch to a fresh memtable for new writes first.
+pub fn flush_memtable(memtable: &Memtable, meta_data: SstableMetaData) -> std::io::Result<Arc<Sstable>> {
+    let mut creator = RowDataFileCreator::new(meta_data)?;
+
+    for (token, partition_key, partition) in memtable.partitions() {
+        write_partition(&mut creator, memtable.table_metadata(), token, partition_key, partition)?;
+    }
+
+    creator.finalize()
+}
+
+/// Sstables must not contain rows inside a range tombstone (see `RowDataFileCreator::append_row`),
+///  so range tombstones are split around the rows they contain. The rows' columns that are not
+///  newer than the tombstone get explicit cell tombstones instead, so that the tombstone still
+///  shadows older data for those rows in other sstables.
+pub fn write_partition(creator: &mut RowDataFileCreator, table_metadata: &Arc<TableMetaData>, token: Token, partition_key: &[u8], partition: &MemPartition) -> std::io::Result<()> {
+    let writer = PartitionWriter { table_metadata, token, partition_key };
+    let comparator = table_metadata.cluster_key_comparator();
+    let mut rows = partition.rows().peekable();
+
+    for tombstone in partition.range_tombstones() {
+        // rows before the tombstone
+        while let Some((cluster_key, row)) = rows.peek() {
+            if tombstone.start.compare_with_key(comparator, cluster_key) != Ordering::Greater {
+                break;
+            }
+            writer.write_row(creator, cluster_key, row, None)?;
+            rows.next();
+        }
+
+        // rows inside the tombstone
+        let mut start = tombstone.start.clone();
+        while let Some((cluster_key, row)) = rows.peek() {
+            if tombstone.end.compare_with_key(comparator, cluster_key) != Ordering::Greater {
+                break;
+            }
+
+            let before_row = MemClusterBound { prefix: (*cluster_key).clone(), side: BoundSide::Before };
+            writer.write_tombstone(creator, &start, &before_row, tombstone)?;
+            writer.write_row(creator, cluster_key, row, Some(tombstone))?;
+            start = MemClusterBound { prefix: (*cluster_key).clone(), side: BoundSide::After };
+            rows.next();
+        }
+        writer.write_tombstone(creator, &start, &tombstone.end, tombstone)?;
+    }
+
+    for (cluster_key, row) in rows {
+        writer.write_row(creator, cluster_key, row, None)?;
+    }
+    Ok(())
+}
+
+struct PartitionWriter<'a> {
+    table_metadata: &'a Arc<TableMetaData>,
+    token: Token,
+    partition_key: &'a [u8],
+}
+
+impl PartitionWriter<'_> {
+    fn write_row(&self, creator: &mut RowDataFileCreator, cluster_key: &ClusterKey, row: &MemRow, enclosing_tombstone: Option<&MemRangeTombstone>) -> std::io::Result<()> {
+        let mut regular_cols: Vec<TableCell> = row.cells.values().map(|c| c.as_table_cell()).collect();
+
+        // for collection columns, this is a tombstone for the whole collection
+        if let Some(tombstone) = enclosing_tombstone {
+            for col in self.table_metadata.regular_columns() {
+                if !row.cells.contains_key(&CellKey::new(col.clone(), None)) {
+                    regular_cols.push(TableCell {
+                        meta_data: col.clone(),
+                        path: None,
+                        timestamp: tombstone.timestamp,
+                        expiry: NO_EXPIRY,
+                        data: TableCellData::Tombstone,
+                    });
+                }
+            }
+        }
+
+        let details = RowDetails::Regular(RegularRowData {
+            pk_expiry: row.pk_expiry,
+            cluster_key: cluster_key.iter().map(|c| c.as_slice()).collect(),
+            regular_cols,
+        });
+        creator.append_row(&TableRow::new_with_known_token(self.table_metadata.clone(), self.partition_key, self.token, details))
+    }
+
+    /// writes the part of a tombstone between two bounds, if it is not empty
+    fn write_tombstone(&self, creator: &mut RowDataFileCreator, start: &MemClusterBound, end: &MemClusterBound, tombstone: &MemRangeTombstone) -> std::io::Result<()> {
+        if start.compare(self.table_metadata.cluster_key_comparator(), end) != Ordering::Less {
+            return Ok(());
+        }
+
+        let details = RowDetails::RowTombstone(RowTombstoneData {
+            timestamp: tombstone.timestamp,
+            lower_bound: to_key_bound(start, BoundSide::Before),
+            upper_bound: to_key_bound(end, BoundSide::After),
+        });
+        creator.append_row(&TableRow::new_with_known_token(self.table_metadata.clone(), self.partition_key, self.token, details))
+    }
+}
+
+/// `unlimited_side` is the side that makes an empty prefix unlimited, i.e. `Before` for lower
+///  bounds and `After` for upper bounds
+fn to_key_bound(bound: &MemClusterBound, unlimited_side: BoundSide) -> Option<KeyBound<'_>> {
+    if bound.prefix.is_empty() && bound.side == unlimited_side {
+        return None;
+    }
+
+    Some(KeyBound {
+        cluster_key_prefix: bound.prefix.iter().map(|c| c.as_slice()).collect(),
+        is_inclusive: bound.side == unlimited_side,
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::db::{ColumnType, KeyBound, RegularRowData, RowDetails, RowTombstoneData, TableCell, TableCellData, TableMetaData, TableOptions, TableRow};
+    use crate::memtable::{MemRangeTombstone, Memtable};
+    use crate::sstable::flush::flush_memtable;
+    use crate::sstable::row_data::RowDataReader;
+    use crate::sstable::testing::{clustered_table_metadata, TempFolder};
+    use crate::sstable::SstableMetaData;
+    use crate::util::{DbTimestamp, NO_EXPIRY};
+
+    fn table_metadata() -> Arc<TableMetaData> {
+        clustered_table_metadata(&[("v1", ColumnType::Text), ("v2", ColumnType::Text)], TableOptions::default())
+    }
+
+    /// serialized text values
+    const A: &[u8] = &[0, 0, 0, 1, b'a'];
+    const B: &[u8] = &[0, 0, 0, 1, b'b'];
+
+    fn key(n: u64) -> Vec<u8> {
+        n.to_be_bytes().to_vec()
+    }
+
+    /// sets column v1, and v2 if `with_v2`
+    fn apply_row(memtable: &mut Memtable, id: u64, ck: u64, timestamp: DbTimestamp, with_v2: bool) {
+        let table_metadata = memtable.table_metadata().clone();
+        let id = key(id);
+        let ck = key(ck);
+        let mut regular_cols = vec!(TableCell {
+            meta_data: table_metadata.columns[2].clone(),
+            path: None,
+            timestamp,
+            expiry: NO_EXPIRY,
+            data: TableCellData::Regular(A),
+        });
+        if with_v2 {
+            regular_cols.push(TableCell {
+                meta_data: table_metadata.columns[3].clone(),
+                path: None,
+                timestamp,
+                expiry: NO_EXPIRY,
+                data: TableCellData::Regular(B),
+            });
+        }
+
+        memtable.apply(&TableRow::new(table_metadata, &id, RowDetails::Regular(RegularRowData {
+            pk_expiry: NO_EXPIRY,
+            cluster_key: vec!(&ck),
+            regular_cols,
+        }))).unwrap();
+    }
+
+    fn apply_tombstone(memtable: &mut Memtable, id: u64, lower: Option<u64>, upper: Option<u64>, timestamp: DbTimestamp) {
+        let table_metadata = memtable.table_metadata().clone();
+        let id = key(id);
+        let lower_key = lower.map(key);
+        let upper_key = upper.map(key);
+
+        memtable.apply(&TableRow::new(table_metadata, &id, RowDetails::RowTombstone(RowTombstoneData {
+            timestamp,
+            lower_bound: lower_key.as_ref().map(|k| KeyBound { cluster_key_prefix: vec!(k), is_inclusive: true }),
+            upper_bound: upper_key.as_ref().map(|k| KeyBound { cluster_key_prefix: vec!(k), is_inclusive: false }),
+        }))).unwrap();
+    }
+
+    /// (partition key, cluster key, column name, timestamp, data)
+    type CellSummary = (Vec<u8>, Vec<u8>, String, DbTimestamp, Option<Vec<u8>>);
+
+    fn all_cells(memtable: &Memtable) -> Vec<CellSummary> {
+        let mut result = Vec::new();
+        for (_, partition_key, partition) in memtable.partitions() {
+            for (cluster_key, row) in partition.rows() {
+                for cell in row.cells.values() {
+                    result.push((partition_key.to_vec(), cluster_key[0].clone(), cell.meta_data.name.clone(), cell.timestamp, cell.data.clone()));
+                }
+            }
+        }
+        result
+    }
+
+    #[test]
+    pub fn test_flush() {
+        let table_metadata = table_metadata();
+        let mut memtable = Memtable::new(table_metadata.clone());
+
+        for id in 0..10 {
+            for ck in 0..10 {
+                apply_row(&mut memtable, id, ck, 10, true);
+            }
+        }
+        // partition 3: [2, 5) and [7, ..) deleted, with rows 3 and 8 partially re-inserted afterwards
+        apply_tombstone(&mut memtable, 3, Some(2), Some(5), 20);
+        apply_tombstone(&mut memtable, 3, Some(7), None, 30);
+        apply_row(&mut memtable, 3, 3, 25, false);
+        apply_row(&mut memtable, 3, 8, 35, false);
+        // partition 5: deleted entirely, with row 4 re-inserted
+        apply_tombstone(&mut memtable, 5, None, None, 20);
+        apply_row(&mut memtable, 5, 4, 25, true);
+        // partition 10: only a tombstone
+        apply_tombstone(&mut memtable, 10, Some(1), Some(2), 20);
+
+        let folder = TempFolder::new();
+        let meta_data = SstableMetaData::new(table_metadata.clone(), folder.path());
+        flush_memtable(&memtable, meta_data.clone()).unwrap();
+
+        let contents = meta_data.read_data().unwrap();
+        let rows: Vec<TableRow> = RowDataReader::for_contents(table_metadata.clone(), &contents, 0).unwrap().map(Result::unwrap).collect();
+
+        // no row is inside a tombstone of the same partition
+        for row in rows.iter() {
+            if let RowDetails::Regular(data) = &row.details {
+                let cluster_key: Vec<Vec<u8>> = data.cluster_key.iter().map(|c| c.to_vec()).collect();
+                for other in rows.iter().filter(|r| r.partition_key == row.partition_key) {
+                    if let RowDetails::RowTombstone(tombstone) = &other.details {
+                        assert!(!MemRangeTombstone::from(tombstone).contains(table_metadata.cluster_key_comparator(), &cluster_key));
+                    }
+                }
+            }
+        }
+
+        // reading the sstable into a memtable restores the data, with v2 of the rows inside
+        //  tombstones replaced by cell tombstones
+        let mut restored = Memtable::new(table_metadata);
+        for row in rows.iter() {
+            restored.apply(row).unwrap();
+        }
+
+        let mut expected: Vec<_> = all_cells(&memtable);
+        expected.push((key(3), key(3), "v2".to_string(), 20, None));
+        expected.push((key(3), key(8), "v2".to_string(), 30, None));
+        expected.sort();
+
+        let mut actual = all_cells(&restored);
+        actual.sort();
+        assert_eq!(expected, actual);
+
+        // tombstones are split around rows, but still cover the rest of their ranges
+        let partition_key = key(3);
+        let partition = restored.partition(fasthash::murmur3::hash128(&partition_key), &partition_key).unwrap();
+        for (ck, expected_timestamp) in [(1, None), (2, Some(20)), (3, None), (4, Some(20)), (5, None), (7, Some(30)), (8, None), (9, Some(30)), (1000, Some(30))].iter() {
+            assert_eq!(*expected_timestamp, partition.tombstone_timestamp(&[key(*ck)]), "cluster key {}", ck);
+        }
+
+        let partition_key = key(10);
+        let partition = restored.partition(fasthash::murmur3::hash128(&partition_key), &partition_key).unwrap();
+        assert_eq!(1, partition.range_tombstones().len());
+    }
+}
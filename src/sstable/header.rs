@@ -0,0 +1,415 @@
+use std::io::{Seek, Write};
+use std::sync::Arc;
+
+use crate::db::{ClusteringOrder, ColumnMetaData, ColumnType, TableMetaData, UserType, UserTypeField};
+use crate::io::{CassRead, CassWrite, DecodeError, DecodeErrorKind, DecodeResult};
+
+/// the format version that is written
+pub const FORMAT_VERSION: u16 = 2;
+/// the oldest format version that can still be read
+pub const MIN_FORMAT_VERSION: u16 = 1;
+/// the first format version that stores each cluster key's clustering order in the header, and a
+///  partition's rows in cluster key order - version 1 ordered them by their serialized bytes
+pub const CLUSTER_ORDER_VERSION: u16 = 2;
+
+const ID_TYPE_TEXT: u8 = 0;
+const ID_TYPE_UUID: u8 = 1;
+const ID_TYPE_INT: u8 = 2;
+const ID_TYPE_LONG: u8 = 3;
+const ID_TYPE_TIMESTAMP: u8 = 4;
+const ID_TYPE_BOOLEAN: u8 = 5;
+const ID_TYPE_TUPLE: u8 = 6;
+const ID_TYPE_LIST: u8 = 7;
+const ID_TYPE_SET: u8 = 8;
+const ID_TYPE_MAP: u8 = 9;
+const ID_TYPE_FLOAT: u8 = 10;
+const ID_TYPE_DOUBLE: u8 = 11;
+const ID_TYPE_DECIMAL: u8 = 12;
+const ID_TYPE_VARINT: u8 = 13;
+const ID_TYPE_BLOB: u8 = 14;
+const ID_TYPE_INET: u8 = 15;
+const ID_TYPE_DATE: u8 = 16;
+const ID_TYPE_TIME: u8 = 17;
+const ID_TYPE_DURATION: u8 = 18;
+const ID_TYPE_USER_TYPE: u8 = 19;
+
+const ID_ORDER_ASCENDING: u8 = 0;
+const ID_ORDER_DESCENDING: u8 = 1;
+
+const COLUMN_TYPE_IDS: &[u8] = &[ID_TYPE_TEXT, ID_TYPE_UUID, ID_TYPE_INT, ID_TYPE_LONG, ID_TYPE_TIMESTAMP, ID_TYPE_BOOLEAN, ID_TYPE_TUPLE, ID_TYPE_LIST, ID_TYPE_SET, ID_TYPE_MAP,
+    ID_TYPE_FLOAT, ID_TYPE_DOUBLE, ID_TYPE_DECIMAL, ID_TYPE_VARINT, ID_TYPE_BLOB, ID_TYPE_INET, ID_TYPE_DATE, ID_TYPE_TIME, ID_TYPE_DURATION,
+    ID_TYPE_USER_TYPE];
+
+
+/// the files an sstable consists of, each starting with its own magic number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Component {
+    Data,
+    Index,
+    Filter,
+    Checksums,
+    Digest,
+    Chunks,
+    Stats,
+}
+
+impl Component {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Component::Data => "data",
+            Component::Index => "index",
+            Component::Filter => "filter",
+            Component::Checksums => "checksum",
+            Component::Digest => "digest",
+            Component::Chunks => "chunk table",
+            Component::Stats => "stats",
+        }
+    }
+
+    fn magic(&self) -> &'static [u8; 4] {
+        match self {
+            Component::Data => b"RCSD",
+            Component::Index => b"RCSI",
+            Component::Filter => b"RCSF",
+            Component::Checksums => b"RCSC",
+            Component::Digest => b"RCSG",
+            Component::Chunks => b"RCSK",
+            Component::Stats => b"RCSS",
+        }
+    }
+}
+
+
+/// Every sstable component starts with a header: the component's magic number (4 bytes) and the
+///  format version (u16), followed by the schema of the table the sstable was written for.
+///
+/// The schema is the table's id (uuid) and name (utf8), the partition key's column index (u32),
+///  the number of cluster keys (u32) followed by each one's column index (u32) and (since version
+///  2) clustering order (u8), and the number of columns (u32) followed by each column's id (uuid), name (utf8)
+///  and type.
+pub struct ComponentHeader {
+    pub version: u16,
+    /// the schema the component was written with, without the table's options
+    pub table_metadata: TableMetaData,
+    /// offset of the component's contents following the header
+    body_offset: usize,
+}
+
+impl ComponentHeader {
+    pub fn read(buf: &[u8], component: Component) -> DecodeResult<ComponentHeader> {
+        let mut r = CassRead::wrap(buf);
+        if buf.len() < 4 || &buf[..4] != component.magic() {
+            return Err(DecodeError::new(0, DecodeErrorKind::InvalidMagic { component: component.name() }));
+        }
+        r.pos = 4;
+
+        let version = r.read_u16()?;
+        // versions that encode the header differently are decoded here
+        let table_metadata = match version {
+            MIN_FORMAT_VERSION..=FORMAT_VERSION => read_table_metadata(&mut r, version)?,
+            _ => return Err(DecodeError::new(4, DecodeErrorKind::UnsupportedVersion { version, min: MIN_FORMAT_VERSION, max: FORMAT_VERSION })),
+        };
+
+        Ok(ComponentHeader { version, table_metadata, body_offset: r.pos })
+    }
+
+    /// writes a header with the current format version
+    pub fn write<W>(out: &mut CassWrite<W>, component: Component, table_metadata: &TableMetaData) -> std::io::Result<()> where W: Write+Seek {
+        ComponentHeader::write_version(out, component, table_metadata, FORMAT_VERSION)
+    }
+
+    /// writes a header with an older format version, the component's contents must be encoded
+    ///  for that version as well
+    pub fn write_version<W>(out: &mut CassWrite<W>, component: Component, table_metadata: &TableMetaData, version: u16) -> std::io::Result<()> where W: Write+Seek {
+        out.write_raw(component.magic())?;
+        out.write_u16(version)?;
+
+        out.write_uuid(&table_metadata.id)?;
+        out.write_utf8(&table_metadata.name)?;
+        out.write_u32(table_metadata.idx_partition_key as u32)?;
+        out.write_u32(table_metadata.idx_cluster_keys.len() as u32)?;
+        for (idx, order) in table_metadata.idx_cluster_keys.iter().zip(table_metadata.clustering_orders.iter()) {
+            out.write_u32(*idx as u32)?;
+            if version >= CLUSTER_ORDER_VERSION {
+                out.write_u8(match order {
+                    ClusteringOrder::Ascending => ID_ORDER_ASCENDING,
+                    ClusteringOrder::Descending => ID_ORDER_DESCENDING,
+                })?;
+            }
+        }
+        out.write_u32(table_metadata.columns.len() as u32)?;
+        for col in table_metadata.columns.iter() {
+            out.write_uuid(&col.id)?;
+            out.write_utf8(&col.name)?;
+            write_column_type(out, &col.col_type)?;
+        }
+        Ok(())
+    }
+
+    pub fn body_offset(&self) -> usize {
+        self.body_offset
+    }
+
+    /// Rows are decoded with the current schema, so the component must have been written for the
+    ///  same table with the same key columns and clustering orders. Regular columns may have been
+    ///  added or dropped since.
+    pub fn check_table(&self, table_metadata: &TableMetaData) -> DecodeResult<()> {
+        let written = &self.table_metadata;
+        if written.id != table_metadata.id {
+            return Err(DecodeError::new(6, DecodeErrorKind::SchemaMismatch("table")));
+        }
+        let key_ids = |t: &TableMetaData| {
+            let mut result = vec!(t.partition_key().id);
+            result.extend((0..t.idx_cluster_keys.len()).map(|idx| t.cluster_key(idx).id));
+            result
+        };
+        if key_ids(written) != key_ids(table_metadata) {
+            return Err(DecodeError::new(6, DecodeErrorKind::SchemaMismatch("primary key")));
+        }
+        if written.clustering_orders != table_metadata.clustering_orders {
+            return Err(DecodeError::new(6, DecodeErrorKind::SchemaMismatch("clustering order")));
+        }
+        Ok(())
+    }
+}
+
+fn read_table_metadata(r: &mut CassRead, version: u16) -> DecodeResult<TableMetaData> {
+    let id = r.read_uuid()?;
+    let name = r.read_utf8()?.to_string();
+
+    let idx_partition_key = r.read_u32()? as usize;
+    let mut idx_cluster_keys = Vec::new();
+    let mut clustering_orders = Vec::new();
+    for _ in 0..r.read_u32()? {
+        idx_cluster_keys.push(r.read_u32()? as usize);
+        // older versions only had ascending cluster keys
+        if version < CLUSTER_ORDER_VERSION {
+            clustering_orders.push(ClusteringOrder::Ascending);
+            continue;
+        }
+        let offset = r.pos;
+        clustering_orders.push(match r.read_u8()? {
+            ID_ORDER_ASCENDING => ClusteringOrder::Ascending,
+            ID_ORDER_DESCENDING => ClusteringOrder::Descending,
+            n => return Err(DecodeError::invalid_tag(offset, "clustering order", &[ID_ORDER_ASCENDING, ID_ORDER_DESCENDING], n)),
+        });
+    }
+
+    let mut columns = Vec::new();
+    for _ in 0..r.read_u32()? {
+        columns.push(Arc::new(ColumnMetaData {
+            id: r.read_uuid()?,
+            name: r.read_utf8()?.to_string(),
+            col_type: read_column_type(r)?,
+        }));
+    }
+
+    let offset = r.pos;
+    if idx_partition_key >= columns.len() || idx_cluster_keys.iter().any(|idx| *idx >= columns.len()) {
+        return Err(DecodeError::new(offset, DecodeErrorKind::InvalidValue("key column index")));
+    }
+    Ok(TableMetaData::new(name, id, columns, idx_partition_key, idx_cluster_keys).with_clustering_orders(clustering_orders))
+}
+
+fn write_column_type<W>(out: &mut CassWrite<W>, col_type: &ColumnType) -> std::io::Result<()> where W: Write+Seek {
+    match col_type {
+        ColumnType::Text => out.write_u8(ID_TYPE_TEXT),
+        ColumnType::Uuid => out.write_u8(ID_TYPE_UUID),
+        ColumnType::Int => out.write_u8(ID_TYPE_INT),
+        ColumnType::Long => out.write_u8(ID_TYPE_LONG),
+        ColumnType::Timestamp => out.write_u8(ID_TYPE_TIMESTAMP),
+        ColumnType::Boolean => out.write_u8(ID_TYPE_BOOLEAN),
+        ColumnType::Float => out.write_u8(ID_TYPE_FLOAT),
+        ColumnType::Double => out.write_u8(ID_TYPE_DOUBLE),
+        ColumnType::Decimal => out.write_u8(ID_TYPE_DECIMAL),
+        ColumnType::Varint => out.write_u8(ID_TYPE_VARINT),
+        ColumnType::Blob => out.write_u8(ID_TYPE_BLOB),
+        ColumnType::Inet => out.write_u8(ID_TYPE_INET),
+        ColumnType::Date => out.write_u8(ID_TYPE_DATE),
+        ColumnType::Time => out.write_u8(ID_TYPE_TIME),
+        ColumnType::Duration => out.write_u8(ID_TYPE_DURATION),
+        ColumnType::Tuple(types) => {
+            out.write_u8(ID_TYPE_TUPLE)?;
+            out.write_u32(types.len() as u32)?;
+            for t in types.iter() {
+                write_column_type(out, t)?;
+            }
+            Ok(())
+        },
+        ColumnType::List(element) => {
+            out.write_u8(ID_TYPE_LIST)?;
+            write_column_type(out, element)
+        },
+        ColumnType::Set(element) => {
+            out.write_u8(ID_TYPE_SET)?;
+            write_column_type(out, element)
+        },
+        ColumnType::Map(key, value) => {
+            out.write_u8(ID_TYPE_MAP)?;
+            write_column_type(out, key)?;
+            write_column_type(out, value)
+        },
+        // user types are written with their definition, i.e. name and fields
+        ColumnType::UserType(user_type) => {
+            out.write_u8(ID_TYPE_USER_TYPE)?;
+            out.write_utf8(&user_type.name)?;
+            out.write_u32(user_type.fields.len() as u32)?;
+            for field in user_type.fields.iter() {
+                out.write_utf8(&field.name)?;
+                write_column_type(out, &field.field_type)?;
+            }
+            Ok(())
+        },
+    }
+}
+
+fn read_column_type(r: &mut CassRead) -> DecodeResult<ColumnType> {
+    let offset = r.pos;
+    match r.read_u8()? {
+        ID_TYPE_TEXT => Ok(ColumnType::Text),
+        ID_TYPE_UUID => Ok(ColumnType::Uuid),
+        ID_TYPE_INT => Ok(ColumnType::Int),
+        ID_TYPE_LONG => Ok(ColumnType::Long),
+        ID_TYPE_TIMESTAMP => Ok(ColumnType::Timestamp),
+        ID_TYPE_BOOLEAN => Ok(ColumnType::Boolean),
+        ID_TYPE_FLOAT => Ok(ColumnType::Float),
+        ID_TYPE_DOUBLE => Ok(ColumnType::Double),
+        ID_TYPE_DECIMAL => Ok(ColumnType::Decimal),
+        ID_TYPE_VARINT => Ok(ColumnType::Varint),
+        ID_TYPE_BLOB => Ok(ColumnType::Blob),
+        ID_TYPE_INET => Ok(ColumnType::Inet),
+        ID_TYPE_DATE => Ok(ColumnType::Date),
+        ID_TYPE_TIME => Ok(ColumnType::Time),
+        ID_TYPE_DURATION => Ok(ColumnType::Duration),
+        ID_TYPE_TUPLE => {
+            let mut types = Vec::new();
+            for _ in 0..r.read_u32()? {
+                types.push(read_column_type(r)?);
+            }
+            Ok(ColumnType::Tuple(types))
+        },
+        ID_TYPE_LIST => Ok(ColumnType::List(Box::new(read_column_type(r)?))),
+        ID_TYPE_SET => Ok(ColumnType::Set(Box::new(read_column_type(r)?))),
+        ID_TYPE_MAP => {
+            let key = read_column_type(r)?;
+            Ok(ColumnType::Map(Box::new(key), Box::new(read_column_type(r)?)))
+        },
+        ID_TYPE_USER_TYPE => {
+            let name = r.read_utf8()?.to_string();
+            let mut fields = Vec::new();
+            for _ in 0..r.read_u32()? {
+                fields.push(UserTypeField {
+                    name: r.read_utf8()?.to_string(),
+                    field_type: read_column_type(r)?,
+                });
+            }
+            Ok(ColumnType::UserType(Arc::new(UserType { name, fields })))
+        },
+        n => Err(DecodeError::invalid_tag(offset, "column type", COLUMN_TYPE_IDS, n)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    use uuid::Uuid;
+
+    use crate::db::{ClusteringOrder, ColumnType, TableMetaData};
+    use crate::io::{CassWrite, DecodeError, DecodeErrorKind};
+    use crate::sstable::header::{Component, ComponentHeader, FORMAT_VERSION, MIN_FORMAT_VERSION};
+    use crate::sstable::row_data::RowDataFileCreator;
+    use crate::sstable::testing::{column, TempFolder};
+    use crate::sstable::SstableMetaData;
+
+    fn table_metadata() -> TableMetaData {
+        let columns = vec!(
+            column("ck", ColumnType::Timestamp),
+            column("id", ColumnType::Uuid),
+            column("tags", ColumnType::Map(Box::new(ColumnType::Text), Box::new(ColumnType::List(Box::new(ColumnType::Long))))),
+            column("v", ColumnType::Tuple(vec!(ColumnType::Text, ColumnType::Int))),
+        );
+        TableMetaData::new("t".to_string(), Uuid::new_v4(), columns, 1, vec!(0))
+    }
+
+    fn write_header(component: Component, table_metadata: &TableMetaData) -> Vec<u8> {
+        let mut out = CassWrite::new(Cursor::new(Vec::new()));
+        ComponentHeader::write(&mut out, component, table_metadata).unwrap();
+        out.write_u64(12345).unwrap();
+        out.into_inner().into_inner()
+    }
+
+    #[test]
+    pub fn test_header() {
+        let table_metadata = table_metadata();
+        let buf = write_header(Component::Index, &table_metadata);
+
+        let header = ComponentHeader::read(&buf, Component::Index).unwrap();
+        assert_eq!(FORMAT_VERSION, header.version);
+        assert_eq!(buf.len() - 8, header.body_offset());
+        assert_eq!(table_metadata.id, header.table_metadata.id);
+        assert_eq!(vec!(0), header.table_metadata.idx_cluster_keys);
+        assert_eq!(format!("{:?}", table_metadata.columns), format!("{:?}", header.table_metadata.columns));
+        assert!(header.check_table(&table_metadata).is_ok());
+
+        let e = header.check_table(&self::table_metadata()).err().unwrap();
+        assert_eq!(DecodeErrorKind::SchemaMismatch("table"), e.kind);
+        let other_key = TableMetaData::new("t".to_string(), table_metadata.id, table_metadata.columns.clone(), 2, vec!(0));
+        assert_eq!(DecodeErrorKind::SchemaMismatch("primary key"), header.check_table(&other_key).err().unwrap().kind);
+        let other_order = TableMetaData::new("t".to_string(), table_metadata.id, table_metadata.columns.clone(), 1, vec!(0))
+            .with_clustering_orders(vec!(ClusteringOrder::Descending));
+        assert_eq!(DecodeErrorKind::SchemaMismatch("clustering order"), header.check_table(&other_order).err().unwrap().kind);
+        let descending = ComponentHeader::read(&write_header(Component::Index, &other_order), Component::Index).unwrap();
+        assert_eq!(vec!(ClusteringOrder::Descending), descending.table_metadata.clustering_orders);
+
+        // version 1 headers have no clustering orders, all cluster keys were ascending
+        let mut out = CassWrite::new(Cursor::new(Vec::new()));
+        ComponentHeader::write_version(&mut out, Component::Index, &other_order, 1).unwrap();
+        let v1 = ComponentHeader::read(&out.into_inner().into_inner(), Component::Index).unwrap();
+        assert_eq!(1, v1.version);
+        assert_eq!(header.body_offset() - 1, v1.body_offset());
+        assert_eq!(vec!(ClusteringOrder::Ascending), v1.table_metadata.clustering_orders);
+
+        let e = ComponentHeader::read(&buf, Component::Data).err().unwrap();
+        assert_eq!(DecodeErrorKind::InvalidMagic { component: "data" }, e.kind);
+        assert!(ComponentHeader::read(&[], Component::Data).is_err());
+
+        let mut newer = buf.clone();
+        newer[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_be_bytes());
+        let e = ComponentHeader::read(&newer, Component::Index).err().unwrap();
+        assert_eq!(DecodeErrorKind::UnsupportedVersion { version: FORMAT_VERSION + 1, min: MIN_FORMAT_VERSION, max: FORMAT_VERSION }, e.kind);
+        assert_eq!(4, e.offset);
+        let mut older = buf.clone();
+        older[4..6].copy_from_slice(&(MIN_FORMAT_VERSION - 1).to_be_bytes());
+        assert!(matches!(ComponentHeader::read(&older, Component::Index).err().unwrap().kind, DecodeErrorKind::UnsupportedVersion { .. }));
+    }
+
+    fn decode_error_kind(e: std::io::Error) -> DecodeErrorKind {
+        e.get_ref().and_then(|e| e.downcast_ref::<DecodeError>()).unwrap().kind.clone()
+    }
+
+    #[test]
+    pub fn test_data_file_header() {
+        let table_metadata = Arc::new(table_metadata());
+        let folder = TempFolder::new();
+        let sstable = SstableMetaData::new(table_metadata.clone(), folder.path());
+        RowDataFileCreator::new(sstable.clone()).unwrap().finalize().unwrap();
+        assert!(sstable.read_data().unwrap().data().is_empty());
+        assert_eq!(Some(None), sstable.token_range().ok());
+
+        let other_key = TableMetaData::new("t".to_string(), table_metadata.id, table_metadata.columns.clone(), 2, vec!(0));
+        let other_key = SstableMetaData::existing(Arc::new(other_key), sstable.folder(), sstable.uuid());
+        assert_eq!(DecodeErrorKind::SchemaMismatch("primary key"), decode_error_kind(other_key.read_data().err().unwrap()));
+
+        let mut data = std::fs::read(sstable.data_filename()).unwrap();
+        data[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_be_bytes());
+        std::fs::write(sstable.data_filename(), &data).unwrap();
+        assert!(matches!(decode_error_kind(sstable.read_data().err().unwrap()), DecodeErrorKind::UnsupportedVersion { .. }));
+
+        // components can not be mixed up
+        std::fs::copy(sstable.filter_filename(), sstable.index_filename()).unwrap();
+        assert_eq!(DecodeErrorKind::InvalidMagic { component: "index" }, decode_error_kind(sstable.token_range().err().unwrap()));
+    }
+}
@@ -1,17 +1,18 @@
 
-use crate::io::{CassWrite, CassSerializer, CassRead, CassDeserializer};
+use crate::io::{CassWrite, CassSerializer, CassRead, CassDeserializer, DecodeError, DecodeResult};
 use std::io::{BufWriter, Write, Seek};
 use std::fs::File;
 use std::path::{Path, PathBuf};
 use std::borrow::Borrow;
 use std::marker::PhantomData;
+use std::ops::Bound;
 
 
 const ID_LEAF_NODE: u8 = 0;
 const ID_BRANCH_NODE: u8 = 1;
 
 pub struct IndexFileCreator<K,V,W,SK,SV,SO>
-        where W: Write+Seek, K: Copy, SK: CassSerializer<K>, SV: CassSerializer<V>, SO: CassSerializer<u64> {
+        where W: Write+Seek, SK: CassSerializer<K>, SV: CassSerializer<V>, SO: CassSerializer<u64> {
     state: IndexFileCreatorState<K,V>,
     io: IndexFileCreatorIo<K,V,SK,SV,SO,W>,
     _sk: PhantomData<*const SK>,
@@ -25,7 +26,7 @@ struct IndexFileCreatorState<K,V> {
     branch_stack: Vec<CreatorBranchNode<K>>, // current hierarchy of partially filled branches. Root goes first, deepest branch goes last
 }
 
-struct IndexFileCreatorIo<K,V,SK,SV,SO,W> where W: Write+Seek, K: Copy,
+struct IndexFileCreatorIo<K,V,SK,SV,SO,W> where W: Write+Seek,
                                                 SK: CassSerializer<K>, SV: CassSerializer<V>, SO: CassSerializer<u64> {
     out: CassWrite<W>,
     _k: PhantomData<*const K>,
@@ -36,27 +37,27 @@ struct IndexFileCreatorIo<K,V,SK,SV,SO,W> where W: Write+Seek, K: Copy,
 }
 
 impl <K,V,SK,SV,SO,W> IndexFileCreatorIo<K,V,SK,SV,SO,W> where W: Write+Seek,
-                                                               K: Copy,
                                                                SK: CassSerializer<K>,
                                                                SV: CassSerializer<V>,
                                                                SO: CassSerializer<u64>,
 {
 
-    fn write_branch(&mut self, n: &CreatorBranchNode<K>) -> std::io::Result<(K, u64)> {
+    /// writes a node and returns its first key (which the parent node uses to reference it) together with its offset
+    fn write_branch(&mut self, n: CreatorBranchNode<K>) -> std::io::Result<(K, u64)> {
         let result = self.out.position()?;
 
         self.out.write_u8(ID_BRANCH_NODE)?;
         self.out.write_u16(n.kvs.len() as u16)?;
         for (k,v) in n.kvs.iter() {
             SK::ser(&mut self.out, k)?;
-            SO::ser(&mut self.out, v);
+            SO::ser(&mut self.out, v)?;
         }
 
-        let (k,v) = n.kvs.first().unwrap();
-        Ok((*k, result))
+        let (k, _) = n.kvs.into_iter().next().unwrap();
+        Ok((k, result))
     }
 
-    fn write_leaf(&mut self, n: &CreatorLeafNode<K,V>) -> std::io::Result<(K, u64)> {
+    fn write_leaf(&mut self, n: CreatorLeafNode<K,V>) -> std::io::Result<(K, u64)> {
         let result = self.out.position()?;
 
         self.out.write_u8(ID_LEAF_NODE)?;
@@ -66,14 +67,13 @@ impl <K,V,SK,SV,SO,W> IndexFileCreatorIo<K,V,SK,SV,SO,W> where W: Write+Seek,
             SV::ser(&mut self.out, v)?;
         }
 
-        let (k,v) = n.kvs.first().unwrap();
-        Ok((*k, result))
+        let (k, _) = n.kvs.into_iter().next().unwrap();
+        Ok((k, result))
     }
 
 }
 
 impl <K,V,W,SK,SV,SO> IndexFileCreator<K,V,W,SK,SV,SO> where W:Write+Seek,
-                                                             K: Copy,
                                                              SK: CassSerializer<K>,
                                                              SV: CassSerializer<V>,
                                                              SO: CassSerializer<u64>, {
@@ -109,7 +109,7 @@ impl <K,V,W,SK,SV,SO> IndexFileCreator<K,V,W,SK,SV,SO> where W:Write+Seek,
             Some(n) => {
                 if n.kvs.len() >= self.state.arity {
                     // leaf node is full
-                    self.flush_leaf();
+                    self.flush_leaf()?;
                     self.add_entry(key, value)?;
                 }
                 else {
@@ -121,18 +121,23 @@ impl <K,V,W,SK,SV,SO> IndexFileCreator<K,V,W,SK,SV,SO> where W:Write+Seek,
         Ok(())
     }
 
-    /// returns the root node offset (None means 'empty index')
-    pub fn finalize(mut self) -> std::io::Result<Option<u64>> {
+    /// returns the root node offset (None means 'empty index') and the underlying writer
+    pub fn finalize(mut self) -> std::io::Result<(Option<u64>, W)> {
+        let root_offset = self.write_remaining_nodes()?;
+        Ok((root_offset, self.io.out.into_inner()))
+    }
+
+    fn write_remaining_nodes(&mut self) -> std::io::Result<Option<u64>> {
         // flush all nodes to disk, even if they are not full yet
-        let l = self.state.cur_leaf.as_ref();
+        let l = self.state.cur_leaf.take();
         let mut cur_children: Vec<(K,u64)> = match l {
             None => {
                 let br = self.state.branch_stack.pop();
-                match &br  {
+                match br  {
                     None => {
                         return Ok(None)
                     },
-                    Some(ref bn) => {
+                    Some(bn) => {
                         vec!(self.io.write_branch(bn)?)
                     }
                 }
@@ -143,8 +148,8 @@ impl <K,V,W,SK,SV,SO> IndexFileCreator<K,V,W,SK,SV,SO> where W:Write+Seek,
         };
 
         loop {
-            let mut cur_branch = self.state.branch_stack.pop();
-            match &mut cur_branch {
+            let cur_branch = self.state.branch_stack.pop();
+            match cur_branch {
                 None => {
                     // we reached the top
                     match cur_children.as_slice() {
@@ -156,14 +161,14 @@ impl <K,V,W,SK,SV,SO> IndexFileCreator<K,V,W,SK,SV,SO> where W:Write+Seek,
                             // more than one child -> create new node and return it as root
                             let root = CreatorBranchNode {
                                 level: 1, // not used here -> arbitrary value
-                                kvs: cur_children.clone()
+                                kvs: cur_children
                             };
-                            let (k,offs) = self.io.write_branch(&root)?;
+                            let (_, offs) = self.io.write_branch(root)?;
                             return Ok(Some(offs))
                         }
                     }
                 },
-                Some(branch) => {
+                Some(mut branch) => {
                     while branch.kvs.len() < self.state.arity && !cur_children.is_empty() {
                         branch.kvs.push(cur_children.remove(0));
                     }
@@ -177,7 +182,7 @@ impl <K,V,W,SK,SV,SO> IndexFileCreator<K,V,W,SK,SV,SO> where W:Write+Seek,
     }
 
     fn flush_leaf(&mut self) -> std::io::Result<()>{
-        let cur_leaf = self.state.cur_leaf.as_ref();
+        let cur_leaf = self.state.cur_leaf.take();
 
         match cur_leaf {
             None => {
@@ -185,19 +190,19 @@ impl <K,V,W,SK,SV,SO> IndexFileCreator<K,V,W,SK,SV,SO> where W:Write+Seek,
             },
             Some(ln) => {
                 let kv = self.io.write_leaf(ln)?;
-                self.bubble_up_rec(0, &kv)
+                self.bubble_up_rec(0, kv)
             }
         }
     }
 
-    fn bubble_up_rec(&mut self, cur_child_level: usize, cur_child: &(K,u64)) -> std::io::Result<()> {
-        let mut cur_branch = self.state.branch_stack.pop();
+    fn bubble_up_rec(&mut self, cur_child_level: usize, cur_child: (K,u64)) -> std::io::Result<()> {
+        let cur_branch = self.state.branch_stack.pop();
         match cur_branch {
             None => {
                 // we have a child but no place to put the reference --> create a new root node
                 self.state.branch_stack.push(CreatorBranchNode {
                     level: cur_child_level+1,
-                    kvs: vec!(*cur_child),
+                    kvs: vec!(cur_child),
                 });
             },
             Some(mut branch) => {
@@ -207,23 +212,23 @@ impl <K,V,W,SK,SV,SO> IndexFileCreator<K,V,W,SK,SV,SO> where W:Write+Seek,
                     self.state.branch_stack.push(branch);
                     self.state.branch_stack.push(CreatorBranchNode {
                         level: cur_child_level+1,
-                        kvs: vec!(*cur_child),
+                        kvs: vec!(cur_child),
                     });
                 }
                 else if branch.kvs.len() < self.state.arity {
                     // there is room -> add to existing branch
-                    branch.kvs.push(*cur_child);
+                    branch.kvs.push(cur_child);
                     self.state.branch_stack.push(branch);
                 }
                 else {
                     // current branch node is full -> flush to disk
-                    let flushed_node = self.io.write_branch(&branch)?;
-                    self.bubble_up_rec(cur_child_level+1, &flushed_node)?;
+                    let flushed_node = self.io.write_branch(branch)?;
+                    self.bubble_up_rec(cur_child_level+1, flushed_node)?;
 
                     // now we add a new branch node for the new child
                     self.state.branch_stack.push(CreatorBranchNode {
                         level: cur_child_level+1,
-                        kvs: vec!(*cur_child),
+                        kvs: vec!(cur_child),
                     });
                 }
             }
@@ -241,23 +246,470 @@ struct CreatorBranchNode<K> {
 }
 
 
-pub struct IndexFileSearcher<'a,K,V,DK,DV,DO> where DK: CassDeserializer<K>, DV: CassDeserializer<V>, DO: CassDeserializer<u64> {
-    r: CassRead<'a>,
-    arity: usize,
-    root_offset: u64,
-    des_k: DK,
-    des_v: DV,
-    des_o: DO,
+/// Searches an index file that was written by an `IndexFileCreator`, starting at the root node
+///  offset returned by `IndexFileCreator::finalize()`.
+///
+/// Lookups take keys by reference to a 'borrowed' form of the index' key type like `BTreeMap` does,
+///  so an index with `&[u8]` keys pointing into the index file can be searched for any `[u8]`.
+pub struct IndexFileSearcher<'a,K,V,DK,DV,DO> where DK: CassDeserializer<'a,K>, DV: CassDeserializer<'a,V>, DO: CassDeserializer<'a,u64> {
+    buf: &'a [u8],
+    root_offset: Option<u64>,
     _k: PhantomData<*const K>,
     _v: PhantomData<*const V>,
+    _dk: PhantomData<*const DK>,
+    _dv: PhantomData<*const DV>,
+    _do: PhantomData<*const DO>,
+}
+
+impl <'a,K,V,DK,DV,DO> IndexFileSearcher<'a,K,V,DK,DV,DO> where DK: CassDeserializer<'a,K>, DV: CassDeserializer<'a,V>, DO: CassDeserializer<'a,u64> {
+    /// root_offset 'None' means 'empty index'
+    pub fn new(buf: &'a [u8], root_offset: Option<u64>) -> IndexFileSearcher<'a,K,V,DK,DV,DO> {
+        IndexFileSearcher {
+            buf,
+            root_offset,
+            _k: PhantomData,
+            _v: PhantomData,
+            _dk: PhantomData,
+            _dv: PhantomData,
+            _do: PhantomData,
+        }
+    }
+
+    pub fn find_exact<Q>(&self, key: &Q) -> DecodeResult<Option<V>> where K: Borrow<Q>, Q: Ord + ?Sized {
+        let mut r = CassRead::wrap(self.buf);
+        r.pos = match self.root_offset {
+            Some(offs) => offs as usize,
+            None => return Ok(None),
+        };
+
+        loop {
+            let offset = r.pos;
+            match r.read_u8()? {
+                ID_BRANCH_NODE => {
+                    // a branch node's keys are the first keys of its children, so we descend into the
+                    //  last child whose key is not greater than the key we are looking for
+                    let num_children = r.read_u16()?;
+                    let mut child_offset = None;
+                    for _ in 0..num_children {
+                        let k = DK::deser(&mut r)?;
+                        let offs = DO::deser(&mut r)?;
+                        if k.borrow() > key {
+                            break;
+                        }
+                        child_offset = Some(offs);
+                    }
+                    r.pos = match child_offset {
+                        Some(offs) => offs as usize,
+                        None => return Ok(None),
+                    };
+                },
+                ID_LEAF_NODE => {
+                    let num_entries = r.read_u16()?;
+                    for _ in 0..num_entries {
+                        let k = DK::deser(&mut r)?;
+                        let v = DV::deser(&mut r)?;
+                        if k.borrow() == key {
+                            return Ok(Some(v));
+                        }
+                        if k.borrow() > key {
+                            return Ok(None);
+                        }
+                    }
+                    return Ok(None);
+                },
+                n => return Err(DecodeError::invalid_tag(offset, "index node", &[ID_LEAF_NODE, ID_BRANCH_NODE], n)),
+            }
+        }
+    }
+
+
+    /// Iterates over all entries with keys between the two bounds, in ascending or descending
+    ///  key order.
+    pub fn range<Q>(&self, lower: Bound<&Q>, upper: Bound<&Q>, ascending: bool) -> IndexRangeIterator<'a,K,Q,V,DK,DV,DO>
+            where K: Borrow<Q>, Q: Ord + ToOwned + ?Sized {
+        self.range_owned(to_owned_bound(lower), to_owned_bound(upper), ascending)
+    }
+
+    /// Iterates over all entries with byte string keys starting with a given prefix, in ascending
+    ///  or descending key order.
+    pub fn prefix(&self, prefix: &[u8], ascending: bool) -> IndexRangeIterator<'a,K,[u8],V,DK,DV,DO> where K: Borrow<[u8]> {
+        let upper = match prefix_successor(prefix) {
+            Some(s) => Bound::Excluded(s),
+            None => Bound::Unbounded,
+        };
+        self.range_owned(Bound::Included(prefix.to_vec()), upper, ascending)
+    }
+
+    fn range_owned<Q>(&self, lower: Bound<Q::Owned>, upper: Bound<Q::Owned>, ascending: bool) -> IndexRangeIterator<'a,K,Q,V,DK,DV,DO>
+            where K: Borrow<Q>, Q: Ord + ToOwned + ?Sized {
+        let mut result = IndexRangeIterator {
+            buf: self.buf,
+            lower,
+            upper,
+            ascending,
+            stack: Vec::new(),
+            error: None,
+            _k: PhantomData,
+            _dk: PhantomData,
+            _dv: PhantomData,
+            _do: PhantomData,
+        };
+
+        if let Some(root_offset) = self.root_offset {
+            if let Err(e) = result.push_node(root_offset) {
+                result.error = Some(e);
+            }
+        }
+        result
+    }
+}
+
+fn to_owned_bound<Q>(b: Bound<&Q>) -> Bound<Q::Owned> where Q: ToOwned + ?Sized {
+    match b {
+        Bound::Unbounded => Bound::Unbounded,
+        Bound::Included(k) => Bound::Included(k.to_owned()),
+        Bound::Excluded(k) => Bound::Excluded(k.to_owned()),
+    }
 }
 
-impl <'a,K,V,DK,DV,DO> IndexFileSearcher<'a,K,V,DK,DV,DO> where DK: CassDeserializer<K>, DV: CassDeserializer<V>, DO: CassDeserializer<u64> {
-    pub fn find_exact(self, key: K) -> Option<V> {
+/// the smallest byte string that is greater than all byte strings starting with a given prefix, or
+///  None if there is no such byte string (i.e. the prefix consists only of 0xFF)
+fn prefix_successor(prefix: &[u8]) -> Option<Vec<u8>> {
+    let mut result = prefix.to_vec();
+    while let Some(last) = result.pop() {
+        if last < u8::MAX {
+            result.push(last + 1);
+            return Some(result);
+        }
+    }
+    None
+}
+
+/// a node's remaining entries in *reverse* visiting order, i.e. the next entry to visit is the last
+enum RangeIterNode<K,V> {
+    Branch(Vec<(K,u64)>),
+    Leaf(Vec<(K,V)>),
+}
+
+pub struct IndexRangeIterator<'a,K,Q,V,DK,DV,DO> where Q: ToOwned + ?Sized, DK: CassDeserializer<'a,K>, DV: CassDeserializer<'a,V>, DO: CassDeserializer<'a,u64> {
+    buf: &'a [u8],
+    lower: Bound<Q::Owned>,
+    upper: Bound<Q::Owned>,
+    ascending: bool,
+    stack: Vec<RangeIterNode<K,V>>, // root goes first, the node that is currently visited goes last
+    /// a decode error that is returned by the next call to `next()`, which ends the iteration
+    error: Option<DecodeError>,
+    _k: PhantomData<*const K>,
+    _dk: PhantomData<*const DK>,
+    _dv: PhantomData<*const DV>,
+    _do: PhantomData<*const DO>,
+}
+
+impl <'a,K,Q,V,DK,DV,DO> IndexRangeIterator<'a,K,Q,V,DK,DV,DO>
+        where K: Borrow<Q>, Q: Ord + ToOwned + ?Sized, DK: CassDeserializer<'a,K>, DV: CassDeserializer<'a,V>, DO: CassDeserializer<'a,u64> {
+    fn is_below_lower(&self, key: &K) -> bool {
+        match &self.lower {
+            Bound::Unbounded => false,
+            Bound::Included(l) => key.borrow() < l.borrow(),
+            Bound::Excluded(l) => key.borrow() <= l.borrow(),
+        }
+    }
+
+    fn is_above_upper(&self, key: &K) -> bool {
+        match &self.upper {
+            Bound::Unbounded => false,
+            Bound::Included(u) => key.borrow() > u.borrow(),
+            Bound::Excluded(u) => key.borrow() >= u.borrow(),
+        }
+    }
+
+    /// a child's entries are all less than the next child's key
+    fn is_before_lower(&self, next_child_key: &K) -> bool {
+        match &self.lower {
+            Bound::Unbounded => false,
+            Bound::Included(l) | Bound::Excluded(l) => next_child_key.borrow() <= l.borrow(),
+        }
+    }
 
+    fn push_node(&mut self, offset: u64) -> DecodeResult<()> {
+        let mut r = CassRead::wrap(self.buf);
+        r.pos = offset as usize;
 
+        let node = match r.read_u8()? {
+            ID_BRANCH_NODE => {
+                let num_children = r.read_u16()?;
+                let mut children: Vec<(K,u64)> = Vec::with_capacity(num_children as usize);
+                for _ in 0..num_children {
+                    let k = DK::deser(&mut r)?;
+                    let offs = DO::deser(&mut r)?;
 
+                    if self.is_above_upper(&k) {
+                        break;
+                    }
+                    if children.last().is_some() && self.is_before_lower(&k) {
+                        children.pop();
+                    }
+                    children.push((k, offs));
+                }
+                if self.ascending {
+                    children.reverse();
+                }
+                RangeIterNode::Branch(children)
+            },
+            ID_LEAF_NODE => {
+                let num_entries = r.read_u16()?;
+                let mut entries = Vec::with_capacity(num_entries as usize);
+                for _ in 0..num_entries {
+                    let k = DK::deser(&mut r)?;
+                    let v = DV::deser(&mut r)?;
+
+                    if self.is_above_upper(&k) {
+                        break;
+                    }
+                    if !self.is_below_lower(&k) {
+                        entries.push((k, v));
+                    }
+                }
+                if self.ascending {
+                    entries.reverse();
+                }
+                RangeIterNode::Leaf(entries)
+            },
+            n => return Err(DecodeError::invalid_tag(offset as usize, "index node", &[ID_LEAF_NODE, ID_BRANCH_NODE], n)),
+        };
+
+        self.stack.push(node);
+        Ok(())
+    }
+}
 
-        None//TODO
+impl <'a,K,Q,V,DK,DV,DO> Iterator for IndexRangeIterator<'a,K,Q,V,DK,DV,DO>
+        where K: Borrow<Q>, Q: Ord + ToOwned + ?Sized, DK: CassDeserializer<'a,K>, DV: CassDeserializer<'a,V>, DO: CassDeserializer<'a,u64> {
+    type Item = DecodeResult<(K,V)>;
+
+    fn next(&mut self) -> Option<DecodeResult<(K,V)>> {
+        if let Some(e) = self.error.take() {
+            self.stack.clear();
+            return Some(Err(e));
+        }
+
+        loop {
+            let next_child = match self.stack.last_mut()? {
+                RangeIterNode::Leaf(entries) => {
+                    match entries.pop() {
+                        Some(kv) => return Some(Ok(kv)),
+                        None => None,
+                    }
+                },
+                RangeIterNode::Branch(children) => {
+                    children.pop().map(|(_, offs)| offs)
+                },
+            };
+
+            match next_child {
+                Some(offs) => if let Err(e) = self.push_node(offs) {
+                    self.stack.clear();
+                    return Some(Err(e));
+                },
+                None => {
+                    self.stack.pop();
+                },
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::ops::{Bound, RangeBounds};
+
+    use crate::io::{BytesSerializer, U64Serializer};
+    use crate::sstable::index::{IndexFileCreator, IndexFileSearcher};
+
+    fn create_index(arity: usize, keys: &[u64]) -> (Vec<u8>, Option<u64>) {
+        let mut creator: IndexFileCreator<u64, u64, _, U64Serializer, U64Serializer, U64Serializer> =
+            IndexFileCreator::new(arity, Cursor::new(Vec::new()));
+
+        for key in keys {
+            creator.add_entry(*key, key * 1000).unwrap();
+        }
+
+        let (root_offset, out) = creator.finalize().unwrap();
+        (out.into_inner(), root_offset)
+    }
+
+    #[test]
+    pub fn test_find_exact_empty() {
+        let (buf, root_offset) = create_index(4, &[]);
+        assert_eq!(None, root_offset);
+
+        let searcher: IndexFileSearcher<u64, u64, U64Serializer, U64Serializer, U64Serializer> =
+            IndexFileSearcher::new(&buf, root_offset);
+        assert_eq!(None, searcher.find_exact(&0).unwrap());
+        assert_eq!(None, searcher.find_exact(&1).unwrap());
+    }
+
+    #[test]
+    pub fn test_find_exact_single_leaf() {
+        let (buf, root_offset) = create_index(4, &[2, 4, 6]);
+        assert_eq!(Some(0), root_offset);
+
+        let searcher: IndexFileSearcher<u64, u64, U64Serializer, U64Serializer, U64Serializer> =
+            IndexFileSearcher::new(&buf, root_offset);
+        assert_eq!(Some(2000), searcher.find_exact(&2).unwrap());
+        assert_eq!(Some(4000), searcher.find_exact(&4).unwrap());
+        assert_eq!(Some(6000), searcher.find_exact(&6).unwrap());
+
+        assert_eq!(None, searcher.find_exact(&1).unwrap());
+        assert_eq!(None, searcher.find_exact(&3).unwrap());
+        assert_eq!(None, searcher.find_exact(&7).unwrap());
+    }
+
+    #[test]
+    pub fn test_find_exact_arities_and_depths() {
+        for arity in [2, 3, 4, 7, 16].iter() {
+            // covers trees from a single leaf up to several levels of branch nodes, with full and
+            //  partially filled nodes at every level
+            for num_entries in 0..300u64 {
+                let keys: Vec<u64> = (0..num_entries).map(|n| 2*n + 2).collect();
+                let (buf, root_offset) = create_index(*arity, &keys);
+
+                let searcher: IndexFileSearcher<u64, u64, U64Serializer, U64Serializer, U64Serializer> =
+                    IndexFileSearcher::new(&buf, root_offset);
+
+                for key in keys.iter() {
+                    assert_eq!(Some(key * 1000), searcher.find_exact(key).unwrap(), "arity {}, {} entries, key {}", arity, num_entries, key);
+                    assert_eq!(None, searcher.find_exact(&(key - 1)).unwrap(), "arity {}, {} entries, key {}", arity, num_entries, key - 1);
+                }
+                assert_eq!(None, searcher.find_exact(&(2*num_entries + 3)).unwrap());
+            }
+        }
+    }
+
+    fn bounds(key: u64) -> Vec<Bound<u64>> {
+        vec!(Bound::Unbounded, Bound::Included(key), Bound::Excluded(key))
+    }
+
+    #[test]
+    pub fn test_range() {
+        for arity in [2, 3, 5, 16].iter() {
+            for num_entries in [0u64, 1, 2, 5, 17, 90].iter() {
+                let keys: Vec<u64> = (0..*num_entries).map(|n| 2*n + 2).collect();
+                let (buf, root_offset) = create_index(*arity, &keys);
+
+                let searcher: IndexFileSearcher<u64, u64, U64Serializer, U64Serializer, U64Serializer> =
+                    IndexFileSearcher::new(&buf, root_offset);
+
+                let max_key = 2 * num_entries + 3;
+                for lower_key in (0..max_key).step_by(7) {
+                    for upper_key in (lower_key..max_key).step_by(11) {
+                        for lower in bounds(lower_key) {
+                            for upper in bounds(upper_key) {
+                                let expected: Vec<(u64,u64)> = keys.iter()
+                                    .filter(|&&k| (lower, upper).contains(&k))
+                                    .map(|&k| (k, k*1000))
+                                    .collect();
+
+                                let ascending: Vec<(u64,u64)> = searcher.range(lower.as_ref(), upper.as_ref(), true).map(Result::unwrap).collect();
+                                assert_eq!(expected, ascending, "arity {}, {} entries, {:?}..{:?}", arity, num_entries, lower, upper);
+
+                                let mut descending: Vec<(u64,u64)> = searcher.range(lower.as_ref(), upper.as_ref(), false).map(Result::unwrap).collect();
+                                descending.reverse();
+                                assert_eq!(expected, descending, "arity {}, {} entries, {:?}..{:?} descending", arity, num_entries, lower, upper);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// keys of very different lengths, including the empty key and keys sharing long prefixes
+    fn byte_keys() -> Vec<Vec<u8>> {
+        let mut result = vec!(
+            Vec::new(),
+            vec!(0u8),
+            vec!(0u8, 0),
+            b"a".to_vec(),
+            b"ab".to_vec(),
+            b"abc".to_vec(),
+            b"abd".to_vec(),
+            b"b".to_vec(),
+            vec!(b'b'; 5000),
+            vec!(255u8),
+            vec!(255u8, 255, 1),
+        );
+        for n in 0..100u32 {
+            let mut key = b"key-".to_vec();
+            key.extend_from_slice(format!("{:05}", n * 7).as_bytes());
+            key.extend(vec!(b'x'; n as usize));
+            result.push(key);
+        }
+        result.sort();
+        result
+    }
+
+    fn create_bytes_index(arity: usize, keys: &[Vec<u8>]) -> (Vec<u8>, Option<u64>) {
+        let mut creator: IndexFileCreator<Vec<u8>, u64, _, BytesSerializer, U64Serializer, U64Serializer> =
+            IndexFileCreator::new(arity, Cursor::new(Vec::new()));
+
+        for (idx, key) in keys.iter().enumerate() {
+            creator.add_entry(key.clone(), idx as u64).unwrap();
+        }
+
+        let (root_offset, out) = creator.finalize().unwrap();
+        (out.into_inner(), root_offset)
+    }
+
+    #[test]
+    pub fn test_variable_length_keys() {
+        let keys = byte_keys();
+
+        for arity in [2, 3, 16].iter() {
+            let (buf, root_offset) = create_bytes_index(*arity, &keys);
+            let searcher: IndexFileSearcher<&[u8], u64, BytesSerializer, U64Serializer, U64Serializer> =
+                IndexFileSearcher::new(&buf, root_offset);
+
+            for (idx, key) in keys.iter().enumerate() {
+                assert_eq!(Some(idx as u64), searcher.find_exact(key.as_slice()).unwrap());
+
+                let mut missing = key.clone();
+                missing.push(1);
+                if !keys.contains(&missing) {
+                    assert_eq!(None, searcher.find_exact(missing.as_slice()).unwrap());
+                }
+            }
+
+            let all: Vec<&[u8]> = searcher.range::<[u8]>(Bound::Unbounded, Bound::Unbounded, true).map(Result::unwrap).map(|(k,_)| k).collect();
+            assert_eq!(keys, all.iter().map(|k| k.to_vec()).collect::<Vec<_>>());
+
+            let from_b: Vec<u64> = searcher.range(Bound::Excluded(b"b".as_ref()), Bound::Included(b"key-00014".as_ref()), true).map(Result::unwrap).map(|(_,v)| v).collect();
+            let expected_from_b: Vec<u64> = keys.iter().enumerate()
+                .filter(|(_,k)| k.as_slice() > b"b".as_ref() && k.as_slice() <= b"key-00014".as_ref())
+                .map(|(idx,_)| idx as u64)
+                .collect();
+            assert_eq!(expected_from_b, from_b);
+        }
+    }
+
+    #[test]
+    pub fn test_prefix() {
+        let keys = byte_keys();
+        let (buf, root_offset) = create_bytes_index(3, &keys);
+        let searcher: IndexFileSearcher<&[u8], u64, BytesSerializer, U64Serializer, U64Serializer> =
+            IndexFileSearcher::new(&buf, root_offset);
+
+        for prefix in [b"".as_ref(), b"a", b"ab", b"abc", b"key-001", b"key-0", b"c", &[0u8], &[255u8], &[255u8, 255]].iter() {
+            let expected: Vec<Vec<u8>> = keys.iter().filter(|k| k.starts_with(prefix)).cloned().collect();
+
+            let ascending: Vec<Vec<u8>> = searcher.prefix(prefix, true).map(Result::unwrap).map(|(k,_)| k.to_vec()).collect();
+            assert_eq!(expected, ascending, "prefix {:?}", prefix);
+
+            let mut descending: Vec<Vec<u8>> = searcher.prefix(prefix, false).map(Result::unwrap).map(|(k,_)| k.to_vec()).collect();
+            descending.reverse();
+            assert_eq!(expected, descending, "prefix {:?} descending", prefix);
+        }
     }
 }
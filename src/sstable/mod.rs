@@ -6,11 +6,32 @@ use std::io::{Write, BufWriter};
 use uuid::*;
 use crate::io::{CassWrite, CassRead};
 use std::sync::Arc;
-use crate::util::DbTimestamp;
+use crate::util::{DbTimestamp, Token};
+use crate::sstable::checksum::BlockChecksums;
+use crate::sstable::compression::{ChunkInfo, DataFileContents};
+use crate::sstable::stats::SstableStats;
+use crate::sstable::partition_index::PartitionIndex;
+use crate::sstable::filter::BloomFilter;
+use crate::sstable::header::{Component, ComponentHeader};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::mem::size_of;
 
-mod row_data;
+use memmap::{Mmap, MmapOptions};
+
+pub mod row_data;
 mod index;
+pub mod partition_index;
+pub mod filter;
+pub mod flush;
+pub mod set;
+pub mod checksum;
+pub mod verify;
+pub mod compression;
+pub mod header;
+pub mod stats;
+/// fixtures shared by the tests of the sstable components and the modules built on them
+#[cfg(test)]
+pub mod testing;
 
 const ID_ROW_TOMBSTONE: u8 = 0;
 const ID_ROW_REGULAR: u8 = 1;
@@ -22,20 +43,143 @@ const ID_KEY_BOUND_EXCLUSIVE: u8 = 2;
 const ID_CELL_DATA_TOMBSTONE: u8 = 0;
 const ID_CELL_DATA_REGULAR: u8 = 1;
 
+const ID_CELL_PATH_NONE: u8 = 0;
+const ID_CELL_PATH: u8 = 1;
+
+
+/// Memory maps a file read-only. Empty files can not be mapped, so they are returned as `None`.
+pub fn map_file(path: &Path) -> std::io::Result<Option<Mmap>> {
+    let file = File::open(path)?;
+    if file.metadata()?.len() == 0 {
+        return Ok(None);
+    }
+    Ok(Some(unsafe { MmapOptions::new().map(&file)? }))
+}
+
 
 #[derive(Clone)]
-struct SstableMetaData {
+pub struct SstableMetaData {
     pub table_metadata: Arc<TableMetaData>,
     sstable_uuid: Uuid,
     folder: Box<PathBuf>,
 }
 impl SstableMetaData {
+    /// metadata for a new sstable with a freshly generated UUID
+    pub fn new(table_metadata: Arc<TableMetaData>, folder: &Path) -> SstableMetaData {
+        SstableMetaData {
+            table_metadata,
+            sstable_uuid: Uuid::new_v4(),
+            folder: Box::new(folder.to_path_buf()),
+        }
+    }
+
+    /// metadata for an existing sstable
+    pub fn existing(table_metadata: Arc<TableMetaData>, folder: &Path, sstable_uuid: Uuid) -> SstableMetaData {
+        SstableMetaData {
+            table_metadata,
+            sstable_uuid,
+            folder: Box::new(folder.to_path_buf()),
+        }
+    }
+
+    pub fn uuid(&self) -> Uuid {
+        self.sstable_uuid
+    }
+
+    pub fn folder(&self) -> &Path {
+        &self.folder
+    }
+
+    /// tokens of the sstable's first and last partition, None if it is empty
+    pub fn token_range(&self) -> std::io::Result<Option<(Token, Token)>> {
+        match map_file(&self.index_filename())? {
+            None => Ok(None),
+            Some(m) => Ok(PartitionIndex::new(&m)
+                .and_then(|index| index.token_range())
+                .map_err(|e| e.in_file(&self.index_filename()))?),
+        }
+    }
+
+    /// the data file's block checksums
+    pub fn read_checksums(&self) -> std::io::Result<BlockChecksums> {
+        let buf = std::fs::read(self.checksum_filename())?;
+        Ok(BlockChecksums::read(&buf).map_err(|e| e.in_file(&self.checksum_filename()))?)
+    }
+
+    /// the data file's chunk table
+    pub fn read_chunk_info(&self) -> std::io::Result<ChunkInfo> {
+        let buf = std::fs::read(self.chunk_info_filename())?;
+        Ok(ChunkInfo::read(&buf).map_err(|e| e.in_file(&self.chunk_info_filename()))?)
+    }
+
+    /// the statistics collected while the sstable was written
+    pub fn read_stats(&self) -> std::io::Result<SstableStats> {
+        let buf = std::fs::read(self.stats_filename())?;
+        Ok(SstableStats::read(&buf).map_err(|e| e.in_file(&self.stats_filename()))?)
+    }
+
+    /// the data file's uncompressed contents
+    pub fn read_data(&self) -> std::io::Result<DataFileContents> {
+        compression::read_contents(self, 0, None)
+    }
+
+    /// the uncompressed contents between two offsets, decompressing only the chunks containing them
+    pub fn read_data_range(&self, start: usize, end: Option<usize>) -> std::io::Result<DataFileContents> {
+        compression::read_contents(self, start, end)
+    }
+
+    /// Maps one of the sstable's components and checks that its header belongs to the table.
+    ///  Components always have a header, so they are never empty.
+    pub fn map_component(&self, path: &Path, component: Component) -> std::io::Result<(ComponentHeader, Mmap)> {
+        let file = map_file(path)?;
+        let buf: &[u8] = file.as_ref().map_or(&[], |m| m);
+        let header = ComponentHeader::read(buf, component)
+            .and_then(|header| header.check_table(&self.table_metadata).map(|_| header))
+            .map_err(|e| e.in_file(path))?;
+        Ok((header, file.unwrap()))
+    }
+
+    /// size of the (compressed) data file in bytes
+    pub fn data_size(&self) -> std::io::Result<u64> {
+        Ok(std::fs::metadata(self.data_filename())?.len())
+    }
+
+    /// deletes all of the sstable's components
+    pub fn delete_files(&self) -> std::io::Result<()> {
+        for filename in self.all_filenames().iter() {
+            match std::fs::remove_file(filename) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+
+    fn all_filenames(&self) -> Vec<PathBuf> {
+        vec!(self.data_filename(), self.index_filename(), self.filter_filename(), self.checksum_filename(), self.digest_filename(), self.chunk_info_filename(), self.stats_filename())
+    }
+
     pub fn data_filename(&self) -> PathBuf {
         self.filename("data")
     }
     pub fn index_filename(&self) -> PathBuf {
         self.filename("index")
     }
+    pub fn filter_filename(&self) -> PathBuf {
+        self.filename("filter")
+    }
+    pub fn checksum_filename(&self) -> PathBuf {
+        self.filename("crc")
+    }
+    pub fn digest_filename(&self) -> PathBuf {
+        self.filename("digest")
+    }
+    pub fn chunk_info_filename(&self) -> PathBuf {
+        self.filename("chunks")
+    }
+    pub fn stats_filename(&self) -> PathBuf {
+        self.filename("stats")
+    }
 
     fn filename(&self, extension: &str) -> PathBuf {
         self.folder.join(format!("{}_{}_{}.{}",
@@ -46,3 +190,112 @@ impl SstableMetaData {
         ))
     }
 }
+
+
+/// An open sstable, holding the memory mapped data, index and filter files and the parsed chunk
+///  table, checksums and stats. Sstables are shared as `Arc<Sstable>` between the live set and
+///  reads that are in progress.
+///
+/// Once an sstable is marked obsolete (e.g. because it was compacted), its files are deleted when
+///  the last reference to it is dropped, so reads that still hold it are not affected.
+pub struct Sstable {
+    meta_data: SstableMetaData,
+    data_header: ComponentHeader,
+    data: Arc<Mmap>,
+    chunk_info: ChunkInfo,
+    checksums: BlockChecksums,
+    index: Mmap,
+    filter: Mmap,
+    stats: SstableStats,
+    obsolete: AtomicBool,
+}
+
+impl Sstable {
+    /// opens an existing sstable, checking its components' headers
+    pub fn open(meta_data: SstableMetaData) -> std::io::Result<Arc<Sstable>> {
+        let (data_header, data) = meta_data.map_component(&meta_data.data_filename(), Component::Data)?;
+        let (_, index) = meta_data.map_component(&meta_data.index_filename(), Component::Index)?;
+        let (_, filter) = meta_data.map_component(&meta_data.filter_filename(), Component::Filter)?;
+        let chunk_info = meta_data.read_chunk_info()?;
+        let checksums = meta_data.read_checksums()?;
+        let stats = meta_data.read_stats()?;
+
+        Ok(Arc::new(Sstable {
+            meta_data,
+            data_header,
+            data: Arc::new(data),
+            chunk_info,
+            checksums,
+            index,
+            filter,
+            stats,
+            obsolete: AtomicBool::new(false),
+        }))
+    }
+
+    pub fn meta_data(&self) -> &SstableMetaData {
+        &self.meta_data
+    }
+
+    pub fn uuid(&self) -> Uuid {
+        self.meta_data.uuid()
+    }
+
+    pub fn stats(&self) -> &SstableStats {
+        &self.stats
+    }
+
+    /// the data file's block checksums
+    pub fn checksums(&self) -> &BlockChecksums {
+        &self.checksums
+    }
+
+    pub fn filter(&self) -> std::io::Result<BloomFilter<'_>> {
+        Ok(BloomFilter::new(&self.filter).map_err(|e| e.in_file(&self.meta_data.filter_filename()))?)
+    }
+
+    pub fn index(&self) -> std::io::Result<PartitionIndex<'_>> {
+        Ok(PartitionIndex::new(&self.index).map_err(|e| e.in_file(&self.meta_data.index_filename()))?)
+    }
+
+    /// the data file's uncompressed contents
+    pub fn read_data(&self) -> std::io::Result<DataFileContents> {
+        self.read_data_range(0, None)
+    }
+
+    /// the uncompressed contents between two offsets, decompressing only the chunks containing them
+    pub fn read_data_range(&self, start: usize, end: Option<usize>) -> std::io::Result<DataFileContents> {
+        compression::contents_of(&self.meta_data, &self.chunk_info, &self.data_header, &self.data, start, end)
+    }
+
+    /// continues reading the data file sequentially after `contents`, keeping only the chunks from
+    ///  the one containing `start`, see `compression::next_chunk_of`
+    pub fn read_next_chunk(&self, contents: &mut DataFileContents, start: usize) -> std::io::Result<bool> {
+        compression::next_chunk_of(&self.meta_data, &self.chunk_info, &self.data, self.data_header.body_offset(), contents, start)
+    }
+
+    /// length of the uncompressed data file, excluding its header
+    pub fn data_length(&self) -> usize {
+        self.chunk_info.uncompressed_length()
+    }
+
+    /// Marks the sstable's files for deletion once it is no longer referenced. It must not be part
+    ///  of the live set anymore.
+    pub fn mark_obsolete(&self) {
+        self.obsolete.store(true, Ordering::Release);
+    }
+
+    pub fn is_obsolete(&self) -> bool {
+        self.obsolete.load(Ordering::Acquire)
+    }
+}
+
+impl Drop for Sstable {
+    fn drop(&mut self) {
+        if self.is_obsolete() {
+            // there is no caller to report to - if deleting fails, the files are left behind
+            //  like after a crash, and their data is reconciled with that of their replacements
+            let _ = self.meta_data.delete_files();
+        }
+    }
+}
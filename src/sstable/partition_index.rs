@@ -0,0 +1,239 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufWriter, Seek, Write};
+use std::cmp::Ordering;
+use std::mem::size_of;
+use std::sync::Arc;
+
+use crate::db::{ClusterKeyComparator, RowDetails, TableMetaData, TableRow};
+use crate::io::{CassDeserializer, CassRead, CassSerializer, CassWrite, DecodeResult, U64Serializer};
+use crate::sstable::header::{Component, ComponentHeader};
+use crate::memtable::ClusterKey;
+use crate::sstable::index::{IndexFileCreator, IndexFileSearcher, IndexRangeIterator};
+use crate::util::{DbTimestamp, Token, other_error};
+
+const PARTITION_INDEX_ARITY: usize = 64;
+
+/// stored in the index file's trailer instead of a root node offset if the index is empty
+const NO_ROOT_NODE: u64 = u64::MAX;
+
+
+/// Partitions are stored ordered by token, and by partition key for partitions with colliding tokens.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PartitionIndexKey<'a> {
+    pub token: Token,
+    pub partition_key: Cow<'a, [u8]>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartitionIndexEntry {
+    /// offset of the partition's first row in the data file
+    pub offset: u64,
+    /// oldest cell or tombstone timestamp in the partition, DbTimestamp::MAX if there are none
+    pub min_timestamp: DbTimestamp,
+    /// youngest cell or tombstone timestamp in the partition, 0 if there are none
+    pub max_timestamp: DbTimestamp,
+}
+
+pub struct PartitionIndexKeySerializer {}
+
+impl <'b> CassSerializer<PartitionIndexKey<'b>> for PartitionIndexKeySerializer {
+    fn ser<W>(out: &mut CassWrite<W>, o: &PartitionIndexKey<'b>) -> std::io::Result<()> where W: Write+Seek {
+        out.write_u128(o.token)?;
+        out.write_bytes(&o.partition_key)
+    }
+
+    fn fixed_size() -> Option<usize> {
+        None
+    }
+}
+impl <'a> CassDeserializer<'a, PartitionIndexKey<'a>> for PartitionIndexKeySerializer {
+    fn deser(r: &mut CassRead<'a>) -> DecodeResult<PartitionIndexKey<'a>> {
+        let token = r.read_u128()?;
+        let partition_key = Cow::Borrowed(r.read_bytes()?);
+        Ok(PartitionIndexKey { token, partition_key })
+    }
+
+    fn fixed_size() -> Option<usize> {
+        None
+    }
+}
+
+pub struct PartitionIndexEntrySerializer {}
+
+impl CassSerializer<PartitionIndexEntry> for PartitionIndexEntrySerializer {
+    fn ser<W>(out: &mut CassWrite<W>, o: &PartitionIndexEntry) -> std::io::Result<()> where W: Write+Seek {
+        out.write_u64(o.offset)?;
+        out.write_db_timestamp(o.min_timestamp)?;
+        out.write_db_timestamp(o.max_timestamp)
+    }
+
+    fn fixed_size() -> Option<usize> {
+        Some(size_of::<u64>() + 2*size_of::<DbTimestamp>())
+    }
+}
+impl <'a> CassDeserializer<'a, PartitionIndexEntry> for PartitionIndexEntrySerializer {
+    fn deser(r: &mut CassRead<'a>) -> DecodeResult<PartitionIndexEntry> {
+        Ok(PartitionIndexEntry {
+            offset: r.read_u64()?,
+            min_timestamp: r.read_db_timestamp()?,
+            max_timestamp: r.read_db_timestamp()?,
+        })
+    }
+
+    fn fixed_size() -> Option<usize> {
+        Some(size_of::<u64>() + 2*size_of::<DbTimestamp>())
+    }
+}
+
+
+/// Collects a data file's rows into one index entry per partition. The index file consists of
+///  the header and the B-tree written by `IndexFileCreator`, followed by the root node's offset
+///  as a trailer. Node offsets are relative to the start of the file.
+pub struct PartitionIndexWriter {
+    index: IndexFileCreator<PartitionIndexKey<'static>, PartitionIndexEntry, BufWriter<File>, PartitionIndexKeySerializer, PartitionIndexEntrySerializer, U64Serializer>,
+    cur_partition: Option<(PartitionIndexKey<'static>, PartitionIndexEntry)>,
+    comparator: Arc<ClusterKeyComparator>,
+    /// the cluster key of the current partition's last regular row
+    last_cluster_key: Option<ClusterKey>,
+}
+
+impl PartitionIndexWriter {
+    pub fn new(out: File, table_metadata: &TableMetaData) -> std::io::Result<PartitionIndexWriter> {
+        let mut out = CassWrite::new(BufWriter::new(out));
+        ComponentHeader::write(&mut out, Component::Index, table_metadata)?;
+
+        Ok(PartitionIndexWriter {
+            index: IndexFileCreator::new(PARTITION_INDEX_ARITY, out.into_inner()),
+            cur_partition: None,
+            comparator: table_metadata.cluster_key_comparator().clone(),
+            last_cluster_key: None,
+        })
+    }
+
+    /// rows must be added in partition order, and in cluster key order inside a partition. `offset`
+    ///  is the row's offset in the data file
+    pub fn append_row(&mut self, row: &TableRow, offset: u64) -> std::io::Result<()> {
+        let is_same_partition = match &self.cur_partition {
+            None => false,
+            Some((key, _)) => {
+                if (row.token, row.partition_key) < (key.token, key.partition_key.as_ref()) {
+                    return other_error("rows must be appended in partition order");
+                }
+                row.token == key.token && row.partition_key == key.partition_key.as_ref()
+            }
+        };
+
+        if !is_same_partition {
+            self.flush_partition()?;
+            self.cur_partition = Some((
+                PartitionIndexKey { token: row.token, partition_key: Cow::Owned(row.partition_key.to_vec()) },
+                PartitionIndexEntry { offset, min_timestamp: DbTimestamp::MAX, max_timestamp: 0 },
+            ));
+            self.last_cluster_key = None;
+        }
+        self.check_cluster_order(row)?;
+
+        if let Some((_, entry)) = self.cur_partition.as_mut() {
+            match &row.details {
+                RowDetails::Regular(data) => {
+                    for cell in &data.regular_cols {
+                        register_timestamp(entry, cell.timestamp);
+                    }
+                },
+                RowDetails::RowTombstone(data) => register_timestamp(entry, data.timestamp),
+            }
+        }
+        Ok(())
+    }
+
+    fn check_cluster_order(&mut self, row: &TableRow) -> std::io::Result<()> {
+        if let RowDetails::Regular(data) = &row.details {
+            let cluster_key: Vec<Vec<u8>> = data.cluster_key.iter().map(|c| c.to_vec()).collect();
+            if self.last_cluster_key.as_ref().is_some_and(|last| self.comparator.compare_keys(last, &cluster_key) == Ordering::Greater) {
+                return other_error("rows must be appended in cluster key order");
+            }
+            self.last_cluster_key = Some(cluster_key);
+        }
+        Ok(())
+    }
+
+    fn flush_partition(&mut self) -> std::io::Result<()> {
+        match self.cur_partition.take() {
+            None => Ok(()),
+            Some((key, entry)) => self.index.add_entry(key, entry),
+        }
+    }
+
+    pub fn finalize(mut self) -> std::io::Result<()> {
+        self.flush_partition()?;
+
+        let (root_offset, out) = self.index.finalize()?;
+        let mut out = CassWrite::new(out);
+        out.write_u64(root_offset.unwrap_or(NO_ROOT_NODE))?;
+        out.into_inner().flush()
+    }
+}
+
+fn register_timestamp(entry: &mut PartitionIndexEntry, timestamp: DbTimestamp) {
+    entry.min_timestamp = entry.min_timestamp.min(timestamp);
+    entry.max_timestamp = entry.max_timestamp.max(timestamp);
+}
+
+
+type PartitionIndexSearcher<'a> = IndexFileSearcher<'a, PartitionIndexKey<'a>, PartitionIndexEntry, PartitionIndexKeySerializer, PartitionIndexEntrySerializer, U64Serializer>;
+
+/// read access to a partition index file's contents
+pub struct PartitionIndex<'a> {
+    searcher: PartitionIndexSearcher<'a>,
+}
+
+impl <'a> PartitionIndex<'a> {
+    pub fn new(buf: &'a [u8]) -> DecodeResult<PartitionIndex<'a>> {
+        ComponentHeader::read(buf, Component::Index)?;
+
+        let mut r = CassRead::wrap(buf);
+        r.pos = buf.len().saturating_sub(size_of::<u64>());
+        let root_offset = match r.read_u64()? {
+            NO_ROOT_NODE => None,
+            offs => Some(offs),
+        };
+
+        Ok(PartitionIndex {
+            searcher: IndexFileSearcher::new(buf, root_offset),
+        })
+    }
+
+    pub fn find(&self, token: Token, partition_key: &[u8]) -> DecodeResult<Option<PartitionIndexEntry>> {
+        let key = PartitionIndexKey { token, partition_key: Cow::Owned(partition_key.to_vec()) };
+        self.searcher.find_exact(&key)
+    }
+
+    /// offset of the first row following a partition, None if it is the last partition. Together
+    ///  with the partition's own offset, this is the range of the data file holding its rows.
+    pub fn next_partition_offset(&self, token: Token, partition_key: &[u8]) -> DecodeResult<Option<u64>> {
+        let key = PartitionIndexKey { token, partition_key: Cow::Owned(partition_key.to_vec()) };
+        match self.searcher.range(std::ops::Bound::Excluded(&key), std::ops::Bound::Unbounded, true).next() {
+            None => Ok(None),
+            Some(entry) => Ok(Some(entry?.1.offset)),
+        }
+    }
+
+    /// all partitions in token order
+    pub fn iter(&self) -> IndexRangeIterator<'a, PartitionIndexKey<'a>, PartitionIndexKey<'a>, PartitionIndexEntry, PartitionIndexKeySerializer, PartitionIndexEntrySerializer, U64Serializer> {
+        self.searcher.range(std::ops::Bound::Unbounded, std::ops::Bound::Unbounded, true)
+    }
+
+    /// tokens of the first and the last partition, None if the index is empty
+    pub fn token_range(&self) -> DecodeResult<Option<(Token, Token)>> {
+        let first = match self.iter().next() {
+            None => return Ok(None),
+            Some(entry) => entry?.0,
+        };
+        let last = match self.searcher.range(std::ops::Bound::Unbounded, std::ops::Bound::Unbounded, false).next() {
+            None => return Ok(None),
+            Some(entry) => entry?.0,
+        };
+        Ok(Some((first.token, last.token)))
+    }
+}
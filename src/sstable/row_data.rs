@@ -1,55 +1,152 @@
 use std::fs::{File, OpenOptions};
-use std::io::{BufWriter, Write};
-use std::mem::size_of;
+use std::io::{BufWriter, Seek, Write};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use uuid::*;
 
 use crate::db::{ColumnMetaData, ColumnType, KeyBound, RegularRowData, RowDetails, RowTombstoneData, TableCell, TableCellData, TableMetaData, TableRow};
-use crate::io::{CassRead, CassWrite};
-use crate::sstable::{SstableMetaData, ID_ROW_REGULAR, ID_ROW_TOMBSTONE, ID_KEY_BOUND_NONE, ID_KEY_BOUND_INCLUSIVE, ID_KEY_BOUND_EXCLUSIVE, ID_CELL_DATA_TOMBSTONE, ID_CELL_DATA_REGULAR};
-use crate::util::DbTimestamp;
-
-struct RowDataReader<'a> {
-    meta_data: SstableMetaData,
+use crate::io::{CassRead, CassWrite, DecodeError, DecodeErrorKind, DecodeResult};
+use crate::sstable::partition_index::PartitionIndexWriter;
+use crate::sstable::filter::BloomFilterBuilder;
+use crate::sstable::checksum::{write_digest, BlockChecksumWriter, BlockChecksums};
+use crate::sstable::compression::{ChunkWriter, DataFileContents};
+use crate::sstable::header::{Component, ComponentHeader, FORMAT_VERSION, MIN_FORMAT_VERSION};
+use crate::sstable::stats::StatsCollector;
+use crate::sstable::{Sstable, SstableMetaData, ID_ROW_REGULAR, ID_ROW_TOMBSTONE, ID_KEY_BOUND_NONE, ID_KEY_BOUND_INCLUSIVE, ID_KEY_BOUND_EXCLUSIVE, ID_CELL_DATA_TOMBSTONE, ID_CELL_DATA_REGULAR, ID_CELL_PATH_NONE, ID_CELL_PATH};
+use crate::util::{other_error, Token};
+use crate::value::value_size;
+
+/// Decodes rows from a data file's contents. Corrupt or truncated data is reported as a
+///  `DecodeError`, after which iteration stops.
+pub struct RowDataReader<'a> {
+    table_metadata: Arc<TableMetaData>,
     buf: CassRead<'a>,
+    /// offset of the buffer's start in the (uncompressed) data file
+    base: usize,
+    /// the data file's format version, which determines how rows are encoded
+    version: u16,
+    file: Option<PathBuf>,
+    checksums: Option<&'a BlockChecksums>,
+    /// blocks before this one were verified already
+    next_unverified_block: usize,
+    failed: bool,
 }
 
 impl <'a> RowDataReader<'a> {
-    fn new(meta_data: SstableMetaData, buf: CassRead) -> RowDataReader {
-        RowDataReader { meta_data, buf }
+    pub fn new(table_metadata: Arc<TableMetaData>, buf: CassRead<'a>) -> RowDataReader<'a> {
+        RowDataReader { table_metadata, buf, base: 0, version: FORMAT_VERSION, file: None, checksums: None, next_unverified_block: 0, failed: false }
+    }
+
+    /// reads the rows starting at `offset` in a data file's uncompressed contents, which must
+    ///  contain the offset
+    pub fn for_contents(table_metadata: Arc<TableMetaData>, contents: &'a DataFileContents, offset: usize) -> DecodeResult<RowDataReader<'a>> {
+        let mut buf = CassRead::wrap(contents.data());
+        buf.pos = match offset.checked_sub(contents.base()) {
+            Some(pos) if pos <= contents.data().len() => pos,
+            _ => return Err(DecodeError::new(offset, DecodeErrorKind::UnexpectedEnd { needed: 1, available: 0 })),
+        };
+        Ok(RowDataReader { base: contents.base(), version: contents.version(), ..RowDataReader::new(table_metadata, buf) })
+    }
+
+    /// the file the data was read from, for error reporting
+    pub fn in_file(mut self, file: &Path) -> RowDataReader<'a> {
+        self.file = Some(file.to_path_buf());
+        self
+    }
+
+    /// verifies every block of the data file before returning rows stored in it, the buffer must
+    ///  contain the blocks in their entirety
+    pub fn with_checksums(mut self, checksums: &'a BlockChecksums) -> RowDataReader<'a> {
+        self.checksums = Some(checksums);
+        self
+    }
+
+    /// offset of the next row to be read
+    pub fn position(&self) -> usize {
+        self.base + self.buf.pos
+    }
+
+    pub fn is_at_end(&self) -> bool {
+        self.buf.is_at_end()
+    }
+
+    /// iterates over the remaining rows together with the offset in the data file at which each
+    ///  row starts
+    pub fn with_offsets(self) -> RowsWithOffsets<'a> {
+        RowsWithOffsets { reader: self }
     }
 
-    fn read_row(&mut self) -> TableRow<'a> {
-        let partition_key_def = self.meta_data.table_metadata.partition_key();
-        let partition_key = self.read_table_cell_data_raw(partition_key_def);
+    pub fn read_row(&mut self) -> DecodeResult<TableRow<'a>> {
+        let start = self.base + self.buf.pos;
+        self.verify_blocks(start, start)
+            .and_then(|_| self.decode_row().map_err(|e| DecodeError { offset: self.base + e.offset, ..e }))
+            .and_then(|row| {
+                self.verify_blocks(start, self.base + self.buf.pos - 1)?;
+                Ok(row)
+            })
+            .map_err(|e| match &self.file {
+                Some(file) => e.in_file(file),
+                None => e,
+            })
+    }
 
-        let table_metadata = self.meta_data.table_metadata.clone();
+    /// verifies the blocks containing the data between two (absolute) offsets (inclusive) that
+    ///  were not verified yet
+    fn verify_blocks(&mut self, from: usize, to: usize) -> DecodeResult<()> {
+        if let Some(checksums) = self.checksums {
+            let first = self.next_unverified_block.max(checksums.block_of(from));
+            let last = checksums.block_of(to);
+            for block in first..=last {
+                if !checksums.verify_block(self.buf.data(), self.base, block) {
+                    return Err(DecodeError::new(block * checksums.block_size(), DecodeErrorKind::ChecksumMismatch { block }));
+                }
+            }
+            self.next_unverified_block = self.next_unverified_block.max(last + 1);
+        }
+        Ok(())
+    }
 
-        match self.buf.read_u8() {
+    /// older format versions keep their own decoding when the row encoding changes
+    fn decode_row(&mut self) -> DecodeResult<TableRow<'a>> {
+        match self.version {
+            // rows are encoded the same way in all readable versions - version 2 changed the order
+            //  of a partition's rows and added clustering orders to the header
+            MIN_FORMAT_VERSION..=FORMAT_VERSION => self.decode_row_v1(),
+            version => Err(DecodeError::new(self.buf.pos, DecodeErrorKind::UnsupportedVersion { version, min: MIN_FORMAT_VERSION, max: FORMAT_VERSION })),
+        }
+    }
+
+    fn decode_row_v1(&mut self) -> DecodeResult<TableRow<'a>> {
+        let partition_key_def = self.table_metadata.partition_key();
+        let partition_key = self.read_table_cell_data_raw(partition_key_def)?;
+
+        let table_metadata = self.table_metadata.clone();
+
+        let offset = self.buf.pos;
+        match self.buf.read_u8()? {
             ID_ROW_TOMBSTONE => {
                 let row_details = RowDetails::RowTombstone(RowTombstoneData {
-                    lower_bound: self.read_key_bound(),
-                    upper_bound: self.read_key_bound(),
+                    timestamp: self.buf.read_db_timestamp()?,
+                    lower_bound: self.read_key_bound()?,
+                    upper_bound: self.read_key_bound()?,
                 });
 
-                TableRow::new(table_metadata, partition_key, row_details)
+                Ok(TableRow::new(table_metadata, partition_key, row_details))
             },
             ID_ROW_REGULAR => {
-                let pk_expiry = self.buf.read_db_expiry_timestamp();
+                let pk_expiry = self.buf.read_db_expiry_timestamp()?;
                 let mut cluster_key = Vec::new();
                 for idx in table_metadata.idx_cluster_keys.iter() {
                     let key_col_meta = &table_metadata.columns.get(*idx).unwrap().clone();
-                    let key_cell = self.read_table_cell_data_raw(key_col_meta.clone());
+                    let key_cell = self.read_table_cell_data_raw(key_col_meta.clone())?;
                     cluster_key.push(key_cell);
                 }
 
                 let mut regular_cols = Vec::new();
 
-                let num_regular_cols = self.buf.read_u32();
+                let num_regular_cols = self.buf.read_u32()?;
                 for _ in 0..num_regular_cols {
-                    regular_cols.push(self.read_table_cell());
+                    regular_cols.push(self.read_table_cell()?);
                 }
 
                 let row_details = RowDetails::Regular(RegularRowData {
@@ -58,112 +155,234 @@ impl <'a> RowDataReader<'a> {
                     regular_cols,
                 });
 
-                TableRow::new(table_metadata, partition_key, row_details)
+                Ok(TableRow::new(table_metadata, partition_key, row_details))
             },
-            n => panic!("invalid row kind ID: {}", n),
+            n => Err(DecodeError::invalid_tag(offset, "row kind", &[ID_ROW_TOMBSTONE, ID_ROW_REGULAR], n)),
         }
     }
 
-    fn read_key_bound(&mut self) -> Option<KeyBound<'a>> {
-        let is_inclusive = match self.buf.read_u8() {
-            ID_KEY_BOUND_NONE => return None,
+    fn read_key_bound(&mut self) -> DecodeResult<Option<KeyBound<'a>>> {
+        let offset = self.buf.pos;
+        let is_inclusive = match self.buf.read_u8()? {
+            ID_KEY_BOUND_NONE => return Ok(None),
             ID_KEY_BOUND_INCLUSIVE => true,
             ID_KEY_BOUND_EXCLUSIVE => false,
-            n => panic!("invalid key bound id: {}", n),
+            n => return Err(DecodeError::invalid_tag(offset, "key bound", &[ID_KEY_BOUND_NONE, ID_KEY_BOUND_INCLUSIVE, ID_KEY_BOUND_EXCLUSIVE], n)),
         };
 
         let mut cluster_key_prefix = Vec::new();
-        let num_cluster_key_cols = self.buf.read_u8();
+        let num_offset = self.buf.pos;
+        let num_cluster_key_cols = self.buf.read_u8()?;
+        if num_cluster_key_cols as usize > self.table_metadata.idx_cluster_keys.len() {
+            return Err(DecodeError::new(num_offset, DecodeErrorKind::InvalidValue("number of cluster key columns")));
+        }
         for idx in 0..num_cluster_key_cols {
-            cluster_key_prefix.push(self.read_table_cell_data_raw(self.meta_data.table_metadata.cluster_key(idx as usize)))
+            cluster_key_prefix.push(self.read_table_cell_data_raw(self.table_metadata.cluster_key(idx as usize))?)
         }
 
-        Some(KeyBound {
+        Ok(Some(KeyBound {
             cluster_key_prefix,
             is_inclusive
-        })
+        }))
     }
 
-    fn read_table_cell(&mut self) -> TableCell<'a> {
-        let col_id = self.buf.read_uuid();
-        let timestamp = self.buf.read_db_timestamp();
-        let expiry = self.buf.read_db_expiry_timestamp();
-
-        let column_metadata = self.meta_data.table_metadata.column_by_id(&col_id).clone();
-        let data = self.read_table_cell_data(column_metadata.clone());
+    fn read_table_cell(&mut self) -> DecodeResult<TableCell<'a>> {
+        let offset = self.buf.pos;
+        let col_id = self.buf.read_uuid()?;
+        let timestamp = self.buf.read_db_timestamp()?;
+        let expiry = self.buf.read_db_expiry_timestamp()?;
+
+        let column_metadata = self.table_metadata.column_by_id(&col_id)
+            .ok_or_else(|| DecodeError::new(offset, DecodeErrorKind::UnknownColumn(col_id)))?;
+        let path = match column_metadata.col_type.cell_path_type() {
+            None => None,
+            Some(path_type) => self.read_cell_path(path_type)?,
+        };
+        let value_type = column_metadata.col_type.cell_value_type(path)
+            .ok_or_else(|| DecodeError::new(offset, DecodeErrorKind::InvalidValue("user type field")))?;
+        let data = self.read_table_cell_data(value_type)?;
 
-        TableCell {
+        Ok(TableCell {
             meta_data: column_metadata,
+            path,
             timestamp,
             expiry,
             data
+        })
+    }
+
+    /// multi-cell columns' cells have a path, except for tombstones of the whole value
+    fn read_cell_path(&mut self, path_type: &ColumnType) -> DecodeResult<Option<&'a [u8]>> {
+        let offset = self.buf.pos;
+        match self.buf.read_u8()? {
+            ID_CELL_PATH_NONE => Ok(None),
+            ID_CELL_PATH => Ok(Some(self.read_raw(path_type)?)),
+            n => Err(DecodeError::invalid_tag(offset, "cell path", &[ID_CELL_PATH_NONE, ID_CELL_PATH], n)),
         }
     }
 
-    fn read_table_cell_data(&mut self, column_meta_data: Arc<ColumnMetaData>) -> TableCellData<'a> {
-        match self.buf.read_u8() {
-            ID_CELL_DATA_TOMBSTONE => TableCellData::Tombstone,
-            ID_CELL_DATA_REGULAR => TableCellData::Regular(self.read_table_cell_data_raw(column_meta_data)),
-            n => panic!("invalid cell data id: {}", n)
+    fn read_table_cell_data(&mut self, value_type: &ColumnType) -> DecodeResult<TableCellData<'a>> {
+        let offset = self.buf.pos;
+        match self.buf.read_u8()? {
+            ID_CELL_DATA_TOMBSTONE => Ok(TableCellData::Tombstone),
+            ID_CELL_DATA_REGULAR => Ok(TableCellData::Regular(self.read_raw(value_type)?)),
+            n => Err(DecodeError::invalid_tag(offset, "cell data", &[ID_CELL_DATA_TOMBSTONE, ID_CELL_DATA_REGULAR], n)),
         }
     }
 
-    fn read_table_cell_data_raw(&mut self, column_meta_data: Arc<ColumnMetaData>) -> &'a [u8] {
-        let len = self.size(&column_meta_data.col_type, 0);
+    fn read_table_cell_data_raw(&mut self, column_meta_data: Arc<ColumnMetaData>) -> DecodeResult<&'a [u8]> {
+        self.read_raw(&column_meta_data.col_type)
+    }
+
+    fn read_raw(&mut self, col_type: &ColumnType) -> DecodeResult<&'a [u8]> {
+        let len = value_size(&self.buf, col_type, 0)?;
         self.buf.read_slice(len)
     }
+}
 
-    fn size(&mut self, col_type: &ColumnType, offs: usize) -> usize {
-        match col_type {
-            ColumnType::Text => {
-                let len = self.buf.peek_u32_offs(offs);
-                size_of::<u32>() + len as usize
-            },
-            ColumnType::Uuid => 16,
-            ColumnType::Boolean => 1,
-            ColumnType::Int => 4,
-            ColumnType::Long => 8,
-            ColumnType::Timestamp => size_of::<DbTimestamp>(),
-            ColumnType::Tuple(parts) => {
-                let mut result = 0;
-                for part in parts {
-                    result += self.size(part, offs + result);
-                }
-                result
-            }
+/// iterates over the remaining rows in data file order
+impl <'a> Iterator for RowDataReader<'a> {
+    type Item = DecodeResult<TableRow<'a>>;
+
+    fn next(&mut self) -> Option<DecodeResult<TableRow<'a>>> {
+        if self.failed || self.is_at_end() {
+            return None;
         }
+
+        let result = self.read_row();
+        self.failed = result.is_err();
+        Some(result)
     }
 }
 
-//TODO do not store partition key (or cluster key for regular rows) - they are available by access through index
+pub struct RowsWithOffsets<'a> {
+    reader: RowDataReader<'a>,
+}
 
-struct RowDataFileCreator {
-    meta_data: SstableMetaData,
-    out: CassWrite<BufWriter<File>>,
+impl <'a> Iterator for RowsWithOffsets<'a> {
+    type Item = DecodeResult<(usize, TableRow<'a>)>;
+
+    fn next(&mut self) -> Option<DecodeResult<(usize, TableRow<'a>)>> {
+        let offset = self.reader.position();
+        self.reader.next().map(|row| row.map(|row| (offset, row)))
+    }
 }
 
-impl RowDataFileCreator {
-    pub fn new(meta_data: SstableMetaData) -> std::io::Result<RowDataFileCreator> {
-        let data_file = OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(meta_data.data_filename())?;
+/// Reads an sstable's rows in data file order, decompressing the data file one chunk at a time as
+///  the rows are read rather than all of it up front. Every block is verified against its checksum
+///  before any data stored in it is decoded.
+///
+/// Rows borrow the decompressed chunks, which are dropped once all their rows are read, so each row
+///  is passed to a function rather than returned.
+pub struct RowDataStream {
+    sstable: Arc<Sstable>,
+    /// the chunks from the one containing `position`
+    contents: DataFileContents,
+    /// offset of the next row in the uncompressed data file
+    position: usize,
+    /// the data before this offset was verified
+    verified_end: usize,
+    /// token and partition key of the next row, once they were decoded
+    next_partition: Option<(Token, Vec<u8>)>,
+}
 
-        let data_out = CassWrite::new(BufWriter::new(data_file));
+impl RowDataStream {
+    pub fn new(sstable: Arc<Sstable>) -> std::io::Result<RowDataStream> {
+        let contents = sstable.read_data_range(0, Some(0))?;
+        Ok(RowDataStream { sstable, contents, position: 0, verified_end: 0, next_partition: None })
+    }
 
-        Ok(RowDataFileCreator {
-            meta_data,
-            out: data_out,
-        })
+    /// token and partition key of the next row, None at the end of the data file
+    pub fn peek_partition(&mut self) -> std::io::Result<Option<(Token, &[u8])>> {
+        while self.next_partition.is_none() && self.position < self.sstable.data_length() {
+            let partition_key_def = self.sstable.meta_data().table_metadata.partition_key();
+            let mut reader = RowDataReader::for_contents(self.sstable.meta_data().table_metadata.clone(), &self.contents, self.position)
+                .map_err(|e| e.in_file(&self.sstable.meta_data().data_filename()))?;
+            let result = reader.read_table_cell_data_raw(partition_key_def)
+                .map_err(|e| DecodeError { offset: self.contents.base() + e.offset, ..e });
+            match result {
+                Ok(partition_key) if reader.position() <= self.verified_end => {
+                    self.next_partition = Some((fasthash::murmur3::hash128(partition_key), partition_key.to_vec()));
+                },
+                Ok(_) => self.verify_more(None)?,
+                Err(e) => self.verify_more(Some(e))?,
+            }
+        }
+        Ok(self.next_partition.as_ref().map(|(token, partition_key)| (*token, partition_key.as_slice())))
     }
 
-    /// no shadowing inside a single sstable, i.e. callers must e.g. split range tombstones
-    ///  if a row is added inside the range
-    pub fn append_row(&mut self, row: &TableRow) -> std::io::Result<()> {
-        //TODO write index (incl. oldest / youngest timestamp)
-        //TODO write bloom filter
+    /// passes the next row to `f`, None at the end of the data file
+    pub fn next_row<T>(&mut self, f: impl FnOnce(&TableRow) -> T) -> std::io::Result<Option<T>> {
+        while self.position < self.sstable.data_length() {
+            let mut reader = RowDataReader::for_contents(self.sstable.meta_data().table_metadata.clone(), &self.contents, self.position)
+                .map_err(|e| e.in_file(&self.sstable.meta_data().data_filename()))?;
+            match reader.read_row() {
+                Ok(row) if reader.position() <= self.verified_end => {
+                    self.position = reader.position();
+                    self.next_partition = None;
+                    return Ok(Some(f(&row)));
+                },
+                Ok(_) => self.verify_more(None)?,
+                Err(e) => self.verify_more(Some(e))?,
+            }
+        }
+        Ok(None)
+    }
+
+    /// Called if the next row could not be decoded from the verified data, either because it
+    ///  continues past it or because of `error`: verifies the next block if the row may continue
+    ///  there, and returns the error otherwise.
+    fn verify_more(&mut self, error: Option<DecodeError>) -> std::io::Result<()> {
+        let data_length = self.sstable.data_length();
+        let error = match error {
+            Some(e) if !matches!(e.kind, DecodeErrorKind::UnexpectedEnd { .. }) && e.offset < self.verified_end => Some(e),
+            e if self.verified_end >= data_length => Some(e.unwrap_or_else(|| DecodeError::new(data_length, DecodeErrorKind::UnexpectedEnd { needed: 1, available: 0 }))),
+            _ => None,
+        };
+        if let Some(e) = error {
+            return Err(e.in_file(&self.sstable.meta_data().data_filename()).into());
+        }
+
+        let checksums = self.sstable.checksums();
+        let block = checksums.block_of(self.verified_end);
+        let block_start = block * checksums.block_size();
+        let block_end = (block_start + checksums.block_size()).min(self.sstable.data_length());
+        while self.contents.base() + self.contents.data().len() < block_end {
+            if !self.sstable.read_next_chunk(&mut self.contents, self.position)? {
+                let available = (self.contents.base() + self.contents.data().len()).saturating_sub(block_start);
+                let e = DecodeError::new(block_start, DecodeErrorKind::UnexpectedEnd { needed: block_end - block_start, available });
+                return Err(e.in_file(&self.sstable.meta_data().data_filename()).into());
+            }
+        }
+        if !checksums.verify_block(self.contents.data(), self.contents.base(), block) {
+            let e = DecodeError::new(block_start, DecodeErrorKind::ChecksumMismatch { block });
+            return Err(e.in_file(&self.sstable.meta_data().data_filename()).into());
+        }
+        self.verified_end = block_end;
+        Ok(())
+    }
+}
+
+//TODO do not store partition key (or cluster key for regular rows) - they are available by access through index
 
-        self.write_raw_cell_data(&row.partition_key)?;
+/// serializes rows in the data file format - the commit log uses that format as well
+pub struct RowWriter<W> where W: Write+Seek {
+    out: CassWrite<W>,
+}
+
+impl <W> RowWriter<W> where W: Write+Seek {
+    pub fn new(out: W) -> RowWriter<W> {
+        RowWriter {
+            out: CassWrite::new(out),
+        }
+    }
+
+    pub fn position(&mut self) -> std::io::Result<u64> {
+        self.out.position()
+    }
+
+    pub fn write_row(&mut self, row: &TableRow) -> std::io::Result<()> {
+        self.write_raw_cell_data(row.partition_key)?;
 
         match &row.details {
             RowDetails::RowTombstone(data) => self.write_tombstone_row(data),
@@ -178,7 +397,7 @@ impl RowDataFileCreator {
             self.write_raw_cell_data(cell)?;
         }
 
-        self.out.write_u32(data.regular_cols.len() as u32);
+        self.out.write_u32(data.regular_cols.len() as u32)?;
         for cell in &data.regular_cols {
             self.write_cell(cell)?;
         }
@@ -188,6 +407,7 @@ impl RowDataFileCreator {
 
     fn write_tombstone_row(&mut self, data: &RowTombstoneData) -> std::io::Result<()> {
         self.out.write_u8(ID_ROW_TOMBSTONE)?;
+        self.out.write_db_timestamp(data.timestamp)?;
 
         for b in [&data.lower_bound, &data.upper_bound].iter() {
             match b {
@@ -202,10 +422,10 @@ impl RowDataFileCreator {
                         self.out.write_u8(ID_KEY_BOUND_EXCLUSIVE)?;
                     }
 
-                    self.out.write_u8(key_bound.cluster_key_prefix.len() as u8); //TODO enforce max 255 columns in cluster key
+                    self.out.write_u8(key_bound.cluster_key_prefix.len() as u8)?; //TODO enforce max 255 columns in cluster key
 
                     for cell in &key_bound.cluster_key_prefix {
-                        self.write_raw_cell_data(&cell);
+                        self.write_raw_cell_data(cell)?;
                     }
                 }
             }
@@ -218,6 +438,16 @@ impl RowDataFileCreator {
         self.out.write_db_timestamp(cell.timestamp)?;
         self.out.write_db_expiry_timestamp(cell.expiry)?;
 
+        if cell.meta_data.col_type.is_multi_cell() {
+            match cell.path {
+                None => self.out.write_u8(ID_CELL_PATH_NONE)?,
+                Some(path) => {
+                    self.out.write_u8(ID_CELL_PATH)?;
+                    self.out.write_raw(path)?;
+                },
+            }
+        }
+
         self.write_cell_data(&cell.data)
     }
 
@@ -236,17 +466,132 @@ impl RowDataFileCreator {
         }
     }
 
+    pub fn into_inner(self) -> W {
+        self.out.into_inner()
+    }
+}
+
+pub struct RowDataFileCreator {
+    meta_data: SstableMetaData,
+    out: RowWriter<BlockChecksumWriter<ChunkWriter<BufWriter<File>>>>,
+    index: PartitionIndexWriter,
+    filter: BloomFilterBuilder,
+    stats: StatsCollector,
+    last_token: Option<Token>,
+}
+
+impl RowDataFileCreator {
+    pub fn new(meta_data: SstableMetaData) -> std::io::Result<RowDataFileCreator> {
+        let filter = BloomFilterBuilder::new(meta_data.table_metadata.options.bloom_filter_fp_chance)?;
+
+        // the chunk table stores the chunk size as a u32
+        let chunk_size = meta_data.table_metadata.options.compression.chunk_size;
+        if chunk_size == 0 || chunk_size > u32::MAX as usize {
+            return other_error(&format!("compression chunk size must be between 1 and {}, was {}", u32::MAX, chunk_size));
+        }
+
+        let data_file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(meta_data.data_filename())?;
+
+        let mut header_out = CassWrite::new(BufWriter::new(data_file));
+        ComponentHeader::write(&mut header_out, Component::Data, &meta_data.table_metadata)?;
+
+        // checksum blocks coincide with compression chunks, so a chunk is verified as a whole
+        let compression = &meta_data.table_metadata.options.compression;
+        let chunks = ChunkWriter::new(header_out.into_inner(), compression.codec, compression.chunk_size);
+        let data_out = RowWriter::new(BlockChecksumWriter::new(chunks, compression.chunk_size));
+
+        let index_file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(meta_data.index_filename())?;
+
+        let index = PartitionIndexWriter::new(index_file, &meta_data.table_metadata)?;
+
+        Ok(RowDataFileCreator {
+            meta_data,
+            out: data_out,
+            index,
+            filter,
+            stats: StatsCollector::default(),
+            last_token: None,
+        })
+    }
 
-    //TODO move this to orchestrator?
-    fn finalize(mut self) -> std::io::Result<()>{
-        let mut data_file = self.out.into_inner();
+    /// no shadowing inside a single sstable, i.e. callers must e.g. split range tombstones
+    ///  if a row is added inside the range
+    ///
+    /// rows must be appended in partition order, i.e. ordered by token and partition key
+    pub fn append_row(&mut self, row: &TableRow) -> std::io::Result<()> {
+        let offset = self.out.position()?;
+        self.index.append_row(row, offset)?;
+        self.stats.add_row(row, offset);
+
+        if self.last_token != Some(row.token) {
+            self.filter.add(row.token);
+            self.last_token = Some(row.token);
+        }
+
+        self.out.write_row(row)
+    }
+
+    /// number of (uncompressed) bytes written to the data file so far
+    pub fn data_size(&mut self) -> std::io::Result<u64> {
+        self.out.position()
+    }
+
+    /// writes the remaining components and opens the finished sstable
+    pub fn finalize(mut self) -> std::io::Result<Arc<Sstable>> {
+        let stats = self.stats.finish(self.out.position()?);
+        let (chunks, checksums, digest) = self.out.into_inner().finish();
+        let (mut data_file, chunk_info) = chunks.finish()?;
         data_file.flush()?;
 
-        Ok(()) //TODO return type?
+        let chunk_info_file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(self.meta_data.chunk_info_filename())?;
+        let mut chunk_info_out = CassWrite::new(BufWriter::new(chunk_info_file));
+        chunk_info.write(&mut chunk_info_out, &self.meta_data.table_metadata)?;
+        chunk_info_out.into_inner().flush()?;
+
+        let checksum_file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(self.meta_data.checksum_filename())?;
+        let mut checksum_out = CassWrite::new(BufWriter::new(checksum_file));
+        checksums.write(&mut checksum_out, &self.meta_data.table_metadata)?;
+        checksum_out.into_inner().flush()?;
+
+        let digest_file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(self.meta_data.digest_filename())?;
+        let mut digest_out = CassWrite::new(BufWriter::new(digest_file));
+        write_digest(&mut digest_out, digest, &self.meta_data.table_metadata)?;
+        digest_out.into_inner().flush()?;
+
+        self.index.finalize()?;
 
-//        Ok(Sstable {
-//            meta_data: self.meta_data,
-//        })
+        let filter_file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(self.meta_data.filter_filename())?;
+        let mut filter_out = CassWrite::new(BufWriter::new(filter_file));
+        self.filter.write(&mut filter_out, &self.meta_data.table_metadata)?;
+        filter_out.into_inner().flush()?;
+
+        let stats_file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(self.meta_data.stats_filename())?;
+        let mut stats_out = CassWrite::new(BufWriter::new(stats_file));
+        stats.write(&mut stats_out, &self.meta_data.table_metadata)?;
+        stats_out.into_inner().flush()?;
+
+        Sstable::open(self.meta_data)
     }
 }
 
@@ -261,53 +606,36 @@ mod tests {
     use memmap::MmapOptions;
     use uuid::Uuid;
 
-    use crate::db::{ColumnMetaData, ColumnType, RegularRowData, RowDetails, TableCell, TableCellData, TableMetaData, TableRow};
-    use crate::io::{CassRead, CassWrite};
-    use crate::sstable::{SstableMetaData};
-    use crate::sstable::row_data::{RowDataFileCreator, RowDataReader};
-
-    fn sstable_metadata() -> SstableMetaData {
-        let col_partition_key = ColumnMetaData {
-            name: "id".to_string(),
-            id: Uuid::new_v4(),
-            col_type: ColumnType::Long,
-        };
-        let col_name = ColumnMetaData {
-            name: "name".to_string(),
-            id: Uuid::new_v4(),
-            col_type: ColumnType::Text,
-        };
-
-        let columns = vec!(
-            Arc::new(col_partition_key),
-            Arc::new(col_name),
-        );
-
-        let table_metadata =
-            TableMetaData::new("person".to_string(), Uuid::new_v4(), columns, 0, Vec::new());
-
-        SstableMetaData {
-            table_metadata: Arc::new(table_metadata),
-            sstable_uuid: Uuid::new_v4(),
-            folder: Box::new(std::env::temp_dir())
-        }
+    use crate::db::{ColumnMetaData, ColumnType, CompressionCodec, CompressionOptions, RegularRowData, RowDetails, TableCell, TableCellData, TableMetaData, TableRow, UserType, UserTypeField};
+    use crate::io::{CassRead, CassWrite, DecodeError, DecodeErrorKind, DecodeResult};
+    use crate::sstable::{Sstable, SstableMetaData, ID_KEY_BOUND_INCLUSIVE, ID_ROW_TOMBSTONE};
+    use crate::sstable::header::{Component, ComponentHeader};
+    use crate::sstable::row_data::{RowDataFileCreator, RowDataReader, RowDataStream, RowWriter};
+    use crate::sstable::testing::{column, id_column, ids, rows, table_metadata, text_value, write_sstable, TempFolder};
+    use crate::util::NO_EXPIRY;
+    use crate::sstable::partition_index::PartitionIndex;
+    use crate::sstable::filter::BloomFilter;
+
+    fn sstable_metadata(folder: &TempFolder) -> SstableMetaData {
+        SstableMetaData::new(table_metadata(CompressionOptions::default()), folder.path())
     }
 
     fn ser_utf8(s: &str) -> Vec<u8> {
         let mut w = CassWrite::new(Cursor::new(Vec::new()));
-        w.write_utf8(s);
+        w.write_utf8(s).unwrap();
         w.into_inner().into_inner()
     }
     fn ser_u64(n: u64) -> Vec<u8> {
         let mut w = CassWrite::new(Cursor::new(Vec::new()));
-        w.write_u64(n);
+        w.write_u64(n).unwrap();
         w.into_inner().into_inner()
     }
 
 
     #[test]
     pub fn test_write_read() {
-        let meta_data = sstable_metadata();
+        let folder = TempFolder::new();
+        let meta_data = sstable_metadata(&folder);
         let table_metadata = meta_data.table_metadata.clone();
 
         let mut creator = RowDataFileCreator::new(meta_data.clone()).unwrap();
@@ -318,6 +646,7 @@ mod tests {
         let id_cell = &id_buf;
         let name_cell = TableCell {
             meta_data: table_metadata.columns.get(0).unwrap().clone(),
+            path: None,
             timestamp: 8888,
             expiry: 7777,
             data: TableCellData::Regular(&name_buf),
@@ -333,15 +662,13 @@ mod tests {
             })
         );
 
-        creator.append_row(&row);
-        creator.finalize();
-        println!("data file: {:?}", meta_data.data_filename());
+        creator.append_row(&row).unwrap();
+        creator.finalize().unwrap();
 
-        let f = File::open(meta_data.data_filename()).unwrap();
-        let m = unsafe { MmapOptions::new().map(&f).unwrap() };
+        let contents = meta_data.read_data().unwrap();
 
-        let mut reader = RowDataReader::new(meta_data, CassRead::wrap(&m));
-        let read_row = reader.read_row();
+        let mut reader = RowDataReader::for_contents(meta_data.table_metadata.clone(), &contents, 0).unwrap();
+        let read_row = reader.read_row().unwrap();
 
         assert_eq!(*read_row.partition_key, *id_buf);
 
@@ -362,4 +689,400 @@ mod tests {
             _ => assert!(false)
         }
     }
+
+    #[test]
+    pub fn test_partition_index() {
+        let folder = TempFolder::new();
+        let meta_data = sstable_metadata(&folder);
+        let table_metadata = meta_data.table_metadata.clone();
+
+        let ids: Vec<Vec<u8>> = (0..50).map(ser_u64).collect();
+        let name_buf = ser_utf8("Arno");
+
+        // two rows per partition, with different cell timestamps
+        let mut rows = Vec::new();
+        for (n, id) in ids.iter().enumerate() {
+            for timestamp in [1000 + n as u64, 2000 + n as u64].iter() {
+                let name_cell = TableCell {
+                    meta_data: table_metadata.columns.get(1).unwrap().clone(),
+                    path: None,
+                    timestamp: *timestamp,
+                    expiry: 7777,
+                    data: TableCellData::Regular(&name_buf),
+                };
+                rows.push(TableRow::new(
+                    table_metadata.clone(),
+                    id,
+                    RowDetails::Regular(RegularRowData {
+                        pk_expiry: 9999u32,
+                        cluster_key: Vec::new(),
+                        regular_cols: vec!(name_cell),
+                    })
+                ));
+            }
+        }
+        rows.sort_by_key(|r| r.token);
+
+        let mut creator = RowDataFileCreator::new(meta_data.clone()).unwrap();
+        for row in rows.iter() {
+            creator.append_row(row).unwrap();
+        }
+        creator.finalize().unwrap();
+
+        let contents = meta_data.read_data().unwrap();
+        let index_file = File::open(meta_data.index_filename()).unwrap();
+        let index_buf = unsafe { MmapOptions::new().map(&index_file).unwrap() };
+        let index = PartitionIndex::new(&index_buf).unwrap();
+        let filter_file = File::open(meta_data.filter_filename()).unwrap();
+        let filter_buf = unsafe { MmapOptions::new().map(&filter_file).unwrap() };
+        let filter = BloomFilter::new(&filter_buf).unwrap();
+
+        for (n, id) in ids.iter().enumerate() {
+            let token = fasthash::murmur3::hash128(id);
+            assert!(filter.might_contain(token));
+            let entry = index.find(token, id).unwrap().unwrap();
+            assert_eq!(1000 + n as u64, entry.min_timestamp);
+            assert_eq!(2000 + n as u64, entry.max_timestamp);
+
+            let mut reader = RowDataReader::for_contents(table_metadata.clone(), &contents, entry.offset as usize).unwrap();
+            let row = reader.read_row().unwrap();
+            assert_eq!(*row.partition_key, **id);
+        }
+
+        assert_eq!(None, index.find(fasthash::murmur3::hash128(&ser_u64(50)), &ser_u64(50)).unwrap());
+
+        let tokens: Vec<u128> = index.iter().map(|e| e.unwrap().0.token).collect();
+        let mut expected_tokens: Vec<u128> = rows.iter().map(|r| r.token).collect();
+        expected_tokens.dedup();
+        assert_eq!(expected_tokens, tokens);
+    }
+
+    #[test]
+    pub fn test_iterate_with_offsets() {
+        let folder = TempFolder::new();
+        let meta_data = sstable_metadata(&folder);
+        let table_metadata = meta_data.table_metadata.clone();
+
+        let ids: Vec<Vec<u8>> = (0..20).map(ser_u64).collect();
+        let mut rows: Vec<TableRow> = ids.iter()
+            .map(|id| TableRow::new(table_metadata.clone(), id, RowDetails::Regular(RegularRowData {
+                pk_expiry: 9999u32,
+                cluster_key: Vec::new(),
+                regular_cols: Vec::new(),
+            })))
+            .collect();
+        rows.sort_by_key(|r| r.token);
+
+        let mut creator = RowDataFileCreator::new(meta_data.clone()).unwrap();
+        for row in rows.iter() {
+            creator.append_row(row).unwrap();
+        }
+        creator.finalize().unwrap();
+
+        let contents = meta_data.read_data().unwrap();
+        let index_file = File::open(meta_data.index_filename()).unwrap();
+        let index_buf = unsafe { MmapOptions::new().map(&index_file).unwrap() };
+        let index = PartitionIndex::new(&index_buf).unwrap();
+
+        let read: Vec<(usize, TableRow)> = RowDataReader::for_contents(table_metadata.clone(), &contents, 0).unwrap().with_offsets().map(Result::unwrap).collect();
+        assert_eq!(rows.len(), read.len());
+        assert_eq!(0, read[0].0);
+        for ((offset, row), expected) in read.iter().zip(rows.iter()) {
+            assert_eq!(expected.partition_key, row.partition_key);
+            assert_eq!(*offset as u64, index.find(row.token, row.partition_key).unwrap().unwrap().offset);
+        }
+
+        assert!(RowDataReader::new(table_metadata.clone(), CassRead::wrap(&[])).next().is_none());
+    }
+
+    #[test]
+    pub fn test_invalid_chunk_size() {
+        let folder = TempFolder::new();
+        for chunk_size in [0, u32::MAX as usize + 1].iter() {
+            let table_metadata = table_metadata(CompressionOptions { codec: CompressionCodec::Lz4, chunk_size: *chunk_size });
+            assert!(RowDataFileCreator::new(SstableMetaData::new(table_metadata, folder.path())).is_err());
+        }
+        assert_eq!(0, std::fs::read_dir(folder.path()).unwrap().count());
+    }
+
+    #[test]
+    pub fn test_stream() {
+        for codec in [CompressionCodec::None, CompressionCodec::Lz4, CompressionCodec::Deflate].iter() {
+            let table_metadata = table_metadata(CompressionOptions { codec: *codec, chunk_size: 256 });
+            let ids = ids(100);
+            let value = text_value(300);
+            let rows = rows(&table_metadata, &ids, &value);
+            let folder = TempFolder::new();
+            let sstable = write_sstable(&table_metadata, folder.path(), &rows);
+
+            let mut stream = RowDataStream::new(sstable.clone()).unwrap();
+            for row in rows.iter() {
+                assert_eq!(Some((row.token, row.partition_key)), stream.peek_partition().unwrap());
+                assert_eq!(Some(row.partition_key.to_vec()), stream.next_row(|r| r.partition_key.to_vec()).unwrap());
+                if *codec != CompressionCodec::None {
+                    // each row spans at most three chunks, only those are kept
+                    assert!(stream.contents.data().len() <= 3 * 256, "{:?}", codec);
+                }
+            }
+            assert_eq!(None, stream.peek_partition().unwrap());
+            assert_eq!(None, stream.next_row(|_| ()).unwrap());
+
+            if *codec != CompressionCodec::None {
+                continue;
+            }
+
+            // rows are read up to the corrupt block
+            let mut data = std::fs::read(sstable.meta_data().data_filename()).unwrap();
+            let body_offset = ComponentHeader::read(&data, Component::Data).unwrap().body_offset();
+            data[body_offset + 2 * 256 + 10] ^= 1;
+            std::fs::write(sstable.meta_data().data_filename(), &data).unwrap();
+
+            let mut stream = RowDataStream::new(Sstable::open(sstable.meta_data().clone()).unwrap()).unwrap();
+            let e = loop {
+                match stream.next_row(|_| ()) {
+                    Ok(row) => assert!(row.is_some()),
+                    Err(e) => break e,
+                }
+            };
+            let kind = &e.get_ref().and_then(|e| e.downcast_ref::<DecodeError>()).unwrap().kind;
+            assert_eq!(DecodeErrorKind::ChecksumMismatch { block: 2 }, *kind);
+            assert!(stream.position > 0 && stream.position <= 2 * 256);
+        }
+    }
+
+    #[test]
+    pub fn test_decode_errors() {
+        let folder = TempFolder::new();
+        let meta_data = sstable_metadata(&folder);
+        let table_metadata = meta_data.table_metadata.clone();
+
+        let id_buf = ser_u64(99);
+        let name_buf = ser_utf8("Arno");
+        let row = TableRow::new(table_metadata.clone(), &id_buf, RowDetails::Regular(RegularRowData {
+            pk_expiry: 9999u32,
+            cluster_key: Vec::new(),
+            regular_cols: vec!(TableCell {
+                meta_data: table_metadata.columns[1].clone(),
+                path: None,
+                timestamp: 8888,
+                expiry: 7777,
+                data: TableCellData::Regular(&name_buf),
+            }),
+        }));
+
+        let mut creator = RowDataFileCreator::new(meta_data.clone()).unwrap();
+        creator.append_row(&row).unwrap();
+        creator.finalize().unwrap();
+        let data = meta_data.read_data().unwrap().data().to_vec();
+
+        let read_all = |buf: &[u8], table_metadata: &Arc<TableMetaData>| -> Vec<DecodeResult<()>> {
+            RowDataReader::new(table_metadata.clone(), CassRead::wrap(buf))
+                .in_file(&meta_data.data_filename())
+                .map(|r| r.map(|_| ()))
+                .collect()
+        };
+
+        assert_eq!(vec!(Ok(())), read_all(&data, &table_metadata));
+
+        // the reader stops after the first error
+        let truncated = read_all(&data[..data.len() - 1], &table_metadata);
+        assert_eq!(1, truncated.len());
+        let e = truncated[0].clone().unwrap_err();
+        assert_eq!(Some(meta_data.data_filename()), e.file);
+        assert!(matches!(e.kind, DecodeErrorKind::UnexpectedEnd { needed: 8, available: 7 }));
+
+        // the row kind follows the 8 byte partition key
+        let mut invalid_row_kind = data.clone();
+        invalid_row_kind[8] = 17;
+        let e = read_all(&invalid_row_kind, &table_metadata)[0].clone().unwrap_err();
+        assert_eq!(8, e.offset);
+        assert!(matches!(e.kind, DecodeErrorKind::InvalidTag { tag: "row kind", actual: 17, .. }));
+
+        let other_schema = sstable_metadata(&folder).table_metadata;
+        let e = read_all(&data, &other_schema)[0].clone().unwrap_err();
+        assert_eq!(DecodeErrorKind::UnknownColumn(table_metadata.columns[1].id), e.kind);
+
+        let e: std::io::Error = e.into();
+        assert_eq!(std::io::ErrorKind::InvalidData, e.kind());
+
+        // a key bound with more columns than the table's cluster key
+        let mut w = CassWrite::new(Cursor::new(Vec::new()));
+        w.write_u64(99).unwrap();
+        w.write_u8(ID_ROW_TOMBSTONE).unwrap();
+        w.write_db_timestamp(8888).unwrap();
+        w.write_u8(ID_KEY_BOUND_INCLUSIVE).unwrap();
+        w.write_u8(1).unwrap();
+        let e = read_all(&w.into_inner().into_inner(), &table_metadata)[0].clone().unwrap_err();
+        assert_eq!(DecodeErrorKind::InvalidValue("number of cluster key columns"), e.kind);
+
+        // a length that overflows when the length's own size is added
+        let blob_key = Arc::new(TableMetaData::new("t".to_string(), Uuid::new_v4(), vec!(
+            Arc::new(ColumnMetaData { name: "id".to_string(), id: Uuid::new_v4(), col_type: ColumnType::Blob }),
+        ), 0, Vec::new()));
+        let mut w = CassWrite::new(Cursor::new(Vec::new()));
+        w.write_varint(u64::MAX).unwrap();
+        let e = read_all(&w.into_inner().into_inner(), &blob_key)[0].clone().unwrap_err();
+        assert!(matches!(e.kind, DecodeErrorKind::UnexpectedEnd { .. }), "{:?}", e);
+
+        let contents = meta_data.read_data().unwrap();
+        assert!(RowDataReader::for_contents(table_metadata.clone(), &contents, data.len()).is_ok());
+        let e = RowDataReader::for_contents(table_metadata.clone(), &contents, data.len() + 1).err().unwrap();
+        assert!(matches!(e.kind, DecodeErrorKind::UnexpectedEnd { .. }));
+    }
+
+    #[test]
+    pub fn test_rows_out_of_partition_order() {
+        let folder = TempFolder::new();
+        let meta_data = sstable_metadata(&folder);
+        let table_metadata = meta_data.table_metadata.clone();
+
+        let id_a = ser_u64(1);
+        let id_b = ser_u64(2);
+        let mut rows: Vec<TableRow> = [&id_a, &id_b].iter()
+            .map(|id| TableRow::new(table_metadata.clone(), id, RowDetails::Regular(RegularRowData {
+                pk_expiry: 9999u32,
+                cluster_key: Vec::new(),
+                regular_cols: Vec::new(),
+            })))
+            .collect();
+        rows.sort_by_key(|r| std::cmp::Reverse(r.token));
+
+        let mut creator = RowDataFileCreator::new(meta_data).unwrap();
+        creator.append_row(&rows[0]).unwrap();
+        assert!(creator.append_row(&rows[1]).is_err());
+    }
+
+    /// (column name, path, data)
+    type CellSummary = (String, Option<Vec<u8>>, Option<Vec<u8>>);
+
+    #[test]
+    pub fn test_collections() {
+        let columns = vec!(
+            column("frozen", ColumnType::Tuple(vec!(ColumnType::Text, ColumnType::List(Box::new(ColumnType::Int))))),
+            id_column(),
+            column("list", ColumnType::List(Box::new(ColumnType::Long))),
+            column("map", ColumnType::Map(Box::new(ColumnType::Text), Box::new(ColumnType::Long))),
+            column("set", ColumnType::Set(Box::new(ColumnType::Text))),
+        );
+        let table_metadata = Arc::new(TableMetaData::new("t".to_string(), Uuid::new_v4(), columns, 1, Vec::new()));
+
+        let id = ser_u64(1);
+        let key_a = ser_utf8("a");
+        let key_b = ser_utf8("b");
+        let value = ser_u64(42);
+        let list_path = Uuid::new_v4();
+        // a text and a list of two ints
+        let frozen = [ser_utf8("x"), vec!(0, 0, 0, 2), vec!(0, 0, 0, 7), vec!(0, 0, 0, 8)].concat();
+
+        fn cell<'a>(meta_data: &Arc<ColumnMetaData>, path: Option<&'a [u8]>, data: TableCellData<'a>) -> TableCell<'a> {
+            TableCell { meta_data: meta_data.clone(), path, timestamp: 10, expiry: NO_EXPIRY, data }
+        }
+        let columns = &table_metadata.columns;
+
+        let row = TableRow::new(table_metadata.clone(), &id, RowDetails::Regular(RegularRowData {
+            pk_expiry: NO_EXPIRY,
+            cluster_key: Vec::new(),
+            regular_cols: vec!(
+                cell(&columns[0], None, TableCellData::Regular(&frozen)),
+                cell(&columns[2], Some(list_path.as_bytes()), TableCellData::Regular(&value)),
+                // a whole-collection tombstone, followed by the map's new contents
+                cell(&columns[3], None, TableCellData::Tombstone),
+                cell(&columns[3], Some(&key_a), TableCellData::Regular(&value)),
+                cell(&columns[3], Some(&key_b), TableCellData::Tombstone),
+                cell(&columns[4], Some(&key_a), TableCellData::Regular(&[])),
+            ),
+        }));
+
+        let mut out = RowWriter::new(Cursor::new(Vec::new()));
+        out.write_row(&row).unwrap();
+        let buf = out.into_inner().into_inner();
+
+        let mut reader = RowDataReader::new(table_metadata.clone(), CassRead::wrap(&buf));
+        let read_row = reader.read_row().unwrap();
+        assert!(reader.is_at_end());
+
+        let summary = |cells: &[TableCell]| -> Vec<CellSummary> {
+            cells.iter()
+                .map(|c| (c.meta_data.name.clone(), c.path.map(|p| p.to_vec()), match c.data {
+                    TableCellData::Tombstone => None,
+                    TableCellData::Regular(data) => Some(data.to_vec()),
+                }))
+                .collect()
+        };
+        match (&row.details, &read_row.details) {
+            (RowDetails::Regular(expected), RowDetails::Regular(actual)) => assert_eq!(summary(&expected.regular_cols), summary(&actual.regular_cols)),
+            _ => panic!("regular row expected"),
+        }
+    }
+
+    #[test]
+    pub fn test_scalar_types() {
+        type Out = CassWrite<Cursor<Vec<u8>>>;
+        fn ser(f: impl Fn(&mut Out) -> std::io::Result<()>) -> Vec<u8> {
+            let mut w = CassWrite::new(Cursor::new(Vec::new()));
+            f(&mut w).unwrap();
+            w.into_inner().into_inner()
+        }
+        let values = vec!(
+            (ColumnType::Float, 1.5f32.to_be_bytes().to_vec()),
+            (ColumnType::Double, (-2.25f64).to_be_bytes().to_vec()),
+            (ColumnType::Decimal, ser(|w| { w.write_u32(2)?; w.write_varint(2)?; w.write_raw(&[0x01, 0x2c]) })),
+            (ColumnType::Varint, ser(|w| { w.write_varint(200)?; w.write_raw(&[7; 200]) })),
+            (ColumnType::Blob, ser(|w| { w.write_varint(3)?; w.write_raw(b"abc") })),
+            (ColumnType::Inet, vec!(4, 127, 0, 0, 1)),
+            (ColumnType::Date, 19000i32.to_be_bytes().to_vec()),
+            (ColumnType::Time, 3_600_000_000_000i64.to_be_bytes().to_vec()),
+            (ColumnType::Duration, ser(|w| { w.write_signed_varint(1)?; w.write_signed_varint(-2)?; w.write_signed_varint(1 << 40) })),
+            // a user type value written before its second field was added
+            (ColumnType::Tuple(vec!(ColumnType::UserType(Arc::new(UserType { name: "u".to_string(), fields: vec!(
+                UserTypeField { name: "a".to_string(), field_type: ColumnType::Text },
+                UserTypeField { name: "b".to_string(), field_type: ColumnType::Int },
+            )})))), ser(|w| { w.write_u32(1)?; w.write_utf8("x") })),
+        );
+        let (types, values): (Vec<ColumnType>, Vec<Vec<u8>>) = values.into_iter().unzip();
+
+        let mut columns = vec!(Arc::new(ColumnMetaData { name: "id".to_string(), id: Uuid::new_v4(), col_type: ColumnType::Long }));
+        for (idx, col_type) in types.into_iter().enumerate() {
+            columns.push(Arc::new(ColumnMetaData { name: format!("v{}", idx), id: Uuid::new_v4(), col_type }));
+        }
+        let table_metadata = Arc::new(TableMetaData::new("t".to_string(), Uuid::new_v4(), columns, 0, Vec::new()));
+
+        let id = ser_u64(1);
+        let row = TableRow::new(table_metadata.clone(), &id, RowDetails::Regular(RegularRowData {
+            pk_expiry: NO_EXPIRY,
+            cluster_key: Vec::new(),
+            regular_cols: values.iter().enumerate()
+                .map(|(idx, value)| TableCell {
+                    meta_data: table_metadata.columns[idx + 1].clone(),
+                    path: None,
+                    timestamp: 10,
+                    expiry: NO_EXPIRY,
+                    data: TableCellData::Regular(value),
+                })
+                .collect(),
+        }));
+
+        let mut out = RowWriter::new(Cursor::new(Vec::new()));
+        out.write_row(&row).unwrap();
+        let buf = out.into_inner().into_inner();
+
+        let mut reader = RowDataReader::new(table_metadata.clone(), CassRead::wrap(&buf));
+        match reader.read_row().unwrap().details {
+            RowDetails::Regular(data) => {
+                let actual: Vec<Vec<u8>> = data.regular_cols.iter()
+                    .map(|c| match c.data {
+                        TableCellData::Regular(data) => data.to_vec(),
+                        TableCellData::Tombstone => panic!("tombstone"),
+                    })
+                    .collect();
+                assert_eq!(values, actual);
+            },
+            _ => panic!("regular row expected"),
+        }
+        assert!(reader.is_at_end());
+
+        // truncated data
+        let e = RowDataReader::new(table_metadata, CassRead::wrap(&buf[..buf.len() - 8])).read_row().err().unwrap();
+        assert!(matches!(e.kind, DecodeErrorKind::UnexpectedEnd { .. }));
+    }
 }
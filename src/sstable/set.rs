@@ -0,0 +1,56 @@
+use std::sync::{Arc, RwLock};
+
+use crate::sstable::{Sstable, SstableMetaData};
+use crate::util::other_error;
+
+
+/// A table's live sstables. Readers work on immutable snapshots, so flushes and compactions can
+///  change the set without affecting reads that are in progress.
+pub struct SstableSet {
+    sstables: RwLock<Arc<Vec<Arc<Sstable>>>>,
+}
+
+impl SstableSet {
+    pub fn new(sstables: Vec<Arc<Sstable>>) -> SstableSet {
+        SstableSet {
+            sstables: RwLock::new(Arc::new(sstables)),
+        }
+    }
+
+    pub fn snapshot(&self) -> Arc<Vec<Arc<Sstable>>> {
+        self.sstables.read().unwrap().clone()
+    }
+
+    pub fn add(&self, sstable: Arc<Sstable>) {
+        let mut sstables = self.sstables.write().unwrap();
+        let mut new_sstables = sstables.as_ref().clone();
+        new_sstables.push(sstable);
+        *sstables = Arc::new(new_sstables);
+    }
+
+    /// Replaces `old` with `new` in a single step, i.e. no snapshot contains both or neither. Fails
+    ///  without changing the set if one of the `old` sstables is not (or no longer) part of it.
+    ///
+    /// The replaced sstables are marked obsolete, so their files are deleted once the last
+    ///  snapshot holding them is dropped.
+    pub fn replace(&self, old: &[SstableMetaData], new: Vec<Arc<Sstable>>) -> std::io::Result<()> {
+        let mut sstables = self.sstables.write().unwrap();
+
+        for o in old {
+            if !sstables.iter().any(|s| s.uuid() == o.uuid()) {
+                return other_error("replaced sstable is not in the set");
+            }
+        }
+
+        let (replaced, mut new_sstables): (Vec<Arc<Sstable>>, Vec<Arc<Sstable>>) = sstables.iter()
+            .cloned()
+            .partition(|s| old.iter().any(|o| o.uuid() == s.uuid()));
+        new_sstables.extend(new);
+        *sstables = Arc::new(new_sstables);
+
+        for sstable in replaced {
+            sstable.mark_obsolete();
+        }
+        Ok(())
+    }
+}
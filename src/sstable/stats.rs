@@ -0,0 +1,355 @@
+use std::collections::BTreeMap;
+use std::io::{Seek, Write};
+
+use crate::db::{RowDetails, TableCellData, TableMetaData, TableRow};
+use crate::io::{CassRead, CassWrite, DecodeResult};
+use crate::sstable::header::{Component, ComponentHeader};
+use crate::util::{expiry_of_timestamp, DbExpiryTimestamp, DbTimestamp, Token, NO_EXPIRY};
+
+/// tombstone deletion times are rounded up to this many seconds for the droppable tombstone
+///  estimate, keeping the stats small
+const DELETION_TIME_RESOLUTION: DbExpiryTimestamp = 60 * 60;
+
+
+/// Counts values in buckets with power-of-two bounds: bucket `n` holds values below 2^n that are
+///  not in a lower bucket, i.e. bucket 0 holds 0 and bucket 1 holds 1.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Histogram {
+    buckets: Vec<u64>,
+}
+
+impl Histogram {
+    pub fn add(&mut self, value: u64) {
+        let bucket = (u64::BITS - value.leading_zeros()) as usize;
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+    }
+
+    /// the number of values in each bucket
+    pub fn buckets(&self) -> &[u64] {
+        &self.buckets
+    }
+
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// an upper bound for the given percentile (0.0 to 1.0) of the values, 0 if there are none
+    pub fn percentile(&self, p: f64) -> u64 {
+        let threshold = (self.count() as f64 * p).ceil().max(1.0) as u64;
+        let mut count = 0;
+        for (bucket, n) in self.buckets.iter().enumerate() {
+            count += n;
+            if count >= threshold {
+                return if bucket == 0 { 0 } else { (1u64 << (bucket - 1)).saturating_mul(2) - 1 };
+            }
+        }
+        0
+    }
+}
+
+
+/// Per-sstable metadata for compaction and read path pruning, collected while the sstable is
+///  written. Timestamps are those of cells and row tombstones. Expiry is that of live data, with
+///  tombstones and data without a TTL counting as NO_EXPIRY.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SstableStats {
+    /// DbTimestamp::MAX if there are no cells or tombstones
+    pub min_timestamp: DbTimestamp,
+    /// 0 if there are no cells or tombstones
+    pub max_timestamp: DbTimestamp,
+    /// NO_EXPIRY if there is no data
+    pub min_expiry: DbExpiryTimestamp,
+    /// when the sstable's last data expires, 0 if there is no data
+    pub max_expiry: DbExpiryTimestamp,
+    /// tokens of the first and the last partition, None if the sstable is empty
+    pub token_range: Option<(Token, Token)>,
+    pub partition_count: u64,
+    /// number of regular rows, i.e. rows that are not row tombstones
+    pub row_count: u64,
+    /// number of row tombstones and cell tombstones
+    pub tombstone_count: u64,
+    /// partition sizes in bytes of uncompressed data
+    pub partition_sizes: Histogram,
+    /// number of tombstones and expiring cells by the (rounded up) time from which on they can be
+    ///  garbage collected
+    deletion_times: BTreeMap<DbExpiryTimestamp, u64>,
+}
+
+impl SstableStats {
+    fn empty() -> SstableStats {
+        SstableStats {
+            min_timestamp: DbTimestamp::MAX,
+            max_timestamp: 0,
+            min_expiry: NO_EXPIRY,
+            max_expiry: 0,
+            token_range: None,
+            partition_count: 0,
+            row_count: 0,
+            tombstone_count: 0,
+            partition_sizes: Histogram::default(),
+            deletion_times: BTreeMap::new(),
+        }
+    }
+
+    /// Estimates how many tombstones compaction could drop with a given `gc_before`, including
+    ///  expiring cells that turn into tombstones. Tombstones that shadow data in other sstables are
+    ///  not dropped, so this is an upper bound for what compaction actually removes.
+    pub fn estimated_droppable_tombstones(&self, gc_before: DbExpiryTimestamp) -> u64 {
+        self.deletion_times.range(..=gc_before).map(|(_, n)| *n).sum()
+    }
+
+    /// The stats file consists of the header, min / max timestamp (u64), min / max expiry (u32),
+    ///  partition, row and tombstone counts (u64) and - if there are partitions - the first and
+    ///  last token (u128). These are followed by the partition size histogram's number of buckets
+    ///  (u32) and their counts (u64), and finally the number of deletion times (u32) with each
+    ///  deletion time (u32) and count (u64).
+    pub fn read(buf: &[u8]) -> DecodeResult<SstableStats> {
+        let mut r = CassRead::wrap(buf);
+        r.pos = ComponentHeader::read(buf, Component::Stats)?.body_offset();
+
+        let mut result = SstableStats::empty();
+        result.min_timestamp = r.read_db_timestamp()?;
+        result.max_timestamp = r.read_db_timestamp()?;
+        result.min_expiry = r.read_db_expiry_timestamp()?;
+        result.max_expiry = r.read_db_expiry_timestamp()?;
+        result.partition_count = r.read_u64()?;
+        result.row_count = r.read_u64()?;
+        result.tombstone_count = r.read_u64()?;
+        if result.partition_count > 0 {
+            result.token_range = Some((r.read_u128()?, r.read_u128()?));
+        }
+
+        for _ in 0..r.read_u32()? {
+            result.partition_sizes.buckets.push(r.read_u64()?);
+        }
+        for _ in 0..r.read_u32()? {
+            let deletion_time = r.read_db_expiry_timestamp()?;
+            result.deletion_times.insert(deletion_time, r.read_u64()?);
+        }
+        Ok(result)
+    }
+
+    pub fn write<W>(&self, out: &mut CassWrite<W>, table_metadata: &TableMetaData) -> std::io::Result<()> where W: Write+Seek {
+        ComponentHeader::write(out, Component::Stats, table_metadata)?;
+
+        out.write_db_timestamp(self.min_timestamp)?;
+        out.write_db_timestamp(self.max_timestamp)?;
+        out.write_db_expiry_timestamp(self.min_expiry)?;
+        out.write_db_expiry_timestamp(self.max_expiry)?;
+        out.write_u64(self.partition_count)?;
+        out.write_u64(self.row_count)?;
+        out.write_u64(self.tombstone_count)?;
+        if let Some((first, last)) = self.token_range {
+            out.write_u128(first)?;
+            out.write_u128(last)?;
+        }
+
+        out.write_u32(self.partition_sizes.buckets.len() as u32)?;
+        for n in self.partition_sizes.buckets.iter() {
+            out.write_u64(*n)?;
+        }
+        out.write_u32(self.deletion_times.len() as u32)?;
+        for (deletion_time, n) in self.deletion_times.iter() {
+            out.write_db_expiry_timestamp(*deletion_time)?;
+            out.write_u64(*n)?;
+        }
+        Ok(())
+    }
+
+    fn register_timestamp(&mut self, timestamp: DbTimestamp) {
+        self.min_timestamp = self.min_timestamp.min(timestamp);
+        self.max_timestamp = self.max_timestamp.max(timestamp);
+    }
+
+    fn register_expiry(&mut self, expiry: DbExpiryTimestamp) {
+        self.min_expiry = self.min_expiry.min(expiry);
+        self.max_expiry = self.max_expiry.max(expiry);
+    }
+
+    fn register_deletion_time(&mut self, deletion_time: DbExpiryTimestamp) {
+        let rounded = deletion_time.div_ceil(DELETION_TIME_RESOLUTION).saturating_mul(DELETION_TIME_RESOLUTION);
+        *self.deletion_times.entry(rounded).or_insert(0) += 1;
+    }
+}
+
+
+/// Collects an sstable's stats from the rows that are written to its data file, which must be
+///  appended in partition order.
+pub struct StatsCollector {
+    stats: SstableStats,
+    /// key and data file offset of the current partition
+    cur_partition: Option<(Token, Vec<u8>, u64)>,
+}
+
+impl Default for StatsCollector {
+    fn default() -> StatsCollector {
+        StatsCollector {
+            stats: SstableStats::empty(),
+            cur_partition: None,
+        }
+    }
+}
+
+impl StatsCollector {
+
+    /// `offset` is the row's offset in the (uncompressed) data file
+    pub fn add_row(&mut self, row: &TableRow, offset: u64) {
+        let is_same_partition = matches!(&self.cur_partition, Some((token, partition_key, _)) if *token == row.token && partition_key == row.partition_key);
+        if !is_same_partition {
+            self.finish_partition(offset);
+            self.cur_partition = Some((row.token, row.partition_key.to_vec(), offset));
+
+            self.stats.partition_count += 1;
+            self.stats.token_range = Some(match self.stats.token_range {
+                None => (row.token, row.token),
+                Some((first, _)) => (first, row.token),
+            });
+        }
+
+        let stats = &mut self.stats;
+        match &row.details {
+            RowDetails::Regular(data) => {
+                stats.row_count += 1;
+                stats.register_expiry(data.pk_expiry);
+                for cell in data.regular_cols.iter() {
+                    stats.register_timestamp(cell.timestamp);
+                    match cell.data {
+                        TableCellData::Tombstone => {
+                            stats.tombstone_count += 1;
+                            stats.register_expiry(NO_EXPIRY);
+                            stats.register_deletion_time(match cell.expiry {
+                                NO_EXPIRY => expiry_of_timestamp(cell.timestamp),
+                                expiry => expiry,
+                            });
+                        },
+                        TableCellData::Regular(_) => {
+                            stats.register_expiry(cell.expiry);
+                            if cell.expiry != NO_EXPIRY {
+                                stats.register_deletion_time(cell.expiry);
+                            }
+                        },
+                    }
+                }
+            },
+            RowDetails::RowTombstone(data) => {
+                stats.tombstone_count += 1;
+                stats.register_timestamp(data.timestamp);
+                stats.register_expiry(NO_EXPIRY);
+                stats.register_deletion_time(expiry_of_timestamp(data.timestamp));
+            },
+        }
+    }
+
+    fn finish_partition(&mut self, end_offset: u64) {
+        if let Some((_, _, start_offset)) = self.cur_partition.take() {
+            self.stats.partition_sizes.add(end_offset - start_offset);
+        }
+    }
+
+    /// `data_size` is the size of the (uncompressed) data file
+    pub fn finish(mut self, data_size: u64) -> SstableStats {
+        self.finish_partition(data_size);
+        self.stats
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::db::{CompressionOptions, RegularRowData, RowDetails, RowTombstoneData, TableCell, TableCellData, TableRow};
+    use crate::io::CassWrite;
+    use crate::sstable::stats::{Histogram, SstableStats};
+    use crate::sstable::testing::{ids, table_metadata, text_value, write_sstable, TempFolder};
+    use crate::util::{DbExpiryTimestamp, DbTimestamp, NO_EXPIRY};
+
+    const SECOND: DbTimestamp = 1_000_000_000;
+
+    #[test]
+    pub fn test_histogram() {
+        let mut histogram = Histogram::default();
+        assert_eq!(0, histogram.percentile(0.5));
+
+        for value in [0, 1, 2, 3, 100, 1000].iter() {
+            histogram.add(*value);
+        }
+        assert_eq!(&[1, 1, 2, 0, 0, 0, 0, 1, 0, 0, 1], histogram.buckets());
+        assert_eq!(6, histogram.count());
+        assert_eq!(3, histogram.percentile(0.5));
+        assert_eq!(127, histogram.percentile(0.8));
+        assert_eq!(1023, histogram.percentile(1.0));
+    }
+
+    #[test]
+    pub fn test_stats() {
+        let table_metadata = table_metadata(CompressionOptions::default());
+        let value = text_value(4);
+
+        // partitions 0..10 with a regular cell each, an expiring cell and a cell tombstone in
+        //  partition 0 and a row tombstone in partition 1
+        let ids = ids(10);
+        let cell = |timestamp: DbTimestamp, expiry: DbExpiryTimestamp, data| TableCell {
+            meta_data: table_metadata.columns[1].clone(),
+            path: None,
+            timestamp,
+            expiry,
+            data,
+        };
+        let mut rows = Vec::new();
+        for (n, id) in ids.iter().enumerate() {
+            let mut cells = vec!(cell((n as u64 + 10) * SECOND, NO_EXPIRY, TableCellData::Regular(&value)));
+            if n == 0 {
+                cells.push(cell(5 * SECOND, 20_000, TableCellData::Regular(&value)));
+                cells.push(cell(30 * SECOND, NO_EXPIRY, TableCellData::Tombstone));
+            }
+            rows.push(TableRow::new(table_metadata.clone(), id, RowDetails::Regular(RegularRowData {
+                pk_expiry: 10_000,
+                cluster_key: Vec::new(),
+                regular_cols: cells,
+            })));
+            if n == 1 {
+                rows.push(TableRow::new(table_metadata.clone(), id, RowDetails::RowTombstone(RowTombstoneData {
+                    timestamp: 40_000 * SECOND,
+                    lower_bound: None,
+                    upper_bound: None,
+                })));
+            }
+        }
+        rows.sort_by_key(|r| r.token);
+
+        let folder = TempFolder::new();
+        let sstable = write_sstable(&table_metadata, folder.path(), &rows);
+        let stats = sstable.stats().clone();
+        assert_eq!(stats, sstable.meta_data().read_stats().unwrap());
+
+        assert_eq!(5 * SECOND, stats.min_timestamp);
+        assert_eq!(40_000 * SECOND, stats.max_timestamp);
+        assert_eq!(10_000, stats.min_expiry);
+        assert_eq!(NO_EXPIRY, stats.max_expiry);
+        assert_eq!(Some((rows[0].token, rows.last().unwrap().token)), stats.token_range);
+        assert_eq!(10, stats.partition_count);
+        assert_eq!(10, stats.row_count);
+        assert_eq!(2, stats.tombstone_count);
+        assert_eq!(10, stats.partition_sizes.count());
+        assert!(stats.partition_sizes.percentile(0.0) > 0);
+
+        // deletion times are rounded up to the hour
+        assert_eq!(0, stats.estimated_droppable_tombstones(30));
+        assert_eq!(1, stats.estimated_droppable_tombstones(3600));
+        assert_eq!(2, stats.estimated_droppable_tombstones(20_000 + 3600));
+        assert_eq!(3, stats.estimated_droppable_tombstones(NO_EXPIRY));
+        assert_eq!(0, stats.estimated_droppable_tombstones(0));
+
+        let empty = write_sstable(&table_metadata, folder.path(), &[]).stats().clone();
+        assert_eq!(None, empty.token_range);
+        assert_eq!(DbTimestamp::MAX, empty.min_timestamp);
+
+        let mut out = CassWrite::new(Cursor::new(Vec::new()));
+        empty.write(&mut out, &table_metadata).unwrap();
+        assert_eq!(empty, SstableStats::read(&out.into_inner().into_inner()).unwrap());
+    }
+}
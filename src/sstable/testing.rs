@@ -0,0 +1,103 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use uuid::Uuid;
+
+use crate::db::{ColumnMetaData, ColumnType, CompressionOptions, RegularRowData, RowDetails, TableCell, TableCellData, TableMetaData, TableOptions, TableRow};
+use crate::sstable::row_data::RowDataFileCreator;
+use crate::sstable::{Sstable, SstableMetaData};
+
+
+/// A folder for a single test's files, which is deleted together with them when it is dropped.
+pub struct TempFolder {
+    path: PathBuf,
+}
+
+impl TempFolder {
+    pub fn new() -> TempFolder {
+        let path = std::env::temp_dir().join(format!("r-cass-test-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&path).unwrap();
+        TempFolder { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for TempFolder {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.path);
+    }
+}
+
+
+pub fn column(name: &str, col_type: ColumnType) -> Arc<ColumnMetaData> {
+    Arc::new(ColumnMetaData { name: name.to_string(), id: Uuid::new_v4(), col_type })
+}
+
+pub fn id_column() -> Arc<ColumnMetaData> {
+    column("id", ColumnType::Long)
+}
+
+/// a table with partition key `id` (Long) and a regular column `v` (Text)
+pub fn table_metadata(compression: CompressionOptions) -> Arc<TableMetaData> {
+    let columns = vec!(
+        id_column(),
+        column("v", ColumnType::Text),
+    );
+    let options = TableOptions {
+        compression,
+        ..TableOptions::default()
+    };
+    Arc::new(TableMetaData::new("t".to_string(), Uuid::new_v4(), columns, 0, Vec::new()).with_options(options))
+}
+
+/// a table with cluster key `ck` (Long), partition key `id` (Long) and regular columns of the
+///  given names and types
+pub fn clustered_table_metadata(regular_columns: &[(&str, ColumnType)], options: TableOptions) -> Arc<TableMetaData> {
+    let mut columns = vec!(column("ck", ColumnType::Long), id_column());
+    columns.extend(regular_columns.iter().map(|(name, col_type)| column(name, col_type.clone())));
+    Arc::new(TableMetaData::new("t".to_string(), Uuid::new_v4(), columns, 1, vec!(0)).with_options(options))
+}
+
+/// partition keys `0..n`
+pub fn ids(n: u64) -> Vec<Vec<u8>> {
+    (0..n).map(|n| n.to_be_bytes().to_vec()).collect()
+}
+
+/// a serialized Text value of `len` bytes
+pub fn text_value(len: usize) -> Vec<u8> {
+    let mut result = (len as u32).to_be_bytes().to_vec();
+    result.resize(result.len() + len, b'x');
+    result
+}
+
+/// a row per partition key with the same value for `v`, in token order as they are written to an
+///  sstable
+pub fn rows<'a>(table_metadata: &Arc<TableMetaData>, ids: &'a [Vec<u8>], value: &'a [u8]) -> Vec<TableRow<'a>> {
+    let mut rows: Vec<TableRow> = ids.iter()
+        .map(|id| TableRow::new(table_metadata.clone(), id, RowDetails::Regular(RegularRowData {
+            pk_expiry: 9999u32,
+            cluster_key: Vec::new(),
+            regular_cols: vec!(TableCell {
+                meta_data: table_metadata.columns[1].clone(),
+                path: None,
+                timestamp: 1000,
+                expiry: 9999,
+                data: TableCellData::Regular(value),
+            }),
+        })))
+        .collect();
+    rows.sort_by_key(|r| r.token);
+    rows
+}
+
+/// writes rows, which must be in token order, to a new sstable in the folder
+pub fn write_sstable(table_metadata: &Arc<TableMetaData>, folder: &Path, rows: &[TableRow]) -> Arc<Sstable> {
+    let mut creator = RowDataFileCreator::new(SstableMetaData::new(table_metadata.clone(), folder)).unwrap();
+    for row in rows.iter() {
+        creator.append_row(row).unwrap();
+    }
+    creator.finalize().unwrap()
+}
@@ -0,0 +1,226 @@
+use std::borrow::Cow;
+
+use crate::db::CompressionCodec;
+use crate::io::{CassRead, DecodeError, DecodeResult};
+use crate::sstable::checksum::{digest, read_digest};
+use crate::sstable::compression::ChunkInfo;
+use crate::sstable::header::{Component, ComponentHeader};
+use crate::sstable::partition_index::PartitionIndex;
+use crate::sstable::row_data::RowDataReader;
+use crate::sstable::{map_file, SstableMetaData};
+use crate::util::Token;
+
+
+/// the findings of `verify()`
+#[derive(Debug)]
+pub struct VerifyReport {
+    /// data file blocks whose checksum does not match, or that have no checksum
+    pub corrupt_blocks: Vec<usize>,
+    /// set if the data file's header or its chunk table can not be decoded, the data file is then
+    ///  verified as if it were empty
+    pub data_error: Option<DecodeError>,
+    pub digest_matches: bool,
+    /// partitions (token and partition key) that are stored in a corrupt block, that can not be
+    ///  decoded, or whose index entry does not point to their rows
+    pub corrupt_partitions: Vec<(Token, Vec<u8>)>,
+    /// set if the partition index can not be traversed completely
+    pub index_error: Option<DecodeError>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.corrupt_blocks.is_empty() && self.data_error.is_none() && self.digest_matches && self.corrupt_partitions.is_empty() && self.index_error.is_none()
+    }
+}
+
+/// Checks every block of an sstable's (uncompressed) data file against its checksum and the whole
+///  file against its digest. Then it walks the partition index, checking that each partition's rows can be
+///  decoded and belong to the partition.
+pub fn verify(sstable: &SstableMetaData) -> std::io::Result<VerifyReport> {
+    let checksums = sstable.read_checksums()?;
+    let digest_buf = std::fs::read(sstable.digest_filename())?;
+    let expected_digest = read_digest(&digest_buf).map_err(|e| e.in_file(&sstable.digest_filename()))?;
+
+    let data_map = map_file(&sstable.data_filename())?;
+    let chunk_info_buf = std::fs::read(sstable.chunk_info_filename())?;
+    let (data, data_error) = match data_body(sstable, data_map.as_ref().map_or(&[], |m| m), &chunk_info_buf) {
+        Ok(data) => (data, None),
+        Err(e) => (Cow::Borrowed(&[][..]), Some(e)),
+    };
+    let data: &[u8] = &data;
+    let index_map = map_file(&sstable.index_filename())?;
+    let index_buf: &[u8] = index_map.as_ref().map_or(&[], |m| m);
+
+    let num_blocks = match data.len() {
+        0 => 0,
+        len => checksums.block_of(len - 1) + 1,
+    };
+    let corrupt_blocks: Vec<usize> = (0..num_blocks.max(checksums.num_blocks()))
+        .filter(|block| !checksums.verify_block(data, 0, *block))
+        .collect();
+
+    let mut entries = Vec::new();
+    let mut index_error = None;
+    match PartitionIndex::new(index_buf) {
+        Err(e) => index_error = Some(e),
+        Ok(index) => for entry in index.iter() {
+            match entry {
+                Ok((key, entry)) => entries.push((key.token, key.partition_key.to_vec(), entry.offset as usize)),
+                Err(e) => index_error = Some(e),
+            }
+        },
+    }
+    let index_error = index_error.map(|e| e.in_file(&sstable.index_filename()));
+
+    let mut corrupt_partitions = Vec::new();
+    for (idx, (token, partition_key, start)) in entries.iter().enumerate() {
+        let end = entries.get(idx + 1).map_or(data.len(), |(_, _, offset)| *offset);
+
+        let is_valid = *start < end && end <= data.len()
+            && !corrupt_blocks.iter().any(|block| *block >= checksums.block_of(*start) && *block <= checksums.block_of(end - 1))
+            && is_valid_partition(sstable, &data[..end], *start, *token, partition_key);
+        if !is_valid {
+            corrupt_partitions.push((*token, partition_key.clone()));
+        }
+    }
+
+    Ok(VerifyReport {
+        corrupt_blocks,
+        data_error,
+        digest_matches: digest(data) == expected_digest,
+        corrupt_partitions,
+        index_error,
+    })
+}
+
+/// the uncompressed contents of the data file after its header
+fn data_body<'a>(sstable: &SstableMetaData, file: &'a [u8], chunk_info_buf: &[u8]) -> DecodeResult<Cow<'a, [u8]>> {
+    let header = ComponentHeader::read(file, Component::Data)
+        .and_then(|header| header.check_table(&sstable.table_metadata).map(|_| header))
+        .map_err(|e| e.in_file(&sstable.data_filename()))?;
+    let info = ChunkInfo::read(chunk_info_buf).map_err(|e| e.in_file(&sstable.chunk_info_filename()))?;
+    Ok(decompress_leniently(&info, &file[header.body_offset()..]))
+}
+
+/// The uncompressed contents of a data file. Chunks that can not be decompressed are replaced by
+///  zeros, so their checksums do not match.
+fn decompress_leniently<'a>(info: &ChunkInfo, file: &'a [u8]) -> Cow<'a, [u8]> {
+    if info.codec() == CompressionCodec::None {
+        return Cow::Borrowed(file);
+    }
+
+    let mut data = Vec::with_capacity(info.uncompressed_length());
+    for chunk in 0..info.num_chunks() {
+        match info.decompress_chunk(file, chunk) {
+            Ok(decompressed) => data.extend(decompressed),
+            Err(_) => data.resize(data.len() + info.chunk_length(chunk), 0),
+        }
+    }
+    Cow::Owned(data)
+}
+
+/// the partition's rows must fill `data` from `start` to its end exactly
+fn is_valid_partition(sstable: &SstableMetaData, data: &[u8], start: usize, token: Token, partition_key: &[u8]) -> bool {
+    let mut buf = CassRead::wrap(data);
+    buf.pos = start;
+
+    RowDataReader::new(sstable.table_metadata.clone(), buf)
+        .all(|row| matches!(row, Ok(row) if row.token == token && row.partition_key == partition_key))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::db::{CompressionCodec, CompressionOptions};
+    use crate::io::DecodeErrorKind;
+    use crate::sstable::header::{Component, ComponentHeader};
+    use crate::sstable::row_data::RowDataReader;
+    use crate::sstable::testing::{ids, rows, table_metadata, text_value, write_sstable, TempFolder};
+    use crate::sstable::verify::verify;
+    use crate::sstable::SstableMetaData;
+
+    /// an sstable spanning several data blocks
+    fn create_sstable(folder: &TempFolder, codec: CompressionCodec) -> SstableMetaData {
+        let table_metadata = table_metadata(CompressionOptions { codec, ..CompressionOptions::default() });
+        let ids = ids(2000);
+        let value = text_value(200);
+        write_sstable(&table_metadata, folder.path(), &rows(&table_metadata, &ids, &value)).meta_data().clone()
+    }
+
+    #[test]
+    pub fn test_verify() {
+        let folder = TempFolder::new();
+        let sstable = create_sstable(&folder, CompressionCodec::None);
+        let checksums = sstable.read_checksums().unwrap();
+        assert!(checksums.num_blocks() > 3);
+        assert!(verify(&sstable).unwrap().is_ok());
+
+        let mut data = std::fs::read(sstable.data_filename()).unwrap();
+        let body_offset = ComponentHeader::read(&data, Component::Data).unwrap().body_offset();
+        data[body_offset + checksums.block_size() + 100] ^= 1;
+        std::fs::write(sstable.data_filename(), &data).unwrap();
+
+        let report = verify(&sstable).unwrap();
+        assert_eq!(vec!(1), report.corrupt_blocks);
+        assert!(!report.digest_matches);
+        assert!(report.index_error.is_none());
+        assert!(!report.corrupt_partitions.is_empty());
+        assert!(report.corrupt_partitions.len() < 2000 / 4);
+
+        // a reader that knows the checksums fails when it reaches the corrupt block
+        let contents = sstable.read_data().unwrap();
+        let rows: Vec<_> = RowDataReader::for_contents(sstable.table_metadata.clone(), &contents, 0).unwrap()
+            .with_checksums(&checksums)
+            .collect();
+        let e = rows.last().unwrap().as_ref().err().unwrap();
+        assert_eq!(DecodeErrorKind::ChecksumMismatch { block: 1 }, e.kind);
+        assert!(rows[..rows.len() - 1].iter().all(|row| row.is_ok()));
+
+        let mut index = std::fs::read(sstable.index_filename()).unwrap();
+        let len = index.len();
+        index[len - 8..].copy_from_slice(&(len as u64 + 1000).to_be_bytes());
+        std::fs::write(sstable.index_filename(), &index).unwrap();
+        assert!(verify(&sstable).unwrap().index_error.is_some());
+    }
+
+    #[test]
+    pub fn test_verify_compressed() {
+        let folder = TempFolder::new();
+        let sstable = create_sstable(&folder, CompressionCodec::Lz4);
+        assert!(sstable.data_size().unwrap() < sstable.read_chunk_info().unwrap().uncompressed_length() as u64 / 2);
+        assert!(verify(&sstable).unwrap().is_ok());
+
+        let mut data = std::fs::read(sstable.data_filename()).unwrap();
+        let len = data.len();
+        data[len / 2] ^= 0xff;
+        std::fs::write(sstable.data_filename(), &data).unwrap();
+
+        let report = verify(&sstable).unwrap();
+        assert_eq!(1, report.corrupt_blocks.len());
+        assert!(!report.digest_matches);
+        assert!(!report.corrupt_partitions.is_empty());
+    }
+
+    #[test]
+    pub fn test_verify_damaged_data_header() {
+        let folder = TempFolder::new();
+        let sstable = create_sstable(&folder, CompressionCodec::Lz4);
+        let chunk_info = std::fs::read(sstable.chunk_info_filename()).unwrap();
+
+        std::fs::write(sstable.chunk_info_filename(), &chunk_info[..chunk_info.len() - 3]).unwrap();
+        let report = verify(&sstable).unwrap();
+        assert!(report.data_error.is_some());
+        assert!(report.corrupt_blocks.contains(&0));
+        assert_eq!(2000, report.corrupt_partitions.len());
+        assert!(report.index_error.is_none());
+        std::fs::write(sstable.chunk_info_filename(), &chunk_info).unwrap();
+
+        let mut data = std::fs::read(sstable.data_filename()).unwrap();
+        data[0] ^= 0xff;
+        std::fs::write(sstable.data_filename(), &data).unwrap();
+        let report = verify(&sstable).unwrap();
+        assert!(report.data_error.is_some());
+        assert!(report.corrupt_blocks.contains(&0));
+        assert!(!report.is_ok());
+    }
+}
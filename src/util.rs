@@ -1,5 +1,9 @@
 use std::io::ErrorKind;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
 use uuid::{Uuid, Variant, Version};
+use uuid::v1::Context;
 
 
 /// a partition key's hash used to assign the key to node(s), among other things
@@ -12,6 +16,41 @@ pub type DbTimestamp = u64;
 /// expiry timestamps are seconds since EPOCH (u32 means overflow end of 21st century - enough for now)
 pub type DbExpiryTimestamp = u32;
 
+/// expiry timestamp for data without a TTL
+pub const NO_EXPIRY: DbExpiryTimestamp = DbExpiryTimestamp::MAX;
+
 pub (crate) fn other_error<T>(text: &str) -> std::io::Result<T> {
     Err(std::io::Error::new(ErrorKind::Other, text))
 }
+
+/// the current time as an expiry timestamp, i.e. data with an expiry timestamp up to this is expired
+pub fn expiry_now() -> DbExpiryTimestamp {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()) as DbExpiryTimestamp
+}
+
+/// the second of a (nanosecond) write timestamp, e.g. a tombstone's deletion time
+pub fn expiry_of_timestamp(timestamp: DbTimestamp) -> DbExpiryTimestamp {
+    (timestamp / 1_000_000_000) as DbExpiryTimestamp
+}
+
+/// A time based (version 1) UUID as the path of a new list element. `ColumnType::compare` orders
+///  them by their timestamps, which strictly increase from call to call, so appended elements
+///  keep the order in which they were added.
+pub fn new_list_element_path() -> Uuid {
+    static LAST_TICKS: AtomicU64 = AtomicU64::new(0);
+    static NODE_ID: OnceLock<[u8; 6]> = OnceLock::new();
+
+    // UUID timestamps count 100ns intervals
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| (d.as_nanos() / 100) as u64);
+    let last = LAST_TICKS.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| Some(now.max(last + 1)))
+        .unwrap_or_else(|last| last);
+    let ticks = now.max(last + 1);
+
+    let node_id = NODE_ID.get_or_init(|| {
+        let mut node_id = [0u8; 6];
+        node_id.copy_from_slice(&Uuid::new_v4().as_bytes()[..6]);
+        node_id
+    });
+    Uuid::new_v1(&Context::new(0), ticks / 10_000_000, (ticks % 10_000_000) as u32 * 100, node_id)
+        .expect("node ids have 6 bytes")
+}
@@ -0,0 +1,563 @@
+use std::cmp::Ordering;
+use std::convert::{TryFrom, TryInto};
+use std::io::Cursor;
+use std::mem::size_of;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use uuid::Uuid;
+
+use crate::db::{ColumnType, RowDetails, TableCellData, TableRow};
+use crate::io::{CassRead, CassWrite, DecodeError, DecodeErrorKind, DecodeResult};
+use crate::util::{other_error, DbTimestamp};
+
+const NANOS_PER_DAY: i64 = 24 * 60 * 60 * 1_000_000_000;
+
+
+/// A typed value of a column, see `ColumnType` for how each type is serialized. Values of
+///  collection and user types are the whole (frozen) value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Text(String),
+    Uuid(Uuid),
+    Int(i32),
+    Long(i64),
+    /// millis since epoch
+    Timestamp(i64),
+    Boolean(bool),
+    Float(f32),
+    Double(f64),
+    /// `unscaled * 10^-scale`, with the unscaled value as two's complement big endian bytes
+    Decimal { scale: i32, unscaled: Vec<u8> },
+    /// two's complement big endian bytes
+    Varint(Vec<u8>),
+    Blob(Vec<u8>),
+    Inet(IpAddr),
+    /// days since epoch
+    Date(i32),
+    /// nanos since midnight
+    Time(i64),
+    Duration { months: i64, days: i64, nanos: i64 },
+    Tuple(Vec<Value>),
+    List(Vec<Value>),
+    /// elements are stored sorted and without duplicates
+    Set(Vec<Value>),
+    /// entries are stored sorted by key, and keys must be unique
+    Map(Vec<(Value, Value)>),
+    /// field values in definition order. Values written before fields were added to the type
+    ///  lack these fields at the end.
+    UserType(Vec<Value>),
+}
+
+impl Value {
+    /// serializes the value as `col_type`, failing if the value does not match the type
+    pub fn encode(&self, col_type: &ColumnType) -> std::io::Result<Vec<u8>> {
+        let mut out = CassWrite::new(Cursor::new(Vec::new()));
+        self.write(&mut out, col_type)?;
+        Ok(out.into_inner().into_inner())
+    }
+
+    fn write(&self, out: &mut CassWrite<Cursor<Vec<u8>>>, col_type: &ColumnType) -> std::io::Result<()> {
+        match (col_type, self) {
+            (ColumnType::Text, Value::Text(v)) => out.write_utf8(v),
+            (ColumnType::Uuid, Value::Uuid(v)) => out.write_uuid(v),
+            (ColumnType::Int, Value::Int(v)) => out.write_raw(&v.to_be_bytes()),
+            (ColumnType::Long, Value::Long(v)) => out.write_raw(&v.to_be_bytes()),
+            (ColumnType::Timestamp, Value::Timestamp(v)) => out.write_raw(&v.to_be_bytes()),
+            (ColumnType::Boolean, Value::Boolean(v)) => out.write_bool(*v),
+            (ColumnType::Float, Value::Float(v)) => out.write_raw(&v.to_be_bytes()),
+            (ColumnType::Double, Value::Double(v)) => out.write_raw(&v.to_be_bytes()),
+            (ColumnType::Decimal, Value::Decimal { scale, unscaled }) => {
+                out.write_raw(&scale.to_be_bytes())?;
+                out.write_varint(unscaled.len() as u64)?;
+                out.write_raw(unscaled)
+            },
+            (ColumnType::Varint, Value::Varint(v)) | (ColumnType::Blob, Value::Blob(v)) => {
+                out.write_varint(v.len() as u64)?;
+                out.write_raw(v)
+            },
+            (ColumnType::Inet, Value::Inet(IpAddr::V4(v))) => {
+                out.write_u8(4)?;
+                out.write_raw(&v.octets())
+            },
+            (ColumnType::Inet, Value::Inet(IpAddr::V6(v))) => {
+                out.write_u8(16)?;
+                out.write_raw(&v.octets())
+            },
+            (ColumnType::Date, Value::Date(v)) => out.write_raw(&v.to_be_bytes()),
+            (ColumnType::Time, Value::Time(v)) => {
+                if !(0..NANOS_PER_DAY).contains(v) {
+                    return other_error("time of day out of range");
+                }
+                out.write_raw(&v.to_be_bytes())
+            },
+            (ColumnType::Duration, Value::Duration { months, days, nanos }) => {
+                out.write_signed_varint(*months)?;
+                out.write_signed_varint(*days)?;
+                out.write_signed_varint(*nanos)
+            },
+            (ColumnType::Tuple(types), Value::Tuple(values)) => {
+                if types.len() != values.len() {
+                    return other_error("number of tuple elements does not match the tuple type");
+                }
+                for (t, v) in types.iter().zip(values.iter()) {
+                    v.write(out, t)?;
+                }
+                Ok(())
+            },
+            (ColumnType::List(element), Value::List(values)) => {
+                out.write_u32(values.len() as u32)?;
+                for v in values {
+                    v.write(out, element)?;
+                }
+                Ok(())
+            },
+            (ColumnType::Set(element), Value::Set(values)) => {
+                let mut elements = values.iter()
+                    .map(|v| v.encode(element))
+                    .collect::<std::io::Result<Vec<_>>>()?;
+                elements.sort_by(|a, b| element.compare(a, b));
+                elements.dedup_by(|a, b| element.compare(a, b) == Ordering::Equal);
+
+                out.write_u32(elements.len() as u32)?;
+                for e in elements {
+                    out.write_raw(&e)?;
+                }
+                Ok(())
+            },
+            (ColumnType::Map(key_type, value_type), Value::Map(entries)) => {
+                let mut encoded = Vec::new();
+                for (k, v) in entries {
+                    encoded.push((k.encode(key_type)?, v.encode(value_type)?));
+                }
+                encoded.sort_by(|(a, _), (b, _)| key_type.compare(a, b));
+                if encoded.windows(2).any(|w| key_type.compare(&w[0].0, &w[1].0) == Ordering::Equal) {
+                    return other_error("duplicate map key");
+                }
+
+                out.write_u32(encoded.len() as u32)?;
+                for (k, v) in encoded {
+                    out.write_raw(&k)?;
+                    out.write_raw(&v)?;
+                }
+                Ok(())
+            },
+            (ColumnType::UserType(user_type), Value::UserType(values)) => {
+                if values.len() > user_type.fields.len() {
+                    return other_error("more values than user type fields");
+                }
+                out.write_u32(values.len() as u32)?;
+                for (field, v) in user_type.fields.iter().zip(values.iter()) {
+                    v.write(out, &field.field_type)?;
+                }
+                Ok(())
+            },
+            _ => other_error("value does not match the column type"),
+        }
+    }
+
+    /// deserializes a value of `col_type`, which must take up all of `buf`
+    pub fn decode(col_type: &ColumnType, buf: &[u8]) -> DecodeResult<Value> {
+        let mut r = CassRead::wrap(buf);
+        let result = Value::read(&mut r, col_type)?;
+        if !r.is_at_end() {
+            return Err(DecodeError::new(r.pos, DecodeErrorKind::InvalidValue("value length")));
+        }
+        Ok(result)
+    }
+
+    fn read(r: &mut CassRead, col_type: &ColumnType) -> DecodeResult<Value> {
+        let offset = r.pos;
+        Ok(match col_type {
+            ColumnType::Text => Value::Text(r.read_utf8()?.to_string()),
+            ColumnType::Uuid => Value::Uuid(r.read_uuid()?),
+            ColumnType::Int => Value::Int(r.read_u32()? as i32),
+            ColumnType::Long => Value::Long(r.read_u64()? as i64),
+            ColumnType::Timestamp => Value::Timestamp(r.read_u64()? as i64),
+            ColumnType::Boolean => match r.read_u8()? {
+                0 => Value::Boolean(false),
+                1 => Value::Boolean(true),
+                _ => return Err(DecodeError::new(offset, DecodeErrorKind::InvalidValue("boolean"))),
+            },
+            ColumnType::Float => Value::Float(f32::from_bits(r.read_u32()?)),
+            ColumnType::Double => Value::Double(f64::from_bits(r.read_u64()?)),
+            ColumnType::Decimal => {
+                let scale = r.read_u32()? as i32;
+                Value::Decimal { scale, unscaled: read_varint_bytes(r)?.to_vec() }
+            },
+            ColumnType::Varint => Value::Varint(read_varint_bytes(r)?.to_vec()),
+            ColumnType::Blob => Value::Blob(read_varint_bytes(r)?.to_vec()),
+            ColumnType::Inet => match r.read_u8()? {
+                4 => {
+                    let octets: [u8; 4] = r.read_slice(4)?.try_into().unwrap();
+                    Value::Inet(IpAddr::V4(Ipv4Addr::from(octets)))
+                },
+                16 => {
+                    let octets: [u8; 16] = r.read_slice(16)?.try_into().unwrap();
+                    Value::Inet(IpAddr::V6(Ipv6Addr::from(octets)))
+                },
+                _ => return Err(DecodeError::new(offset, DecodeErrorKind::InvalidValue("inet address length"))),
+            },
+            ColumnType::Date => Value::Date(r.read_u32()? as i32),
+            ColumnType::Time => {
+                let nanos = r.read_u64()? as i64;
+                if !(0..NANOS_PER_DAY).contains(&nanos) {
+                    return Err(DecodeError::new(offset, DecodeErrorKind::InvalidValue("time of day")));
+                }
+                Value::Time(nanos)
+            },
+            ColumnType::Duration => Value::Duration {
+                months: r.read_signed_varint()?,
+                days: r.read_signed_varint()?,
+                nanos: r.read_signed_varint()?,
+            },
+            ColumnType::Tuple(types) => {
+                let mut values = Vec::new();
+                for t in types {
+                    values.push(Value::read(r, t)?);
+                }
+                Value::Tuple(values)
+            },
+            ColumnType::List(element) => Value::List(read_elements(r, element)?),
+            ColumnType::Set(element) => {
+                let start = r.pos;
+                let values = read_elements(r, element)?;
+                check_ascending(r.data(), start, element, values.len())?;
+                Value::Set(values)
+            },
+            ColumnType::Map(key_type, value_type) => {
+                let mut entries = Vec::new();
+                let mut previous_key: Option<&[u8]> = None;
+                for _ in 0..r.read_u32()? {
+                    let key_offset = r.pos;
+                    let key = Value::read(r, key_type)?;
+                    let key_bytes = &r.data()[key_offset..r.pos];
+                    if previous_key.is_some_and(|p| key_type.compare(p, key_bytes) != Ordering::Less) {
+                        return Err(DecodeError::new(key_offset, DecodeErrorKind::InvalidValue("map key order")));
+                    }
+                    previous_key = Some(key_bytes);
+                    entries.push((key, Value::read(r, value_type)?));
+                }
+                Value::Map(entries)
+            },
+            ColumnType::UserType(user_type) => {
+                let num_fields = r.read_u32()? as usize;
+                if num_fields > user_type.fields.len() {
+                    return Err(DecodeError::new(offset, DecodeErrorKind::InvalidValue("number of user type fields")));
+                }
+                let mut values = Vec::new();
+                for field in &user_type.fields[..num_fields] {
+                    values.push(Value::read(r, &field.field_type)?);
+                }
+                Value::UserType(values)
+            },
+        })
+    }
+}
+
+fn read_varint_bytes<'a>(r: &mut CassRead<'a>) -> DecodeResult<&'a [u8]> {
+    let len = r.read_varint()?;
+    r.read_slice(len.try_into().unwrap_or(usize::MAX))
+}
+
+/// elements of a whole collection: their number (u32) followed by the elements
+fn read_elements(r: &mut CassRead, element: &ColumnType) -> DecodeResult<Vec<Value>> {
+    let mut values = Vec::new();
+    for _ in 0..r.read_u32()? {
+        values.push(Value::read(r, element)?);
+    }
+    Ok(values)
+}
+
+/// checks that the `num_elements` set elements starting at `start` are in strictly ascending order
+fn check_ascending(buf: &[u8], start: usize, element: &ColumnType, num_elements: usize) -> DecodeResult<()> {
+    let mut r = CassRead::wrap(buf);
+    r.pos = start + 4;
+    let mut previous: Option<&[u8]> = None;
+    for _ in 0..num_elements {
+        let element_offset = r.pos;
+        Value::read(&mut r, element)?;
+        let current = &buf[element_offset..r.pos];
+        if previous.is_some_and(|p| element.compare(p, current) != Ordering::Less) {
+            return Err(DecodeError::new(element_offset, DecodeErrorKind::InvalidValue("set element order")));
+        }
+        previous = Some(current);
+    }
+    Ok(())
+}
+
+/// the length of the serialized value of `col_type` that `buf` starts with
+pub fn serialized_len(col_type: &ColumnType, buf: &[u8]) -> DecodeResult<usize> {
+    let r = CassRead::wrap(buf);
+    let len = value_size(&r, col_type, 0)?;
+    r.check_remaining(0, len)?;
+    Ok(len)
+}
+
+/// The length of the serialized value of `col_type` at `offs` after `buf`'s position, without
+///  decoding it. Lengths are read from the data, so they are checked against the available data
+///  before they are added up.
+pub fn value_size(buf: &CassRead, col_type: &ColumnType, offs: usize) -> DecodeResult<usize> {
+    Ok(match col_type {
+        ColumnType::Text => {
+            let len = buf.peek_u32_offs(offs)?;
+            grow(buf, offs, size_of::<u32>(), len as u64)?
+        },
+        ColumnType::Uuid => 16,
+        ColumnType::Boolean => 1,
+        ColumnType::Int => 4,
+        ColumnType::Long => 8,
+        ColumnType::Timestamp => size_of::<DbTimestamp>(),
+        ColumnType::Float => size_of::<f32>(),
+        ColumnType::Double => size_of::<f64>(),
+        ColumnType::Decimal => {
+            let unscaled = value_size(buf, &ColumnType::Varint, offs + size_of::<i32>())?;
+            grow(buf, offs, size_of::<i32>(), unscaled as u64)?
+        },
+        ColumnType::Varint | ColumnType::Blob => {
+            let (len, len_size) = buf.peek_varint_offs(offs)?;
+            grow(buf, offs, len_size, len)?
+        },
+        ColumnType::Inet => {
+            let offset = buf.pos + offs;
+            match buf.peek_u8_offs(offs)? {
+                len @ (4 | 16) => 1 + len as usize,
+                _ => return Err(DecodeError::new(offset, DecodeErrorKind::InvalidValue("inet address length"))),
+            }
+        },
+        ColumnType::Date => size_of::<i32>(),
+        ColumnType::Time => size_of::<i64>(),
+        ColumnType::Duration => {
+            let mut result = 0;
+            for _ in 0..3 {
+                result += buf.peek_varint_offs(offs + result)?.1;
+            }
+            result
+        },
+        ColumnType::Tuple(parts) => {
+            let mut result = 0;
+            for part in parts {
+                let part_size = value_size(buf, part, offs + result)?;
+                result = grow(buf, offs, result, part_size as u64)?;
+            }
+            result
+        },
+        // whole collections are stored as the number of elements followed by the elements
+        ColumnType::List(element) | ColumnType::Set(element) => {
+            let num_elements = buf.peek_u32_offs(offs)?;
+            let mut result = size_of::<u32>();
+            for _ in 0..num_elements {
+                let element_size = value_size(buf, element, offs + result)?;
+                result = grow(buf, offs, result, element_size as u64)?;
+            }
+            result
+        },
+        ColumnType::Map(key, value) => {
+            let num_entries = buf.peek_u32_offs(offs)?;
+            let mut result = size_of::<u32>();
+            for _ in 0..num_entries {
+                let key_size = value_size(buf, key, offs + result)?;
+                result = grow(buf, offs, result, key_size as u64)?;
+                let value_size = value_size(buf, value, offs + result)?;
+                result = grow(buf, offs, result, value_size as u64)?;
+            }
+            result
+        },
+        ColumnType::UserType(user_type) => {
+            let offset = buf.pos + offs;
+            let num_fields = buf.peek_u32_offs(offs)? as usize;
+            if num_fields > user_type.fields.len() {
+                return Err(DecodeError::new(offset, DecodeErrorKind::InvalidValue("number of user type fields")));
+            }
+            let mut result = size_of::<u32>();
+            for field in &user_type.fields[..num_fields] {
+                let field_size = value_size(buf, &field.field_type, offs + result)?;
+                result = grow(buf, offs, result, field_size as u64)?;
+            }
+            result
+        },
+    })
+}
+
+/// `size + more`, which must not exceed the data available at `offs`
+fn grow(buf: &CassRead, offs: usize, size: usize, more: u64) -> DecodeResult<usize> {
+    let result = usize::try_from(more).ok()
+        .and_then(|more| size.checked_add(more))
+        .unwrap_or(usize::MAX);
+    buf.check_remaining(offs, result)?;
+    Ok(result)
+}
+
+/// checks that a serialized value matches `col_type`
+pub fn validate(col_type: &ColumnType, buf: &[u8]) -> DecodeResult<()> {
+    Value::decode(col_type, buf).map(|_| ())
+}
+
+/// Checks that all of a row's keys, cell paths and cell values match their columns' types, e.g.
+///  before a row from an application is applied. Error offsets are relative to the offending value.
+pub fn validate_row(row: &TableRow) -> DecodeResult<()> {
+    let table_metadata = row.meta_data();
+    validate(&table_metadata.partition_key().col_type, row.partition_key)?;
+
+    match &row.details {
+        RowDetails::Regular(data) => {
+            if data.cluster_key.len() != table_metadata.idx_cluster_keys.len() {
+                return Err(DecodeError::new(0, DecodeErrorKind::InvalidValue("number of cluster key columns")));
+            }
+            for (idx, ck) in data.cluster_key.iter().enumerate() {
+                validate(&table_metadata.cluster_key(idx).col_type, ck)?;
+            }
+
+            for cell in &data.regular_cols {
+                let col_type = &cell.meta_data.col_type;
+                match (col_type.cell_path_type(), cell.path) {
+                    (None, Some(_)) => return Err(DecodeError::new(0, DecodeErrorKind::InvalidValue("cell path"))),
+                    (Some(path_type), Some(path)) => validate(path_type, path)?,
+                    _ => {},
+                }
+                if let TableCellData::Regular(value) = &cell.data {
+                    let value_type = col_type.cell_value_type(cell.path)
+                        .ok_or_else(|| DecodeError::new(0, DecodeErrorKind::InvalidValue("user type field")))?;
+                    validate(value_type, value)?;
+                }
+            }
+        },
+        RowDetails::RowTombstone(data) => {
+            for bound in data.lower_bound.iter().chain(data.upper_bound.iter()) {
+                if bound.cluster_key_prefix.len() > table_metadata.idx_cluster_keys.len() {
+                    return Err(DecodeError::new(0, DecodeErrorKind::InvalidValue("number of cluster key columns")));
+                }
+                for (idx, ck) in bound.cluster_key_prefix.iter().enumerate() {
+                    validate(&table_metadata.cluster_key(idx).col_type, ck)?;
+                }
+            }
+        },
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::sync::Arc;
+
+    use uuid::Uuid;
+
+    use crate::db::{ColumnType, RegularRowData, RowDetails, TableCell, TableCellData, TableMetaData, TableRow, UserType, UserTypeField};
+    use crate::io::DecodeErrorKind;
+    use crate::sstable::testing::column;
+    use crate::util::NO_EXPIRY;
+    use crate::value::{validate, validate_row, Value};
+
+    fn user_type() -> Arc<UserType> {
+        Arc::new(UserType { name: "u".to_string(), fields: vec!(
+            UserTypeField { name: "a".to_string(), field_type: ColumnType::Text },
+            UserTypeField { name: "b".to_string(), field_type: ColumnType::Int },
+        )})
+    }
+
+    #[test]
+    pub fn test_round_trip() {
+        let values = vec!(
+            (ColumnType::Text, Value::Text("äbc".to_string())),
+            (ColumnType::Uuid, Value::Uuid(Uuid::new_v4())),
+            (ColumnType::Int, Value::Int(-5)),
+            (ColumnType::Long, Value::Long(i64::MIN)),
+            (ColumnType::Timestamp, Value::Timestamp(1_600_000_000_000)),
+            (ColumnType::Boolean, Value::Boolean(true)),
+            (ColumnType::Float, Value::Float(1.5)),
+            (ColumnType::Double, Value::Double(-0.25)),
+            (ColumnType::Decimal, Value::Decimal { scale: 2, unscaled: vec!(0xff, 0x38) }),
+            (ColumnType::Varint, Value::Varint(vec!(1, 0, 0))),
+            (ColumnType::Blob, Value::Blob(vec!(0; 200))),
+            (ColumnType::Inet, Value::Inet("192.168.0.1".parse::<IpAddr>().unwrap())),
+            (ColumnType::Inet, Value::Inet("::1".parse::<IpAddr>().unwrap())),
+            (ColumnType::Date, Value::Date(-1)),
+            (ColumnType::Time, Value::Time(12 * 60 * 60 * 1_000_000_000)),
+            (ColumnType::Duration, Value::Duration { months: 1, days: -2, nanos: 3 }),
+            (ColumnType::Tuple(vec!(ColumnType::Int, ColumnType::Text)), Value::Tuple(vec!(Value::Int(1), Value::Text("x".to_string())))),
+            (ColumnType::List(Box::new(ColumnType::Int)), Value::List(vec!(Value::Int(3), Value::Int(1), Value::Int(3)))),
+            (ColumnType::Set(Box::new(ColumnType::Int)), Value::Set(vec!(Value::Int(1), Value::Int(3)))),
+            (ColumnType::Map(Box::new(ColumnType::Text), Box::new(ColumnType::Long)), Value::Map(vec!((Value::Text("a".to_string()), Value::Long(1)), (Value::Text("b".to_string()), Value::Long(2))))),
+            (ColumnType::UserType(user_type()), Value::UserType(vec!(Value::Text("x".to_string())))),
+            (ColumnType::UserType(user_type()), Value::UserType(vec!(Value::Text("x".to_string()), Value::Int(2)))),
+        );
+
+        for (col_type, value) in values {
+            let bytes = value.encode(&col_type).unwrap();
+            assert_eq!(value, Value::decode(&col_type, &bytes).unwrap(), "{:?}", col_type);
+        }
+
+        assert_eq!(vec!(0, 0, 0, 0, 0, 0, 0, 42), Value::Long(42).encode(&ColumnType::Long).unwrap());
+        assert_eq!(vec!(0, 0, 0, 3, b'a', b'b', b'c'), Value::Text("abc".to_string()).encode(&ColumnType::Text).unwrap());
+    }
+
+    #[test]
+    pub fn test_encode_mismatch() {
+        assert!(Value::Int(1).encode(&ColumnType::Long).is_err());
+        assert!(Value::Time(-1).encode(&ColumnType::Time).is_err());
+        assert!(Value::Tuple(vec!(Value::Int(1))).encode(&ColumnType::Tuple(vec!(ColumnType::Int, ColumnType::Int))).is_err());
+        assert!(Value::List(vec!(Value::Int(1), Value::Long(2))).encode(&ColumnType::List(Box::new(ColumnType::Int))).is_err());
+        assert!(Value::UserType(vec!(Value::Text("x".to_string()), Value::Int(2), Value::Int(3))).encode(&ColumnType::UserType(user_type())).is_err());
+
+        let map_type = ColumnType::Map(Box::new(ColumnType::Int), Box::new(ColumnType::Int));
+        assert!(Value::Map(vec!((Value::Int(1), Value::Int(1)), (Value::Int(1), Value::Int(2)))).encode(&map_type).is_err());
+
+        // sets are sorted and deduplicated
+        let set_type = ColumnType::Set(Box::new(ColumnType::Int));
+        let set = Value::Set(vec!(Value::Int(2), Value::Int(-1), Value::Int(2)));
+        assert_eq!(Value::Set(vec!(Value::Int(-1), Value::Int(2))), Value::decode(&set_type, &set.encode(&set_type).unwrap()).unwrap());
+    }
+
+    #[test]
+    pub fn test_validate() {
+        let kind = |col_type: &ColumnType, buf: &[u8]| validate(col_type, buf).err().map(|e| e.kind);
+
+        assert_eq!(None, kind(&ColumnType::Int, &[0, 0, 0, 1]));
+        assert!(matches!(kind(&ColumnType::Int, &[0, 0, 1]), Some(DecodeErrorKind::UnexpectedEnd { .. })));
+        assert_eq!(Some(DecodeErrorKind::InvalidValue("value length")), kind(&ColumnType::Int, &[0, 0, 0, 0, 1]));
+        assert_eq!(Some(DecodeErrorKind::InvalidValue("boolean")), kind(&ColumnType::Boolean, &[2]));
+        assert_eq!(Some(DecodeErrorKind::InvalidUtf8), kind(&ColumnType::Text, &[0, 0, 0, 1, 0xff]));
+        assert_eq!(Some(DecodeErrorKind::InvalidValue("inet address length")), kind(&ColumnType::Inet, &[5, 1, 2, 3, 4, 5]));
+        assert_eq!(Some(DecodeErrorKind::InvalidValue("time of day")), kind(&ColumnType::Time, &(-1i64).to_be_bytes()));
+        assert_eq!(Some(DecodeErrorKind::InvalidValue("set element order")), kind(&ColumnType::Set(Box::new(ColumnType::Int)), &[0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0, 1]));
+        assert_eq!(Some(DecodeErrorKind::InvalidValue("map key order")), kind(&ColumnType::Map(Box::new(ColumnType::Int), Box::new(ColumnType::Boolean)), &[0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 0, 1, 1]));
+        assert_eq!(Some(DecodeErrorKind::InvalidValue("number of user type fields")), kind(&ColumnType::UserType(user_type()), &[0, 0, 0, 3]));
+    }
+
+    #[test]
+    pub fn test_validate_row() {
+        let columns = vec!(
+            column("ck", ColumnType::Int),
+            column("id", ColumnType::Text),
+            column("tags", ColumnType::Map(Box::new(ColumnType::Text), Box::new(ColumnType::Long))),
+            column("v", ColumnType::Long),
+        );
+        let table_metadata = Arc::new(TableMetaData::new("t".to_string(), Uuid::new_v4(), columns, 1, vec!(0)));
+
+        let id = Value::Text("a".to_string()).encode(&ColumnType::Text).unwrap();
+        let ck = Value::Int(1).encode(&ColumnType::Int).unwrap();
+        let tag = Value::Text("t".to_string()).encode(&ColumnType::Text).unwrap();
+        let long = Value::Long(1).encode(&ColumnType::Long).unwrap();
+        let is_valid = |col_idx: usize, path: Option<&[u8]>, value: &[u8]| {
+            validate_row(&TableRow::new(table_metadata.clone(), &id, RowDetails::Regular(RegularRowData {
+                pk_expiry: NO_EXPIRY,
+                cluster_key: vec!(&ck),
+                regular_cols: vec!(TableCell {
+                    meta_data: table_metadata.columns[col_idx].clone(),
+                    path,
+                    timestamp: 1,
+                    expiry: NO_EXPIRY,
+                    data: TableCellData::Regular(value),
+                }),
+            }))).is_ok()
+        };
+
+        assert!(is_valid(3, None, &long));
+        assert!(!is_valid(3, None, &ck));
+        assert!(!is_valid(3, Some(&[0]), &long));
+        assert!(is_valid(2, Some(&tag), &long));
+        assert!(!is_valid(2, Some(&[0, 0, 0, 2, b't']), &long));
+        assert!(!is_valid(2, Some(&tag), &tag));
+    }
+}